@@ -0,0 +1,360 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Generation of the platform description of a board from its Jailhouse root cell config, i.e. the
+// system config passed to `jailhouse enable`, either compiled or as a plain root cell .cell.
+// The resources of the initial state.toml (memory for the non-root cells, IVSHMEM BDFs, RPUs) are
+// derived from it, while platform_info.toml is a skeleton, whose TODOs are left to the operator.
+
+use std::error::Error;
+use std::fmt::Write;
+
+use toml::map::Map;
+use toml::Value;
+
+use crate::configGenerator::cellbin;
+use crate::configGenerator::cpu;
+use crate::configGenerator::celldesc::*;
+use crate::configGenerator::ivshmem;
+
+const PAGE_SIZE: u64 = 0x1000;
+// Interrupt of the first shared peripheral of the GIC, offset of the vpci_irq_base of the cells
+const SPI_BASE: u64 = 32;
+
+pub struct InitialPlatform {
+    pub state: String,
+    pub platform_info: String,
+}
+
+// Parses a memory size as the mem= kernel parameter, e.g. 768M, 1G or 0x30000000
+pub fn parse_mem_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (number, shift) = match text.chars().last() {
+        Some('K') | Some('k') => (&text[..text.len() - 1], 10),
+        Some('M') | Some('m') => (&text[..text.len() - 1], 20),
+        Some('G') | Some('g') => (&text[..text.len() - 1], 30),
+        _ => (text, 0),
+    };
+    let value = match number.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => number.parse::<u64>(),
+    }
+    .map_err(|_| format!("invalid memory size {}", text))?;
+    value.checked_mul(1 << shift).ok_or_else(|| format!("memory size {} out of range", text))
+}
+
+// Removes [start, end) from the ranges
+fn subtract(ranges: Vec<(u64, u64)>, start: u64, end: u64) -> Vec<(u64, u64)> {
+    let mut left = Vec::new();
+    for (s, e) in ranges {
+        if end <= s || e <= start {
+            left.push((s, e));
+            continue;
+        }
+        if s < start {
+            left.push((s, start));
+        }
+        if end < e {
+            left.push((end, e));
+        }
+    }
+    left
+}
+
+// Memory of the root cell left to the non-root cells: the RAM of the root cell beyond the first
+// linux_mem bytes used by Linux (mem= on its command line), out of the hypervisor and shared regions
+fn available_memory(system: &cellbin::SystemConfig, linux_mem: u64) -> Result<(u64, u64), Box<dyn Error>> {
+    let root = &system.root_cell;
+    let rw = JAILHOUSE_MEM_READ | JAILHOUSE_MEM_WRITE;
+    let special = JAILHOUSE_MEM_IO | JAILHOUSE_MEM_COMM_REGION | JAILHOUSE_MEM_ROOTSHARED;
+    let mut ram: Vec<(u64, u64)> = root.mem_regions.iter()
+        .filter(|r| r.flags & rw == rw && r.flags & special == 0)
+        .map(|r| (r.phys_start, r.phys_start.saturating_add(r.size)))
+        .collect();
+    ram.sort();
+
+    let mut linux_left = linux_mem;
+    let mut free = Vec::new();
+    for (start, end) in ram {
+        let used = linux_left.min(end - start);
+        linux_left -= used;
+        if start + used < end {
+            free.push((start + used, end));
+        }
+    }
+    let hv = &system.hypervisor_memory;
+    free = subtract(free, hv.phys_start, hv.phys_start.saturating_add(hv.size));
+    for r in root.mem_regions.iter().filter(|r| r.flags & special != 0) {
+        free = subtract(free, r.phys_start, r.phys_start.saturating_add(r.size));
+    }
+
+    free.into_iter()
+        .map(|(start, end)| (start.div_ceil(PAGE_SIZE) * PAGE_SIZE, end / PAGE_SIZE * PAGE_SIZE))
+        .filter(|(start, end)| start < end)
+        .max_by_key(|(start, end)| end - start)
+        .ok_or_else(|| "no RAM of the root cell is left to the non-root cells, check the memory of Linux".into())
+}
+
+// IVSHMEM networking devices of the root cell, as the links the cells join at their BDF, and the
+// device numbers of the ones whose regions are not laid out as the links
+fn network_links(root: &CellDesc) -> (Vec<ivshmem::Link>, Vec<u64>) {
+    let mut devices: Vec<&PciDevice> = root.pci_devices.iter()
+        .filter(|d| d.dev_type as u64 == JAILHOUSE_PCI_TYPE_IVSHMEM && d.shmem_protocol as u64 == JAILHOUSE_SHMEM_PROTO_VETH && d.bdf >> 3 != 0)
+        .collect();
+    devices.sort_by_key(|d| d.bdf);
+    let mut links = Vec::new();
+    let mut unusable = Vec::new();
+    for device in devices {
+        match ivshmem::root_link(&format!("net{}", device.bdf >> 3), root, device) {
+            Some(link) => links.push(link),
+            None => unusable.push((device.bdf >> 3) as u64),
+        }
+    }
+    (links, unusable)
+}
+
+fn bits(words: &[u64]) -> Vec<u64> {
+    (0..words.len() as u64 * 64).filter(|i| words[(*i / 64) as usize] & (1 << (i % 64)) != 0).collect()
+}
+
+fn list(values: &[u64]) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+// Generates the initial state.toml and a skeleton platform_info.toml from a root cell config
+pub fn generate(root_config: &[u8], linux_mem: u64, source: &str) -> Result<InitialPlatform, Box<dyn Error>> {
+    // A plain root cell has no hypervisor memory nor debug console of its own
+    let system = if root_config.starts_with(b"JHSYST") {
+        cellbin::deserialize_system(root_config)?
+    } else {
+        let root_cell = cellbin::deserialize(root_config)?;
+        cellbin::SystemConfig { debug_console: root_cell.header.console.clone(), root_cell, ..Default::default() }
+    };
+    let root = &system.root_cell;
+
+    let (mem_start, mem_end) = available_memory(&system, linux_mem)?;
+    let (links, unusable) = network_links(root);
+    let bdfs: Vec<u64> = links.iter().map(|l| l.bdf as u64).collect();
+    let rcpus = bits(&root.rcpus);
+
+    let mut state = String::new();
+    writeln!(state, "# Initial state of the node, generated from the root cell {}", root.header.name)?;
+    writeln!(state, "[containerid]\nids = []\n")?;
+    writeln!(state, "[available_memory]\nmemory = \"0x{:08x}, 0x{:08x}\"\n", mem_start, mem_end)?;
+    writeln!(state, "[free_segments]\nsegments = [\"0x{:08x}, 0x{:08x}\"]\n", mem_start, mem_end)?;
+    writeln!(state, "[free_pci_devices_bdf]\nbdf = [{}]\n", list(&bdfs))?;
+    if !unusable.is_empty() {
+        writeln!(state, "# TODO: the regions of the networking devices at BDF {} are not laid out as JAILHOUSE_SHMEM_NET_REGIONS\n", list(&unusable))?;
+    }
+    // The device numbers no device of the root cell uses are left to the IVSHMEM links
+    let link_bdfs: Vec<u64> = (1..32).filter(|n| !root.pci_devices.iter().any(|d| (d.bdf >> 3) as u64 == *n)).collect();
+    writeln!(state, "[free_ivshmem_bdf]\nbdf = [{}]\n", list(&link_bdfs))?;
+    writeln!(state, "[free_rcpus]\nids = [{}]", list(&rcpus))?;
+    // The networking links of the root cell, which the cells join at their BDF
+    if !links.is_empty() {
+        let mut entries = Map::new();
+        for link in &links {
+            let mut entry = ivshmem::entry(link);
+            entry.insert("members".to_string(), Value::Array(vec![Value::String(ivshmem::ROOT_MEMBER.to_string())]));
+            entry.insert("ids".to_string(), Value::Array(vec![Value::Integer(link.id as i64)]));
+            entries.insert(link.name.clone(), Value::Table(entry));
+        }
+        let mut table = Map::new();
+        table.insert("ivshmem_links".to_string(), Value::Table(entries));
+        write!(state, "\n{}", toml::to_string(&Value::Table(table))?)?;
+    }
+    // One INTx pin for each BDF, after the ones of the root cell as in the Jailhouse configs. With
+    // a demo device at device number 0 each cell takes its pin too: a window of 4 pins for each
+    // BDF after the ones of the root cell, holding the pins of the demo and of the BDF
+    let base = root.header.vpci_irq_base as u64 + SPI_BASE;
+    let irq_pins: Vec<u64> = if root.pci_devices.iter().any(|d| d.bdf >> 3 == 0) {
+        let mut pins: Vec<u64> = bdfs.iter().enumerate()
+            .flat_map(|(i, bdf)| [base + 4 * (i as u64 + 1), base + 4 * (i as u64 + 1) + (bdf & 3)])
+            .collect();
+        pins.sort();
+        pins.dedup();
+        pins
+    } else {
+        bdfs.iter().map(|bdf| base + bdf).collect()
+    };
+    if root.header.vpci_irq_base != 0 && !irq_pins.is_empty() {
+        writeln!(state, "\n# Pins of the IVSHMEM devices of the cells, no device of the board must use them\n[free_irq_pins]\npins = [{}]", list(&irq_pins))?;
+    } else if !bdfs.is_empty() {
+        writeln!(state, "\n# TODO: free interrupt pins for the IVSHMEM devices of the cells\n[free_irq_pins]\npins = []")?;
+    }
+
+    let console = &system.debug_console;
+    let (preamble, todo) = match console.con_type as u64 {
+        JAILHOUSE_CON_TYPE_PL011 => ("QEMU_PREAMBLE", ""),
+        JAILHOUSE_CON_TYPE_XUARTPS => ("ULTRASCALE_PREAMBLE", ""),
+        JAILHOUSE_CON_TYPE_8250 => ("X86_PREAMBLE", ""),
+        _ if root.header.layout == Layout::Omnivisor => ("ULTRASCALE_PREAMBLE", ""),
+        _ => ("QEMU_PREAMBLE", " # TODO: no built-in preamble matches the board, write one in [templates]"),
+    };
+
+    let mut p = String::new();
+    writeln!(p, "# Skeleton platform description, generated from {} (root cell {})", source, root.header.name)?;
+    writeln!(p, "# Fill in the TODOs, then check it with: runphi platform check\n")?;
+    writeln!(p, "[mem_regions]\nregions = [\"UART_TEMPLATE\", \"RAM_TEMPLATE\", \"COMM_REGION_TEMPLATE\"]\n")?;
+    writeln!(p, "[jailhouse_preamble]\npreamble = \"{}\"{}\n", preamble, todo)?;
+    let cpus = bits(&root.cpus);
+    writeln!(p, "[cpus]\n# CPUs of the root cell: {}\n# CPUs kept by the root cell, never given to the cells\nreserved = \"{}\"", cpu::format_cpulist(&cpus.iter().map(|c| *c as usize).collect::<Vec<_>>()), cpus.first().copied().unwrap_or(0))?;
+    writeln!(p, "# TODO: CPUs sharing a cluster, a cell is kept in one if possible\n# clusters = [\"\"]\n")?;
+    if console.con_type as u64 != JAILHOUSE_CON_TYPE_NONE {
+        writeln!(p, "[console]\ntype = \"{}\"\naddress = \"0x{:08x}\"", render_enum(console.con_type as u64, CON_TYPE_NAMES), console.address)?;
+        writeln!(p, "flags = \"{}\"\n", render_flags(console.flags as u64, CON_FLAG_NAMES).replace(" |\n\t\t\t\t", " | "))?;
+    } else {
+        writeln!(p, "# TODO: UART of the cells, without it they only get the virtual console\n# [console]\n# type = \"\"\n# address = \"\"\n# flags = \"\"\n")?;
+    }
+    writeln!(p, "[UART_TEMPLATE]")?;
+    if console.address != 0 {
+        writeln!(p, "phys_start = \"0x{:08x}\"\nvirt_start = \"0x{:08x}\"\nsize = \"0x1000\"\n", console.address, console.address)?;
+    } else {
+        writeln!(p, "# TODO: UART of the cells, the root cell has no debug console\n# phys_start = \"\"\n# virt_start = \"\"\nsize = \"0x1000\"\n")?;
+    }
+    writeln!(p, "[RAM_TEMPLATE]\n# phys_start and size are allocated by runPHI out of the free segments\nvirt_start = \"0x{:08x}\"\n", mem_start)?;
+    writeln!(p, "[COMM_REGION_TEMPLATE]\n# No additional parameters\n")?;
+    writeln!(p, "[devices]\ndevs = [\"IRQ_CHIP_BOARD_TEMPLATE\"]\n")?;
+    writeln!(p, "[IRQ_CHIP_BOARD_TEMPLATE]")?;
+    match root.irqchips.first() {
+        Some(irqchip) => writeln!(p, "gic_address = \"0x{:08x}\"", irqchip.address)?,
+        None => writeln!(p, "# TODO: address of the GIC distributor\n# gic_address = \"\"")?,
+    }
+    // The skeleton must pass the check, hence the pin of the UART of QEMU virt until it is set
+    writeln!(p, "# TODO: interrupt of the UART (33 on QEMU virt), the ones of the IVSHMEM devices are in [free_irq_pins] of the state\nuart_pin = \"{}\"", SPI_BASE + 1)?;
+
+    Ok(InitialPlatform { state, platform_info: p })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform;
+
+    // QEMU root cell with Linux in the first 768M of its RAM and one networking device at BDF 1
+    fn root_cell() -> CellDesc {
+        let mut root = CellDesc::new("qemu-arm64");
+        root.header.vpci_irq_base = 108;
+        root.cpus = cpu_set(&[0, 1, 2, 3]);
+        root.add_mem_regions(r#"
+/* RAM */ {
+	.phys_start = 0x40000000,
+	.virt_start = 0x40000000,
+	.size = 0x40000000,
+	.flags = JAILHOUSE_MEM_READ | JAILHOUSE_MEM_WRITE | JAILHOUSE_MEM_EXECUTE,
+},
+/* UART */ {
+	.phys_start = 0x09000000,
+	.virt_start = 0x09000000,
+	.size = 0x1000,
+	.flags = JAILHOUSE_MEM_READ | JAILHOUSE_MEM_WRITE | JAILHOUSE_MEM_IO,
+},
+JAILHOUSE_SHMEM_NET_REGIONS(0x7f900000, 0),
+"#).unwrap();
+        root.add_elements(r#"
+.irqchips = {
+	/* GIC */ {
+		.address = 0x08000000,
+		.pin_base = 32,
+		.pin_bitmap = { 0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff },
+	},
+},
+.pci_devices = {
+	{ /* IVSHMEM 00:01.0 (networking) */
+		.type = JAILHOUSE_PCI_TYPE_IVSHMEM,
+		.domain = 1,
+		.bdf = 1 << 3,
+		.bar_mask = JAILHOUSE_IVSHMEM_BAR_MASK_INTX,
+		.shmem_regions_start = 2,
+		.shmem_dev_id = 0,
+		.shmem_peers = 2,
+		.shmem_protocol = JAILHOUSE_SHMEM_PROTO_VETH,
+	},
+},
+"#).unwrap();
+        root
+    }
+
+    // Binary system config as passed to `jailhouse enable`: header, hypervisor memory, debug
+    // console and a zeroed platform info, followed by the root cell
+    fn system_config(system: &cellbin::SystemConfig) -> Vec<u8> {
+        let mut out = b"JHSYST".to_vec();
+        // Revision and flags, not read by runPHI
+        out.extend_from_slice(&[0; 6]);
+        let hv = &system.hypervisor_memory;
+        for field in [hv.phys_start, hv.virt_start, hv.size, hv.flags, hv.colors] {
+            out.extend_from_slice(&field.to_le_bytes());
+        }
+        let con = &system.debug_console;
+        out.extend_from_slice(&con.address.to_le_bytes());
+        out.extend_from_slice(&con.size.to_le_bytes());
+        out.extend_from_slice(&con.con_type.to_le_bytes());
+        out.extend_from_slice(&con.flags.to_le_bytes());
+        out.extend_from_slice(&con.divider.to_le_bytes());
+        out.extend_from_slice(&con.gate_nr.to_le_bytes());
+        out.extend_from_slice(&con.clock_reg.to_le_bytes());
+        out.extend_from_slice(&[0; 64]);
+        out.extend(cellbin::serialize(&system.root_cell).unwrap());
+        out
+    }
+
+    fn system() -> cellbin::SystemConfig {
+        cellbin::SystemConfig {
+            hypervisor_memory: MemRegion { phys_start: 0x7fc00000, size: 0x400000, ..Default::default() },
+            debug_console: Console {
+                address: 0x09000000,
+                size: 0x1000,
+                con_type: JAILHOUSE_CON_TYPE_PL011 as u16,
+                flags: JAILHOUSE_CON_ACCESS_MMIO as u16,
+                ..Default::default()
+            },
+            root_cell: root_cell(),
+        }
+    }
+
+    #[test]
+    fn state_is_derived_from_the_root_cell() {
+        let system = system();
+        let config = system_config(&system);
+        assert_eq!(cellbin::deserialize_system(&config).unwrap().hypervisor_memory, system.hypervisor_memory);
+
+        let generated = generate(&config, parse_mem_size("768M").unwrap(), "qemu-arm64.cell").unwrap();
+        let state: Value = generated.state.parse().unwrap();
+        // The RAM after Linux, up to the regions of the networking link
+        assert_eq!(state["free_segments"]["segments"].as_array().unwrap(), &vec![Value::from("0x70000000, 0x7f900000")]);
+        assert_eq!(state["available_memory"]["memory"].as_str(), Some("0x70000000, 0x7f900000"));
+        assert_eq!(state["free_pci_devices_bdf"]["bdf"].as_array().unwrap(), &vec![Value::from(1)]);
+        let link_bdfs: Vec<i64> = state["free_ivshmem_bdf"]["bdf"].as_array().unwrap().iter().map(|b| b.as_integer().unwrap()).collect();
+        assert_eq!(link_bdfs, (2..32).collect::<Vec<i64>>());
+        assert_eq!(state["free_rcpus"]["ids"].as_array().unwrap(), &Vec::<Value>::new());
+        // The pin of BDF 1 after the ones of the root cell
+        assert_eq!(state["free_irq_pins"]["pins"].as_array().unwrap(), &vec![Value::from(141)]);
+        let link = &state["ivshmem_links"]["net1"];
+        assert_eq!((link["memory"].as_str(), link["members"][0].as_str()), (Some("0x7f900000, 0x7f9ff000"), Some(ivshmem::ROOT_MEMBER)));
+    }
+
+    #[test]
+    fn rpus_of_the_root_cell_are_free() {
+        let mut system = system();
+        system.root_cell.header.layout = Layout::Omnivisor;
+        system.root_cell.rcpus = cpu_set(&[0, 1]);
+        let generated = generate(&system_config(&system), 0, "zcu104.cell").unwrap();
+        let state: Value = generated.state.parse().unwrap();
+        assert_eq!(state["free_rcpus"]["ids"].as_array().unwrap(), &vec![Value::from(0), Value::from(1)]);
+        // Without Linux memory, the RAM up to the regions of the link
+        assert_eq!(state["free_segments"]["segments"][0].as_str(), Some("0x40000000, 0x7f900000"));
+    }
+
+    #[test]
+    fn platform_check_accepts_the_skeleton() {
+        let generated = generate(&system_config(&system()), parse_mem_size("768M").unwrap(), "qemu-arm64.cell").unwrap();
+        let dir = std::env::temp_dir().join(format!("runphi-bootstrap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("platform_info.toml"), &generated.platform_info).unwrap();
+        std::fs::write(dir.join("state.toml"), &generated.state).unwrap();
+        let problems = platform::check(&dir.join("platform_info.toml"), &dir.join("state.toml"), &dir.join("templates"));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(problems, Vec::new(), "{}\n{}", generated.platform_info, generated.state);
+    }
+}
@@ -3,63 +3,116 @@
 //          Francesco Boccola (francesco.boccola@unina.it)
 //*********************************************
 
-use regex::Regex;
 use std::error::Error;
 //use std::fs::{File, self, OpenOptions};
 use std::fs;
 use std::io::Write;
 use std::time::Instant;   //TIME CLOCK MONOTONIC
-use std::process::Command;
 use std::str;
 use toml::{Value, map::Map};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 use f2b;
 pub mod boot;
+pub mod cellbin;
+pub mod cellcache;
+pub mod celldesc;
+pub mod cinit;
 pub mod communication;
 pub mod cpu;
 pub mod device;
+pub mod fdt;
+pub mod ivshmem;
+pub mod linux;
 pub mod mem;
+pub mod memalloc;
 pub mod network;
 pub mod templates;
 pub mod rpu;
+pub mod shm;
 use crate::configGenerator::templates::*;
+use crate::statefile;
 
 const WORKPATH: &str = "/usr/share/runPHI";
 //const RUNDIR: &str = "/run/runPHI";
-const STATEFILE: &str = "state.toml";
 const CONFIG_FILE: &str = "platform_info.toml";
-
+// Jailhouse headers used by the gcc cell builder
+const INCLUDE_DIR: &str = "/usr/share/runPHI/include";
+// Cache of the cells compiled by gcc, in WORKPATH
+const CELL_CACHE_DIR: &str = "cellcache";
+const CELL_CACHE_DEFAULT_BYTES: u64 = 16 * 1024 * 1024;
+
+// Architecture of the cells of the node. It decides how the IVSHMEM devices raise interrupts
+// (MSI-X on x86, INTx otherwise), the PCI domain of the cells and how linux cells are booted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86,
+    Arm,
+}
 
 // This structure holds all the information related to the configuration of the partitioned container
-// There is the configuration file, the configuration string, and needed variables for resources,
-// like cpus, memory addresses, devices, and in general all the output of the configGeneration phase
+// There is the cell description filled in by the resource managers, the configuration string
+// rendered from it, and needed variables for resources, like cpus, memory addresses, devices,
+// and in general all the output of the configGeneration phase
 #[derive(Debug)]
 pub struct Backendconfig {
+    pub desc: celldesc::CellDesc,
     pub conf: String,
     pub cpus: u8,
     pub conffile: String,
     pub net: String,
     pub rpu_req: bool,
-    pub segments: Vec<String>,   
+    // Free memory segments, and the ones reserved for the cell
+    pub segments: Vec<memalloc::Segment>,
+    pub used_memory: Vec<memalloc::Segment>,
     pub bdf: Vec<i8>,
     pub rcpus: Vec<i8>,
     pub used_rcpus: Vec<i8>,
+    // UART of the board and console given to the cell
+    pub uart: Option<celldesc::Console>,
+    pub console: f2b::ConsoleKind,
+    // Free interrupt pins for the IVSHMEM devices, if the node allocates them, and the ones taken
+    pub irq_pins: Option<Vec<u32>>,
+    pub used_irq_pins: Vec<u32>,
+    // CPUs given to the cell
+    pub used_cpus: Vec<usize>,
+    // Shared-memory channels joined by the cell
+    pub shm: Vec<shm::Channel>,
+    // IVSHMEM links joined by the cell
+    pub ivshmem: Vec<ivshmem::Link>,
+    // Architecture of the node, from its preamble
+    pub arch: Arch,
+}
+
+impl Default for Backendconfig {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Backendconfig {
     // Constructor function
     pub fn new() -> Self {
         Self {
+            desc: celldesc::CellDesc::default(),
             conf: String::new(),
             cpus: 0,
             conffile: String::new(),
             net: String::new(),
             rpu_req: false,
-            segments: Vec::new(),  
+            segments: Vec::new(),
+            used_memory: Vec::new(),
             bdf: Vec::new(),
             rcpus: Vec::new(),
             used_rcpus: Vec::new(),
+            uart: None,
+            console: f2b::ConsoleKind::None,
+            irq_pins: None,
+            used_irq_pins: Vec::new(),
+            used_cpus: Vec::new(),
+            shm: Vec::new(),
+            ivshmem: Vec::new(),
+            arch: Arch::Arm,
         }
     }
 }
@@ -73,8 +126,11 @@ pub fn config_generate(fc: &f2b::FrontendConfig) -> Result<Box<f2b::ImageConfig>
 
     // parsing configuration variables from the file
     //THIS IS THE ACCESS TO JSON.CONFIG FROM DOCKER
-    logging::log_message(logging::Level::Debug, format!("Reading the config.json inside the container for id {}", &fc.containerid).as_str());
-    let mut config = Box::new(f2b::ImageConfig::get_from_file(&fc.mountpoint));
+    logging::log_message(logging::Level::Debug, format!("Reading the image config of the container for id {}", &fc.containerid).as_str());
+    let mut config = Box::new(f2b::ImageConfig::get(fc).map_err(|e| {
+        logging::log_message(logging::Level::Error, format!("Failed to load image config for id {}: {}", &fc.containerid, e).as_str());
+        e
+    })?);
     logging::log_message(logging::Level::Debug, format!("The mountpoint for the container with id {} is {}", &fc.containerid, &fc.mountpoint).as_str());
     //Clone the value of config.net (from the internal .json) to c.net
     c.net = config.net.clone();
@@ -82,28 +138,36 @@ pub fn config_generate(fc: &f2b::FrontendConfig) -> Result<Box<f2b::ImageConfig>
     // Do we require rpus?
     c.rpu_req = config.rpu_req;
 
-    // Read the state of the machine from the state.toml file (in particular free memory and free bdfs)
+    // Read the state of the machine from the state.toml file (in particular free memory and free bdfs).
+    // The state stays locked until the resources taken by the cell are saved, so that concurrent
+    // creations and the CNI plugin never take the same ones
+    let _lock = statefile::lock()?;
     //let start = Instant::now(); //TAKE THE START TIME OF THE PHASE
-    let (segments, bdf, rcpus) = retrieve_state()?;
+    let (segments, bdf, rcpus, irq_pins) = retrieve_state()?;
     //log_elapsed_time(start,"Duration of retrieve state"); //TAKE THE END TIME OF THE PHASE
 
     // Update the struct
     c.segments = segments;
     c.bdf = bdf;
     c.rcpus = rcpus;
+    c.irq_pins = irq_pins;
     //c.preamble = preamble;
 
+    // The cell of a pod takes the BDF its sandbox was attached to by the CNI plugin
+    if let Some(sandbox) = network::sandbox_id(&fc.jsonconfig) {
+        if let Some(attachment) = network::lookup(sandbox)? {
+            logging::log_message(logging::Level::Debug, format!("Using BDF {} of sandbox {} for id {}", attachment.bdf, sandbox, &fc.containerid).as_str());
+            c.bdf = vec![attachment.bdf as i8];
+        }
+    }
+
     logging::log_message(logging::Level::Debug, format!("Config helper start for id {}", &fc.containerid).as_str());
     //let start = Instant::now(); //TAKE THE START TIME OF THE PHASE
-    let _ = confighelperstart(fc, &mut c, &config);
+    confighelperstart(fc, &mut c, &config)?;
     //log_elapsed_time(start, "Duration of helperstart"); //TAKE THE END TIME OF THE PHASE
 
     // This region of code could be extended with code to retrieve other specific Docker's flags which set CPU limitations
-    // cpus where allow guest execution set by Docker's flag 'cpuset-cpus'
-    // If flag is not set, let's go for 1. It will be overwritten by quota and period if they are defined
-    let _cpu_set = fc.jsonconfig["linux"]["resources"]["cpu"]["cpus"]
-        .as_f64()
-        .unwrap_or(1.0);
+    // The cpus where to allow guest execution, set by Docker's flag 'cpuset-cpus', are honoured by cpuconf
 
     //Through Docker's flag "cpus=0.0000" user requires an amount cpus usage as percentage
     //That percentage will be expressed in form of quota-period ratio (EG: cpus=2.00 means values:
@@ -134,31 +198,29 @@ pub fn config_generate(fc: &f2b::FrontendConfig) -> Result<Box<f2b::ImageConfig>
     if c.rpu_req{
         let rpus=cpus;
         cpus=0.0;
-        let _ = cpu::cpuconf(fc, &mut c, &quota, &period, &cpus);
-        let _ = rpu::rpuconf(&mut c, &rpus);
+        cpu::cpuconf(fc, &mut c, &config, &quota, &period, &cpus)?;
+        rpu::rpuconf(&mut c, &rpus).map_err(|e| {
+            logging::log_message(logging::Level::Error, format!("Failed to allocate the RPUs for id {}: {}", &fc.containerid, e).as_str());
+            e
+        })?;
     } else {
         //let rpus=0.0;
-        let _ = cpu::cpuconf(fc, &mut c, &quota, &period, &cpus);
+        cpu::cpuconf(fc, &mut c, &config, &quota, &period, &cpus).map_err(|e| {
+            logging::log_message(logging::Level::Error, format!("Failed to allocate the CPUs for id {}: {}", &fc.containerid, e).as_str());
+            e
+        })?;
         //let _ = rpu::rpuconf(&mut c, &rpus);
     }    
     //logging::log_message(logging::Level::Debug, format!("\nconfiguration after cpuconf is  {}", c.conf).as_str());
     //log_elapsed_time(start,"Duration of configuration of CPU"); //TAKE THE END TIME OF THE PHASE
     //This region of code could be extended through code to retrieve other specific Docker's flags which set MEM limitations
-    // Extract values from the JSON structure
-    //In the json structure only limit is created by kubernetes memory reservation doesn't exist so I'll comment it
-    /* let _mem_res = fc.jsonconfig["linux"]["resources"]["memory"]["reservation"] //Domain memory in MB, (--memory-reservation="")
-    .as_u64() // Assuming memory values are in unsigned integers
-    .unwrap_or(512); // Set default value to 512 MB if the value is missing */
+    // Memory limit of the container in bytes (-m, --memory=""), or its reservation
+    // (--memory-reservation="") if it has no limit. Without either, the platform default is used
+    let memory = &fc.jsonconfig["linux"]["resources"]["memory"];
+    let mem_request = memory["limit"].as_u64()
+        .filter(|limit| *limit > 0)
+        .or_else(|| memory["reservation"].as_u64().filter(|reservation| *reservation > 0));
 
-    let mem_request = fc.jsonconfig["linux"]["resources"]["memory"]["limit"] //Maximum domain memory in MB, (-m, --memory="")
-        .as_u64() // Assuming memory values are in unsigned integers
-        .unwrap_or(67_108_864); // Set default value to 64 MiB if the value is missing
-
-    //Convert the value parsed to a hexadecimal String
-    let mem_request_hex = format!("0x{:x}", mem_request);
-
-    //Save the value of segments 
-    let segments_before=c.segments.clone();
     //Save the value of the bdf we use
     let bdf_used = if c.net != "none" {
         c.bdf.iter().min().cloned()
@@ -170,18 +232,46 @@ pub fn config_generate(fc: &f2b::FrontendConfig) -> Result<Box<f2b::ImageConfig>
     logging::log_message(logging::Level::Debug, format!("Configuring memory for id {}", &fc.containerid).as_str());
     
     //let start = Instant::now(); //TAKE THE START TIME OF THE PHASE
-    let _ = mem::memconfig(&mut c, &mem_request_hex);
+    mem::memconfig(&mut c, &config, mem_request).map_err(|e| {
+        logging::log_message(logging::Level::Error, format!("Failed to configure the memory for id {}: {}", &fc.containerid, e).as_str());
+        e
+    })?;
     //log_elapsed_time(start,"Duration of configuration of Memory"); //TAKE THE END TIME OF THE PHASE
 
+    shm::shmconfig(&mut c, &config).map_err(|e| {
+        logging::log_message(logging::Level::Error, format!("Failed to join the shared-memory channels for id {}: {}", &fc.containerid, e).as_str());
+        e
+    })?;
+
     logging::log_message(logging::Level::Debug, format!("Configuring Device for id {}", &fc.containerid).as_str());
     
     //let start = Instant::now(); //TAKE THE START TIME OF THE PHASE
-    let _ = device::devconfig(&mut c);
+    device::devconfig(&mut c).map_err(|e| {
+        logging::log_message(logging::Level::Error, format!("Failed to configure the devices for id {}: {}", &fc.containerid, e).as_str());
+        e
+    })?;
     //log_elapsed_time(start,"Duration of configuration of Device"); //TAKE THE END TIME OF THE PHASE
 
-    let _ = boot::bootconfbackend(fc, &mut config);
-
-    //TODO: call net config here (take net memory areas from memory)
+    ivshmem::ivshmemconfig(&mut c, &config).map_err(|e| {
+        logging::log_message(logging::Level::Error, format!("Failed to join the IVSHMEM links for id {}: {}", &fc.containerid, e).as_str());
+        e
+    })?;
+
+    boot::bootconfbackend(fc, &mut config);
+
+    // The guest finds the configuration of its networking interface at a well-known place
+    let netconf = network::netconfig(&mut c, fc, &mut config, bdf_used).map_err(|e| {
+        logging::log_message(logging::Level::Error, format!("Failed to configure the network for id {}: {}", &fc.containerid, e).as_str());
+        e
+    })?;
+
+    // Linux cells get a kernel command line and a device tree matching the cell
+    if config.os_var == f2b::OsKind::Linux {
+        linux::linuxconfig(&c, fc, &mut config, netconf.as_ref()).map_err(|e| {
+            logging::log_message(logging::Level::Error, format!("Failed to configure the linux boot for id {}: {}", &fc.containerid, e).as_str());
+            e
+        })?;
+    }
 
     // Guest console is allocated when -t flag is provided
     // useful for Hypervisor like XEN or BAO which give the possibility
@@ -198,31 +288,27 @@ pub fn config_generate(fc: &f2b::FrontendConfig) -> Result<Box<f2b::ImageConfig>
         writeln!(file, "{}", fc.guestconsole).expect("Failed to write console file");
     }
 
-    //let _ = communication::communicationconfig(&mut c); //communication è stato incluso direttamente nel preamble
-
-    // Call save_state and log the result
+    // Build the cell before committing anything to the state, so that a failure leaves the
+    // resources of the node untouched and create fails
     //let start = Instant::now(); //TAKE THE START TIME OF THE PHASE
-    match save_state(
-        &fc.containerid,
-        &c.segments,
-        &segments_before,
-        &c.rcpus,
-        bdf_used,
-        &c.used_rcpus,
-    ) {
-        Ok(_) => logging::log_message(logging::Level::Debug, format!("State saved successfully for id {}", &fc.containerid).as_str()),
-        Err(_e) => logging::log_message(logging::Level::Debug, format!("Failed to save state for id {}", &fc.containerid).as_str()),
-    }
-    //log_elapsed_time(start,"Duration of save state"); //TAKE THE END TIME OF THE PHASE
+    confighelperend(fc, &mut c, &config).map_err(|e| {
+        logging::log_message(logging::Level::Error, format!("Failed to build the cell config for id {}: {}", &fc.containerid, e).as_str());
+        e
+    })?;
+    //log_elapsed_time(start,"Duration of compile"); //TAKE THE END TIME OF THE PHASE
 
     //let start = Instant::now(); //TAKE THE START TIME OF THE PHASE
-    let _ = confighelperend(fc, &mut c, &config);
-    //log_elapsed_time(start,"Duration of compile"); //TAKE THE END TIME OF THE PHASE
+    save_state(&fc.containerid, &c, bdf_used).map_err(|e| {
+        logging::log_message(logging::Level::Error, format!("Failed to save state for id {}: {}", &fc.containerid, e).as_str());
+        e
+    })?;
+    logging::log_message(logging::Level::Debug, format!("State saved successfully for id {}", &fc.containerid).as_str());
+    //log_elapsed_time(start,"Duration of save state"); //TAKE THE END TIME OF THE PHASE
 
     //logging::log_message(logging::Level::Debug, format!("Finishing configuration for id {}", &fc.containerid).as_str());
     logging::log_message(logging::Level::Trace, format!("\nactual configuration is  {}", c.conf).as_str());
     
-    return Ok(config);
+    Ok(config)
 }
 
 fn confighelperstart(
@@ -230,70 +316,47 @@ fn confighelperstart(
     c: &mut Backendconfig,
     ic: &f2b::ImageConfig,
 ) -> Result<(), Box<dyn Error>> {
-    // Write the conf file preamble
-
-    if ic.os_var == "zephyr" {
+    // Fill in the cell header from the preamble of the board
+    c.desc = celldesc::CellDesc::new(&fc.cellname);
 
-        logging::log_message(logging::Level::Debug, format!("Starting helper start for id {}", &fc.containerid).as_str());
-        // Construct the full path to the TOML file
-        let config_path = Path::new(WORKPATH).join(CONFIG_FILE);
+    logging::log_message(logging::Level::Debug, format!("Starting helper start for id {}", &fc.containerid).as_str());
+    // Construct the full path to the TOML file
+    let config_path = Path::new(WORKPATH).join(CONFIG_FILE);
 
-        // Read the contents of the TOML file
-        let config_content = fs::read_to_string(config_path)?;
+    // Read the contents of the TOML file
+    let config_content = fs::read_to_string(config_path)?;
 
-        // Parse the content as TOML
-        let parsed_toml: Value = config_content.parse::<Value>()?;
+    // Parse the content as TOML
+    let parsed_toml: Value = config_content.parse::<Value>()?;
 
-        logging::log_message(logging::Level::Debug, format!("Retrieving preamble for id {}", &fc.containerid).as_str());
-        // Retrieve the `preamble` value under `[jailhouse_preamble]`
-        if let Some(preamble) = parsed_toml
-            .get("jailhouse_preamble")
-            .and_then(|section| section.get("preamble"))
+    logging::log_message(logging::Level::Debug, format!("Retrieving preamble for id {}", &fc.containerid).as_str());
+    // Retrieve the `preamble` value under `[jailhouse_preamble]`, or `linux_preamble` for linux
+    // cells, which defaults to the arm64 LINUX_PREAMBLE (e.g. X86_LINUX_PREAMBLE on x86 boards)
+    let section = parsed_toml.get("jailhouse_preamble");
+    let preamble = if ic.os_var == f2b::OsKind::Linux {
+        section.and_then(|s| s.get("linux_preamble")).and_then(|p| p.as_str()).unwrap_or("LINUX_PREAMBLE")
+    } else {
+        section
+            .and_then(|s| s.get("preamble"))
             .and_then(|p| p.as_str())
-        {
-            // Choose the appropriate template
-            //logging::log_message(logging::Level::Debug, format!("Choosing template for id {}", &fc.containerid).as_str());
-            let selected_template = match preamble {
-                "QEMU_PREAMBLE" => QEMU_PREAMBLE_TEMPLATE,
-                "ULTRASCALE_PREAMBLE" => ULTRASCALE_PREAMBLE_TEMPLATE,
-                _ => return Err(format!("Unexpected preamble value: {}", preamble).into()),
-            };
-
-            // Replace `{containerid}` placeholder with `fc.containerid`
-            let filled_template = selected_template.replace("{containerid}", &fc.containerid);
-            //logging::log_message(logging::Level::Debug, format!("Replacement in template for id {}", &fc.containerid).as_str());
-
-            // Append the filled template to `c.conf`
-            c.conf.push_str(&filled_template);
-            logging::log_message(logging::Level::Debug, format!("Conf preamble created for id {}", &fc.containerid).as_str());
-        } else {
-            return Err("Field 'preamble' not found in [jailhouse_preamble]".into());
-        }
+            .ok_or("Field 'preamble' not found in [jailhouse_preamble]")?
+    };
 
-    } else if ic.os_var == "linux" {
-        c.conf = format!(
-            "#include \"cell.h\"
-    struct {{
-        struct jailhouse_cell_desc cell;
-    }} __attribute__((packed)) config = {{
-        .cell = {{
-            .signature = JAILHOUSE_CELL_DESC_SIGNATURE,
-            .revision = JAILHOUSE_CONFIG_REVISION,
-            .name = \"{}\",
-            .flags = JAILHOUSE_CELL_PASSIVE_COMMREG |
-                JAILHOUSE_CELL_VIRTUAL_CONSOLE_PERMITTED,
-    
-            .cpu_set_size = sizeof(config.cpus),
-            .num_memory_regions = ARRAY_SIZE(config.mem_regions),
-            .num_irqchips = ARRAY_SIZE(config.irqchips),
-            .num_pci_devices = ARRAY_SIZE(config.pci_devices),
-    
-            .vpci_irq_base = 140-32, 
-        }},",
-            fc.containerid
-        );
-    }
-    return Ok(());
+    // Choose the appropriate template, among the built-in and the platform ones
+    let templates = get_platform_templates(&parsed_toml, Path::new(TEMPLATE_DIR))?;
+    let (template_name, template) = get_preamble(&templates, preamble)?;
+
+    // Fill in the named placeholders with the [<preamble>_TEMPLATE] table of the platform
+    let selected_template = render_template(&template_name, template, parsed_toml.get(&template_name), &[])?;
+
+    c.desc.apply_preamble(&selected_template)?;
+    c.arch = platform_arch(&parsed_toml)?;
+    logging::log_message(logging::Level::Debug, format!("Conf preamble created for id {}", &fc.containerid).as_str());
+
+    // The console of the cell: the UART of the board, the virtual console, or none
+    communication::communicationconfig(c, &parsed_toml, ic.console)?;
+    logging::log_message(logging::Level::Debug, format!("Console {} given to id {}", c.console, &fc.containerid).as_str());
+    Ok(())
 }
 
 fn confighelperend(
@@ -301,68 +364,89 @@ fn confighelperend(
     c: &mut Backendconfig,
     _ic: &f2b::ImageConfig,
 ) -> Result<(), Box<dyn Error>> {
-    
-    // Append closing brace to the configuration
-    c.conf.push_str("\n};\n");
 
-    // Modify the config file based on the operating system (mockup example)
-    let pattern = r#"\.name = \".*\""#;
-    let _re = Regex::new(pattern).unwrap();
+    // Render the C source out of the cell description
+    c.conf = c.desc.render_c()?;
 
-    // Write the configuration to the .c file
-    //std::fs::write(&c.conffile, &c.conf)?;
-
-    // Define paths in fc.crundir, with the correct naming convention
-    let path_to_compile = Path::new(&fc.crundir).join("tocompile.c");
     let cell_file_path = Path::new(&fc.crundir).join(format!("{}.cell", fc.containerid));
-    let obj_file_path = Path::new(&fc.crundir).join(format!("{}.o", fc.containerid));
-
-    // Write the config to tocompile.c in fc.crundir
-    std::fs::write(&path_to_compile, &c.conf)?;
-
-    // Compile the .c file to .o
-    let compile_status = Command::new("gcc")
-        .args(&[
-            "-Werror",
-            "-Wall",
-            "-Wextra",
-            "-D__LINUX_COMPILER_TYPES_H",
-            "-I/usr/share/runPHI/include",   // Specify the absolute include path
-            "-c",                            // Compile without linking
-            path_to_compile.to_str().unwrap(),
-            "-o",
-            obj_file_path.to_str().unwrap(),
-        ])
-        .status()?;
-
-    if !compile_status.success() {
-        logging::log_message(logging::Level::Error, &format!("Compilation failed!!!"));
-        return Err("Compilation failed".into());
+    let (builder, cache_max_bytes) = cell_config_options()?;
+    match builder {
+        cellbin::CellBuilder::Native => {
+            fs::write(&cell_file_path, cellbin::serialize(&c.desc)?)?;
+        }
+        cellbin::CellBuilder::Gcc => {
+            // Skip gcc and objcopy if the same config was already compiled
+            let cache = cellcache::CellCache::new(&Path::new(WORKPATH).join(CELL_CACHE_DIR), cache_max_bytes);
+            let mut anonymous = c.desc.clone();
+            anonymous.header.name = String::new();
+            let key = cellcache::key(&anonymous.render_c()?, Path::new(INCLUDE_DIR));
+            if let Some(mut cell) = cache.get(&key) {
+                logging::log_message(logging::Level::Debug, format!("Cell config found in cache for id {}", &fc.containerid).as_str());
+                // The cached cell was compiled for another container, it gets the name of this one
+                cellbin::set_name(&mut cell, &c.desc.header.name)?;
+                fs::write(&cell_file_path, cell)?;
+            } else {
+                // tocompile.c, the object and the .cell file are all kept in fc.crundir
+                let cell = cellbin::compile(&c.conf, Path::new(&fc.crundir), &fc.containerid, Path::new(INCLUDE_DIR))?;
+                if let Err(e) = cache.put(&key, &cell) {
+                    logging::log_message(logging::Level::Warn, format!("Failed to cache the cell config for id {}: {}", &fc.containerid, e).as_str());
+                }
+            }
+        }
     }
 
-    // Convert the .o file to .cell directly in fc.crundir
-    let objcopy_status = Command::new("objcopy")
-        .args(&[
-            "-O",
-            "binary",
-            "--remove-section=.note.gnu.property",
-            obj_file_path.to_str().unwrap(),
-            cell_file_path.to_str().unwrap(),
-        ])
-        .status()?;
-
-    if !objcopy_status.success() {
-        logging::log_message(logging::Level::Error, &format!("Conversion to .cell file failed!!!"));
-        return Err("Conversion to .cell file failed".into());
+    Ok(())
+}
+
+// The architecture of the node, given by `arch` of [jailhouse_preamble] ("x86", "arm" or "arm64"),
+// or else by its preambles: x86 with the X86_* ones, arm otherwise
+pub fn platform_arch(platform: &Value) -> Result<Arch, String> {
+    let section = platform.get("jailhouse_preamble");
+    match section.and_then(|s| s.get("arch")) {
+        Some(Value::String(arch)) if arch == "x86" => Ok(Arch::X86),
+        Some(Value::String(arch)) if arch == "arm" || arch == "arm64" => Ok(Arch::Arm),
+        Some(_) => Err("[jailhouse_preamble] arch must be \"x86\", \"arm\" or \"arm64\"".to_string()),
+        None => {
+            let x86 = ["preamble", "linux_preamble"].iter()
+                .filter_map(|key| section.and_then(|s| s.get(*key)).and_then(|p| p.as_str()))
+                .any(|preamble| preamble.starts_with("X86_"));
+            Ok(if x86 { Arch::X86 } else { Arch::Arm })
+        }
     }
+}
 
-    Ok(())
+// The architecture of the node, out of its platform_info.toml
+pub fn node_arch() -> Result<Arch, Box<dyn Error>> {
+    let platform: Value = fs::read_to_string(Path::new(WORKPATH).join(CONFIG_FILE))?.parse()?;
+    Ok(platform_arch(&platform)?)
 }
 
-fn retrieve_state() -> Result<(Vec<String>, Vec<i8>, Vec<i8>), Box<dyn std::error::Error>> {
-    let file_path = PathBuf::from(WORKPATH).join(STATEFILE);
-    let content = fs::read_to_string(&file_path)?;
-    let parsed_toml = content.parse::<Value>()?;
+// Options of the [cell_config] section of the platform:
+//   builder = "gcc"            the .cell file is serialized natively, unless gcc is asked for
+//   cache_max_bytes = 1048576  size of the cache of cells compiled by gcc, 0 disables it
+fn cell_config_options() -> Result<(cellbin::CellBuilder, u64), Box<dyn Error>> {
+    let parsed_toml: Value = fs::read_to_string(Path::new(WORKPATH).join(CONFIG_FILE))?.parse()?;
+    let section = parsed_toml.get("cell_config");
+    let builder = match section.and_then(|s| s.get("builder")).and_then(|b| b.as_str()) {
+        Some(name) => cellbin::CellBuilder::from_name(name)?,
+        None => cellbin::CellBuilder::Native,
+    };
+    let cache_max_bytes = match section.and_then(|s| s.get("cache_max_bytes")) {
+        Some(size) => size
+            .as_integer()
+            .and_then(|size| u64::try_from(size).ok())
+            .ok_or("cell_config.cache_max_bytes must be a non negative integer")?,
+        None => CELL_CACHE_DEFAULT_BYTES,
+    };
+    Ok((builder, cache_max_bytes))
+}
+
+// Free memory segments, free ivshmem BDFs, free RPU ids and free interrupt pins, as stored in the
+// state file. The interrupt pins are optional, without them the pins of the platform are used
+type NodeState = (Vec<memalloc::Segment>, Vec<i8>, Vec<i8>, Option<Vec<u32>>);
+
+fn retrieve_state() -> Result<NodeState, Box<dyn std::error::Error>> {
+    let parsed_toml = statefile::read()?.parse::<Value>()?;
     
     let segments = parsed_toml
         .get("free_segments")
@@ -370,8 +454,8 @@ fn retrieve_state() -> Result<(Vec<String>, Vec<i8>, Vec<i8>), Box<dyn std::erro
         .and_then(|seg| seg.as_array())
         .ok_or("Missing or invalid 'segments' field")?
         .iter()
-        .filter_map(|s| s.as_str().map(String::from))
-        .collect::<Vec<String>>();
+        .map(|s| s.as_str().ok_or("Invalid segment in 'segments'".to_string()).and_then(memalloc::parse_segment))
+        .collect::<Result<Vec<memalloc::Segment>, String>>()?;
 
     let bdf = parsed_toml
         .get("free_pci_devices_bdf")
@@ -379,7 +463,7 @@ fn retrieve_state() -> Result<(Vec<String>, Vec<i8>, Vec<i8>), Box<dyn std::erro
         .and_then(|b| b.as_array())
         .ok_or("Missing or invalid 'bdf' field")?
         .iter()
-        .filter_map(|b| b.as_integer().and_then(|val| Some(val as i8)))
+        .filter_map(|b| b.as_integer().map(|val| val as i8))
         .collect::<Vec<i8>>();
 
     let rcpus = parsed_toml
@@ -388,25 +472,36 @@ fn retrieve_state() -> Result<(Vec<String>, Vec<i8>, Vec<i8>), Box<dyn std::erro
         .and_then(|ids| ids.as_array())
         .ok_or("Missing or invalid 'ids' field in 'free_rcpus'")?
         .iter()
-        .filter_map(|id| id.as_integer().and_then(|val| Some(val as i8)))
+        .filter_map(|id| id.as_integer().map(|val| val as i8))
         .collect::<Vec<i8>>();
 
-        Ok((segments, bdf, rcpus))
+    let irq_pins = match parsed_toml.get("free_irq_pins") {
+        Some(section) => Some(
+            section
+                .get("pins")
+                .and_then(|pins| pins.as_array())
+                .ok_or("Missing or invalid 'pins' field in 'free_irq_pins'")?
+                .iter()
+                .filter_map(|pin| pin.as_integer().and_then(|val| u32::try_from(val).ok()))
+                .collect::<Vec<u32>>(),
+        ),
+        None => None,
+    };
+
+        Ok((segments, bdf, rcpus, irq_pins))
 }
 
 
 fn save_state(
     fc_containerid: &str,
-    c_segments: &Vec<String>,
-    segments_before: &Vec<String>,
-    c_rcpus: &Vec<i8>,
+    c: &Backendconfig,
     bdf_used: Option<i8>,
-    c_used_rcpus: &Vec<i8>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let c_segments = &c.segments;
+    let c_rcpus = &c.rcpus;
+    let c_used_rcpus = &c.used_rcpus;
     // Load the current state from state.toml
-    let file_path = Path::new(WORKPATH).join(STATEFILE);
-    let content = fs::read_to_string(&file_path)?;
-    let mut parsed_toml: Value = content.parse::<Value>()?;
+    let mut parsed_toml: Value = statefile::read()?.parse::<Value>()?;
 
     // 1. Add `fc_containerid` to `ids` in `[containerid]`
     if let Some(containerid) = parsed_toml.get_mut("containerid") {
@@ -417,7 +512,7 @@ fn save_state(
 
     // 2. Replace `free_segments` with `c_segments`
     if let Some(free_segments) = parsed_toml.get_mut("free_segments") {
-        free_segments["segments"] = Value::Array(c_segments.iter().map(|s| Value::String(s.clone())).collect());
+        free_segments["segments"] = Value::Array(c_segments.iter().map(|s| Value::String(memalloc::format_segment(s))).collect());
     }
 
     // 3. Remove `bdf_used` from `free_pci_devices_bdf` if present and update `free_rcpus` with `c_rcpus`
@@ -433,38 +528,19 @@ fn save_state(
         free_rcpus["ids"] = Value::Array(c_rcpus.iter().map(|r| Value::Integer(*r as i64)).collect());
     }
 
+    if let (Some(free_irq_pins), Some(irq_pins)) = (parsed_toml.get_mut("free_irq_pins"), &c.irq_pins) {
+        free_irq_pins["pins"] = Value::Array(irq_pins.iter().map(|p| Value::Integer(*p as i64)).collect());
+    }
+
     // 4. Add a new section for `fc_containerid`
     let new_container_section = {
         let mut container_data = Map::new();
 
-        // Parse start and end addresses from `segments_before` and `c_segments`
-        let (start_before, _end_before) = {
-            let parts: Vec<&str> = segments_before[0].split(", ").collect();
-            (
-                u64::from_str_radix(parts[0].trim_start_matches("0x"), 16).expect("Invalid start address"),
-                u64::from_str_radix(parts[1].trim_start_matches("0x"), 16).expect("Invalid end address"),
-            )
-        };
-
-        let (start_after, _end_after) = {
-            let parts: Vec<&str> = c_segments[0].split(", ").collect();
-            (
-                u64::from_str_radix(parts[0].trim_start_matches("0x"), 16).expect("Invalid start address"),
-                u64::from_str_radix(parts[1].trim_start_matches("0x"), 16).expect("Invalid end address"),
-            )
-        };
-
-        // Calculate the used memory range (segments_before - c_segments)
-        let used_start = start_before;
-        let used_end = start_after;
-        //logging::log_message(logging::Level::Debug, &format!("Used memory start: 0x{:x}, end: 0x{:x}", used_start, used_end));
-
-        // Format the result as a memory segment string
-        let used_memory = format!("0x{:x}, 0x{:x}", used_start, used_end);
-        //logging::log_message(logging::Level::Debug, &format!("Final used_memory string: {:?}", used_memory));
+        // The memory segments reserved for the cell, given back to the free segments on delete
+        let used_memory = c.used_memory.iter().map(|s| Value::String(memalloc::format_segment(s))).collect();
 
         // Insert into container_data
-        container_data.insert("memory".to_string(), Value::String(used_memory));
+        container_data.insert("memory".to_string(), Value::Array(used_memory));
 
 
         // Set `rcpus`
@@ -482,6 +558,31 @@ fn save_state(
         };
         container_data.insert("pci_bdf".to_string(), Value::String(pci_bdf_value));
 
+        // Set `console`, the owner of the UART is found through it
+        container_data.insert("console".to_string(), Value::String(c.console.as_str().to_string()));
+
+        // Set `cpus`
+        let cpus_value = if c.used_cpus.is_empty() {
+            "none".to_string()
+        } else {
+            cpu::format_cpulist(&c.used_cpus)
+        };
+        container_data.insert("cpus".to_string(), Value::String(cpus_value));
+
+        // Set `irq_pins`
+        let irq_pins_value = if c.used_irq_pins.is_empty() {
+            "none".to_string()
+        } else {
+            c.used_irq_pins.iter().map(|p| format!("{}", p)).collect::<Vec<_>>().join(", ")
+        };
+        container_data.insert("irq_pins".to_string(), Value::String(irq_pins_value));
+
+        // Set `shm`, the channels left when the container is deleted
+        container_data.insert("shm".to_string(), Value::Array(c.shm.iter().map(|ch| Value::String(ch.name.clone())).collect()));
+
+        // Set `ivshmem`, the links left when the container is deleted
+        container_data.insert("ivshmem".to_string(), Value::Array(c.ivshmem.iter().map(|l| Value::String(l.name.clone())).collect()));
+
         // Attempt to create the Value::Table and add more debugging information
         match Value::try_from(container_data) {
             Ok(value) => {
@@ -511,12 +612,16 @@ fn save_state(
         return Err("parsed_toml is not a table".into());
     }
 
+    // Add the container to the members of its channels
+    shm::save(&mut parsed_toml, fc_containerid, c)?;
+    // and to the members of its IVSHMEM links
+    ivshmem::save(&mut parsed_toml, fc_containerid, c)?;
+
     // Log a success message after the assignment
     logging::log_message(logging::Level::Debug, "New container section added successfully in state.toml");
 
     // Save the updated TOML back to the file
-    let updated_content = toml::to_string(&parsed_toml)?;
-    fs::write(&file_path, updated_content)?;
+    statefile::write(&toml::to_string(&parsed_toml)?)?;
 
     Ok(())
 }
@@ -0,0 +1,389 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Builders of the binary .cell file out of the cell description.
+// The native builder serializes the packed structs of cell-config.h directly, so that no
+// toolchain is needed on the board. The gcc builder compiles the rendered C source as done by
+// the Jailhouse build system, and is kept as a fallback, e.g. for headers of a different revision.
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::configGenerator::celldesc::{CellDesc, Console, IrqChip, Layout, MemRegion, PciDevice, PioRegion};
+
+// Header constants of the supported cell-config.h variants
+const JAILHOUSE_CELL_DESC_SIGNATURE: &[u8] = b"JHCELL";
+const JAILHOUSE_CONFIG_REVISION: u16 = 13;
+const OMNIVISOR_CELL_DESC_SIGNATURE: &[u8] = b"JHCLL";
+const OMNIVISOR_CONFIG_REVISION: u16 = 14;
+const JAILHOUSE_SYSTEM_SIGNATURE: &[u8] = b"JHSYST";
+// Sizes of the arrays of a cell that are not part of the description, skipped when reading
+const CACHE_SIZE: usize = 12;
+const PCI_CAPABILITY_SIZE: usize = 8;
+const QOS_DEVICE_SIZE: usize = 20;
+// The name of a cell, after the signature and the revision, in both layouts
+const CELL_NAME_OFFSET: usize = 8;
+const CELL_NAME_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellBuilder {
+    Native,
+    Gcc,
+}
+
+impl CellBuilder {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "native" => Ok(CellBuilder::Native),
+            "gcc" => Ok(CellBuilder::Gcc),
+            _ => Err(format!("Unknown cell builder {}, expected native or gcc", name).into()),
+        }
+    }
+}
+
+// Little endian writer of the packed fields
+struct Packer {
+    out: Vec<u8>,
+}
+
+impl Packer {
+    fn u8(&mut self, v: u8) {
+        self.out.push(v);
+    }
+    fn u16(&mut self, v: u16) {
+        self.out.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.out.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.out.extend_from_slice(&v.to_le_bytes());
+    }
+    fn bytes(&mut self, v: &[u8], len: usize) {
+        let mut field = v.to_vec();
+        field.resize(len, 0);
+        self.out.extend_from_slice(&field);
+    }
+}
+
+fn count(len: usize) -> Result<u32, Box<dyn Error>> {
+    u32::try_from(len).map_err(|_| "too many cell elements".into())
+}
+
+// Serializes the cell description in the binary format loaded by `jailhouse cell create`
+pub fn serialize(desc: &CellDesc) -> Result<Vec<u8>, Box<dyn Error>> {
+    desc.validate()?;
+    let h = &desc.header;
+    let cpus = desc.cpu_words();
+    let rcpus = desc.rcpu_words();
+    let mut p = Packer { out: Vec::new() };
+
+    // struct jailhouse_cell_desc
+    match h.layout {
+        Layout::Jailhouse => {
+            p.bytes(JAILHOUSE_CELL_DESC_SIGNATURE, 6);
+            p.u16(JAILHOUSE_CONFIG_REVISION);
+        }
+        Layout::Omnivisor => {
+            p.bytes(OMNIVISOR_CELL_DESC_SIGNATURE, 5);
+            p.u8(h.architecture);
+            p.u16(OMNIVISOR_CONFIG_REVISION);
+        }
+    }
+    p.bytes(h.name.as_bytes(), 32);
+    p.u32(0); // id, set by the driver
+    p.u32(h.flags);
+    p.u32(count(cpus.len() * 8)?);
+    if h.layout == Layout::Omnivisor {
+        p.u32(count(rcpus.len() * 8)?);
+    }
+    p.u32(count(desc.mem_regions.len())?);
+    p.u32(0); // num_cache_regions
+    p.u32(count(desc.irqchips.len())?);
+    p.u32(count(desc.pio_regions.len())?);
+    p.u32(count(desc.pci_devices.len())?);
+    p.u32(0); // num_pci_caps
+    p.u32(count(desc.stream_ids.len())?);
+    p.u32(0); // num_qos_devices
+    p.u32(h.vpci_irq_base);
+    p.u64(h.cpu_reset_address);
+    p.u64(h.msg_reply_timeout);
+    p.u64(h.console.address);
+    p.u32(h.console.size);
+    p.u16(h.console.con_type);
+    p.u16(h.console.flags);
+    p.u32(h.console.divider);
+    p.u32(h.console.gate_nr);
+    p.u64(h.console.clock_reg);
+
+    // Followed by the arrays, in the order of jailhouse_cell_config_size()
+    for word in cpus.iter().chain(rcpus.iter()) {
+        p.u64(*word);
+    }
+    for r in &desc.mem_regions {
+        p.u64(r.phys_start);
+        p.u64(r.virt_start);
+        p.u64(r.size);
+        p.u64(r.flags);
+        p.u64(r.colors);
+    }
+    for i in &desc.irqchips {
+        p.u64(i.address);
+        p.u32(i.id);
+        p.u32(i.pin_base);
+        for word in i.pin_bitmap {
+            p.u32(word);
+        }
+    }
+    for r in &desc.pio_regions {
+        p.u16(r.base);
+        p.u16(r.length);
+    }
+    for d in &desc.pci_devices {
+        p.u8(d.dev_type);
+        p.u8(d.iommu);
+        p.u16(d.domain);
+        p.u16(d.bdf);
+        for word in d.bar_mask {
+            p.u32(word);
+        }
+        p.u16(d.caps_start);
+        p.u16(d.num_caps);
+        p.u8(d.num_msi_vectors);
+        p.u8(d.msi_64bits as u8 | (d.msi_maskable as u8) << 1);
+        p.u16(d.num_msix_vectors);
+        p.u16(d.msix_region_size);
+        p.u64(d.msix_address);
+        p.u32(d.shmem_regions_start);
+        p.u8(d.shmem_dev_id);
+        p.u8(d.shmem_peers);
+        p.u16(d.shmem_protocol);
+    }
+    for id in &desc.stream_ids {
+        p.u32(*id);
+    }
+    Ok(p.out)
+}
+
+// Little endian reader of the packed fields
+struct Unpacker<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Unpacker<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len());
+        let Some(end) = end else {
+            return Err(format!("truncated config, {} bytes expected at offset {}", len, self.pos).into());
+        };
+        let field = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(field)
+    }
+    fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, Box<dyn Error>> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into()?))
+    }
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+    fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+    fn skip(&mut self, count: u32, size: usize) -> Result<(), Box<dyn Error>> {
+        self.bytes(count as usize * size).map(|_| ())
+    }
+    fn console(&mut self) -> Result<Console, Box<dyn Error>> {
+        Ok(Console {
+            address: self.u64()?,
+            size: self.u32()?,
+            con_type: self.u16()?,
+            flags: self.u16()?,
+            divider: self.u32()?,
+            gate_nr: self.u32()?,
+            clock_reg: self.u64()?,
+        })
+    }
+    fn mem_region(&mut self) -> Result<MemRegion, Box<dyn Error>> {
+        Ok(MemRegion {
+            comment: None,
+            phys_start: self.u64()?,
+            virt_start: self.u64()?,
+            size: self.u64()?,
+            flags: self.u64()?,
+            colors: self.u64()?,
+        })
+    }
+}
+
+// System config of Jailhouse, as loaded by `jailhouse enable`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemConfig {
+    pub hypervisor_memory: MemRegion,
+    pub debug_console: Console,
+    pub root_cell: CellDesc,
+}
+
+// Renames the cell of a binary .cell file, e.g. one compiled for another cell
+pub fn set_name(cell: &mut [u8], name: &str) -> Result<(), Box<dyn Error>> {
+    if !cell.starts_with(JAILHOUSE_CELL_DESC_SIGNATURE) && !cell.starts_with(OMNIVISOR_CELL_DESC_SIGNATURE) {
+        return Err("not a cell config, wrong signature".into());
+    }
+    if name.len() >= CELL_NAME_SIZE {
+        return Err(format!("cell name {} longer than {} characters", name, CELL_NAME_SIZE - 1).into());
+    }
+    let field = cell.get_mut(CELL_NAME_OFFSET..CELL_NAME_OFFSET + CELL_NAME_SIZE).ok_or("truncated config, no cell name")?;
+    field.fill(0);
+    field[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(())
+}
+
+// Reads a binary .cell file back into a cell description. Caches, PCI capabilities
+// and QoS devices are not part of the description and are skipped
+pub fn deserialize(data: &[u8]) -> Result<CellDesc, Box<dyn Error>> {
+    let mut u = Unpacker { data, pos: 0 };
+    let mut desc = CellDesc::default();
+    let h = &mut desc.header;
+
+    let signature = u.bytes(6)?;
+    let revision;
+    if signature == JAILHOUSE_CELL_DESC_SIGNATURE {
+        revision = (u.u16()?, JAILHOUSE_CONFIG_REVISION);
+    } else if &signature[..5] == OMNIVISOR_CELL_DESC_SIGNATURE {
+        h.layout = Layout::Omnivisor;
+        h.architecture = signature[5];
+        revision = (u.u16()?, OMNIVISOR_CONFIG_REVISION);
+    } else {
+        return Err("not a cell config, wrong signature".into());
+    }
+    if revision.0 != revision.1 {
+        return Err(format!("config revision {} not supported, expected {}", revision.0, revision.1).into());
+    }
+    let name = u.bytes(32)?;
+    h.name = String::from_utf8_lossy(name.split(|b| *b == 0).next().unwrap_or_default()).into_owned();
+    u.u32()?; // id
+    h.flags = u.u32()?;
+    let cpu_set_size = u.u32()?;
+    let rcpu_set_size = if h.layout == Layout::Omnivisor { u.u32()? } else { 0 };
+    let num_memory_regions = u.u32()?;
+    let num_cache_regions = u.u32()?;
+    let num_irqchips = u.u32()?;
+    let num_pio_regions = u.u32()?;
+    let num_pci_devices = u.u32()?;
+    let num_pci_caps = u.u32()?;
+    let num_stream_ids = u.u32()?;
+    let num_qos_devices = u.u32()?;
+    h.vpci_irq_base = u.u32()?;
+    h.cpu_reset_address = u.u64()?;
+    h.msg_reply_timeout = u.u64()?;
+    h.console = u.console()?;
+
+    for _ in 0..cpu_set_size / 8 {
+        desc.cpus.push(u.u64()?);
+    }
+    for _ in 0..rcpu_set_size / 8 {
+        desc.rcpus.push(u.u64()?);
+    }
+    for _ in 0..num_memory_regions {
+        let region = u.mem_region()?;
+        desc.mem_regions.push(region);
+    }
+    u.skip(num_cache_regions, CACHE_SIZE)?;
+    for _ in 0..num_irqchips {
+        let mut irqchip = IrqChip { address: u.u64()?, id: u.u32()?, pin_base: u.u32()?, ..Default::default() };
+        for word in irqchip.pin_bitmap.iter_mut() {
+            *word = u.u32()?;
+        }
+        desc.irqchips.push(irqchip);
+    }
+    for _ in 0..num_pio_regions {
+        desc.pio_regions.push(PioRegion { comment: None, base: u.u16()?, length: u.u16()? });
+    }
+    for _ in 0..num_pci_devices {
+        let mut d = PciDevice { dev_type: u.u8()?, iommu: u.u8()?, domain: u.u16()?, bdf: u.u16()?, ..Default::default() };
+        for word in d.bar_mask.iter_mut() {
+            *word = u.u32()?;
+        }
+        d.caps_start = u.u16()?;
+        d.num_caps = u.u16()?;
+        d.num_msi_vectors = u.u8()?;
+        let msi = u.u8()?;
+        d.msi_64bits = msi & 1 != 0;
+        d.msi_maskable = msi & 2 != 0;
+        d.num_msix_vectors = u.u16()?;
+        d.msix_region_size = u.u16()?;
+        d.msix_address = u.u64()?;
+        d.shmem_regions_start = u.u32()?;
+        d.shmem_dev_id = u.u8()?;
+        d.shmem_peers = u.u8()?;
+        d.shmem_protocol = u.u16()?;
+        desc.pci_devices.push(d);
+    }
+    u.skip(num_pci_caps, PCI_CAPABILITY_SIZE)?;
+    for _ in 0..num_stream_ids {
+        desc.stream_ids.push(u.u32()?);
+    }
+    u.skip(num_qos_devices, QOS_DEVICE_SIZE)?;
+    Ok(desc)
+}
+
+// Reads a binary system config, i.e. the root cell config passed to `jailhouse enable`.
+// The platform info between the console and the root cell differs among the Jailhouse variants,
+// hence the root cell is found by its signature
+pub fn deserialize_system(data: &[u8]) -> Result<SystemConfig, Box<dyn Error>> {
+    if data.get(..6) != Some(JAILHOUSE_SYSTEM_SIGNATURE) {
+        return Err("not a system config, wrong signature".into());
+    }
+    let mut u = Unpacker { data, pos: 12 };
+    let hypervisor_memory = u.mem_region()?;
+    let debug_console = u.console()?;
+    let root = (u.pos..data.len())
+        .find(|i| data[*i..].starts_with(JAILHOUSE_CELL_DESC_SIGNATURE) || data[*i..].starts_with(OMNIVISOR_CELL_DESC_SIGNATURE))
+        .ok_or("no root cell in the system config")?;
+    Ok(SystemConfig { hypervisor_memory, debug_console, root_cell: deserialize(&data[root..])? })
+}
+
+// Compiles the rendered C source in `workdir` with gcc and objcopy, returning the .cell content
+pub fn compile(source: &str, workdir: &Path, name: &str, include_dir: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let path_to_compile = workdir.join("tocompile.c");
+    let obj_file_path = workdir.join(format!("{}.o", name));
+    let cell_file_path = workdir.join(format!("{}.cell", name));
+
+    // Write the config to tocompile.c in the work dir
+    fs::write(&path_to_compile, source)?;
+
+    // Compile the .c file to .o
+    let compile_status = Command::new("gcc")
+        .args(["-Werror", "-Wall", "-Wextra", "-D__LINUX_COMPILER_TYPES_H"])
+        .arg(format!("-I{}", include_dir.display()))
+        .arg("-c") // Compile without linking
+        .arg(&path_to_compile)
+        .arg("-o")
+        .arg(&obj_file_path)
+        .status()?;
+
+    if !compile_status.success() {
+        logging::log_message(logging::Level::Error, "Compilation failed!!!");
+        return Err("Compilation failed".into());
+    }
+
+    // Convert the .o file to .cell
+    let objcopy_status = Command::new("objcopy")
+        .args(["-O", "binary", "--remove-section=.note.gnu.property"])
+        .arg(&obj_file_path)
+        .arg(&cell_file_path)
+        .status()?;
+
+    if !objcopy_status.success() {
+        logging::log_message(logging::Level::Error, "Conversion to .cell file failed!!!");
+        return Err("Conversion to .cell file failed".into());
+    }
+
+    Ok(fs::read(&cell_file_path)?)
+}
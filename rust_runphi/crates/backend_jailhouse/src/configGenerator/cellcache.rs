@@ -0,0 +1,226 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Content-addressed cache of the compiled .cell files.
+// Restarting the same pod on the same resources renders the very same cell config, but for the
+// name of the cell, so the result of gcc and objcopy can be reused once renamed. Entries are named
+// after a hash of the key (config rendered without the name and fingerprint of the headers).
+// Each entry holds the key followed by the .cell, and the key is compared on lookup, so that a
+// hash collision can never load the wrong cell.
+// The cache is bounded in size, the least recently used entries are evicted first.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const ENTRY_EXT: &str = "entry";
+
+pub struct CellCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+// Key of a compiled cell: the rendered C source, with the name of the cell left empty since it is
+// unique to each container, and the headers it is compiled against
+pub fn key(source: &str, include_dir: &Path) -> String {
+    let mut headers: Vec<String> = fs::read_dir(include_dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter_map(|e| {
+                    let meta = e.metadata().ok()?;
+                    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+                    Some(format!("{} {} {}", e.file_name().to_string_lossy(), meta.len(), mtime))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    headers.sort();
+    format!("{}\n/* headers:\n{}\n*/\n", source, headers.join("\n"))
+}
+
+// FNV-1a, stable across builds and platforms
+fn hash(key: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in key.as_bytes() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+impl CellCache {
+    // A cache of max_bytes = 0 is disabled
+    pub fn new(dir: &Path, max_bytes: u64) -> Self {
+        Self { dir: dir.to_path_buf(), max_bytes }
+    }
+
+    fn entry(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", hash(key), ENTRY_EXT))
+    }
+
+    // Returns the cached .cell for the key, if any
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        if self.max_bytes == 0 {
+            return None;
+        }
+        // The entry may be evicted by a concurrent runPHI in the meantime, that is just a miss
+        let path = self.entry(key);
+        let content = fs::read(&path).ok()?;
+        // Entry layout: key length (u64, little endian), key, .cell
+        let key_len = u64::from_le_bytes(content.get(..8)?.try_into().ok()?) as usize;
+        let stored_key = content.get(8..8usize.checked_add(key_len)?)?;
+        if stored_key != key.as_bytes() {
+            return None;
+        }
+        // Refresh the entry for the LRU eviction
+        if let Ok(file) = File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(content[8 + key_len..].to_vec())
+    }
+
+    // Stores the .cell for the key, then evicts old entries exceeding the size of the cache
+    pub fn put(&self, key: &str, cell: &[u8]) -> Result<(), Box<dyn Error>> {
+        if self.max_bytes == 0 {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let mut content = (key.len() as u64).to_le_bytes().to_vec();
+        content.extend_from_slice(key.as_bytes());
+        content.extend_from_slice(cell);
+        // Write to a temporary file and rename, so that readers never see a partial entry
+        let path = self.entry(key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &path)?;
+        self.evict()
+    }
+
+    fn evict(&self) -> Result<(), Box<dyn Error>> {
+        // (last use, size, path) of the entries
+        let mut entries: Vec<(SystemTime, u64, PathBuf)> = Vec::new();
+        for e in fs::read_dir(&self.dir)?.filter_map(Result::ok) {
+            let path = e.path();
+            if path.extension().and_then(|x| x.to_str()) != Some(ENTRY_EXT) {
+                continue;
+            }
+            let Ok(meta) = e.metadata() else { continue };
+            entries.push((meta.modified().unwrap_or(UNIX_EPOCH), meta.len(), path));
+        }
+        let mut total: u64 = entries.iter().map(|e| e.1).sum();
+        entries.sort();
+        for (_, size, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            logging::log_message(logging::Level::Debug, format!("Evicting cached cell {}", path.display()).as_str());
+            let _ = fs::remove_file(&path);
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // An empty cache directory, under the temporary directory
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("runphi-cellcache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // Sets the last use of the entry of the key, seconds after the epoch
+    fn touch(cache: &CellCache, key: &str, secs: u64) {
+        let file = File::options().write(true).open(cache.entry(key)).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+    }
+
+    #[test]
+    fn put_then_get() {
+        let dir = cache_dir("hit");
+        let cache = CellCache::new(&dir, 1 << 20);
+        assert_eq!(cache.get("cell a"), None);
+        cache.put("cell a", b"JHCELL a").unwrap();
+        assert_eq!(cache.get("cell a"), Some(b"JHCELL a".to_vec()));
+        assert_eq!(cache.get("cell b"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn key_mismatch_is_a_miss() {
+        let dir = cache_dir("collision");
+        let cache = CellCache::new(&dir, 1 << 20);
+        cache.put("cell a", b"JHCELL a").unwrap();
+        // The entry of another key under the hash of this one, as on a hash collision
+        fs::rename(cache.entry("cell a"), cache.entry("cell b")).unwrap();
+        assert_eq!(cache.get("cell b"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_entry_is_a_miss() {
+        let dir = cache_dir("truncated");
+        let cache = CellCache::new(&dir, 1 << 20);
+        cache.put("cell a", b"JHCELL a").unwrap();
+        let content = fs::read(cache.entry("cell a")).unwrap();
+        // Within the length of the key, and within the key
+        for len in [0, 4, 8, 8 + "cell a".len() - 1] {
+            fs::write(cache.entry("cell a"), &content[..len]).unwrap();
+            assert_eq!(cache.get("cell a"), None, "entry of {} bytes", len);
+        }
+        // A length beyond the entry, that would overflow the offset of the cell
+        let mut huge = u64::MAX.to_le_bytes().to_vec();
+        huge.extend_from_slice(b"cell a");
+        fs::write(cache.entry("cell a"), huge).unwrap();
+        assert_eq!(cache.get("cell a"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let dir = cache_dir("lru");
+        let cell = [0u8; 100];
+        let entry_size = (8 + "cell a".len() + cell.len()) as u64;
+        let cache = CellCache::new(&dir, 3 * entry_size);
+        for (i, key) in ["cell a", "cell b", "cell c"].iter().enumerate() {
+            cache.put(key, &cell).unwrap();
+            touch(&cache, key, 1000 + i as u64);
+        }
+        // cell a is used again, cell b becomes the least recently used
+        assert!(cache.get("cell a").is_some());
+        cache.put("cell d", &cell).unwrap();
+        assert!(!cache.entry("cell b").exists());
+        for key in ["cell a", "cell c", "cell d"] {
+            assert!(cache.get(key).is_some(), "{} evicted", key);
+        }
+
+        // Then the oldest ones, until the new entry fits
+        touch(&cache, "cell a", 1000);
+        touch(&cache, "cell c", 1001);
+        touch(&cache, "cell d", 1002);
+        cache.put("cell e", &[0u8; 150]).unwrap();
+        assert!(!cache.entry("cell a").exists() && !cache.entry("cell c").exists());
+        assert!(cache.entry("cell d").exists() && cache.entry("cell e").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zero_max_bytes_disables_the_cache() {
+        let dir = cache_dir("disabled");
+        let cache = CellCache::new(&dir, 0);
+        cache.put("cell a", b"JHCELL a").unwrap();
+        assert!(!dir.exists());
+        assert_eq!(cache.get("cell a"), None);
+        // Entries left by an enabled cache are not read either
+        CellCache::new(&dir, 1 << 20).put("cell a", b"JHCELL a").unwrap();
+        assert_eq!(cache.get("cell a"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,731 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Typed description of a Jailhouse cell. The resource managers (cpu, rpu, mem, device) fill it
+// in, and the C source of the cell config is rendered from it in one place, so that array sizes
+// and counters are derived from what was actually allocated.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write;
+
+use crate::configGenerator::cinit::{self, Item, Value};
+
+pub const JAILHOUSE_CELL_NAME_MAXLEN: usize = 31;
+
+pub const JAILHOUSE_CELL_PASSIVE_COMMREG: u64 = 0x00000001;
+pub const JAILHOUSE_CELL_TEST_DEVICE: u64 = 0x00000002;
+pub const JAILHOUSE_CELL_AARCH32: u64 = 0x00000004;
+pub const JAILHOUSE_CELL_VIRTUAL_CONSOLE_PERMITTED: u64 = 0x40000000;
+pub const JAILHOUSE_CELL_VIRTUAL_CONSOLE_ACTIVE: u64 = 0x80000000;
+
+pub const JAILHOUSE_MEM_READ: u64 = 0x0001;
+pub const JAILHOUSE_MEM_WRITE: u64 = 0x0002;
+pub const JAILHOUSE_MEM_EXECUTE: u64 = 0x0004;
+pub const JAILHOUSE_MEM_DMA: u64 = 0x0008;
+pub const JAILHOUSE_MEM_IO: u64 = 0x0010;
+pub const JAILHOUSE_MEM_COMM_REGION: u64 = 0x0020;
+pub const JAILHOUSE_MEM_LOADABLE: u64 = 0x0040;
+pub const JAILHOUSE_MEM_ROOTSHARED: u64 = 0x0080;
+pub const JAILHOUSE_MEM_NO_HUGEPAGES: u64 = 0x0100;
+pub const JAILHOUSE_MEM_COLORED: u64 = 0x0200;
+pub const JAILHOUSE_MEM_COLORED_NO_COPY: u64 = 0x0400;
+pub const JAILHOUSE_MEM_IO_UNALIGNED: u64 = 0x8000;
+pub const JAILHOUSE_MEM_IO_8: u64 = 1 << 16;
+pub const JAILHOUSE_MEM_IO_16: u64 = 2 << 16;
+pub const JAILHOUSE_MEM_IO_32: u64 = 4 << 16;
+pub const JAILHOUSE_MEM_IO_64: u64 = 8 << 16;
+// Omnivisor only, TCM banks of the RPUs
+pub const JAILHOUSE_MEM_TCM_A: u64 = 1 << 12;
+pub const JAILHOUSE_MEM_TCM_B: u64 = 2 << 12;
+
+pub const JAILHOUSE_CON_TYPE_NONE: u64 = 0x0000;
+pub const JAILHOUSE_CON_TYPE_8250: u64 = 0x0002;
+pub const JAILHOUSE_CON_TYPE_PL011: u64 = 0x0003;
+pub const JAILHOUSE_CON_TYPE_XUARTPS: u64 = 0x0004;
+pub const JAILHOUSE_CON_ACCESS_MMIO: u64 = 0x0001;
+pub const JAILHOUSE_CON_REGDIST_4: u64 = 0x0002;
+
+pub const JAILHOUSE_PCI_TYPE_IVSHMEM: u64 = 0x03;
+pub const JAILHOUSE_SHMEM_PROTO_UNDEFINED: u64 = 0x0000;
+pub const JAILHOUSE_SHMEM_PROTO_VETH: u64 = 0x0001;
+pub const JAILHOUSE_SHMEM_PROTO_VIRTIO_FRONT: u64 = 0x8000;
+pub const JAILHOUSE_SHMEM_PROTO_VIRTIO_BACK: u64 = 0xc000;
+pub const VIRTIO_DEV_BLOCK: u64 = 2;
+pub const VIRTIO_DEV_CONSOLE: u64 = 3;
+
+pub const JAILHOUSE_ARM64: u64 = 2;
+
+pub const JAILHOUSE_IVSHMEM_BAR_MASK_INTX: [u32; 6] = [0xfffff000, 0, 0, 0, 0, 0];
+pub const JAILHOUSE_IVSHMEM_BAR_MASK_MSIX: [u32; 6] = [0xfffff000, 0xfffff000, 0, 0, 0, 0];
+
+// Symbolic names used when rendering, and accepted by the template parser
+pub(crate) const CELL_FLAG_NAMES: &[(u64, &str)] = &[
+    (JAILHOUSE_CELL_PASSIVE_COMMREG, "JAILHOUSE_CELL_PASSIVE_COMMREG"),
+    (JAILHOUSE_CELL_TEST_DEVICE, "JAILHOUSE_CELL_TEST_DEVICE"),
+    (JAILHOUSE_CELL_AARCH32, "JAILHOUSE_CELL_AARCH32"),
+    (JAILHOUSE_CELL_VIRTUAL_CONSOLE_PERMITTED, "JAILHOUSE_CELL_VIRTUAL_CONSOLE_PERMITTED"),
+    (JAILHOUSE_CELL_VIRTUAL_CONSOLE_ACTIVE, "JAILHOUSE_CELL_VIRTUAL_CONSOLE_ACTIVE"),
+];
+
+pub(crate) const MEM_FLAG_NAMES: &[(u64, &str)] = &[
+    (JAILHOUSE_MEM_READ, "JAILHOUSE_MEM_READ"),
+    (JAILHOUSE_MEM_WRITE, "JAILHOUSE_MEM_WRITE"),
+    (JAILHOUSE_MEM_EXECUTE, "JAILHOUSE_MEM_EXECUTE"),
+    (JAILHOUSE_MEM_DMA, "JAILHOUSE_MEM_DMA"),
+    (JAILHOUSE_MEM_IO, "JAILHOUSE_MEM_IO"),
+    (JAILHOUSE_MEM_COMM_REGION, "JAILHOUSE_MEM_COMM_REGION"),
+    (JAILHOUSE_MEM_LOADABLE, "JAILHOUSE_MEM_LOADABLE"),
+    (JAILHOUSE_MEM_ROOTSHARED, "JAILHOUSE_MEM_ROOTSHARED"),
+    (JAILHOUSE_MEM_NO_HUGEPAGES, "JAILHOUSE_MEM_NO_HUGEPAGES"),
+    (JAILHOUSE_MEM_COLORED, "JAILHOUSE_MEM_COLORED"),
+    (JAILHOUSE_MEM_COLORED_NO_COPY, "JAILHOUSE_MEM_COLORED_NO_COPY"),
+    (JAILHOUSE_MEM_IO_UNALIGNED, "JAILHOUSE_MEM_IO_UNALIGNED"),
+    (JAILHOUSE_MEM_IO_8, "JAILHOUSE_MEM_IO_8"),
+    (JAILHOUSE_MEM_IO_16, "JAILHOUSE_MEM_IO_16"),
+    (JAILHOUSE_MEM_IO_32, "JAILHOUSE_MEM_IO_32"),
+    (JAILHOUSE_MEM_IO_64, "JAILHOUSE_MEM_IO_64"),
+];
+
+pub(crate) const TCM_FLAG_NAMES: &[(u64, &str)] = &[
+    (JAILHOUSE_MEM_TCM_A, "JAILHOUSE_MEM_TCM_A"),
+    (JAILHOUSE_MEM_TCM_B, "JAILHOUSE_MEM_TCM_B"),
+];
+
+pub(crate) const CON_TYPE_NAMES: &[(u64, &str)] = &[
+    (JAILHOUSE_CON_TYPE_NONE, "JAILHOUSE_CON_TYPE_NONE"),
+    (0x0001, "JAILHOUSE_CON_TYPE_EFIFB"),
+    (JAILHOUSE_CON_TYPE_8250, "JAILHOUSE_CON_TYPE_8250"),
+    (JAILHOUSE_CON_TYPE_PL011, "JAILHOUSE_CON_TYPE_PL011"),
+    (JAILHOUSE_CON_TYPE_XUARTPS, "JAILHOUSE_CON_TYPE_XUARTPS"),
+    (0x0005, "JAILHOUSE_CON_TYPE_MVEBU"),
+    (0x0006, "JAILHOUSE_CON_TYPE_HSCIF"),
+    (0x0007, "JAILHOUSE_CON_TYPE_SCIFA"),
+    (0x0008, "JAILHOUSE_CON_TYPE_IMX"),
+    (0x0009, "JAILHOUSE_CON_TYPE_IMX_LPUART"),
+    (0x000a, "JAILHOUSE_CON_TYPE_LINFLEX"),
+];
+
+pub(crate) const CON_FLAG_NAMES: &[(u64, &str)] = &[
+    (JAILHOUSE_CON_ACCESS_MMIO, "JAILHOUSE_CON_ACCESS_MMIO"),
+    (JAILHOUSE_CON_REGDIST_4, "JAILHOUSE_CON_REGDIST_4"),
+    (0x0004, "JAILHOUSE_CON_FB_1920x1080"),
+    (0x1000, "JAILHOUSE_CON_INVERTED_GATE"),
+    (0x2000, "JAILHOUSE_CON_MDR_QUIRK"),
+];
+
+pub(crate) const PCI_TYPE_NAMES: &[(u64, &str)] = &[
+    (0x01, "JAILHOUSE_PCI_TYPE_DEVICE"),
+    (0x02, "JAILHOUSE_PCI_TYPE_BRIDGE"),
+    (JAILHOUSE_PCI_TYPE_IVSHMEM, "JAILHOUSE_PCI_TYPE_IVSHMEM"),
+];
+
+pub(crate) const SHMEM_PROTO_NAMES: &[(u64, &str)] = &[
+    (JAILHOUSE_SHMEM_PROTO_UNDEFINED, "JAILHOUSE_SHMEM_PROTO_UNDEFINED"),
+    (JAILHOUSE_SHMEM_PROTO_VETH, "JAILHOUSE_SHMEM_PROTO_VETH"),
+    (JAILHOUSE_SHMEM_PROTO_VIRTIO_FRONT + VIRTIO_DEV_BLOCK, "JAILHOUSE_SHMEM_PROTO_VIRTIO_FRONT + VIRTIO_DEV_BLOCK"),
+    (JAILHOUSE_SHMEM_PROTO_VIRTIO_FRONT + VIRTIO_DEV_CONSOLE, "JAILHOUSE_SHMEM_PROTO_VIRTIO_FRONT + VIRTIO_DEV_CONSOLE"),
+    (JAILHOUSE_SHMEM_PROTO_VIRTIO_BACK + VIRTIO_DEV_BLOCK, "JAILHOUSE_SHMEM_PROTO_VIRTIO_BACK + VIRTIO_DEV_BLOCK"),
+    (JAILHOUSE_SHMEM_PROTO_VIRTIO_BACK + VIRTIO_DEV_CONSOLE, "JAILHOUSE_SHMEM_PROTO_VIRTIO_BACK + VIRTIO_DEV_CONSOLE"),
+];
+
+pub(crate) const ARCHITECTURE_NAMES: &[(u64, &str)] = &[
+    (0, "JAILHOUSE_X86"),
+    (1, "JAILHOUSE_ARM"),
+    (JAILHOUSE_ARM64, "JAILHOUSE_ARM64"),
+];
+
+pub(crate) const BAR_MASK_NAMES: &[([u32; 6], &str)] = &[
+    (JAILHOUSE_IVSHMEM_BAR_MASK_INTX, "JAILHOUSE_IVSHMEM_BAR_MASK_INTX"),
+    (JAILHOUSE_IVSHMEM_BAR_MASK_MSIX, "JAILHOUSE_IVSHMEM_BAR_MASK_MSIX"),
+    ([0xffff0000, 0, 0, 0, 0, 0], "JAILHOUSE_IVSHMEM_BAR_MASK_INTX_64K"),
+    ([0xffff0000, 0xfffff000, 0, 0, 0, 0], "JAILHOUSE_IVSHMEM_BAR_MASK_MSIX_64K"),
+];
+
+// Layout of struct jailhouse_cell_desc. The Omnivisor fork adds the architecture byte and the
+// set of RPUs (rcpus) assigned to the cell
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Layout {
+    #[default]
+    Jailhouse,
+    Omnivisor,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Console {
+    pub address: u64,
+    pub size: u32,
+    pub con_type: u16,
+    pub flags: u16,
+    pub divider: u32,
+    pub gate_nr: u32,
+    pub clock_reg: u64,
+}
+
+// struct jailhouse_cell_desc, without the fields derived from the rest of the description
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CellHeader {
+    pub name: String,
+    pub layout: Layout,
+    // Omnivisor only
+    pub architecture: u8,
+    pub flags: u32,
+    pub vpci_irq_base: u32,
+    pub cpu_reset_address: u64,
+    pub msg_reply_timeout: u64,
+    pub console: Console,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemRegion {
+    pub comment: Option<String>,
+    pub phys_start: u64,
+    pub virt_start: u64,
+    pub size: u64,
+    pub flags: u64,
+    pub colors: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IrqChip {
+    pub comment: Option<String>,
+    pub address: u64,
+    pub id: u32,
+    pub pin_base: u32,
+    pub pin_bitmap: [u32; 4],
+}
+
+// Range of I/O ports of x86 cells
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PioRegion {
+    pub comment: Option<String>,
+    pub base: u16,
+    pub length: u16,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PciDevice {
+    pub comment: Option<String>,
+    pub dev_type: u8,
+    pub iommu: u8,
+    pub domain: u16,
+    pub bdf: u16,
+    pub bar_mask: [u32; 6],
+    pub caps_start: u16,
+    pub num_caps: u16,
+    pub num_msi_vectors: u8,
+    pub msi_64bits: bool,
+    pub msi_maskable: bool,
+    pub num_msix_vectors: u16,
+    pub msix_region_size: u16,
+    pub msix_address: u64,
+    pub shmem_regions_start: u32,
+    pub shmem_dev_id: u8,
+    pub shmem_peers: u8,
+    pub shmem_protocol: u16,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CellDesc {
+    pub header: CellHeader,
+    // CPU bitmaps, 64 CPUs per word
+    pub cpus: Vec<u64>,
+    pub rcpus: Vec<u64>,
+    pub mem_regions: Vec<MemRegion>,
+    pub irqchips: Vec<IrqChip>,
+    pub pio_regions: Vec<PioRegion>,
+    pub pci_devices: Vec<PciDevice>,
+    pub stream_ids: Vec<u32>,
+}
+
+// Bitmap words of the given CPU ids, in the format of the cpu set of the cell
+pub fn cpu_set(ids: &[usize]) -> Vec<u64> {
+    let mut words = vec![0u64; ids.iter().max().map_or(1, |max| max / 64 + 1)];
+    for id in ids {
+        words[id / 64] |= 1 << (id % 64);
+    }
+    words
+}
+
+impl CellDesc {
+    pub fn new(name: &str) -> Self {
+        let mut desc = Self::default();
+        desc.header.name = name.to_string();
+        desc
+    }
+
+    // Applies a preamble template, i.e. the `.cell` header of the board and, optionally, its
+    // `.stream_ids`. Fields derived from the description (sizes, counters, signature) are ignored
+    pub fn apply_preamble(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        let items = cinit::parse(text).map_err(|e| format!("preamble: {}", e))?;
+        for item in &items {
+            match (item.field.as_deref(), &item.value) {
+                (Some("cell"), Value::List(fields)) => self.apply_header(fields)?,
+                (Some("stream_ids"), Value::List(ids)) => {
+                    for id in ids {
+                        self.stream_ids.push(stream_id(id)?);
+                    }
+                }
+                (field, _) => return Err(format!("preamble: unexpected element {:?}", field).into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_header(&mut self, items: &[Item]) -> Result<(), Box<dyn Error>> {
+        const DERIVED: &[&str] = &[
+            "signature", "revision", "name", "cpu_set_size", "rcpu_set_size", "num_memory_regions",
+            "num_cache_regions", "num_irqchips", "num_pio_regions", "num_pci_devices", "num_pci_caps",
+            "num_stream_ids", "num_qos_devices",
+        ];
+        let f = Fields::new("cell", items, &[
+            "architecture", "flags", "vpci_irq_base", "cpu_reset_address", "msg_reply_timeout", "console",
+        ], DERIVED)?;
+        let h = &mut self.header;
+        if let Some(arch) = f.int::<u8>("architecture")? {
+            // Only the Omnivisor header carries the architecture
+            h.layout = Layout::Omnivisor;
+            h.architecture = arch;
+        }
+        h.flags = f.int("flags")?.unwrap_or(h.flags);
+        h.vpci_irq_base = f.int("vpci_irq_base")?.unwrap_or(h.vpci_irq_base);
+        h.cpu_reset_address = f.int("cpu_reset_address")?.unwrap_or(h.cpu_reset_address);
+        h.msg_reply_timeout = f.int("msg_reply_timeout")?.unwrap_or(h.msg_reply_timeout);
+        if let Some(console) = f.list("console")? {
+            let c = Fields::new("console", console, &[
+                "address", "size", "type", "flags", "divider", "gate_nr", "clock_reg",
+            ], &[])?;
+            h.console = Console {
+                address: c.int("address")?.unwrap_or(0),
+                size: c.int("size")?.unwrap_or(0),
+                con_type: c.int("type")?.unwrap_or(0),
+                flags: c.int("flags")?.unwrap_or(0),
+                divider: c.int("divider")?.unwrap_or(0),
+                gate_nr: c.int("gate_nr")?.unwrap_or(0),
+                clock_reg: c.int("clock_reg")?.unwrap_or(0),
+            };
+        }
+        Ok(())
+    }
+
+    // Appends the memory regions of a template made of region initializers, e.g. `/* RAM */ {...},`
+    pub fn add_mem_regions(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        for item in cinit::parse(text)? {
+            self.mem_regions.push(mem_region(&item)?);
+        }
+        Ok(())
+    }
+
+    // Appends the elements of a template made of designated arrays of the cell config,
+    // e.g. `.irqchips = {...}, .pci_devices = {...},`
+    pub fn add_elements(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        for item in cinit::parse(text)? {
+            let Value::List(elements) = &item.value else {
+                return Err(format!("expected a list for {:?}", item.field).into());
+            };
+            for element in elements {
+                match item.field.as_deref() {
+                    Some("mem_regions") => self.mem_regions.push(mem_region(element)?),
+                    Some("irqchips") => self.irqchips.push(irqchip(element)?),
+                    Some("pio_regions") => self.pio_regions.push(pio_region(element)?),
+                    Some("pci_devices") => self.pci_devices.push(pci_device(element)?),
+                    Some("stream_ids") => self.stream_ids.push(stream_id(element)?),
+                    field => return Err(format!("unsupported cell element {:?}", field).into()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Checks the constraints of the cell-config.h layout, before rendering or serializing
+    pub(crate) fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let h = &self.header;
+        if h.name.len() > JAILHOUSE_CELL_NAME_MAXLEN {
+            return Err(format!("cell name {} longer than {} chars", h.name, JAILHOUSE_CELL_NAME_MAXLEN).into());
+        }
+        if h.layout != Layout::Omnivisor && self.rcpus.iter().any(|w| *w != 0) {
+            return Err("rcpus requested, but the cell layout has no RPU set".into());
+        }
+        Ok(())
+    }
+
+    // The cpu set is mandatory, even if empty
+    pub(crate) fn cpu_words(&self) -> Vec<u64> {
+        if self.cpus.is_empty() { vec![0] } else { self.cpus.clone() }
+    }
+
+    // The rcpu set is always present in the Omnivisor layout, and never in the Jailhouse one
+    pub(crate) fn rcpu_words(&self) -> Vec<u64> {
+        match self.header.layout {
+            Layout::Jailhouse => Vec::new(),
+            Layout::Omnivisor if self.rcpus.is_empty() => vec![0],
+            Layout::Omnivisor => self.rcpus.clone(),
+        }
+    }
+
+    // Renders the C source of the cell config, to be compiled against cell-config.h
+    pub fn render_c(&self) -> Result<String, Box<dyn Error>> {
+        self.validate()?;
+        let h = &self.header;
+        let omnivisor = h.layout == Layout::Omnivisor;
+        let cpus = self.cpu_words();
+        let rcpus = self.rcpu_words();
+        let mem_flag_names: Vec<(u64, &str)> = if omnivisor {
+            MEM_FLAG_NAMES.iter().chain(TCM_FLAG_NAMES).cloned().collect()
+        } else {
+            MEM_FLAG_NAMES.to_vec()
+        };
+
+        let mut out = String::new();
+        out.push_str("#include \"types.h\"\n#include \"cell-config.h\"\n\nstruct {\n");
+        out.push_str("\tstruct jailhouse_cell_desc cell;\n");
+        let _ = writeln!(out, "\t__u64 cpus[{}];", cpus.len());
+        if !rcpus.is_empty() {
+            let _ = writeln!(out, "\t__u64 rcpus[{}];", rcpus.len());
+        }
+        let arrays = [
+            ("struct jailhouse_memory", "mem_regions", self.mem_regions.len(), "num_memory_regions"),
+            ("struct jailhouse_irqchip", "irqchips", self.irqchips.len(), "num_irqchips"),
+            ("struct jailhouse_pio", "pio_regions", self.pio_regions.len(), "num_pio_regions"),
+            ("struct jailhouse_pci_device", "pci_devices", self.pci_devices.len(), "num_pci_devices"),
+            ("union jailhouse_stream_id", "stream_ids", self.stream_ids.len(), "num_stream_ids"),
+        ];
+        for (ctype, name, len, _) in arrays.iter().filter(|a| a.2 > 0) {
+            let _ = writeln!(out, "\t{} {}[{}];", ctype, name, len);
+        }
+        out.push_str("} __attribute__((packed)) config = {\n\t.cell = {\n");
+        out.push_str("\t\t.signature = JAILHOUSE_CELL_DESC_SIGNATURE,\n");
+        out.push_str("\t\t.revision = JAILHOUSE_CONFIG_REVISION,\n");
+        if omnivisor {
+            let _ = writeln!(out, "\t\t.architecture = {},", render_enum(h.architecture as u64, ARCHITECTURE_NAMES));
+        }
+        let _ = writeln!(out, "\t\t.name = \"{}\",", h.name);
+        let _ = writeln!(out, "\t\t.flags = {},\n", render_flags(h.flags as u64, CELL_FLAG_NAMES));
+        out.push_str("\t\t.cpu_set_size = sizeof(config.cpus),\n");
+        if !rcpus.is_empty() {
+            out.push_str("\t\t.rcpu_set_size = sizeof(config.rcpus),\n");
+        }
+        for (_, name, _, counter) in arrays.iter().filter(|a| a.2 > 0) {
+            let _ = writeln!(out, "\t\t.{} = ARRAY_SIZE(config.{}),", counter, name);
+        }
+        if h.vpci_irq_base != 0 {
+            let _ = writeln!(out, "\n\t\t.vpci_irq_base = {},", h.vpci_irq_base);
+        }
+        if h.cpu_reset_address != 0 {
+            let _ = writeln!(out, "\t\t.cpu_reset_address = 0x{:x},", h.cpu_reset_address);
+        }
+        if h.msg_reply_timeout != 0 {
+            let _ = writeln!(out, "\t\t.msg_reply_timeout = {},", h.msg_reply_timeout);
+        }
+        if h.console != Console::default() {
+            let c = &h.console;
+            out.push_str("\n\t\t.console = {\n");
+            let _ = writeln!(out, "\t\t\t.address = 0x{:x},", c.address);
+            if c.size != 0 {
+                let _ = writeln!(out, "\t\t\t.size = 0x{:x},", c.size);
+            }
+            let _ = writeln!(out, "\t\t\t.type = {},", render_enum(c.con_type as u64, CON_TYPE_NAMES));
+            let _ = writeln!(out, "\t\t\t.flags = {},", render_flags(c.flags as u64, CON_FLAG_NAMES));
+            for (name, value) in [("divider", c.divider as u64), ("gate_nr", c.gate_nr as u64), ("clock_reg", c.clock_reg)] {
+                if value != 0 {
+                    let _ = writeln!(out, "\t\t\t.{} = 0x{:x},", name, value);
+                }
+            }
+            out.push_str("\t\t},\n");
+        }
+        out.push_str("\t},\n");
+
+        render_words(&mut out, "cpus", &cpus);
+        if !rcpus.is_empty() {
+            render_words(&mut out, "rcpus", &rcpus);
+        }
+
+        if !self.mem_regions.is_empty() {
+            out.push_str("\n\t.mem_regions = {\n");
+            for r in &self.mem_regions {
+                if *r == MemRegion::default() {
+                    out.push_str("\t\t{ 0 },\n");
+                    continue;
+                }
+                open_element(&mut out, &r.comment);
+                let _ = writeln!(out, "\t\t\t.phys_start = 0x{:x},", r.phys_start);
+                let _ = writeln!(out, "\t\t\t.virt_start = 0x{:x},", r.virt_start);
+                let _ = writeln!(out, "\t\t\t.size = 0x{:x},", r.size);
+                let _ = writeln!(out, "\t\t\t.flags = {},", render_flags(r.flags, &mem_flag_names));
+                if r.colors != 0 {
+                    let _ = writeln!(out, "\t\t\t.colors = 0x{:x},", r.colors);
+                }
+                out.push_str("\t\t},\n");
+            }
+            out.push_str("\t},\n");
+        }
+
+        if !self.irqchips.is_empty() {
+            out.push_str("\n\t.irqchips = {\n");
+            for i in &self.irqchips {
+                open_element(&mut out, &i.comment);
+                let _ = writeln!(out, "\t\t\t.address = 0x{:x},", i.address);
+                if i.id != 0 {
+                    let _ = writeln!(out, "\t\t\t.id = 0x{:x},", i.id);
+                }
+                let _ = writeln!(out, "\t\t\t.pin_base = {},", i.pin_base);
+                let bitmap: Vec<String> = i.pin_bitmap.iter().map(|w| format!("0x{:x}", w)).collect();
+                let _ = writeln!(out, "\t\t\t.pin_bitmap = {{\n\t\t\t\t{},\n\t\t\t}},", bitmap.join(", "));
+                out.push_str("\t\t},\n");
+            }
+            out.push_str("\t},\n");
+        }
+
+        if !self.pio_regions.is_empty() {
+            out.push_str("\n\t.pio_regions = {\n");
+            for p in &self.pio_regions {
+                if let Some(text) = &p.comment {
+                    let _ = writeln!(out, "\t\t/* {} */", text);
+                }
+                let _ = writeln!(out, "\t\tPIO_RANGE(0x{:x}, 0x{:x}),", p.base, p.length);
+            }
+            out.push_str("\t},\n");
+        }
+
+        if !self.pci_devices.is_empty() {
+            out.push_str("\n\t.pci_devices = {\n");
+            for d in &self.pci_devices {
+                open_element(&mut out, &d.comment);
+                let _ = writeln!(out, "\t\t\t.type = {},", render_enum(d.dev_type as u64, PCI_TYPE_NAMES));
+                if d.iommu != 0 {
+                    let _ = writeln!(out, "\t\t\t.iommu = {},", d.iommu);
+                }
+                let _ = writeln!(out, "\t\t\t.domain = {},", d.domain);
+                let _ = writeln!(out, "\t\t\t.bdf = 0x{:x},", d.bdf);
+                let _ = writeln!(out, "\t\t\t.bar_mask = {},", render_bar_mask(&d.bar_mask));
+                for (name, value) in [
+                    ("caps_start", d.caps_start as u64),
+                    ("num_caps", d.num_caps as u64),
+                    ("num_msi_vectors", d.num_msi_vectors as u64),
+                    ("msi_64bits", d.msi_64bits as u64),
+                    ("msi_maskable", d.msi_maskable as u64),
+                    ("num_msix_vectors", d.num_msix_vectors as u64),
+                    ("msix_region_size", d.msix_region_size as u64),
+                    ("msix_address", d.msix_address),
+                ] {
+                    if value != 0 {
+                        let _ = writeln!(out, "\t\t\t.{} = 0x{:x},", name, value);
+                    }
+                }
+                let _ = writeln!(out, "\t\t\t.shmem_regions_start = {},", d.shmem_regions_start);
+                let _ = writeln!(out, "\t\t\t.shmem_dev_id = {},", d.shmem_dev_id);
+                let _ = writeln!(out, "\t\t\t.shmem_peers = {},", d.shmem_peers);
+                let _ = writeln!(out, "\t\t\t.shmem_protocol = {},", render_enum(d.shmem_protocol as u64, SHMEM_PROTO_NAMES));
+                out.push_str("\t\t},\n");
+            }
+            out.push_str("\t},\n");
+        }
+
+        if !self.stream_ids.is_empty() {
+            out.push_str("\n\t.stream_ids = {\n");
+            for id in &self.stream_ids {
+                let _ = writeln!(out, "\t\t{{ .id = 0x{:x} }},", id);
+            }
+            out.push_str("\t},\n");
+        }
+        out.push_str("};\n");
+        Ok(out)
+    }
+}
+
+fn render_words(out: &mut String, name: &str, words: &[u64]) {
+    let words: Vec<String> = words.iter().map(|w| format!("0x{:x}", w)).collect();
+    let _ = writeln!(out, "\n\t.{} = {{\n\t\t{},\n\t}},", name, words.join(", "));
+}
+
+fn open_element(out: &mut String, comment: &Option<String>) {
+    match comment {
+        Some(text) => {
+            let _ = writeln!(out, "\t\t/* {} */ {{", text);
+        }
+        None => out.push_str("\t\t{\n"),
+    }
+}
+
+// Flags as an or of the known names, unknown bits are kept as a number
+pub(crate) fn render_flags(value: u64, names: &[(u64, &str)]) -> String {
+    let mut parts = Vec::new();
+    let mut rest = value;
+    for (bit, name) in names {
+        if *bit != 0 && value & bit == *bit {
+            parts.push(name.to_string());
+            rest &= !bit;
+        }
+    }
+    if rest != 0 || parts.is_empty() {
+        parts.push(format!("0x{:x}", rest));
+    }
+    // Two flags per line, as in the Jailhouse configs
+    parts
+        .chunks(2)
+        .map(|pair| pair.join(" | "))
+        .collect::<Vec<_>>()
+        .join(" |\n\t\t\t\t")
+}
+
+pub(crate) fn render_enum(value: u64, names: &[(u64, &str)]) -> String {
+    names
+        .iter()
+        .find(|(v, _)| *v == value)
+        .map(|(_, n)| n.to_string())
+        .unwrap_or_else(|| format!("0x{:x}", value))
+}
+
+fn render_bar_mask(mask: &[u32; 6]) -> String {
+    if let Some((_, name)) = BAR_MASK_NAMES.iter().find(|(m, _)| m == mask) {
+        return name.to_string();
+    }
+    let words: Vec<String> = mask.iter().map(|w| format!("0x{:x}", w)).collect();
+    format!("{{ {} }}", words.join(", "))
+}
+
+// Designated fields of an initializer, checked against the fields of the target struct
+struct Fields<'a> {
+    what: &'static str,
+    values: HashMap<&'a str, &'a Value>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(what: &'static str, items: &'a [Item], allowed: &[&str], ignored: &[&str]) -> Result<Self, String> {
+        let mut values = HashMap::new();
+        // `{ 0 }` zero-initializes the whole struct, as in C
+        if let [Item { field: None, value: Value::Int(0), .. }] = items {
+            return Ok(Self { what, values });
+        }
+        for item in items {
+            let field = item
+                .field
+                .as_deref()
+                .ok_or_else(|| format!("{}: positional values are not supported", what))?;
+            if ignored.contains(&field) {
+                continue;
+            }
+            if !allowed.contains(&field) {
+                return Err(format!("{}: unknown field {}", what, field));
+            }
+            if values.insert(field, &item.value).is_some() {
+                return Err(format!("{}: field {} given twice", what, field));
+            }
+        }
+        Ok(Self { what, values })
+    }
+
+    fn int<T: TryFrom<u64>>(&self, field: &str) -> Result<Option<T>, String> {
+        match self.values.get(field) {
+            None => Ok(None),
+            Some(Value::Int(v)) => T::try_from(*v)
+                .map(Some)
+                .map_err(|_| format!("{}: {} = 0x{:x} out of range", self.what, field, v)),
+            Some(_) => Err(format!("{}: {} must be a number", self.what, field)),
+        }
+    }
+
+    fn list(&self, field: &str) -> Result<Option<&'a [Item]>, String> {
+        match self.values.get(field) {
+            None => Ok(None),
+            Some(Value::List(items)) => Ok(Some(items)),
+            Some(_) => Err(format!("{}: {} must be a list", self.what, field)),
+        }
+    }
+
+    // Fixed size array of numbers, missing trailing elements are zero as in C
+    fn words<const N: usize>(&self, field: &str) -> Result<[u32; N], String> {
+        let mut words = [0u32; N];
+        let items = self.list(field)?.unwrap_or(&[]);
+        if items.len() > N {
+            return Err(format!("{}: {} has more than {} elements", self.what, field, N));
+        }
+        for (word, item) in words.iter_mut().zip(items) {
+            *word = match (&item.field, &item.value) {
+                (None, Value::Int(v)) => u32::try_from(*v)
+                    .map_err(|_| format!("{}: {} element 0x{:x} out of range", self.what, field, v))?,
+                _ => return Err(format!("{}: {} must be a list of numbers", self.what, field)),
+            };
+        }
+        Ok(words)
+    }
+}
+
+fn element_fields<'a>(what: &'static str, item: &'a Item, allowed: &[&str]) -> Result<Fields<'a>, String> {
+    match &item.value {
+        Value::List(items) => Fields::new(what, items, allowed, &[]),
+        _ => Err(format!("{}: expected an initializer in braces", what)),
+    }
+}
+
+fn mem_region(item: &Item) -> Result<MemRegion, String> {
+    let f = element_fields("mem_regions", item, &["phys_start", "virt_start", "size", "flags", "colors"])?;
+    Ok(MemRegion {
+        comment: item.comment.clone(),
+        phys_start: f.int("phys_start")?.unwrap_or(0),
+        virt_start: f.int("virt_start")?.unwrap_or(0),
+        size: f.int("size")?.unwrap_or(0),
+        flags: f.int("flags")?.unwrap_or(0),
+        colors: f.int("colors")?.unwrap_or(0),
+    })
+}
+
+fn irqchip(item: &Item) -> Result<IrqChip, String> {
+    let f = element_fields("irqchips", item, &["address", "id", "pin_base", "pin_bitmap"])?;
+    Ok(IrqChip {
+        comment: item.comment.clone(),
+        address: f.int("address")?.unwrap_or(0),
+        id: f.int("id")?.unwrap_or(0),
+        pin_base: f.int("pin_base")?.unwrap_or(0),
+        pin_bitmap: f.words("pin_bitmap")?,
+    })
+}
+
+fn pio_region(item: &Item) -> Result<PioRegion, String> {
+    let f = element_fields("pio_regions", item, &["base", "length"])?;
+    Ok(PioRegion {
+        comment: item.comment.clone(),
+        base: f.int("base")?.unwrap_or(0),
+        length: f.int("length")?.unwrap_or(0),
+    })
+}
+
+fn pci_device(item: &Item) -> Result<PciDevice, String> {
+    let f = element_fields("pci_devices", item, &[
+        "type", "iommu", "domain", "bdf", "bar_mask", "caps_start", "num_caps", "num_msi_vectors",
+        "msi_64bits", "msi_maskable", "num_msix_vectors", "msix_region_size", "msix_address",
+        "shmem_regions_start", "shmem_dev_id", "shmem_peers", "shmem_protocol",
+    ])?;
+    Ok(PciDevice {
+        comment: item.comment.clone(),
+        dev_type: f.int("type")?.unwrap_or(0),
+        iommu: f.int("iommu")?.unwrap_or(0),
+        domain: f.int("domain")?.unwrap_or(0),
+        bdf: f.int("bdf")?.unwrap_or(0),
+        bar_mask: f.words("bar_mask")?,
+        caps_start: f.int("caps_start")?.unwrap_or(0),
+        num_caps: f.int("num_caps")?.unwrap_or(0),
+        num_msi_vectors: f.int("num_msi_vectors")?.unwrap_or(0),
+        msi_64bits: f.int::<u8>("msi_64bits")?.unwrap_or(0) != 0,
+        msi_maskable: f.int::<u8>("msi_maskable")?.unwrap_or(0) != 0,
+        num_msix_vectors: f.int("num_msix_vectors")?.unwrap_or(0),
+        msix_region_size: f.int("msix_region_size")?.unwrap_or(0),
+        msix_address: f.int("msix_address")?.unwrap_or(0),
+        shmem_regions_start: f.int("shmem_regions_start")?.unwrap_or(0),
+        shmem_dev_id: f.int("shmem_dev_id")?.unwrap_or(0),
+        shmem_peers: f.int("shmem_peers")?.unwrap_or(0),
+        shmem_protocol: f.int("shmem_protocol")?.unwrap_or(0),
+    })
+}
+
+// union jailhouse_stream_id, either as `.id` or as the `.mmu500` pair
+fn stream_id(item: &Item) -> Result<u32, String> {
+    let f = element_fields("stream_ids", item, &["id", "mmu500.id", "mmu500.mask_out"])?;
+    if let Some(id) = f.int::<u32>("id")? {
+        return Ok(id);
+    }
+    let id: u16 = f.int("mmu500.id")?.unwrap_or(0);
+    let mask_out: u16 = f.int("mmu500.mask_out")?.unwrap_or(0);
+    Ok(id as u32 | (mask_out as u32) << 16)
+}
@@ -0,0 +1,552 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Parser for the subset of C initializers used by the cell templates.
+// Templates are written as in the Jailhouse cell configs (designated initializers, JAILHOUSE_*
+// constants, shifts and ors, JAILHOUSE_SHMEM_NET_REGIONS, PIO_RANGE), so that snippets can be copied from
+// the root cell config of the board, plus IRQ_PINS to fill in the pin_bitmap of the irqchips.
+// They are parsed and evaluated here, and then turned into the typed cell description by celldesc.
+
+use crate::configGenerator::celldesc::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(u64),
+    Str(String),
+    List(Vec<Item>),
+}
+
+// One element of an initializer list, e.g. `/* RAM */ { ... }` or `.size = 0x1000`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    // Designator, e.g. "phys_start" for `.phys_start = ...`
+    pub field: Option<String>,
+    // C comment preceding the element or opening its braces, used as label when rendering
+    pub comment: Option<String>,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(u64),
+    Ident(String),
+    Str(String),
+    Comment(String),
+    Shl,
+    Shr,
+    Punct(char),
+}
+
+// Parses a sequence of comma separated initializer elements, as found between the braces of a
+// C initializer
+pub fn parse(text: &str) -> Result<Vec<Item>, String> {
+    let mut parser = Parser { toks: tokenize(text)?, pos: 0 };
+    let items = parser.parse_items()?;
+    parser.skip_comments();
+    if let Some(tok) = parser.toks.get(parser.pos) {
+        return Err(format!("unexpected {:?}", tok));
+    }
+    Ok(items)
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut toks = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            let start = i + 2;
+            let mut end = start;
+            while end + 1 < chars.len() && !(chars[end] == '*' && chars[end + 1] == '/') {
+                end += 1;
+            }
+            if end + 1 >= chars.len() {
+                return Err("unterminated comment".to_string());
+            }
+            toks.push(Token::Comment(chars[start..end].iter().collect::<String>().trim().to_string()));
+            i = end + 2;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            let start = i + 2;
+            let mut end = start;
+            while end < chars.len() && chars[end] != '\n' {
+                end += 1;
+            }
+            toks.push(Token::Comment(chars[start..end].iter().collect::<String>().trim().to_string()));
+            i = end;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let literal: String = chars[start..i].iter().collect();
+            toks.push(Token::Int(parse_int(&literal)?));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            toks.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '"' {
+            let start = i + 1;
+            let mut end = start;
+            while end < chars.len() && chars[end] != '"' {
+                end += 1;
+            }
+            if end >= chars.len() {
+                return Err("unterminated string".to_string());
+            }
+            toks.push(Token::Str(chars[start..end].iter().collect()));
+            i = end + 1;
+        } else if c == '<' && chars.get(i + 1) == Some(&'<') {
+            toks.push(Token::Shl);
+            i += 2;
+        } else if c == '>' && chars.get(i + 1) == Some(&'>') {
+            toks.push(Token::Shr);
+            i += 2;
+        } else if "{}(),.=|&^+-*/%~".contains(c) {
+            toks.push(Token::Punct(c));
+            i += 1;
+        } else {
+            return Err(format!("unexpected character '{}'", c));
+        }
+    }
+    Ok(toks)
+}
+
+// C integer literal: hex, octal or decimal, with optional U/L suffixes
+fn parse_int(literal: &str) -> Result<u64, String> {
+    let digits = literal.trim_end_matches(['u', 'U', 'l', 'L']);
+    let parsed = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        u64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse::<u64>()
+    };
+    parsed.map_err(|_| format!("invalid number '{}'", literal))
+}
+
+// Value of the Jailhouse constants allowed in the templates
+fn constant(name: &str) -> Option<Value> {
+    let int = |v: u64| Some(Value::Int(v));
+    let mask = |m: &[u32; 6]| {
+        let items = m
+            .iter()
+            .map(|v| Item { field: None, comment: None, value: Value::Int(*v as u64) })
+            .collect();
+        Some(Value::List(items))
+    };
+    if let Some((value, _)) = MEM_FLAG_NAMES
+        .iter()
+        .chain(CELL_FLAG_NAMES)
+        .chain(CON_FLAG_NAMES)
+        .chain(CON_TYPE_NAMES)
+        .chain(PCI_TYPE_NAMES)
+        .chain(SHMEM_PROTO_NAMES)
+        .chain(ARCHITECTURE_NAMES)
+        .chain(TCM_FLAG_NAMES)
+        .find(|(_, n)| *n == name)
+    {
+        return int(*value);
+    }
+    match name {
+        "JAILHOUSE_MEM_IO_WIDTH_SHIFT" => int(16),
+        "JAILHOUSE_CON_ACCESS_PIO" | "JAILHOUSE_CON_REGDIST_1" | "JAILHOUSE_CON_FB_1024x768" => int(0),
+        "JAILHOUSE_SHMEM_PROTO_VIRTIO_FRONT" => int(0x8000),
+        "JAILHOUSE_SHMEM_PROTO_VIRTIO_BACK" => int(0xc000),
+        "JAILHOUSE_SHMEM_PROTO_CUSTOM" => int(0x4000),
+        "VIRTIO_DEV_NET" => int(1),
+        "VIRTIO_DEV_BLOCK" => int(2),
+        "VIRTIO_DEV_CONSOLE" => int(3),
+        _ => BAR_MASK_NAMES.iter().find(|(_, n)| *n == name).and_then(|(m, _)| mask(m)),
+    }
+}
+
+fn int_item(field: &str, value: u64) -> Item {
+    Item { field: Some(field.to_string()), comment: None, value: Value::Int(value) }
+}
+
+fn region_item(comment: Option<String>, start: u64, size: u64, flags: u64) -> Item {
+    let fields = vec![
+        int_item("phys_start", start),
+        int_item("virt_start", start),
+        int_item("size", size),
+        int_item("flags", flags),
+    ];
+    Item { field: None, comment, value: Value::List(fields) }
+}
+
+// JAILHOUSE_SHMEM_NET_REGIONS(start, dev_id) as defined in cell-config.h: state table, no
+// read/write region and one output region per peer
+fn shmem_net_regions(comment: Option<String>, args: &[u64]) -> Result<Vec<Item>, String> {
+    let [start, dev_id] = args else {
+        return Err("JAILHOUSE_SHMEM_NET_REGIONS takes 2 arguments".to_string());
+    };
+    let rw = |peer: u64| if *dev_id == peer { JAILHOUSE_MEM_WRITE } else { 0 };
+    Ok(vec![
+        region_item(comment, *start, 0x1000, JAILHOUSE_MEM_READ | JAILHOUSE_MEM_ROOTSHARED),
+        Item { field: None, comment: None, value: Value::List(vec![Item { field: None, comment: None, value: Value::Int(0) }]) },
+        region_item(None, start + 0x1000, 0x7f000, JAILHOUSE_MEM_READ | JAILHOUSE_MEM_ROOTSHARED | rw(0)),
+        region_item(None, start + 0x80000, 0x7f000, JAILHOUSE_MEM_READ | JAILHOUSE_MEM_ROOTSHARED | rw(1)),
+    ])
+}
+
+// Macros of cell-config.h, expanding to the elements of a list
+type Macro = fn(Option<String>, &[u64]) -> Result<Vec<Item>, String>;
+
+// PIO_RANGE(base, length) as defined in cell-config.h
+fn pio_range(comment: Option<String>, args: &[u64]) -> Result<Vec<Item>, String> {
+    let [base, length] = args else {
+        return Err("PIO_RANGE takes 2 arguments".to_string());
+    };
+    let fields = vec![int_item("base", *base), int_item("length", *length)];
+    Ok(vec![Item { field: None, comment, value: Value::List(fields) }])
+}
+
+// IRQ_PINS(pin_base, pin, ...), not part of cell-config.h: the words of the pin_bitmap of an
+// irqchip routing the given pins, each placed in its word whatever the pin_base
+fn irq_pins(_comment: Option<String>, args: &[u64]) -> Result<Vec<Item>, String> {
+    let [pin_base, pins @ ..] = args else {
+        return Err("IRQ_PINS takes the pin_base and the pins".to_string());
+    };
+    let mut words = [0u64; 4];
+    for pin in pins {
+        let bit = pin.checked_sub(*pin_base).filter(|bit| *bit < 32 * words.len() as u64)
+            .ok_or_else(|| format!("IRQ_PINS: pin {} out of the irqchip, from pin {} to {}", pin, pin_base, pin_base + 127))?;
+        words[(bit / 32) as usize] |= 1 << (bit % 32);
+    }
+    Ok(words.iter().map(|word| Item { field: None, comment: None, value: Value::Int(*word) }).collect())
+}
+
+struct Parser {
+    toks: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    // Collects the comments at the current position, returning the first one
+    fn take_comments(&mut self) -> Option<String> {
+        let mut first = None;
+        while let Some(Token::Comment(text)) = self.toks.get(self.pos) {
+            if first.is_none() && !text.is_empty() {
+                first = Some(text.clone());
+            }
+            self.pos += 1;
+        }
+        first
+    }
+
+    fn skip_comments(&mut self) {
+        let _ = self.take_comments();
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        self.skip_comments();
+        self.toks.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.skip_comments();
+        let tok = self.toks.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Punct(p)) if p == c => Ok(()),
+            Some(tok) => Err(format!("expected '{}', found {:?}", c, tok)),
+            None => Err(format!("expected '{}', found end of template", c)),
+        }
+    }
+
+    fn at(&mut self, c: char) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == c)
+    }
+
+    fn parse_items(&mut self) -> Result<Vec<Item>, String> {
+        let mut items = Vec::new();
+        loop {
+            let comment = self.take_comments();
+            if self.peek().is_none() || self.at('}') {
+                break;
+            }
+            let mut field = None;
+            if self.at('.') {
+                let mut path = Vec::new();
+                while self.at('.') {
+                    self.pos += 1;
+                    match self.next() {
+                        Some(Token::Ident(name)) => path.push(name),
+                        tok => return Err(format!("expected field name, found {:?}", tok)),
+                    }
+                }
+                self.expect('=')?;
+                field = Some(path.join("."));
+            }
+            // Macros expanding to several elements
+            if let (Some(Token::Ident(name)), Some(Token::Punct('('))) =
+                (self.peek().cloned(), self.toks.get(self.pos + 1))
+            {
+                let expand = match name.as_str() {
+                    "JAILHOUSE_SHMEM_NET_REGIONS" => Some(shmem_net_regions as Macro),
+                    "PIO_RANGE" => Some(pio_range as Macro),
+                    "IRQ_PINS" => Some(irq_pins as Macro),
+                    _ => None,
+                };
+                if let Some(expand) = expand {
+                    self.pos += 2;
+                    // Empty arguments, left by placeholders without a value, are skipped
+                    let mut args = Vec::new();
+                    loop {
+                        if !self.at(',') && !self.at(')') {
+                            args.push(self.parse_expr()?);
+                        }
+                        if !self.at(',') {
+                            break;
+                        }
+                        self.pos += 1;
+                    }
+                    self.expect(')')?;
+                    items.extend(expand(comment, &args)?);
+                    if !self.at(',') {
+                        break;
+                    }
+                    self.pos += 1;
+                    continue;
+                }
+            }
+            let (value, label) = self.parse_value()?;
+            items.push(Item { field, comment: comment.or(label), value });
+            if !self.at(',') {
+                break;
+            }
+            self.pos += 1;
+        }
+        Ok(items)
+    }
+
+    // Returns the value and the comment found right after its opening brace, if any
+    fn parse_value(&mut self) -> Result<(Value, Option<String>), String> {
+        match self.peek().cloned() {
+            Some(Token::Punct('{')) => {
+                self.pos += 1;
+                // A comment opening a list of elements labels its first element, not the list
+                let start = self.pos;
+                let mut label = self.take_comments();
+                if matches!(self.toks.get(self.pos), Some(Token::Punct('{')) | Some(Token::Ident(_))) {
+                    self.pos = start;
+                    label = None;
+                }
+                let items = self.parse_items()?;
+                self.expect('}')?;
+                Ok((Value::List(items), label))
+            }
+            Some(Token::Str(s)) => {
+                self.pos += 1;
+                Ok((Value::Str(s), None))
+            }
+            Some(Token::Ident(name)) => {
+                // List constants, e.g. JAILHOUSE_IVSHMEM_BAR_MASK_INTX, cannot be part of expressions
+                if let Some(Value::List(items)) = constant(&name) {
+                    self.pos += 1;
+                    return Ok((Value::List(items), None));
+                }
+                Ok((Value::Int(self.parse_expr()?), None))
+            }
+            _ => Ok((Value::Int(self.parse_expr()?), None)),
+        }
+    }
+
+    // Binary operators by increasing C precedence
+    fn parse_expr(&mut self) -> Result<u64, String> {
+        self.parse_binary(0)
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<u64, String> {
+        const LEVELS: [&[&str]; 5] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"]];
+        if level == LEVELS.len() {
+            return self.parse_mul();
+        }
+        let mut lhs = self.parse_binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Shl) => "<<",
+                Some(Token::Shr) => ">>",
+                Some(Token::Punct(c)) => match c {
+                    '|' => "|",
+                    '^' => "^",
+                    '&' => "&",
+                    '+' => "+",
+                    '-' => "-",
+                    _ => break,
+                },
+                _ => break,
+            };
+            if !LEVELS[level].contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = match op {
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "<<" => lhs.checked_shl(rhs as u32).ok_or("shift out of range")?,
+                ">>" => lhs.checked_shr(rhs as u32).ok_or("shift out of range")?,
+                "+" => lhs.checked_add(rhs).ok_or("overflow in expression")?,
+                _ => lhs.checked_sub(rhs).ok_or("negative value in expression")?,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_mul(&mut self) -> Result<u64, String> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(Token::Punct(op @ ('*' | '/' | '%'))) = self.peek().cloned() {
+            self.pos += 1;
+            let rhs = self.parse_unary()?;
+            lhs = match op {
+                '*' => lhs.checked_mul(rhs).ok_or("overflow in expression")?,
+                '/' => lhs.checked_div(rhs).ok_or("division by zero")?,
+                _ => lhs.checked_rem(rhs).ok_or("division by zero")?,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<u64, String> {
+        match self.next() {
+            Some(Token::Int(v)) => Ok(v),
+            Some(Token::Punct('~')) => Ok(!self.parse_unary()?),
+            Some(Token::Punct('(')) => {
+                let v = self.parse_expr()?;
+                self.expect(')')?;
+                Ok(v)
+            }
+            Some(Token::Ident(name)) => match constant(&name) {
+                Some(Value::Int(v)) => Ok(v),
+                Some(_) => Err(format!("{} cannot be used in an expression", name)),
+                None => Err(format!("unknown identifier '{}'", name)),
+            },
+            Some(tok) => Err(format!("unexpected {:?}", tok)),
+            None => Err("unexpected end of template".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(text: &str) -> u64 {
+        match parse(text).unwrap().as_slice() {
+            [Item { value: Value::Int(v), .. }] => *v,
+            items => panic!("{} is not a number: {:?}", text, items),
+        }
+    }
+
+    fn ints(items: &[Item]) -> Vec<u64> {
+        items.iter().map(|item| match item.value {
+            Value::Int(v) => v,
+            _ => panic!("{:?} is not a number", item),
+        }).collect()
+    }
+
+    fn fields(item: &Item) -> Vec<(&str, u64)> {
+        let Value::List(fields) = &item.value else { panic!("{:?} is not a list", item) };
+        fields.iter().map(|f| match f.value {
+            Value::Int(v) => (f.field.as_deref().unwrap(), v),
+            _ => panic!("{:?} is not a number", f),
+        }).collect()
+    }
+
+    #[test]
+    fn expressions_follow_the_c_precedence() {
+        assert_eq!(int("1 | 2 & 3"), 3);
+        assert_eq!(int("1 << 2 + 1"), 8);
+        assert_eq!(int("2 + 3 * 4"), 14);
+        assert_eq!(int("(2 + 3) * 4"), 20);
+        assert_eq!(int("0xf0 ^ 0xff & 0x0f"), 0xff);
+        assert_eq!(int("10 - 4 - 3"), 3);
+        assert_eq!(int("~0 >> 60"), 0xf);
+        assert_eq!(int("JAILHOUSE_MEM_READ | JAILHOUSE_MEM_WRITE"), JAILHOUSE_MEM_READ | JAILHOUSE_MEM_WRITE);
+        assert_eq!(int("010 + 0x10 + 10UL"), 34);
+    }
+
+    #[test]
+    fn designated_initializers_and_comments() {
+        let items = parse("/* RAM */ { .phys_start = 0x1000, .size = 0x2000, }, .cell.name = \"test\"").unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].comment.as_deref(), Some("RAM"));
+        assert_eq!(fields(&items[0]), vec![("phys_start", 0x1000), ("size", 0x2000)]);
+        assert_eq!(items[1].field.as_deref(), Some("cell.name"));
+        assert_eq!(items[1].value, Value::Str("test".to_string()));
+    }
+
+    #[test]
+    fn shmem_net_regions_expands_to_four_regions() {
+        let items = parse("/* IVSHMEM */ JAILHOUSE_SHMEM_NET_REGIONS(0x7f900000, 1),").unwrap();
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[0].comment.as_deref(), Some("IVSHMEM"));
+        let shared = JAILHOUSE_MEM_READ | JAILHOUSE_MEM_ROOTSHARED;
+        assert_eq!(fields(&items[0]), vec![("phys_start", 0x7f900000), ("virt_start", 0x7f900000), ("size", 0x1000), ("flags", shared)]);
+        assert_eq!(items[1].value, Value::List(vec![Item { field: None, comment: None, value: Value::Int(0) }]));
+        assert_eq!(fields(&items[2]), vec![("phys_start", 0x7f901000), ("virt_start", 0x7f901000), ("size", 0x7f000), ("flags", shared)]);
+        assert_eq!(fields(&items[3]), vec![("phys_start", 0x7f980000), ("virt_start", 0x7f980000), ("size", 0x7f000), ("flags", shared | JAILHOUSE_MEM_WRITE)]);
+    }
+
+    #[test]
+    fn pio_range_expands_to_a_region() {
+        let items = parse("PIO_RANGE(0x3f8, 8), PIO_RANGE(0x40 + 0x2, 1)").unwrap();
+        assert_eq!(fields(&items[0]), vec![("base", 0x3f8), ("length", 8)]);
+        assert_eq!(fields(&items[1]), vec![("base", 0x42), ("length", 1)]);
+    }
+
+    #[test]
+    fn irq_pins_places_each_pin_in_its_word() {
+        assert_eq!(ints(&parse("IRQ_PINS(32, 33, 140, 141)").unwrap()), vec![1 << 1, 0, 0, (1 << 12) | (1 << 13)]);
+        assert_eq!(ints(&parse("IRQ_PINS(0, 4)").unwrap()), vec![1 << 4, 0, 0, 0]);
+        // Empty arguments are left by placeholders without a value
+        assert_eq!(ints(&parse("IRQ_PINS(32, , 140, )").unwrap()), vec![0, 0, 0, 1 << 12]);
+        assert_eq!(ints(&parse("IRQ_PINS(32, )").unwrap()), vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn macros_reject_wrong_arguments() {
+        assert!(parse("JAILHOUSE_SHMEM_NET_REGIONS(0x1000)").is_err());
+        assert!(parse("PIO_RANGE(0x3f8, 8, 1)").is_err());
+        assert!(parse("IRQ_PINS()").is_err());
+        assert!(parse("IRQ_PINS(32, 31)").is_err());
+        assert!(parse("IRQ_PINS(32, 160)").is_err());
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        for text in [
+            "{ .size = 0x1000",
+            ".size 0x1000",
+            ". = 1",
+            "/* unterminated",
+            "\"unterminated",
+            "0x",
+            "09",
+            "1 +",
+            "(1 + 2",
+            "UNKNOWN_CONSTANT",
+            "JAILHOUSE_IVSHMEM_BAR_MASK_INTX | 1",
+            "1 / 0",
+            "1 - 2",
+            "1 << 64",
+            "0xffffffffffffffff + 1",
+            "1 } 2",
+            "$",
+        ] {
+            assert!(parse(text).is_err(), "{} accepted", text);
+        }
+    }
+}
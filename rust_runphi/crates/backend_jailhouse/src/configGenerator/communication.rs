@@ -1,32 +1,122 @@
 //*********************************************
 // Authors: Marco Barletta (marco.barletta@unina.it)
 //*********************************************
-//DEAD FOR NOW COMMUNICATION WAS INCLUDED IN PREAMBLE TEMPLATE
-use regex::Regex;
+
+// Console of the cell, chosen per container among the UART of the board, the virtual console of
+// the hypervisor and none. The UART is described in the [console] table of platform_info.toml,
+// whose values are C expressions as in the templates, e.g.
+//   [console]
+//   type = "JAILHOUSE_CON_TYPE_PL011"
+//   address = "0x09000000"
+//   size = "0x1000"
+//   flags = "JAILHOUSE_CON_ACCESS_MMIO | JAILHOUSE_CON_REGDIST_4"
+// Platforms without [console] keep using the console of their preamble, if any, as UART.
+// The UART is given to one cell at a time, its owner is recorded in state.toml.
 use std::error::Error;
+use toml::Value;
 
 use crate::configGenerator;
-//use f2b;
-
-pub fn communicationconfig(c: &mut configGenerator::Backendconfig) -> Result<(), Box<dyn Error>> {
-    // Insert line into the config file
-    let pattern = r"cell = \{";
-    //let linetoinsert = "\t.console = {.address = 0x09000000,.type = JAILHOUSE_CON_TYPE_PL011,.flags = JAILHOUSE_CON_ACCESS_MMIO | JAILHOUSE_CON_REGDIST_4,	},";
-    let linetoinsert = "\
-    .console = {\n\
-        \t.address = 0x09000000,\n\
-        \t.type = JAILHOUSE_CON_TYPE_PL011,\n\
-        \t.flags = JAILHOUSE_CON_ACCESS_MMIO |\n\
-        \t\t JAILHOUSE_CON_REGDIST_4,\n\
-    },";
-    // Compile a regular expression to match the pattern and insert the cpus
-    let re = Regex::new(&pattern)?;
-    if let Some(pos) = re.find(&c.conf) {
-        c.conf
-            .insert_str(pos.end(), &format!("\n{}\n", linetoinsert));
-    } else {
-        return Err("\"cell = {\" not found".into());
+use crate::configGenerator::celldesc::*;
+use crate::statefile;
+use f2b::ConsoleKind;
+
+const CONSOLE_FIELDS: [&str; 7] = ["address", "size", "type", "flags", "divider", "gate_nr", "clock_reg"];
+
+// The UART described by the [console] table of the platform, if any
+pub fn platform_console(platform: &Value) -> Result<Option<Console>, String> {
+    let Some(section) = platform.get("console") else { return Ok(None) };
+    let table = section.as_table().ok_or("[console] must be a table")?;
+    for field in ["type", "address"] {
+        if !table.contains_key(field) {
+            return Err(format!("[console] {} missing", field));
+        }
+    }
+    let mut snippet = String::from(".cell = { .console = {\n");
+    for (key, value) in table {
+        if !CONSOLE_FIELDS.contains(&key.as_str()) {
+            return Err(format!("[console] unknown field {}, expected one of {}", key, CONSOLE_FIELDS.join(", ")));
+        }
+        let value = match value {
+            Value::String(s) => s.clone(),
+            Value::Integer(i) => i.to_string(),
+            other => return Err(format!("[console] {} must be a string or an integer, found {}", key, other)),
+        };
+        snippet.push_str(&format!("\t.{} = {},\n", key, value));
+    }
+    snippet.push_str("}, },\n");
+
+    let mut desc = CellDesc::default();
+    desc.apply_preamble(&snippet).map_err(|e| format!("[console] {}", e))?;
+    Ok(Some(desc.header.console))
+}
+
+// The container owning the UART, as recorded in its section of state.toml
+pub fn uart_owner(state: &Value) -> Option<String> {
+    state.as_table()?.iter()
+        .find(|(_, section)| section.get("console").and_then(|c| c.as_str()) == Some(ConsoleKind::Uart.as_str()))
+        .map(|(id, _)| id.clone())
+}
+
+pub fn communicationconfig(
+    c: &mut configGenerator::Backendconfig,
+    platform: &Value,
+    choice: Option<ConsoleKind>,
+) -> Result<(), Box<dyn Error>> {
+    // The UART of the board, from the platform or left by the preamble
+    c.uart = match platform_console(platform)? {
+        Some(console) => Some(console),
+        None if c.desc.header.console != Console::default() => Some(c.desc.header.console.clone()),
+        None => None,
+    };
+
+    let state: Value = statefile::read()?.parse()?;
+    let owner = uart_owner(&state);
+    c.console = match (choice, &c.uart, owner) {
+        (Some(ConsoleKind::Uart), None, _) => {
+            return Err("the console \"uart\" was asked for, but the platform has no [console]".into());
+        }
+        (Some(ConsoleKind::Uart), Some(_), Some(owner)) => {
+            return Err(format!("the console \"uart\" was asked for, but the UART is used by container {}", owner).into());
+        }
+        (Some(kind), _, _) => kind,
+        // By default the UART, unless missing or taken
+        (None, Some(_), None) => ConsoleKind::Uart,
+        (None, _, _) => ConsoleKind::Virtual,
+    };
+
+    let virtual_console = (JAILHOUSE_CELL_VIRTUAL_CONSOLE_PERMITTED | JAILHOUSE_CELL_VIRTUAL_CONSOLE_ACTIVE) as u32;
+    let h = &mut c.desc.header;
+    match c.console {
+        ConsoleKind::Uart => h.console = c.uart.clone().unwrap_or_default(),
+        ConsoleKind::Virtual => {
+            h.console = Console::default();
+            h.flags |= virtual_console;
+        }
+        ConsoleKind::None => {
+            h.console = Console::default();
+            h.flags &= !virtual_console;
+        }
     }
 
     Ok(())
 }
+
+// Whether a memory region maps the UART of the board, which is left out of the cells not owning it
+pub fn maps_uart(c: &configGenerator::Backendconfig, region: &MemRegion) -> bool {
+    match &c.uart {
+        Some(uart) if c.console != ConsoleKind::Uart && uart.flags as u64 & JAILHOUSE_CON_ACCESS_MMIO != 0 => {
+            region.phys_start <= uart.address && uart.address < region.phys_start.saturating_add(region.size)
+        }
+        _ => false,
+    }
+}
+
+// Removes the I/O ports of the UART (x86) from a cell not owning it
+pub fn release_uart_ports(c: &mut configGenerator::Backendconfig) {
+    let Some(uart) = &c.uart else { return };
+    if c.console == ConsoleKind::Uart || uart.flags as u64 & JAILHOUSE_CON_ACCESS_MMIO != 0 {
+        return;
+    }
+    let port = uart.address;
+    c.desc.pio_regions.retain(|r| !(r.base as u64 <= port && port < r.base as u64 + r.length as u64));
+}
@@ -3,75 +3,292 @@
 //          Francesco Boccola (francesco.boccola@unina.it)
 //*********************************************
 
-//use regex::Regex;
+// Allocation of the CPUs of the cells. The free CPUs are the ones still assigned to the root cell,
+// minus the ones the platform reserves to it and the ones already given to other containers in
+// the state. The [cpus] section of platform_info.toml is optional:
+//   [cpus]
+//   reserved = "0"              CPUs never given to the cells, cpulist syntax
+//   clusters = ["0-3", "4-5"]   CPUs sharing a cluster (or NUMA node), a cell is kept in one if possible
+// Without `reserved` the root cell keeps its last CPU.
+
 use std::error::Error;
-//use std::fs::{self, File, OpenOptions};
-use std::fs::File;
-//use std::io::{Read, Seek, SeekFrom, Write};
-use std::io::Read;
-//use std::process::{Command, Stdio};
+use std::fs;
+use std::path::Path;
+use toml::Value;
 
 use crate::configGenerator;
+use crate::configGenerator::celldesc;
+use crate::statefile;
 use f2b;
 
-//const WORKPATH: &str = "/usr/share/runPHI";
-//const RUNDIR: &str = "/run/runPHI";
+const WORKPATH: &str = "/usr/share/runPHI";
+const CONFIG_FILE: &str = "platform_info.toml";
+const ROOT_CPUS_FILE: &str = "/sys/devices/jailhouse/cells/0/cpus_assigned_list";
+
+// Parses a list of CPUs in the Linux cpulist syntax, e.g. "0,2-3" or "0-7:2"
+pub fn parse_cpulist(text: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for part in text.trim().split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let invalid = || format!("invalid cpu list \"{}\"", text.trim());
+        let (range, stride) = match part.split_once(':') {
+            Some((range, stride)) => (range, stride.parse::<usize>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.parse::<usize>().map_err(|_| invalid())?, end.parse::<usize>().map_err(|_| invalid())?),
+            None => {
+                let cpu = range.parse::<usize>().map_err(|_| invalid())?;
+                (cpu, cpu)
+            }
+        };
+        if start > end || stride == 0 {
+            return Err(invalid());
+        }
+        cpus.extend((start..=end).step_by(stride));
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+// Formats a list of CPUs in the cpulist syntax, e.g. "0,2-3"
+pub fn format_cpulist(cpus: &[usize]) -> String {
+    let mut sorted = cpus.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut parts: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < sorted.len() {
+        let start = sorted[i];
+        while i + 1 < sorted.len() && sorted[i + 1] == sorted[i] + 1 {
+            i += 1;
+        }
+        if sorted[i] == start {
+            parts.push(start.to_string());
+        } else {
+            parts.push(format!("{}-{}", start, sorted[i]));
+        }
+        i += 1;
+    }
+    parts.join(",")
+}
+
+// The reserved CPUs, if given, and the clusters
+pub type PlatformCpus = (Option<Vec<usize>>, Vec<Vec<usize>>);
+
+// The [cpus] section of the platform
+pub fn platform_cpus(platform: &Value) -> Result<PlatformCpus, String> {
+    let Some(section) = platform.get("cpus") else { return Ok((None, Vec::new())) };
+    let reserved = match section.get("reserved") {
+        Some(Value::String(list)) => Some(parse_cpulist(list).map_err(|e| format!("[cpus] reserved: {}", e))?),
+        Some(_) => return Err("[cpus] reserved must be a cpu list string".to_string()),
+        None => None,
+    };
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    match section.get("clusters") {
+        Some(Value::Array(list)) => {
+            for (i, cluster) in list.iter().enumerate() {
+                let cluster = cluster.as_str().ok_or(format!("[cpus] clusters[{}] must be a cpu list string", i))?;
+                let cpus = parse_cpulist(cluster).map_err(|e| format!("[cpus] clusters[{}]: {}", i, e))?;
+                if let Some(cpu) = cpus.iter().find(|cpu| clusters.iter().any(|other| other.contains(cpu))) {
+                    return Err(format!("[cpus] clusters[{}]: CPU {} is in another cluster", i, cpu));
+                }
+                clusters.push(cpus);
+            }
+        }
+        Some(_) => return Err("[cpus] clusters must be an array of cpu lists".to_string()),
+        None => {}
+    }
+    Ok((reserved, clusters))
+}
+
+// CPUs given to the containers, as recorded in their sections of the state
+fn assigned_cpus(state: &Value) -> Vec<usize> {
+    let Some(table) = state.as_table() else { return Vec::new() };
+    table.values()
+        .filter_map(|section| section.get("cpus").and_then(|c| c.as_str()))
+        .filter_map(|list| parse_cpulist(list).ok())
+        .flatten()
+        .collect()
+}
+
+// Chooses `count` CPUs among the free ones. The request is kept in a single cluster when possible,
+// the fullest one able to host it, so that the emptier clusters stay available for larger cells.
+// Otherwise the CPUs are taken cluster after cluster. `allowed` restricts the choice to some clusters
+pub fn allocate(free: &[usize], count: usize, clusters: &[Vec<usize>], allowed: Option<&[usize]>) -> Result<Vec<usize>, String> {
+    // CPUs outside of any cluster form a cluster of their own
+    let mut groups: Vec<Vec<usize>> = clusters.iter()
+        .map(|cluster| free.iter().copied().filter(|cpu| cluster.contains(cpu)).collect())
+        .collect();
+    groups.push(free.iter().copied().filter(|cpu| !clusters.iter().any(|cluster| cluster.contains(cpu))).collect());
+    let candidates: Vec<&Vec<usize>> = match allowed {
+        Some(allowed) => {
+            if let Some(index) = allowed.iter().find(|index| **index >= clusters.len()) {
+                return Err(format!("no CPU cluster {}, the platform has {}", index, clusters.len()));
+            }
+            allowed.iter().map(|index| &groups[*index]).collect()
+        }
+        None => groups.iter().collect(),
+    };
+
+    if let Some(group) = candidates.iter().filter(|group| group.len() >= count).min_by_key(|group| group.len()) {
+        return Ok(group[..count].to_vec());
+    }
+    let spread: Vec<usize> = candidates.iter().flat_map(|group| group.iter().copied()).take(count).collect();
+    if spread.len() < count {
+        return Err(format!("Not enough free CPU left: {} requested, {} free", count, spread.len()));
+    }
+    Ok(spread)
+}
+
+// The CPUs asked with the OCI cpuset, which must all be free
+fn take_cpuset(free: &[usize], cpuset: Vec<usize>) -> Result<Vec<usize>, String> {
+    match cpuset.iter().find(|cpu| !free.contains(cpu)) {
+        Some(cpu) => Err(format!("CPU {} of the cpuset {} is not free", cpu, format_cpulist(&cpuset))),
+        None => Ok(cpuset),
+    }
+}
+
+// The CPUs of a cell asking for the given number of CPUs, or for the OCI cpuset. A cell gets at
+// least one CPU, also when its quota is unlimited (-1) or rounds to 0
+fn select(
+    free: &[usize],
+    cpus: usize,
+    cpuset: Option<Vec<usize>>,
+    clusters: &[Vec<usize>],
+    allowed: Option<&[usize]>,
+) -> Result<Vec<usize>, String> {
+    match cpuset {
+        Some(cpuset) => take_cpuset(free, cpuset),
+        None => allocate(free, cpus.max(1), clusters, allowed),
+    }
+}
 
 pub fn cpuconf(
-    _fc: &f2b::FrontendConfig,
+    fc: &f2b::FrontendConfig,
     c: &mut configGenerator::Backendconfig,
+    ic: &f2b::ImageConfig,
     _quota: &f64,
     _period: &f64,
     cpusf64: &f64,
 ) -> Result<(), Box<dyn Error>> {
 
     let cpus: u8 = cpusf64.ceil() as u8; // Casting with ceil due to jh not supporting fraction of cpu allocation
-    c.cpus = cpus;
-
-    let mut file = File::open("/sys/devices/jailhouse/cells/0/cpus_assigned_list")?;
-    let mut output_str = String::new();
-    file.read_to_string(&mut output_str)?;
-
-    // Trim any trailing whitespace/newlines
-    let output_str = output_str.trim();
-
-    // Split the range by '-' and parse the numbers
-    let mut parts = output_str.split('-');
-    let start: usize = parts
-        .next()
-        .ok_or("Failed to parse start of CPU range")?
-        .parse()?;
-    let end: usize = parts
-        .next()
-        .ok_or("Failed to parse end of CPU range: just the root cell CPU left")?
-        .parse()?;
-
-    // Create the free_cpus vector excluding the last CPU in the range
-    let free_cpus: Vec<usize> = (start..end).collect();
-
-    // Ensure there are enough available CPUs
-    if free_cpus.len() < cpus as usize {
-        return Err("Not enough free CPU left".to_owned().into());
+
+    // The CPUs still in the root cell
+    let root_cpus = parse_cpulist(&fs::read_to_string(ROOT_CPUS_FILE)?)?;
+
+    let platform: Value = fs::read_to_string(Path::new(WORKPATH).join(CONFIG_FILE))?.parse()?;
+    let (reserved, clusters) = platform_cpus(&platform)?;
+    let reserved = match reserved {
+        Some(reserved) => reserved,
+        // The root cell keeps its last CPU
+        None => root_cpus.last().copied().into_iter().collect(),
+    };
+    let state: Value = statefile::read()?.parse()?;
+    let taken = assigned_cpus(&state);
+    let free: Vec<usize> = root_cpus.into_iter()
+        .filter(|cpu| !reserved.contains(cpu) && !taken.contains(cpu))
+        .collect();
+
+    // The CPUs asked with the OCI cpuset (e.g. --cpuset-cpus), if any, and the clusters asked with
+    // the image config or, as NUMA nodes, with the OCI cpuset.mems
+    let cpu_resources = &fc.jsonconfig["linux"]["resources"]["cpu"];
+    let cpuset = match cpu_resources["cpus"].as_str().filter(|s| !s.trim().is_empty()) {
+        Some(list) => Some(parse_cpulist(list)?),
+        None => None,
+    };
+    let allowed = match (ic.cpu_cluster, cpu_resources["mems"].as_str().filter(|s| !s.trim().is_empty())) {
+        (Some(cluster), _) => Some(vec![cluster as usize]),
+        (None, Some(mems)) if !clusters.is_empty() => Some(parse_cpulist(mems)?),
+        _ => None,
+    };
+
+    // RPU cells get no CPU
+    let cpusassigned: Vec<usize> = if c.rpu_req {
+        Vec::new()
+    } else {
+        select(&free, cpus as usize, cpuset, &clusters, allowed.as_deref())?
+    };
+    c.cpus = cpusassigned.len() as u8;
+
+    // Record the assigned CPUs in the cpu set of the cell, and in the state
+    c.desc.cpus = celldesc::cpu_set(&cpusassigned);
+    c.used_cpus = cpusassigned;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cpulist_ranges_and_strides() {
+        assert_eq!(parse_cpulist("0,2-3"), Ok(vec![0, 2, 3]));
+        assert_eq!(parse_cpulist("0-7:2"), Ok(vec![0, 2, 4, 6]));
+        assert_eq!(parse_cpulist("1-8:3,0"), Ok(vec![0, 1, 4, 7]));
+        assert_eq!(parse_cpulist(" 3, 1,1-2\n"), Ok(vec![1, 2, 3]));
+        assert_eq!(parse_cpulist(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn parse_cpulist_rejects_malformed_lists() {
+        for list in ["3-1", "0-4:0", "a", "1-", "-1", "0-3:x", "1:2:3"] {
+            assert!(parse_cpulist(list).is_err(), "{} accepted", list);
+        }
     }
 
-    // Assign the required number of CPUs
-    let cpusassigned: Vec<usize> = free_cpus[..cpus as usize].to_vec();
+    #[test]
+    fn format_cpulist_merges_ranges() {
+        assert_eq!(format_cpulist(&[0, 2, 3]), "0,2-3");
+        assert_eq!(format_cpulist(&[5, 3, 4, 4, 0]), "0,3-5");
+        assert_eq!(format_cpulist(&[]), "");
+        let list = "0,2-3,6-9";
+        assert_eq!(format_cpulist(&parse_cpulist(list).unwrap()), list);
+    }
 
-    // Append Cpuarray
-    // A binary string representation of the assigned CPUs is created.
-    //The binary string is formatted and appended to the configuration string (c.conf)
-    let mut cpus_bitmask: u64 = 0;
-    for &cpu in &cpusassigned {
-        cpus_bitmask |= 1 << cpu;
+    #[test]
+    fn allocate_takes_the_fullest_cluster_able_to_host_the_cell() {
+        let clusters = vec![vec![0, 1, 2, 3], vec![4, 5]];
+        let free = [1, 2, 3, 4, 5];
+        assert_eq!(allocate(&free, 2, &clusters, None), Ok(vec![4, 5]));
+        assert_eq!(allocate(&free, 3, &clusters, None), Ok(vec![1, 2, 3]));
+        // CPUs out of the clusters form one of their own
+        assert_eq!(allocate(&[1, 6, 7], 2, &[vec![0, 1]], None), Ok(vec![6, 7]));
     }
 
-    let hex_str = format!("{:x}", cpus_bitmask); //for cpus in 0x
+    #[test]
+    fn allocate_spreads_across_clusters() {
+        let clusters = vec![vec![0, 1, 2, 3], vec![4, 5]];
+        assert_eq!(allocate(&[1, 2, 3, 4, 5], 4, &clusters, None), Ok(vec![1, 2, 3, 4]));
+        assert!(allocate(&[1, 2, 3, 4, 5], 6, &clusters, None).is_err());
+    }
 
-    //let binary_str = format!("{:b}", cpus_bitmask); //for cpus in 0b
+    #[test]
+    fn allocate_in_the_allowed_clusters() {
+        let clusters = vec![vec![0, 1, 2, 3], vec![4, 5]];
+        let free = [1, 2, 3, 4, 5];
+        assert_eq!(allocate(&free, 2, &clusters, Some(&[0])), Ok(vec![1, 2]));
+        assert!(allocate(&free, 3, &clusters, Some(&[1])).is_err());
+        assert!(allocate(&free, 1, &clusters, Some(&[2])).is_err());
+    }
 
-    //c.conf.push_str(&format!("\n\t.cpus = {{\n\t\t0b{},\n\t}},\n", binary_str)); //for cpus in 0b
-    c.conf
-        .push_str(&format!("\n\t.cpus = {{\n\t\t0x{},\n\t}},\n", hex_str)); //for cpus in 0x
+    #[test]
+    fn select_gives_at_least_one_cpu() {
+        let clusters = vec![vec![0, 1, 2, 3]];
+        let free = [1, 2, 3];
+        // Unlimited quota (-1), or a quota rounding to 0
+        assert_eq!(select(&free, (-1e-4f64).ceil() as u8 as usize, None, &clusters, None), Ok(vec![1]));
+        assert_eq!(select(&free, 2, None, &clusters, None), Ok(vec![1, 2]));
+        // The cpuset wins over the quota, whatever it is
+        assert_eq!(select(&free, 0, Some(vec![2, 3]), &clusters, None), Ok(vec![2, 3]));
+        assert_eq!(select(&free, 1, Some(vec![1, 2, 3]), &clusters, None), Ok(vec![1, 2, 3]));
+    }
 
-    return Ok(());
+    #[test]
+    fn cpuset_must_be_free() {
+        assert_eq!(take_cpuset(&[1, 2, 3], vec![1, 3]), Ok(vec![1, 3]));
+        assert!(take_cpuset(&[1, 2, 3], vec![0, 1]).is_err());
+    }
 }
@@ -4,7 +4,6 @@
 //*********************************************
 
 use crate::configGenerator;
-use regex::Regex;
 //use std::collections::HashSet;
 //use std::collections::HashMap;
 
@@ -19,17 +18,41 @@ use crate::configGenerator::templates::*;
 const WORKPATH: &str = "/usr/share/runPHI";
 //const PCI_IVSHMEM_ID_FILE: &str = "pci_ivshmem_id.txt";
 //const STATEFILE: &str = "state.toml";
+// Interrupt of the first shared peripheral of the GIC, the INTx of the virtual PCI devices of a
+// cell are vpci_irq_base + SPI_BASE + (device number & 3)
+pub(crate) const SPI_BASE: u32 = 32;
+
+// Takes the pins for the INTx of the IVSHMEM devices at the given device numbers out of the free
+// pins of the node, and moves the vpci_irq_base of the cell so that the devices raise them. The
+// lowest base whose pins are all free is taken, devices sharing the two low bits share the pin
+pub(crate) fn allocate_irq_pins(c: &mut configGenerator::Backendconfig, bdfs: &[u32]) -> Result<Vec<u32>, Box<dyn Error>> {
+    let offsets: Vec<u32> = bdfs.iter().map(|bdf| SPI_BASE + (bdf & 3)).collect();
+    let free = c.irq_pins.as_mut().ok_or("No free interrupt pins in the state")?;
+    let base = free.iter()
+        .flat_map(|pin| offsets.iter().filter_map(move |offset| pin.checked_sub(*offset)))
+        .filter(|base| offsets.iter().all(|offset| free.contains(&(base + offset))))
+        .min()
+        .ok_or_else(|| format!("No free interrupt pin left for the IVSHMEM devices {:?}", bdfs))?;
+    let pins: Vec<u32> = offsets.iter().map(|offset| base + offset).collect();
+    for pin in &pins {
+        if let Some(index) = free.iter().position(|p| p == pin) {
+            free.remove(index);
+            c.used_irq_pins.push(*pin);
+        }
+    }
+    c.desc.header.vpci_irq_base = base;
+    Ok(pins)
+}
 
-pub fn devconfig(c: &mut configGenerator::Backendconfig) -> Result<(), Box<dyn Error>> {
+// Same, for a single device
+pub(crate) fn allocate_irq_pin(c: &mut configGenerator::Backendconfig, bdf: u32) -> Result<u32, Box<dyn Error>> {
+    Ok(allocate_irq_pins(c, &[bdf])?[0])
+}
 
-    // Insert line into the config file
-    let pattern = r"struct jailhouse_memory mem_regions\[\d+\];";
+pub fn devconfig(c: &mut configGenerator::Backendconfig) -> Result<(), Box<dyn Error>> {
 
-    let file_path = Path::new(WORKPATH).join(format!("platform_info.toml"));
-    let templates_map = get_templates_map(); // Get all templates
+    let file_path = Path::new(WORKPATH).join("platform_info.toml");
     
-    // Get minimum BDF from c.bdf
-    let bdf_used = c.bdf.iter().min().ok_or("No available BDFs")?;
 
     // Read device configurations from the platform TOML file
     let config: toml::Value = toml::from_str(&std::fs::read_to_string(&file_path)?)?;
@@ -37,118 +60,78 @@ pub fn devconfig(c: &mut configGenerator::Backendconfig) -> Result<(), Box<dyn E
         .and_then(|dev| dev.get("devs"))
         .and_then(|devs| devs.as_array())
         .ok_or("Device list 'devs' not found in configuration")?;
+    let templates_map = get_platform_templates(&config, Path::new(TEMPLATE_DIR))?; // Get all templates
 
-    // Initialize num_pci_dev with zero
-    let mut num_pci_dev = 0;
-    let mut num_irq_chip = 0;
-
-    // Fill the variable with the number of pci_devices inferred from the actually used templates
-    for dev in devs {
-        if let Some(dev_name) = dev.as_str() {
-            match dev_name {
-                "PCI_DEVICE_EMPTY_TEMPLATE" => {
-                    // Skip these devices
-                    continue;
-                },
-                "IRQ_CHIP_TEMPLATE" | "IRQ_CHIP_BOARD_TEMPLATE" => {
-                    // Count this device as one
-                    num_irq_chip += 1;
-                },
-                "PCI_DEVICE_TEMPLATE_WITH_DEMO" => {
-                    // Count this device as two
-                    num_pci_dev += 2;
-                },
-                _ => {
-                    // Count all other devices as one
-                    num_pci_dev += 1;
-                },
-            }
-        }
-    }
+    // Without networking the cell gets no ivshmem device, the irqchips are kept. The networking
+    // device itself is added with its link by ivshmemconfig, at the BDF of the cell
+    let skip_pci = c.net == "none";
 
-    // Check the value of c.net and set linetoinsert accordingly
-    let linetoinsert = if c.net == "none" {
-        "\tstruct jailhouse_irqchip irqchips[1];\n\tstruct jailhouse_pci_device pci_devices[0];"
-    } else {    
-        &format! ("\tstruct jailhouse_irqchip irqchips[{}];\n\tstruct jailhouse_pci_device pci_devices[{}];",num_irq_chip ,num_pci_dev)
+    // Get minimum BDF from c.bdf, not needed when the PCI devices are dropped
+    let bdf_used = match c.bdf.iter().min() {
+        Some(bdf) => *bdf,
+        None if skip_pci => 0,
+        None => return Err("No available BDFs".into()),
     };
 
-    // Compile a regular expression to match the pattern and insert the pci_devices
-    let re = Regex::new(&pattern)?;
-    if let Some(pos) = re.find(&c.conf) {
-        c.conf
-            .insert_str(pos.end(), &format!("\n{}", linetoinsert));
+    // The pins of the ivshmem devices are allocated when the node tracks the free pins and the
+    // templates use them, otherwise the ones in the table of the template are kept. The demo
+    // device at device number 0 raises the first INTx of the cell, its pin is taken as well.
+    // Without networking no PCI device is left, and the irqchip routes none of their pins
+    let mut computed = vec![("ivshmem_bdf", bdf_used.to_string())];
+    let uses = |placeholder: &str| devs.iter()
+        .filter_map(|d| d.as_str())
+        .filter_map(|name| templates_map.get(name))
+        .any(|template| has_placeholder(template, placeholder));
+    let (uses_pin, uses_demo_pin) = (uses("ivshmem_pin"), uses("ivshmem_demo_pin"));
+    if skip_pci {
+        computed.push(("ivshmem_pin", String::new()));
+        computed.push(("ivshmem_demo_pin", String::new()));
+    } else if (uses_pin || uses_demo_pin) && c.irq_pins.is_some() {
+        let mut bdfs = Vec::new();
+        if uses_pin {
+            bdfs.push(bdf_used as u32);
+        }
+        if uses_demo_pin {
+            bdfs.push(0);
+        }
+        let pins = allocate_irq_pins(c, &bdfs)?;
+        if uses_pin {
+            computed.push(("ivshmem_pin", pins[0].to_string()));
+        }
+        if uses_demo_pin {
+            computed.push(("ivshmem_demo_pin", pins[pins.len() - 1].to_string()));
+        }
     } else {
-        return Err("\"pattern struct jailhouse_memory mem_regions not found".into());
+        computed.push(("ivshmem_demo_pin", (c.desc.header.vpci_irq_base + SPI_BASE).to_string()));
+    }
+
+    // The interrupt of the UART goes only to the cell owning it, as its regions
+    if c.uart.is_some() && c.console != f2b::ConsoleKind::Uart {
+        computed.push(("uart_pin", String::new()));
     }
 
     // Placeholder insertions for each device in devs
-    let mut result = String::new();
     for device in devs {
         if let Some(template_name) = device.as_str() {
             // Get the template from the map
-            let mut template = templates_map.get(template_name)
-                .ok_or_else(|| format!("Unknown template: {}", template_name))?
-                .to_string();
-
-            // Substitute common fields
-            template = template.replace("{ivshmem_bdf}", &bdf_used.to_string());
-            // AGGIUSTARE PER NON PRENDERE ANCHE IL TEMPLATE DI DEVICE SE C.NET=NONE
-            // Apply custom fields from TOML for the current device template
-            if let Some(params) = config.get(template_name) {
-                let params_str = params.as_table()
-                    .ok_or_else(|| format!("Expected table for template: {}", template_name))?
-                    .iter()
-                    .map(|(k, v)| format!("{}={}", k, v.as_str().unwrap_or("")))
-                    .collect::<Vec<_>>()
-                    .join(", ");
-                
-                // Generate the final device configuration using template and params
-                result.push_str(&generate_mem_region(&template, &params_str));
-            } else {
-                // Append template with no custom parameters
-                result.push_str(&template);
+            let template = templates_map.get(template_name)
+                .ok_or_else(|| format!("Unknown template: {}", template_name))?;
+
+            // Fill in the common fields and the custom ones from the TOML for the current device template
+            let template = render_template(template_name, template, config.get(template_name), &computed)?;
+
+            // Add the elements of the template to the cell
+            let pci_before = c.desc.pci_devices.len();
+            c.desc.add_elements(&template)
+                .map_err(|e| format!("Template {}: {}", template_name, e))?;
+            if skip_pci {
+                c.desc.pci_devices.truncate(pci_before);
             }
         }
     }
 
-    // Update the config with dynamically generated result
-    c.conf.push_str(&result);
+    // The I/O ports of the UART go only to the cell owning it
+    configGenerator::communication::release_uart_ports(c);
 
     Ok(())
 }
-
-// Replaces the placeholders in the template with the actual values from the `params` string
-fn generate_mem_region(template: &str, params: &str) -> String {
-    let mut filled_template = template.to_string();
-
-    // If params are empty, skip the parameter replacement logic
-    if params.is_empty() {
-        return filled_template;
-    }
-
-    // Split the params string and replace each placeholder in the template
-    for param in params.split(", ") {
-        let mut key_value = param.split('=');
-
-        let key = match key_value.next() {
-            Some(k) => k.trim(),
-            None => {
-                eprintln!("Warning: Malformed parameter (missing key): {}", param);
-                continue;
-            }
-        };
-
-        let value = match key_value.next() {
-            Some(v) => v.trim(),
-            None => {
-                eprintln!("Warning: Malformed parameter (missing value for key {}): {}", key, param);
-                continue;
-            }
-        };
-
-        filled_template = filled_template.replace(&format!("{{{}}}", key), value);
-    }
-
-    filled_template
-}
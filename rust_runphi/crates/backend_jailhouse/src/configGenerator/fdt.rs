@@ -0,0 +1,254 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Reader and writer of flattened device trees (the .dtb format of the devicetree specification),
+// to patch the device tree of linux cells with the resources allocated to them, without dtc.
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Node {
+    // Name with the unit address, e.g. "cpu@2", empty for the root
+    pub name: String,
+    pub props: Vec<(String, Vec<u8>)>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fdt {
+    pub boot_cpuid: u32,
+    // Memory reservation block, as address and size
+    pub reserved: Vec<(u64, u64)>,
+    pub root: Node,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Node { name: name.to_string(), ..Default::default() }
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_slice())
+    }
+
+    pub fn set_prop(&mut self, name: &str, value: Vec<u8>) {
+        match self.props.iter_mut().find(|(n, _)| n == name) {
+            Some(prop) => prop.1 = value,
+            None => self.props.push((name.to_string(), value)),
+        }
+    }
+
+    pub fn remove_prop(&mut self, name: &str) {
+        self.props.retain(|(n, _)| n != name);
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        self.prop(name).filter(|v| v.len() == 4).map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+    }
+
+    pub fn prop_str(&self, name: &str) -> Option<&str> {
+        self.prop(name).and_then(|v| std::str::from_utf8(v.strip_suffix(&[0]).unwrap_or(v)).ok())
+    }
+
+    // Name without the unit address
+    pub fn base_name(&self) -> &str {
+        self.name.split('@').next().unwrap_or("")
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|n| n.name == name)
+    }
+
+    pub fn child_mut(&mut self, name: &str) -> Option<&mut Node> {
+        self.children.iter_mut().find(|n| n.name == name)
+    }
+
+    // The child of that name, added if missing
+    pub fn child_or_insert(&mut self, name: &str) -> &mut Node {
+        match self.children.iter().position(|n| n.name == name) {
+            Some(index) => &mut self.children[index],
+            None => {
+                self.children.push(Node::new(name));
+                self.children.last_mut().unwrap()
+            }
+        }
+    }
+
+    // The node with the given phandle, in the subtree
+    pub fn find_phandle(&self, phandle: u32) -> Option<&Node> {
+        if self.prop_u32("phandle").or(self.prop_u32("linux,phandle")) == Some(phandle) {
+            return Some(self);
+        }
+        self.children.iter().find_map(|n| n.find_phandle(phandle))
+    }
+}
+
+// Property values
+pub fn cells(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+pub fn string(value: &str) -> Vec<u8> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+pub fn to_cells(value: &[u8]) -> Vec<u32> {
+    value.chunks_exact(4).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]])).collect()
+}
+
+// A number as `count` cells, most significant first
+pub fn number_cells(value: u64, count: u32) -> Vec<u32> {
+    (0..count).rev().map(|i| if i < 2 { (value >> (32 * i)) as u32 } else { 0 }).collect()
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.data.get(self.pos..self.pos + 4).ok_or("truncated structure block")?;
+        self.pos += 4;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8], String> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or("truncated structure block")?;
+        self.pos = (self.pos + len).next_multiple_of(4);
+        Ok(bytes)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let rest = self.data.get(self.pos..).ok_or("truncated structure block")?;
+        let len = rest.iter().position(|b| *b == 0).ok_or("unterminated node name")?;
+        let name = String::from_utf8_lossy(&rest[..len]).to_string();
+        self.pos = (self.pos + len + 1).next_multiple_of(4);
+        Ok(name)
+    }
+}
+
+fn header_field(data: &[u8], index: usize) -> u32 {
+    u32::from_be_bytes([data[4 * index], data[4 * index + 1], data[4 * index + 2], data[4 * index + 3]])
+}
+
+pub fn parse(data: &[u8]) -> Result<Fdt, String> {
+    if data.len() < FDT_HEADER_SIZE || header_field(data, 0) != FDT_MAGIC {
+        return Err("not a flattened device tree".to_string());
+    }
+    let total = header_field(data, 1) as usize;
+    let (off_struct, off_strings, off_rsvmap) = (header_field(data, 2) as usize, header_field(data, 3) as usize, header_field(data, 4) as usize);
+    if header_field(data, 6) > FDT_VERSION || total > data.len() {
+        return Err("unsupported or truncated device tree".to_string());
+    }
+    let data = &data[..total];
+    let strings = data.get(off_strings..).ok_or("bad strings block offset")?;
+
+    let mut reserved = Vec::new();
+    let mut pos = off_rsvmap;
+    loop {
+        let entry = data.get(pos..pos + 16).ok_or("truncated memory reservation block")?;
+        let address = u64::from_be_bytes(entry[..8].try_into().unwrap());
+        let size = u64::from_be_bytes(entry[8..].try_into().unwrap());
+        if address == 0 && size == 0 {
+            break;
+        }
+        reserved.push((address, size));
+        pos += 16;
+    }
+
+    let mut reader = Reader { data, pos: off_struct };
+    let mut stack: Vec<Node> = Vec::new();
+    let mut root = None;
+    loop {
+        match reader.u32()? {
+            FDT_BEGIN_NODE => stack.push(Node::new(&reader.name()?)),
+            FDT_END_NODE => {
+                let node = stack.pop().ok_or("unbalanced end of node")?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => root = Some(node),
+                }
+            }
+            FDT_PROP => {
+                let len = reader.u32()? as usize;
+                let name_off = reader.u32()? as usize;
+                let name = strings.get(name_off..).and_then(|s| s.split(|b| *b == 0).next()).ok_or("bad property name offset")?;
+                let name = String::from_utf8_lossy(name).to_string();
+                let value = reader.bytes(len)?.to_vec();
+                stack.last_mut().ok_or("property outside of a node")?.props.push((name, value));
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            token => return Err(format!("unknown token {} in the structure block", token)),
+        }
+    }
+    Ok(Fdt { boot_cpuid: header_field(data, 7), reserved, root: root.ok_or("no root node")? })
+}
+
+fn write_node(node: &Node, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+    structure.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+    structure.extend_from_slice(node.name.as_bytes());
+    structure.push(0);
+    structure.resize(structure.len().next_multiple_of(4), 0);
+    for (name, value) in &node.props {
+        // Property names are shared in the strings block
+        let mut needle = name.as_bytes().to_vec();
+        needle.push(0);
+        let name_off = strings.windows(needle.len()).enumerate()
+            .find(|(i, w)| *w == needle.as_slice() && (*i == 0 || strings[i - 1] == 0))
+            .map(|(i, _)| i)
+            .unwrap_or_else(|| {
+                strings.extend_from_slice(&needle);
+                strings.len() - needle.len()
+            });
+        structure.extend_from_slice(&FDT_PROP.to_be_bytes());
+        structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        structure.extend_from_slice(&(name_off as u32).to_be_bytes());
+        structure.extend_from_slice(value);
+        structure.resize(structure.len().next_multiple_of(4), 0);
+    }
+    for child in &node.children {
+        write_node(child, structure, strings);
+    }
+    structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+}
+
+pub fn serialize(fdt: &Fdt) -> Vec<u8> {
+    let mut structure = Vec::new();
+    let mut strings = Vec::new();
+    write_node(&fdt.root, &mut structure, &mut strings);
+    structure.extend_from_slice(&FDT_END.to_be_bytes());
+
+    let off_rsvmap = FDT_HEADER_SIZE.next_multiple_of(8);
+    let off_struct = off_rsvmap + 16 * (fdt.reserved.len() + 1);
+    let off_strings = off_struct + structure.len();
+    let total = off_strings + strings.len();
+
+    let mut out = Vec::with_capacity(total);
+    for field in [
+        FDT_MAGIC, total as u32, off_struct as u32, off_strings as u32, off_rsvmap as u32,
+        FDT_VERSION, FDT_LAST_COMP_VERSION, fdt.boot_cpuid, strings.len() as u32, structure.len() as u32,
+    ] {
+        out.extend_from_slice(&field.to_be_bytes());
+    }
+    out.resize(off_rsvmap, 0);
+    for (address, size) in fdt.reserved.iter().chain(std::iter::once(&(0, 0))) {
+        out.extend_from_slice(&address.to_be_bytes());
+        out.extend_from_slice(&size.to_be_bytes());
+    }
+    out.extend(structure);
+    out.extend(strings);
+    out
+}
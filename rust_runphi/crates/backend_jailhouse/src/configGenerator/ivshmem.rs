@@ -0,0 +1,585 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// IVSHMEM devices linking cells. A link is a named IVSHMEM device the cells of its members get at
+// the same BDF, each with its own peer id. The first member allocates the BDF out of the free ones
+// and the memory out of the free segments, the last one gives them back. The links are kept in
+// state.toml with their members:
+//   [free_ivshmem_bdf]
+//   bdf = [8, 9, 10]
+//   [ivshmem_links.ctrl]
+//   bdf = 8
+//   memory = "0x7c000000, 0x7c010000"
+//   protocol = "virtio-console"
+//   peers = 2
+//   rw_size = "0xf000"
+//   out_size = "0x0"
+//   members = ["<containerid>", ...]
+//   ids = [0, ...]
+// and each container lists the links it joined in the `ivshmem` entry of its section.
+// The memory of a link is laid out as Jailhouse expects: the state table, the read/write section
+// shared by all the peers, then the output section of each peer.
+// The networking device of a cell is the veth link with the root cell at its BDF, one of
+// [free_pci_devices_bdf]. The root cell maps the memory of these links from its own config, so
+// they are recorded once for all by `runphi platform init`, with the root cell as member
+// "root", and are never freed. Their BDFs are never given to the other links.
+
+use std::error::Error;
+use toml::map::Map;
+use toml::Value;
+
+use crate::configGenerator;
+use crate::configGenerator::celldesc::*;
+use crate::configGenerator::device::{allocate_irq_pin, SPI_BASE};
+use crate::configGenerator::memalloc;
+use crate::configGenerator::templates::parse_number;
+use crate::statefile;
+use f2b::ShmemProtocol;
+
+const LINKS: &str = "ivshmem_links";
+pub const FREE_BDFS: &str = "free_ivshmem_bdf";
+const NETWORK_BDFS: &str = "free_pci_devices_bdf";
+// Member standing for the root cell in the links it is part of
+pub const ROOT_MEMBER: &str = "root";
+// Size of the state table at the start of the memory of a link
+const STATE_TABLE_SIZE: u64 = 0x1000;
+const DEFAULT_PEERS: u8 = 2;
+// MSI-X vectors of the devices on x86
+const MSIX_VECTORS: u16 = 2;
+
+// A link joined by the cell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub name: String,
+    // Device number of the link, the same in all the members
+    pub bdf: u32,
+    pub memory: memalloc::Segment,
+    pub protocol: ShmemProtocol,
+    pub peers: u8,
+    pub rw_size: u64,
+    pub out_size: u64,
+    // Peer id of the cell
+    pub id: u8,
+    // Whether the cell creates the link, being its first member
+    pub created: bool,
+    // Whether the root cell is a member, its regions are then shared with the root cell
+    pub root: bool,
+}
+
+// Sizes of the read/write and output sections of a link created with the protocol, as in the
+// Jailhouse demo configs
+fn default_sizes(protocol: ShmemProtocol) -> (u64, u64) {
+    match protocol {
+        ShmemProtocol::Veth => (0, 0x7f000),
+        ShmemProtocol::VirtioConsole => (0xf000, 0),
+        ShmemProtocol::VirtioBlock => (0xdf000, 0),
+        ShmemProtocol::Undefined => (0xf000, 0),
+    }
+}
+
+fn protocol_id(protocol: ShmemProtocol, id: u8) -> u64 {
+    // The peer with id 0 is the virtio backend
+    let virtio = if id == 0 { JAILHOUSE_SHMEM_PROTO_VIRTIO_BACK } else { JAILHOUSE_SHMEM_PROTO_VIRTIO_FRONT };
+    match protocol {
+        ShmemProtocol::Veth => JAILHOUSE_SHMEM_PROTO_VETH,
+        ShmemProtocol::VirtioConsole => virtio + VIRTIO_DEV_CONSOLE,
+        ShmemProtocol::VirtioBlock => virtio + VIRTIO_DEV_BLOCK,
+        ShmemProtocol::Undefined => JAILHOUSE_SHMEM_PROTO_UNDEFINED,
+    }
+}
+
+fn parse_protocol(name: &str) -> Option<ShmemProtocol> {
+    [ShmemProtocol::Veth, ShmemProtocol::VirtioConsole, ShmemProtocol::VirtioBlock, ShmemProtocol::Undefined]
+        .into_iter()
+        .find(|p| p.as_str() == name)
+}
+
+// The device numbers left to the links, in [free_ivshmem_bdf] of the state
+pub fn free_bdfs(state: &Value) -> Result<Vec<u32>, String> {
+    let Some(bdfs) = state.get(FREE_BDFS) else { return Ok(Vec::new()) };
+    let bdfs = bdfs.get("bdf").and_then(|b| b.as_array()).ok_or(format!("[{}] bdf missing", FREE_BDFS))?;
+    bdfs.iter()
+        .map(|b| b.as_integer().filter(|b| (1..32).contains(b)).map(|b| b as u32)
+            .ok_or(format!("[{}] {} is not a device number between 1 and 31", FREE_BDFS, b)))
+        .collect()
+}
+
+// The link as recorded in the state, if it exists, with the peer ids taken
+fn existing(state: &Value, name: &str) -> Result<Option<(Link, Vec<u8>)>, String> {
+    let Some(link) = state.get(LINKS).and_then(|l| l.get(name)) else { return Ok(None) };
+    let missing = |field: &str| format!("[{}.{}] {} missing", LINKS, name, field);
+    let number = |field: &str| -> Result<u64, String> {
+        match link.get(field) {
+            Some(Value::String(value)) => parse_number(value.trim()).ok_or(format!("[{}.{}] {} is not a number", LINKS, name, field)),
+            Some(Value::Integer(value)) => u64::try_from(*value).map_err(|_| format!("[{}.{}] {} is negative", LINKS, name, field)),
+            _ => Err(missing(field)),
+        }
+    };
+    let memory = link.get("memory").and_then(|m| m.as_str()).ok_or(missing("memory"))?;
+    let memory = memalloc::parse_segment(memory).map_err(|e| format!("[{}.{}] memory: {}", LINKS, name, e))?;
+    let protocol = link.get("protocol").and_then(|p| p.as_str()).ok_or(missing("protocol"))?;
+    let protocol = parse_protocol(protocol).ok_or(format!("[{}.{}] protocol {} is unknown", LINKS, name, protocol))?;
+    let ids = link.get("ids").and_then(|i| i.as_array()).ok_or(missing("ids"))?
+        .iter().filter_map(|i| i.as_integer()).map(|i| i as u8).collect();
+    let root = link.get("members").and_then(|m| m.as_array()).ok_or(missing("members"))?
+        .iter().any(|m| m.as_str() == Some(ROOT_MEMBER));
+    let link = Link {
+        name: name.to_string(),
+        bdf: number("bdf")? as u32,
+        memory,
+        protocol,
+        peers: number("peers")? as u8,
+        rw_size: number("rw_size")?,
+        out_size: number("out_size")?,
+        id: 0,
+        created: false,
+        root,
+    };
+    Ok(Some((link, ids)))
+}
+
+// The networking link of the root cell at the device number, with the peer ids taken
+pub fn network_link(state: &Value, bdf: u32) -> Result<(Link, Vec<u8>), String> {
+    let names = state.get(LINKS).and_then(|l| l.as_table()).into_iter().flatten()
+        .filter(|(_, link)| link.get("bdf").and_then(|b| b.as_integer()) == Some(bdf as i64))
+        .map(|(name, _)| name);
+    for name in names {
+        let Some((link, ids)) = existing(state, name)?.filter(|(link, _)| link.root) else { continue };
+        if link.protocol != ShmemProtocol::Veth {
+            return Err(format!("[{}.{}] the link of the root cell at BDF {} has protocol {}, not veth", LINKS, name, bdf, link.protocol));
+        }
+        return Ok((link, ids));
+    }
+    Err(format!("no link of the root cell at BDF {} in [{}] of the state, see `runphi platform init`", bdf, LINKS))
+}
+
+// Device numbers no new link can take: the ones of the links in the state, among which the
+// networking links of the root cell, and the free networking BDFs
+pub fn taken_bdfs(state: &Value) -> Vec<u32> {
+    let links = state.get(LINKS).and_then(|l| l.as_table()).into_iter().flatten()
+        .filter_map(|(_, link)| link.get("bdf").and_then(|b| b.as_integer()));
+    let network = state.get(NETWORK_BDFS).and_then(|n| n.get("bdf")).and_then(|b| b.as_array()).into_iter().flatten()
+        .filter_map(|b| b.as_integer());
+    links.chain(network).filter_map(|b| u32::try_from(b).ok()).collect()
+}
+
+// The link of an IVSHMEM networking device of the root cell, named as given, when its regions
+// are laid out as the ones of the links
+pub fn root_link(name: &str, root: &CellDesc, device: &PciDevice) -> Option<Link> {
+    if device.shmem_protocol as u64 != JAILHOUSE_SHMEM_PROTO_VETH || device.shmem_peers < 2 {
+        return None;
+    }
+    let start = device.shmem_regions_start as usize;
+    let regions = root.mem_regions.get(start..start + 2 + device.shmem_peers as usize)?;
+    let base = regions[0].phys_start;
+    let (rw_size, out_size) = (regions[1].size, regions[2].size);
+    let expected = |i: usize| match i {
+        0 => (base, STATE_TABLE_SIZE),
+        1 => (base + STATE_TABLE_SIZE, rw_size),
+        peer => (base + STATE_TABLE_SIZE + rw_size + (peer - 2) as u64 * out_size, out_size),
+    };
+    let laid_out = regions.iter().enumerate()
+        .all(|(i, r)| (r.size == 0 || r.phys_start == expected(i).0) && r.size == expected(i).1);
+    if !laid_out {
+        return None;
+    }
+    let peers = device.shmem_peers;
+    Some(Link {
+        name: name.to_string(),
+        bdf: (device.bdf >> 3) as u32,
+        memory: (base, base + STATE_TABLE_SIZE + rw_size + peers as u64 * out_size),
+        protocol: ShmemProtocol::Veth,
+        peers,
+        rw_size,
+        out_size,
+        id: device.shmem_dev_id,
+        created: false,
+        root: true,
+    })
+}
+
+// Device numbers used in the cell, by its templates or by the links already joined
+fn used_bdfs(c: &configGenerator::Backendconfig) -> Vec<u32> {
+    c.desc.pci_devices.iter().map(|d| (d.bdf >> 3) as u32).chain(c.ivshmem.iter().map(|l| l.bdf)).collect()
+}
+
+// Interrupt pin of the INTx of the device at the given device number, when the cell has INTx
+fn intx_pin(c: &configGenerator::Backendconfig, bdf: u32) -> u32 {
+    c.desc.header.vpci_irq_base + SPI_BASE + (bdf & 3)
+}
+
+// Takes the pin of the INTx of the link device, routing it to the cell. Devices whose device
+// numbers share the two low bits share the pin
+fn route_intx(c: &mut configGenerator::Backendconfig, link: &str, bdf: u32) -> Result<(), Box<dyn Error>> {
+    let pin = if c.irq_pins.is_none() {
+        intx_pin(c, bdf)
+    } else if c.used_irq_pins.is_empty() {
+        // The first device of the cell moves its vpci_irq_base onto a free pin
+        allocate_irq_pin(c, bdf)?
+    } else {
+        let pin = intx_pin(c, bdf);
+        let free = c.irq_pins.get_or_insert_with(Vec::new);
+        if let Some(index) = free.iter().position(|p| *p == pin) {
+            free.remove(index);
+            c.used_irq_pins.push(pin);
+        } else if !c.used_irq_pins.contains(&pin) {
+            return Err(format!("the interrupt pin {} of link \"{}\" at device number {} is not free", pin, link, bdf).into());
+        }
+        pin
+    };
+    let irqchip = c.desc.irqchips.iter_mut()
+        .find(|i| i.pin_base <= pin && pin < i.pin_base + 128)
+        .ok_or_else(|| format!("no irqchip of the cell covers the interrupt pin {} of link \"{}\"", pin, link))?;
+    let bit = pin - irqchip.pin_base;
+    irqchip.pin_bitmap[(bit / 32) as usize] |= 1 << (bit % 32);
+    Ok(())
+}
+
+// Joins the networking link of the cell and the links asked by the image, creating the missing
+// ones, and adds their devices and regions to the cell. Called after devconfig, so that the device
+// numbers and the pins of the devices of the templates are known
+pub fn ivshmemconfig(c: &mut configGenerator::Backendconfig, ic: &f2b::ImageConfig) -> Result<(), Box<dyn Error>> {
+    if ic.ivshmem.is_empty() && c.net == "none" {
+        return Ok(());
+    }
+    let state: Value = statefile::read()?.parse()?;
+    let taken = taken_bdfs(&state);
+    let mut free: Vec<u32> = free_bdfs(&state)?.into_iter().filter(|b| !taken.contains(b)).collect();
+    // On x86 the devices use MSI-X, any BDF will do
+    let x86 = c.arch == configGenerator::Arch::X86;
+
+    // The networking device joins the link of the root cell at the BDF of the cell
+    if c.net != "none" {
+        let bdf = c.bdf.iter().min().map(|b| *b as u32).ok_or("No available BDFs")?;
+        let (link, ids) = network_link(&state, bdf)?;
+        let id = (0..link.peers).find(|id| !ids.contains(id))
+            .ok_or_else(|| format!("the networking link \"{}\" at BDF {} has already {} members", link.name, bdf, link.peers))?;
+        if used_bdfs(c).contains(&bdf) {
+            return Err(format!("the networking link \"{}\" is at BDF {}, already used by a device of the templates", link.name, bdf).into());
+        }
+        add_link(c, Link { id, ..link })?;
+    }
+
+    for request in &ic.ivshmem {
+        let page = |size: Option<f2b::Address>| size.map(|size| size.0.next_multiple_of(memalloc::PAGE_SIZE));
+        let (rw_size, out_size) = (page(request.rw_size), page(request.out_size));
+        let link = match existing(&state, &request.name)? {
+            Some((link, _)) if link.root => {
+                return Err(format!("link \"{}\" is a networking link of the root cell", link.name).into());
+            }
+            Some((link, ids)) => {
+                // The members join the link as it was created
+                if let Some(protocol) = request.protocol.filter(|p| *p != link.protocol) {
+                    return Err(format!("link \"{}\" asked with protocol {}, but it has protocol {}", link.name, protocol, link.protocol).into());
+                }
+                if let Some(peers) = request.peers.filter(|p| *p != link.peers) {
+                    return Err(format!("link \"{}\" asked with {} peers, but it has {}", link.name, peers, link.peers).into());
+                }
+                if let Some(size) = rw_size.filter(|s| *s != link.rw_size) {
+                    return Err(format!("link \"{}\" asked with rw_size 0x{:x}, but it has 0x{:x}", link.name, size, link.rw_size).into());
+                }
+                if let Some(size) = out_size.filter(|s| *s != link.out_size) {
+                    return Err(format!("link \"{}\" asked with out_size 0x{:x}, but it has 0x{:x}", link.name, size, link.out_size).into());
+                }
+                let id = match request.id {
+                    Some(id) if id >= link.peers => return Err(format!("link \"{}\": id {} is not below the {} peers", link.name, id, link.peers).into()),
+                    Some(id) if ids.contains(&id) => return Err(format!("link \"{}\": id {} is taken", link.name, id).into()),
+                    Some(id) => id,
+                    None => (0..link.peers).find(|id| !ids.contains(id))
+                        .ok_or_else(|| format!("link \"{}\" has already {} members", link.name, link.peers))?,
+                };
+                if used_bdfs(c).contains(&link.bdf) {
+                    return Err(format!("link \"{}\" is at device number {}, already used in the cell", link.name, link.bdf).into());
+                }
+                Link { id, ..link }
+            }
+            None => {
+                let protocol = request.protocol.unwrap_or(ShmemProtocol::Undefined);
+                let peers = request.peers.unwrap_or(DEFAULT_PEERS);
+                let (default_rw, default_out) = default_sizes(protocol);
+                let (rw_size, out_size) = (rw_size.unwrap_or(default_rw), out_size.unwrap_or(default_out));
+                let id = request.id.unwrap_or(0);
+                if id >= peers {
+                    return Err(format!("link \"{}\": id {} is not below the {} peers", request.name, id, peers).into());
+                }
+
+                // The lowest free device number not used in the cell, preferring the ones whose
+                // INTx pin is free or already routed to the cell
+                let used = used_bdfs(c);
+                let candidates: Vec<u32> = free.iter().copied().filter(|b| !used.contains(b)).collect();
+                let pin_ok = |bdf: &u32| match &c.irq_pins {
+                    Some(pins) if !c.used_irq_pins.is_empty() => {
+                        let pin = intx_pin(c, *bdf);
+                        pins.contains(&pin) || c.used_irq_pins.contains(&pin)
+                    }
+                    _ => true,
+                };
+                let bdf = candidates.iter().copied().filter(|b| x86 || pin_ok(b)).min()
+                    .or(candidates.iter().copied().min())
+                    .ok_or_else(|| format!("no free device number left for link \"{}\", see [{}] in the state", request.name, FREE_BDFS))?;
+                free.retain(|b| *b != bdf);
+
+                let size = STATE_TABLE_SIZE + rw_size + peers as u64 * out_size;
+                let alloc = memalloc::Request { size, align: memalloc::PAGE_SIZE, guard: 0, fixed: None };
+                let memory = memalloc::allocate(&mut c.segments, &alloc)
+                    .map_err(|e| format!("Cannot allocate the link \"{}\": {}", request.name, e))?;
+                Link { name: request.name.clone(), bdf, memory, protocol, peers, rw_size, out_size, id, created: true, root: false }
+            }
+        };
+
+        add_link(c, link)?;
+    }
+
+    Ok(())
+}
+
+// Adds the regions and the device of the link to the cell
+fn add_link(c: &mut configGenerator::Backendconfig, link: Link) -> Result<(), Box<dyn Error>> {
+    // The regions of the link are mapped at their physical address, which must be free in the cell
+    let (start, end) = link.memory;
+    if let Some(region) = c.desc.mem_regions.iter().find(|r| r.size > 0 && r.virt_start < end && start < r.virt_start.saturating_add(r.size)) {
+        return Err(format!(
+            "link \"{}\" at 0x{:x}-0x{:x} overlaps the region at 0x{:x} of the cell",
+            link.name, start, end, region.virt_start
+        ).into());
+    }
+    let regions_start = c.desc.mem_regions.len() as u32;
+    let region = |comment: String, address: u64, size: u64, flags: u64| MemRegion {
+        comment: Some(comment),
+        phys_start: if size > 0 { address } else { 0 },
+        virt_start: if size > 0 { address } else { 0 },
+        size,
+        flags: if size > 0 { flags } else { 0 },
+        ..Default::default()
+    };
+    // The root cell keeps the regions of its links mapped
+    let read = if link.root { JAILHOUSE_MEM_READ | JAILHOUSE_MEM_ROOTSHARED } else { JAILHOUSE_MEM_READ };
+    let rw = read | JAILHOUSE_MEM_WRITE;
+    c.desc.mem_regions.push(region(format!("IVSHMEM link {}: state table", link.name), start, STATE_TABLE_SIZE, read));
+    c.desc.mem_regions.push(region(format!("IVSHMEM link {}: read/write section", link.name), start + STATE_TABLE_SIZE, link.rw_size, rw));
+    for peer in 0..link.peers {
+        let address = start + STATE_TABLE_SIZE + link.rw_size + peer as u64 * link.out_size;
+        let flags = if peer == link.id { rw } else { read };
+        c.desc.mem_regions.push(region(format!("IVSHMEM link {}: output section of peer {}", link.name, peer), address, link.out_size, flags));
+    }
+
+    // The domain of the devices of the templates, 0 on x86 and 1 on the other boards otherwise
+    let x86 = c.arch == configGenerator::Arch::X86;
+    let domain = c.desc.pci_devices.first().map(|d| d.domain).unwrap_or(if x86 { 0 } else { 1 });
+    let (bar_mask, num_msix_vectors) = if x86 {
+        (JAILHOUSE_IVSHMEM_BAR_MASK_MSIX, MSIX_VECTORS)
+    } else {
+        route_intx(c, &link.name, link.bdf)?;
+        (JAILHOUSE_IVSHMEM_BAR_MASK_INTX, 0)
+    };
+    c.desc.pci_devices.push(PciDevice {
+        comment: Some(format!("IVSHMEM link {} ({}), 00:{:02x}.0", link.name, link.protocol, link.bdf)),
+        dev_type: JAILHOUSE_PCI_TYPE_IVSHMEM as u8,
+        domain,
+        bdf: (link.bdf << 3) as u16,
+        bar_mask,
+        num_msix_vectors,
+        shmem_regions_start: regions_start,
+        shmem_dev_id: link.id,
+        shmem_peers: link.peers,
+        shmem_protocol: protocol_id(link.protocol, link.id) as u16,
+        ..Default::default()
+    });
+    c.ivshmem.push(link);
+    Ok(())
+}
+
+// Entry of a new link in [ivshmem_links] of the state, still without members
+pub fn entry(link: &Link) -> Map<String, Value> {
+    let mut entry = Map::new();
+    entry.insert("bdf".to_string(), Value::Integer(link.bdf as i64));
+    entry.insert("memory".to_string(), Value::String(memalloc::format_segment(&link.memory)));
+    entry.insert("protocol".to_string(), Value::String(link.protocol.as_str().to_string()));
+    entry.insert("peers".to_string(), Value::Integer(link.peers as i64));
+    entry.insert("rw_size".to_string(), Value::String(format!("0x{:x}", link.rw_size)));
+    entry.insert("out_size".to_string(), Value::String(format!("0x{:x}", link.out_size)));
+    entry.insert("members".to_string(), Value::Array(Vec::new()));
+    entry.insert("ids".to_string(), Value::Array(Vec::new()));
+    entry
+}
+
+// Records the links joined by the container in the state, taking the device numbers of the links
+// it created out of the free ones
+pub fn save(state: &mut Value, containerid: &str, c: &configGenerator::Backendconfig) -> Result<(), String> {
+    if c.ivshmem.is_empty() {
+        return Ok(());
+    }
+    let table = state.as_table_mut().ok_or("the state is not a table")?;
+    if let Some(bdfs) = table.get_mut(FREE_BDFS).and_then(|f| f.get_mut("bdf")).and_then(|b| b.as_array_mut()) {
+        bdfs.retain(|b| !c.ivshmem.iter().any(|l| l.created && b.as_integer() == Some(l.bdf as i64)));
+    }
+    let links = table.entry(LINKS).or_insert_with(|| Value::Table(Map::new()))
+        .as_table_mut().ok_or(format!("[{}] is not a table", LINKS))?;
+    for link in &c.ivshmem {
+        if link.created {
+            links.insert(link.name.clone(), Value::Table(entry(link)));
+        }
+        let entry = links.get_mut(&link.name).and_then(|l| l.as_table_mut())
+            .ok_or(format!("[{}.{}] missing", LINKS, link.name))?;
+        for (field, value) in [("members", Value::String(containerid.to_string())), ("ids", Value::Integer(link.id as i64))] {
+            entry.get_mut(field).and_then(|m| m.as_array_mut())
+                .ok_or(format!("[{}.{}] {} missing", LINKS, link.name, field))?
+                .push(value);
+        }
+    }
+    Ok(())
+}
+
+// Removes the container from the members of its links. The links left without members are
+// removed, their device numbers given back to the free ones and their memory returned, while the
+// networking links keep the root cell
+pub fn leave(state: &mut Value, containerid: &str, names: &[String]) -> Vec<memalloc::Segment> {
+    let mut freed = Vec::new();
+    let mut freed_bdfs = Vec::new();
+    if let Some(links) = state.get_mut(LINKS).and_then(|l| l.as_table_mut()) {
+        for name in names {
+            let Some(link) = links.get_mut(name) else { continue };
+            let member = link.get("members").and_then(|m| m.as_array())
+                .and_then(|m| m.iter().position(|m| m.as_str() == Some(containerid)));
+            if let Some(index) = member {
+                for field in ["members", "ids"] {
+                    if let Some(array) = link.get_mut(field).and_then(|a| a.as_array_mut()).filter(|a| index < a.len()) {
+                        array.remove(index);
+                    }
+                }
+            }
+            if link.get("members").and_then(|m| m.as_array()).is_some_and(|m| !m.is_empty()) {
+                continue;
+            }
+            if let Some(memory) = link.get("memory").and_then(|m| m.as_str()).and_then(|m| memalloc::parse_segment(m).ok()) {
+                freed.push(memory);
+            }
+            if let Some(bdf) = link.get("bdf").and_then(|b| b.as_integer()) {
+                freed_bdfs.push(bdf);
+            }
+            links.remove(name);
+        }
+    }
+    if let Some(bdfs) = state.get_mut(FREE_BDFS).and_then(|f| f.get_mut("bdf")).and_then(|b| b.as_array_mut()) {
+        bdfs.extend(freed_bdfs.into_iter().map(Value::Integer));
+        bdfs.sort_by_key(|b| b.as_integer());
+    }
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configGenerator::templates::{IVSHMEM_TEMPLATE, PCI_DEVICE_TEMPLATE};
+
+    // Networking regions and device of the Jailhouse demo configs at BDF 1, for peer id 1
+    fn net_cell(address: u64) -> CellDesc {
+        let mut desc = CellDesc::new("net");
+        desc.add_mem_regions(&IVSHMEM_TEMPLATE.replace("{address}", &format!("0x{:x}", address))).unwrap();
+        desc.add_elements(&PCI_DEVICE_TEMPLATE.replace("{ivshmem_bdf}", "1")).unwrap();
+        desc
+    }
+
+    fn state(text: &str) -> Value {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn root_link_follows_the_net_regions() {
+        let root = net_cell(0x7f900000);
+        let link = root_link("net1", &root, &root.pci_devices[0]).unwrap();
+        assert_eq!((link.bdf, link.memory, link.protocol, link.peers), (1, (0x7f900000, 0x7f9ff000), ShmemProtocol::Veth, 2));
+        assert_eq!((link.rw_size, link.out_size, link.id, link.root), (0, 0x7f000, 1, true));
+
+        // Output sections out of place, or a device of another protocol
+        let mut moved = root.clone();
+        moved.mem_regions[3].phys_start += 0x1000;
+        assert_eq!(root_link("net1", &moved, &moved.pci_devices[0]), None);
+        let mut demo = root.clone();
+        demo.pci_devices[0].shmem_protocol = JAILHOUSE_SHMEM_PROTO_UNDEFINED as u16;
+        assert_eq!(root_link("net1", &demo, &demo.pci_devices[0]), None);
+    }
+
+    #[test]
+    fn network_link_maps_the_net_regions() {
+        let expected = net_cell(0x7f900000);
+        let link = root_link("net1", &expected, &expected.pci_devices[0]).unwrap();
+        let mut c = configGenerator::Backendconfig::new();
+        c.desc.irqchips.push(IrqChip { pin_base: 32, ..Default::default() });
+        c.desc.header.vpci_irq_base = 108;
+        add_link(&mut c, link).unwrap();
+
+        let uncommented = |desc: &CellDesc| -> (Vec<MemRegion>, Vec<PciDevice>) {
+            (
+                desc.mem_regions.iter().map(|r| MemRegion { comment: None, ..r.clone() }).collect(),
+                desc.pci_devices.iter().map(|d| PciDevice { comment: None, ..d.clone() }).collect(),
+            )
+        };
+        assert_eq!(uncommented(&c.desc), uncommented(&expected));
+        // The INTx of BDF 1 is routed to the cell
+        assert_eq!(c.desc.irqchips[0].pin_bitmap, [0, 0, 0, 1 << 13]);
+    }
+
+    #[test]
+    fn network_link_uses_msix_on_x86() {
+        let root = net_cell(0x7f900000);
+        let link = root_link("net1", &root, &root.pci_devices[0]).unwrap();
+        let platform: Value = "[jailhouse_preamble]\npreamble = \"X86_PREAMBLE\"\n".parse().unwrap();
+        let mut c = configGenerator::Backendconfig::new();
+        c.arch = configGenerator::platform_arch(&platform).unwrap();
+        c.desc.irqchips.push(IrqChip::default());
+        add_link(&mut c, link).unwrap();
+
+        let device = &c.desc.pci_devices[0];
+        assert_eq!((device.domain, device.bar_mask, device.num_msix_vectors), (0, JAILHOUSE_IVSHMEM_BAR_MASK_MSIX, MSIX_VECTORS));
+        // No pin is taken on the IOAPIC
+        assert_eq!(c.desc.irqchips[0].pin_bitmap, [0; 4]);
+    }
+
+    #[test]
+    fn arch_follows_the_preamble() {
+        let arch = |text: &str| configGenerator::platform_arch(&text.parse::<Value>().unwrap());
+        assert_eq!(arch("[jailhouse_preamble]\npreamble = \"QEMU_PREAMBLE\"\n"), Ok(configGenerator::Arch::Arm));
+        assert_eq!(arch("[jailhouse_preamble]\npreamble = \"BOARD\"\nlinux_preamble = \"X86_LINUX_PREAMBLE\"\n"), Ok(configGenerator::Arch::X86));
+        // The arch of the table wins over the names of the preambles
+        assert_eq!(arch("[jailhouse_preamble]\npreamble = \"MY_PC\"\narch = \"x86\"\n"), Ok(configGenerator::Arch::X86));
+        assert_eq!(arch("[jailhouse_preamble]\npreamble = \"X86_PREAMBLE\"\narch = \"arm64\"\n"), Ok(configGenerator::Arch::Arm));
+        assert!(arch("[jailhouse_preamble]\narch = \"riscv\"\n").is_err());
+    }
+
+    #[test]
+    fn network_link_is_the_root_link_at_the_bdf() {
+        let state = state(r#"
+            [free_pci_devices_bdf]
+            bdf = [2]
+            [ivshmem_links.ctrl]
+            bdf = 1
+            memory = "0x7c000000, 0x7c010000"
+            protocol = "undefined"
+            peers = 2
+            rw_size = "0xf000"
+            out_size = "0x0"
+            members = ["abc"]
+            ids = [0]
+            [ivshmem_links.net2]
+            bdf = 2
+            memory = "0x7fa00000, 0x7faff000"
+            protocol = "veth"
+            peers = 2
+            rw_size = "0x0"
+            out_size = "0x7f000"
+            members = ["root"]
+            ids = [0]
+        "#);
+        let (link, ids) = network_link(&state, 2).unwrap();
+        assert_eq!((link.name.as_str(), link.memory, link.root, ids), ("net2", (0x7fa00000, 0x7faff000), true, vec![0]));
+        // The link at BDF 1 is no link of the root cell
+        assert!(network_link(&state, 1).is_err());
+        assert!(network_link(&state, 3).is_err());
+
+        let mut taken = taken_bdfs(&state);
+        taken.sort();
+        assert_eq!(taken, vec![1, 2, 2]);
+    }
+}
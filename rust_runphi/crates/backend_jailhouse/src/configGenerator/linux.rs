@@ -0,0 +1,207 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Linux cells, booted with `jailhouse cell linux` on the generated .cell like any other cell.
+// The kernel command line is built from the configuration of the cell, and on arm the device tree
+// of the image (or of the node) is patched so that memory, CPUs and PCI match what was allocated.
+// On x86 the hypervisor tool takes them from the .cell itself. Optional table of the platform:
+// [linux]
+//   console = "ttyAMA0,115200"   console of the kernel on the UART of the board, ttyS0,115200 on x86
+
+use std::error::Error;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use toml::Value;
+
+use crate::configGenerator;
+use crate::configGenerator::celldesc::{MemRegion, JAILHOUSE_MEM_COMM_REGION, JAILHOUSE_MEM_EXECUTE, JAILHOUSE_MEM_LOADABLE, JAILHOUSE_MEM_READ, JAILHOUSE_MEM_WRITE};
+use crate::configGenerator::fdt::{self, Fdt, Node};
+use crate::configGenerator::network::NetConfig;
+use f2b;
+
+const WORKPATH: &str = "/usr/share/runPHI";
+const CONFIG_FILE: &str = "platform_info.toml";
+// Files in the run directory of the container, read when the cell is started
+pub const CMDLINE_FILE: &str = "cmdline";
+const DTB_FILE: &str = "linux.dtb";
+// Console of the kernel on the virtual console of the hypervisor (jailhouse dbcon driver)
+const VIRTUAL_CONSOLE: &str = "jailhouse";
+const RAM_FLAGS: u64 = JAILHOUSE_MEM_READ | JAILHOUSE_MEM_WRITE | JAILHOUSE_MEM_EXECUTE | JAILHOUSE_MEM_LOADABLE;
+// Type of the shared peripheral interrupts in the specifier of the GIC
+const GIC_SPI: u32 = 0;
+
+// The console of the kernel on the UART, from [linux] of the platform
+pub fn platform_console(platform: &Value) -> Result<String, String> {
+    // A wrong arch is reported by the preamble of the cells
+    let default = match configGenerator::platform_arch(platform) {
+        Ok(configGenerator::Arch::X86) => "ttyS0,115200",
+        _ => "ttyAMA0,115200",
+    };
+    match platform.get("linux").map(|s| s.as_table().ok_or("[linux] must be a table")).transpose()?.and_then(|s| s.get("console")) {
+        Some(Value::String(console)) if !console.trim().is_empty() && !console.contains(char::is_whitespace) => Ok(console.clone()),
+        Some(_) => Err("[linux] console must be a device name without spaces, e.g. \"ttyAMA0,115200\"".to_string()),
+        None => Ok(default.to_string()),
+    }
+}
+
+fn netmask(prefix: u8) -> Ipv4Addr {
+    Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0))
+}
+
+// The kernel command line: console, root device, address of the interface and the arguments of
+// the image, in this order, so that the image can override the others
+pub fn cmdline(c: &configGenerator::Backendconfig, ic: &f2b::ImageConfig, uart_console: &str, net: Option<&NetConfig>, hostname: &str) -> String {
+    let mut args = Vec::new();
+    match c.console {
+        f2b::ConsoleKind::Uart => args.push(format!("console={}", uart_console)),
+        f2b::ConsoleKind::Virtual => args.push(format!("console={}", VIRTUAL_CONSOLE)),
+        f2b::ConsoleKind::None => {}
+    }
+    if let Some(root) = &ic.root {
+        args.push(format!("root={}", root.trim()));
+    }
+    match net {
+        Some(net) if net.kind == f2b::NetConfKind::Static => args.push(format!(
+            "ip={}::{}:{}:{}::off",
+            net.link.guest, net.link.host, netmask(net.link.prefix), hostname
+        )),
+        Some(_) => args.push("ip=dhcp".to_string()),
+        None => {}
+    }
+    if let Some(extra) = &ic.cmdline {
+        args.push(extra.trim().to_string());
+    }
+    args.join(" ")
+}
+
+fn is_ram(region: &MemRegion) -> bool {
+    region.flags & RAM_FLAGS == RAM_FLAGS && region.flags & JAILHOUSE_MEM_COMM_REGION == 0
+}
+
+// CPUs of the cell, from its bitmap
+fn cpu_ids(c: &configGenerator::Backendconfig) -> Vec<u32> {
+    c.desc.cpus.iter().enumerate()
+        .flat_map(|(word, bits)| (0..64).filter(move |bit| bits & (1 << bit) != 0).map(move |bit| (64 * word + bit) as u32))
+        .collect()
+}
+
+// One memory node covering the RAM of the cell, as seen by the cell. The low RAM at 0 holds the
+// loader of the hypervisor tool and is left out
+fn patch_memory(tree: &mut Fdt, c: &configGenerator::Backendconfig) -> Result<(), String> {
+    let address_cells = tree.root.prop_u32("#address-cells").unwrap_or(2);
+    let size_cells = tree.root.prop_u32("#size-cells").unwrap_or(1);
+    let ram: Vec<&MemRegion> = c.desc.mem_regions.iter().filter(|r| is_ram(r) && r.virt_start != 0).collect();
+    let first = ram.first().ok_or("the cell has no RAM")?;
+
+    let mut memory = Node::new(&format!("memory@{:x}", first.virt_start));
+    memory.set_prop("device_type", fdt::string("memory"));
+    let reg: Vec<u32> = ram.iter()
+        .flat_map(|r| fdt::number_cells(r.virt_start, address_cells).into_iter().chain(fdt::number_cells(r.size, size_cells)))
+        .collect();
+    memory.set_prop("reg", fdt::cells(&reg));
+    tree.root.children.retain(|n| n.base_name() != "memory" && n.prop_str("device_type") != Some("memory"));
+    tree.root.children.push(memory);
+    Ok(())
+}
+
+// A cpu node for each CPU of the cell. Nodes of the device tree for the same CPU are kept, the
+// others are copied from the first one
+fn patch_cpus(tree: &mut Fdt, c: &configGenerator::Backendconfig) -> Result<(), String> {
+    let cpus = tree.root.child_mut("cpus").ok_or("no /cpus node in the device tree")?;
+    let address_cells = cpus.prop_u32("#address-cells").unwrap_or(1);
+    let (old, others): (Vec<Node>, Vec<Node>) = cpus.children.drain(..).partition(|n| n.base_name() == "cpu");
+    let template = old.first().ok_or("no cpu node in /cpus of the device tree")?;
+
+    let mut nodes = Vec::new();
+    for id in cpu_ids(c) {
+        let mut node = match old.iter().find(|n| n.prop("reg").map(fdt::to_cells).and_then(|r| r.last().copied()) == Some(id)) {
+            Some(node) => node.clone(),
+            None => {
+                let mut node = template.clone();
+                node.remove_prop("phandle");
+                node.remove_prop("linux,phandle");
+                node
+            }
+        };
+        node.name = format!("cpu@{:x}", id);
+        node.set_prop("reg", fdt::cells(&fdt::number_cells(id as u64, address_cells)));
+        nodes.push(node);
+    }
+    if nodes.is_empty() {
+        return Err("the cell has no CPU".to_string());
+    }
+    cpus.children = nodes.into_iter().chain(others).collect();
+    Ok(())
+}
+
+// The virtual PCI host bridge: removed if the cell has no PCI device, otherwise its INTx are
+// routed to the interrupts of the cell, vpci_irq_base + pin - 1
+fn patch_pci(tree: &mut Fdt, c: &configGenerator::Backendconfig) -> Result<(), String> {
+    let Some(index) = tree.root.children.iter().position(|n| n.prop_str("device_type") == Some("pci")) else { return Ok(()) };
+    if c.desc.pci_devices.is_empty() {
+        tree.root.children.remove(index);
+        return Ok(());
+    }
+    let pci = &tree.root.children[index];
+    let Some(map) = pci.prop("interrupt-map").map(fdt::to_cells) else { return Ok(()) };
+    let child_cells = pci.prop_u32("#address-cells").unwrap_or(3) as usize;
+    let child_int_cells = pci.prop_u32("#interrupt-cells").unwrap_or(1) as usize;
+
+    let mut patched = map.clone();
+    let mut pos = 0;
+    while pos < map.len() {
+        let pin_at = pos + child_cells;
+        let phandle = *map.get(pin_at + child_int_cells).ok_or("truncated interrupt-map of the PCI host")?;
+        let parent = tree.root.find_phandle(phandle).ok_or_else(|| format!("interrupt-map of the PCI host refers to the missing phandle {}", phandle))?;
+        let parent_cells = parent.prop_u32("#address-cells").unwrap_or(0) as usize;
+        let parent_int_cells = parent.prop_u32("#interrupt-cells").ok_or("interrupt parent of the PCI host without #interrupt-cells")? as usize;
+        let spec = pin_at + child_int_cells + 1 + parent_cells;
+        if spec + parent_int_cells > map.len() {
+            return Err("truncated interrupt-map of the PCI host".to_string());
+        }
+        if parent_int_cells >= 2 && map[spec] == GIC_SPI && (1..=4).contains(&map[pin_at]) {
+            patched[spec + 1] = c.desc.header.vpci_irq_base + map[pin_at] - 1;
+        }
+        pos = spec + parent_int_cells;
+    }
+    tree.root.children[index].set_prop("interrupt-map", fdt::cells(&patched));
+    Ok(())
+}
+
+// Patches the device tree with the resources of the cell and the command line
+pub fn patch_dtb(tree: &mut Fdt, c: &configGenerator::Backendconfig, cmdline: &str) -> Result<(), String> {
+    patch_memory(tree, c)?;
+    patch_cpus(tree, c)?;
+    patch_pci(tree, c)?;
+    tree.root.child_or_insert("chosen").set_prop("bootargs", fdt::string(cmdline));
+    Ok(())
+}
+
+// Writes the command line of the kernel, and on arm the patched device tree, to the run directory
+// of the container. The device tree of the cell becomes the patched one
+pub fn linuxconfig(
+    c: &configGenerator::Backendconfig,
+    fc: &f2b::FrontendConfig,
+    ic: &mut f2b::ImageConfig,
+    net: Option<&NetConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let platform: Value = fs::read_to_string(Path::new(WORKPATH).join(CONFIG_FILE))?.parse()?;
+    let cmdline = cmdline(c, ic, &platform_console(&platform)?, net, &fc.cellname);
+    let crundir = Path::new(&fc.crundir);
+    fs::write(crundir.join(CMDLINE_FILE), &cmdline)?;
+    logging::log_message(logging::Level::Debug, format!("Kernel command line of id {}: {}", &fc.containerid, cmdline).as_str());
+
+    if c.arch == configGenerator::Arch::X86 {
+        return Ok(());
+    }
+    let dtb = ic.dtb.as_ref().ok_or("No device tree for the linux cell")?;
+    let data = fs::read(dtb.as_path()).map_err(|e| format!("Cannot read the device tree {}: {}", dtb, e))?;
+    let mut tree = fdt::parse(&data).map_err(|e| format!("Invalid device tree {}: {}", dtb, e))?;
+    patch_dtb(&mut tree, c, &cmdline).map_err(|e| format!("Cannot patch the device tree {}: {}", dtb, e))?;
+    let path = crundir.join(DTB_FILE);
+    fs::write(&path, fdt::serialize(&tree))?;
+    ic.dtb = Some(f2b::ImagePath::host(path));
+    Ok(())
+}
@@ -3,7 +3,6 @@
 //          Francesco Boccola (francesco.boccola@unina.it)
 //*********************************************
 
-use regex::Regex;
 use std::error::Error;
 //use std::fs::OpenOptions; //DEBUG
 //use std::fs::File;
@@ -15,7 +14,10 @@ use std::path::Path;
 //use std::collections::HashMap;
 
 use crate::configGenerator;
-//use f2b;
+use crate::configGenerator::celldesc::{CellDesc, JAILHOUSE_MEM_COMM_REGION};
+use crate::configGenerator::communication;
+use crate::configGenerator::memalloc;
+use f2b;
 
 //use crate::configGenerator::templates::{RAM_TEMPLATE, UART_TEMPLATE};
 use crate::configGenerator::templates::*;
@@ -30,105 +32,81 @@ fn load_config(file_path: &Path) -> Result<Value, Box<dyn std::error::Error>> {
     Ok(config)
 }
 
-// Replaces the placeholders in the template with the actual values from the `params` string
-fn generate_mem_region(template: &str, params: &str) -> String {
-    let mut filled_template = template.to_string();
-
-    // If params are empty, skip the parameter replacement logic
-    if params.is_empty() {
-        return filled_template;
-    }
-
-    // Split the params string and replace each placeholder in the template
-    for param in params.split(", ") {
-        let mut key_value = param.split('=');
-
-        let key = match key_value.next() {
-            Some(k) => k.trim(),
-            None => {
-                eprintln!("Warning: Malformed parameter (missing key): {}", param);
-                continue;
-            }
-        };
-
-        let value = match key_value.next() {
-            Some(v) => v.trim(),
-            None => {
-                eprintln!("Warning: Malformed parameter (missing value for key {}): {}", key, param);
-                continue;
-            }
-        };
-
-        filled_template = filled_template.replace(&format!("{{{}}}", key), value);
-    }
-
-    filled_template
+// Where the RAM of the cell lies: the physical start and the size of each of its pieces, mapped
+// one after the other in the cell, the physical start and the size of the low RAM, and the
+// virtual start of the RAM when the load address of the binary decides it
+struct RamLayout {
+    pieces: Vec<(u64, u64)>,
+    low_ram_start: u64,
+    low_ram_size: u64,
+    virt_start: Option<u64>,
 }
 
-
-
-// Generates the final configuration string by replacing the placeholders with the correct values
+// Fills in the memory region templates with the correct values and adds the regions to the cell
 fn generate_config(
     config: &Value,
-    mem_request_hex: &str,
+    c: &mut configGenerator::Backendconfig,
     skip_ivshmem: bool,
-    base_address: &str,
-    ram0_phys_start: &str,
-    ram_phys_start: &str,
-) -> Result<String, String> {
-    let mut result = String::new();
-    let templates_map = get_templates_map(); // Retrieve the map of templates
+    ram: &RamLayout,
+) -> Result<(), String> {
+    let templates_map = get_platform_templates(config, Path::new(TEMPLATE_DIR))?; // Retrieve the map of templates
+    // Virtual ranges of the RAM and of the low RAM, which must not overlap
+    let mut ram_ranges: Vec<(&str, u64, u64)> = Vec::new();
 
     // Safely check if the mem_regions exist in the config
     if let Some(mem_regions) = config.get("mem_regions") {
         if let Some(regions) = mem_regions.get("regions").and_then(|r| r.as_array()) {
             for region in regions {
                 if let Some(template_name) = region.as_str() {
-                    // Skip IVSHMEM templates if `skip_ivshmem` is true
-                    if skip_ivshmem
-                        && (template_name == "IVSHMEM_DEMO_TEMPLATE" || template_name == "IVSHMEM_TEMPLATE")
-                    {
+                    // Skip the regions of the demo device if `skip_ivshmem` is true
+                    if skip_ivshmem && template_name == "IVSHMEM_DEMO_TEMPLATE" {
                         continue;
                     }
 
                     // Look up the template in the templates_map
-                    let mut template = match templates_map.get(template_name) {
-                        Some(&template) => template.to_string(),
+                    let template = match templates_map.get(template_name) {
+                        Some(template) => template,
                         None => return Err(format!("Unknown template: {}", template_name)),
                     };
 
-                    // Apply specific replacements for RAM and IVSHMEM templates
-                    if template_name == "RAM0_TEMPLATE" {
-                        template = template.replace("{phys_start}", ram0_phys_start);
-                    }
-                    if template_name == "RAM_TEMPLATE" {
-                        template = template
-                            .replace("{phys_start}", ram_phys_start)
-                            .replace("{size}", mem_request_hex);
-                    }
-                    if template_name == "IVSHMEM_TEMPLATE" {
-                        template = template.replace("{address}", base_address);
-                    }
-
-                    // Auto indent templates
-                    template = template
-                    .lines()
-                    .map(|line| format!("\t\t{}", line))
-                    .collect::<Vec<String>>()
-                    .join("\n");
-
-                    // Process additional parameters from the config
-                    if let Some(params) = config.get(template_name) {
-                        let params_str = params.as_table()
-                            .ok_or_else(|| format!("Expected table for template: {}", template_name))?
-                            .iter()
-                            .map(|(k, v)| format!("{}={}", k, v.as_str().unwrap_or("")))
-                            .collect::<Vec<_>>()
-                            .join(", ");
-                        result.push_str(&generate_mem_region(&template, &params_str));
-                    } else {
-                        // Append templates with no params directly
-                        result.push_str(&template);
+                    // The RAM template is filled in once for each piece of the RAM
+                    let instances = if MAIN_RAM_TEMPLATES.contains(&template_name) { ram.pieces.len() } else { 1 };
+                    // The RAM starts at the load address of the binary, if any, and each piece
+                    // follows the previous one
+                    let mut next_virt_start = ram.virt_start;
+                    for i in 0..instances {
+                        // Values computed by runPHI for the RAM templates
+                        let computed: Vec<(&str, String)> = match template_name {
+                            _ if LOW_RAM_TEMPLATES.contains(&template_name) => vec![
+                                ("phys_start", format!("0x{:x}", ram.low_ram_start)),
+                                ("size", format!("0x{:x}", ram.low_ram_size)),
+                            ],
+                            _ if MAIN_RAM_TEMPLATES.contains(&template_name) => {
+                                let (phys_start, size) = ram.pieces[i];
+                                let mut computed = vec![
+                                    ("phys_start", format!("0x{:x}", phys_start)),
+                                    ("size", format!("0x{:x}", size)),
+                                ];
+                                if let Some(virt_start) = next_virt_start {
+                                    computed.push(("virt_start", format!("0x{:x}", virt_start)));
+                                }
+                                computed
+                            }
+                            _ => Vec::new(),
+                        };
+                        let template = render_template(template_name, template, config.get(template_name), &computed)?;
+                        let mut regions = CellDesc::default();
+                        regions.add_mem_regions(&template)
+                            .map_err(|e| format!("Template {}: {}", template_name, e))?;
+                        if LOW_RAM_TEMPLATES.contains(&template_name) || MAIN_RAM_TEMPLATES.contains(&template_name) {
+                            for r in &regions.mem_regions {
+                                ram_ranges.push((template_name, r.virt_start, r.virt_start.saturating_add(r.size)));
+                                next_virt_start = Some(r.virt_start.saturating_add(r.size));
+                            }
+                        }
+                        // The UART is mapped only in the cell owning it
+                        regions.mem_regions.retain(|r| !communication::maps_uart(c, r));
+                        c.desc.mem_regions.extend(regions.mem_regions);
                     }
                 } else {
                     return Err("Region is not a string".to_string());
@@ -141,185 +119,137 @@ fn generate_config(
         return Err("mem_regions key missing in config".to_string());
     }
 
-    Ok(result)
+    for (i, (name, start, end)) in ram_ranges.iter().enumerate() {
+        if let Some((other, _, _)) = ram_ranges[i + 1..].iter().find(|(_, s, e)| s < end && start < e) {
+            return Err(format!("the virtual range 0x{:x}-0x{:x} of {} overlaps the one of {}", start, end, name, other));
+        }
+    }
+
+    Ok(())
 }
 
 
+// Size of the communication regions among the regions of the platform, provided by the hypervisor
+fn comm_region_size(config: &Value) -> Result<u64, String> {
+    let templates_map = get_platform_templates(config, Path::new(TEMPLATE_DIR))?;
+    let mut size = 0;
+    let names = config.get("mem_regions").and_then(|m| m.get("regions")).and_then(|r| r.as_array());
+    for name in names.into_iter().flatten().filter_map(|r| r.as_str()) {
+        // The RAM regions take computed values, and neither they nor the IVSHMEM ones are
+        // communication regions
+        if LOW_RAM_TEMPLATES.contains(&name) || MAIN_RAM_TEMPLATES.contains(&name) || name.starts_with("IVSHMEM_") {
+            continue;
+        }
+        let Some(template) = templates_map.get(name) else { continue };
+        let template = render_template(name, template, config.get(name), &[])?;
+        let mut regions = CellDesc::default();
+        regions.add_mem_regions(&template).map_err(|e| format!("Template {}: {}", name, e))?;
+        size += regions.mem_regions.iter().filter(|r| r.flags & JAILHOUSE_MEM_COMM_REGION != 0).map(|r| r.size).sum::<u64>();
+    }
+    Ok(size)
+}
+
+// Reserves the memory of the cell out of the free segments and adds its regions. The RAM starts
+// at the reserved segment, aligned as the platform asks, and is followed by the low RAM, if any.
+// `requested` is the memory limit of the container, in bytes
 pub fn memconfig(
     c: &mut configGenerator::Backendconfig,
-    mem_request_hex: &str,
+    ic: &f2b::ImageConfig,
+    requested: Option<u64>,
 ) -> Result<(), Box<dyn Error>> {
-    let file_path = Path::new(WORKPATH).join(format!("platform_info.toml"));
-
-    // // Insert line into the config file
-    // let pattern = r"__u64 rcpus\[\d*\];";
-    // let num_regions = match count_mem_regions(&file_path) {
-    // Ok(count) => count,
-    // Err(e) => {
-    //     eprintln!("Error counting memory regions: {}", e);
-    //     return Err(e.into()); // Propagate the error
-    //     }
-    // };
-    // // Use format! to include the variable
-    // let linetoinsert = format!("\tstruct jailhouse_memory mem_regions[{}];", num_regions); 
-
-    // // Compile a regular expression to match the pattern and insert the mem_regions
-    // let re = Regex::new(&pattern)?;
-    // if let Some(pos) = re.find(&c.conf) {
-    //     c.conf
-    //         .insert_str(pos.end(), &format!("\n{}\n", linetoinsert));
-    // } else {
-    //     return Err("\"pattern __u64 rcpus\" not found".into());
-    // }
-
-    // Define both patterns
-    let pattern1 = r"__u64 rcpus\[\d*\];";
-    let pattern2 = r"__u64 cpus\[\d*\];";
-
-    // Compile both regexes
-    let re1 = Regex::new(pattern1)?;
-    let re2 = Regex::new(pattern2)?;
+    let file_path = Path::new(WORKPATH).join("platform_info.toml");
+    let config = load_config(&file_path)?;
+
+    // Check if we need to skip the demo IVSHMEM regions based on `c.net`. The regions of the
+    // networking device come with its link, see ivshmem.rs
+    let skip_ivshmem = c.net == "none";
+
+    // Check if a low RAM template (RAM0_TEMPLATE, X86_LOW_RAM_TEMPLATE) is present in the regions list
+    let low_ram_template = config.get("mem_regions")
+    .and_then(|mem_regions| mem_regions.get("regions"))
+    .and_then(|regions| regions.as_array())
+    .and_then(|regions| regions.iter().filter_map(|r| r.as_str()).find(|r| LOW_RAM_TEMPLATES.contains(r)));
+
+    // The low RAM takes the size in its table, 0x10000 by default
+    let low_ram_size = match low_ram_template {
+        Some(name) => match config.get(name).and_then(|t| t.get("size")) {
+            Some(Value::String(size)) => parse_number(size.trim()).ok_or(format!("[{}] size is not a number", name))?,
+            Some(Value::Integer(size)) => u64::try_from(*size)?,
+            Some(_) => return Err(format!("[{}] size is not a number", name).into()),
+            None => LOW_RAM_DEFAULT_SIZE,
+        },
+        None => 0,
+    };
 
-    // Count memory regions (as in your original snippet)
-    let num_regions = match count_mem_regions(&file_path) {
-        Ok(count) => count,
-        Err(e) => {
-            eprintln!("Error counting memory regions: {}", e);
-            return Err(e.into());
+    // Size the RAM, accounting for the low RAM and the communication region too
+    let policy = memalloc::platform_memory(&config)?;
+    let overhead = low_ram_size + comm_region_size(&config)?;
+    let mem_request_size = memalloc::ram_size(&policy, requested, overhead)?;
+    logging::log_message(logging::Level::Debug, format!("Memory of the cell: 0x{:x} bytes of RAM and 0x{:x} of overhead", mem_request_size, overhead).as_str());
+
+    // The load address of the binary is the virtual start of the RAM. Without an MMU (RPUs, or
+    // cores with an MPU only) the binary runs at physical addresses, so the RAM is placed there
+    let load_address = ic.starting_vaddress.map(|address| address.0);
+    if let Some(address) = load_address.filter(|address| address % memalloc::PAGE_SIZE != 0) {
+        return Err(format!("starting_vaddress 0x{:x} is not page aligned", address).into());
+    }
+    let physical = c.rpu_req || !policy.mmu;
+    let fixed = match (load_address.filter(|_| physical), ic.phys_address.map(|address| address.0)) {
+        (Some(load), Some(phys)) if load != phys => {
+            return Err(format!("starting_vaddress 0x{:x} differs from phys_address 0x{:x}, but the cell has no MMU", load, phys).into());
         }
+        (Some(load), _) => Some(load),
+        (None, phys) => phys,
     };
-
-    // Create the line to insert using the count
-    let linetoinsert = format!("\tstruct jailhouse_memory mem_regions[{}];", num_regions);
-
-    // Try to find the first pattern; if not found, try the second
-    let pos = if let Some(m) = re1.find(&c.conf) {
-        m
-    } else if let Some(m) = re2.find(&c.conf) {
-        m
-    } else {
-        return Err("Neither pattern __u64 rcpus nor __u64 cpus found".into());
+    let request = memalloc::Request {
+        size: mem_request_size + low_ram_size,
+        align: policy.align,
+        guard: policy.guard,
+        fixed,
     };
 
-    // Insert the new line after the matched pattern
-    c.conf.insert_str(pos.end(), &format!("\n{}\n", linetoinsert));
-
-
-    match load_config(&file_path) {
-        Ok(config) => {
-            // Check if we need to skip IVSHMEM templates based on `c.net`
-            let skip_ivshmem = c.net == "none";
-
-            // Calculate the base address based on the lowest value in c.bdf
-            if let Some(&min_bdf) = c.bdf.iter().filter(|&&b| b > 0).min() {
-                // Retrieve the base address from IVSHMEM_TEMPLATE in the TOML configuration
-                let base_address = config
-                .get("IVSHMEM_TEMPLATE")
-                .and_then(|section| section.get("address"))
-                .and_then(|addr| addr.as_str())
-                .ok_or("IVSHMEM_TEMPLATE address not found in configuration")?;
-
-                // Parse the base address from hex string to u64
-                let base_address = u64::from_str_radix(base_address.trim_start_matches("0x"), 16)? + ((min_bdf as u64 - 1) * 0x100000);
-
-                let address_hex = format!("0x{:x}", base_address);
-
-
-                // Check if RAM0_TEMPLATE is present in the regions list
-                let has_ram0_template = config.get("mem_regions")
-                .and_then(|mem_regions| mem_regions.get("regions"))
-                .and_then(|regions| regions.as_array())
-                .map_or(false, |regions| regions.iter().any(|r| r.as_str() == Some("RAM0_TEMPLATE")));
-                
-                // Calculate required memory size in hexadecimal, adding 0x10000 for RAM0_TEMPLATE if present
-                let mem_request_size = u64::from_str_radix(&mem_request_hex.trim_start_matches("0x"), 16)?;
-                let required_size = if has_ram0_template {
-                    mem_request_size + 0x10000
-                } else {
-                    mem_request_size
-                };
-
-                // Find a suitable segment in `c.segments`
-                if let Some((segment_index, chosen_segment)) = c.segments.iter().enumerate()
-                    .find_map(|(i, seg)| {
-                        let parts: Vec<&str> = seg.split(", ").collect();
-                        if parts.len() != 2 { return None; }  // Segment is not in the correct format
-
-                        // Parse start and end of segment
-                        let start = u64::from_str_radix(parts[0].trim_start_matches("0x"), 16).ok()?;
-                        let end = u64::from_str_radix(parts[1].trim_start_matches("0x"), 16).ok()?;
-                        
-                        // Check if the segment is large enough
-                        if end - start >= required_size {
-                            Some((i, (start, end)))
-                        } else {
-                            None
-                        }
-                    })
-                {
-                    // Update the chosen segment's start address and push remaining free space back to `c.segments`
-                    let new_start = chosen_segment.0 + required_size;
-                    c.segments[segment_index] = format!("0x{:x}, 0x{:x}", new_start, chosen_segment.1);
-
-                    // Set the physical start addresses
-                    let ram0_phys_start = format!("0x{:x}", chosen_segment.0);
-                    let ram_phys_start = if has_ram0_template {
-                        format!("0x{:x}", chosen_segment.0 + 0x10000)
-                    } else {
-                        ram0_phys_start.clone()
-                    };
-
-                    match generate_config(&config, mem_request_hex, skip_ivshmem, &address_hex, &ram0_phys_start, &ram_phys_start) {
-                        Ok(config_string) => {
-                            // Wrap the configuration in the .mem_regions jailhouse field
-                            let wrapped_config = format!(
-                                "\n\t.mem_regions = {{{}\n\t}},\n",
-                                config_string
-                            );
-                            // Append the generated configuration to c.conf
-                            c.conf.push_str(&wrapped_config);
-                            
-                        }
-                        Err(e) => eprintln!("Error generating config: {}", e),
-                    }
-                } else {
-                    eprintln!("Error: No suitable segment found in `c.segments` for required memory size.");
-                }
-            } else {
-                eprintln!("Error: No valid bdf values found in `c.bdf`");
+    // Reserve it in the free segments, they are saved back to the state with the reserved ones
+    let ram = match memalloc::allocate(&mut c.segments, &request) {
+        Ok(reserved) => {
+            c.used_memory = vec![reserved];
+            RamLayout {
+                pieces: vec![(reserved.0, mem_request_size)],
+                low_ram_start: reserved.0 + mem_request_size,
+                low_ram_size,
+                virt_start: load_address,
             }
         }
-        Err(e) => eprintln!("Error loading config: {}", e),
-    }
-
-    Ok(())
-}
-
-fn count_mem_regions<P: AsRef<Path>>(file_path: P) -> Result<usize, Box<dyn std::error::Error>> {
-    // Read the content of the file
-    let content = fs::read_to_string(file_path)?;
-    
-    // Parse the content as TOML
-    let toml_value: Value = content.parse()?;
-    
-    // Access the "mem_regions" table
-    let mem_regions = toml_value
-        .get("mem_regions")
-        .and_then(|table| table.get("regions"))
-        .and_then(|regions| regions.as_array())
-        .ok_or("Failed to read 'mem_regions.regions' as an array")?;
-
-    // Count the regions with specific rules
-    let mut count = 0;
-    for region in mem_regions {
-        if let Some(region_name) = region.as_str() {
-            match region_name {
-                "IVSHMEM_DEMO_TEMPLATE" => count += 5,
-                "IVSHMEM_TEMPLATE" => count += 4,
-                _ => count += 1,
+        Err(e) if fixed.is_some() => {
+            return Err(match (load_address, physical) {
+                (Some(load), true) => format!("Cannot place the RAM at the load address 0x{:x} of the binary: {}", load, e),
+                _ => format!("Cannot place the RAM at the address asked for: {}", e),
+            }.into());
+        }
+        // No free segment can host the RAM whole: spread it over several segments, only when the
+        // cell translates addresses, so that it still sees a contiguous RAM
+        Err(e) if physical || policy.ram_regions == 1 => {
+            return Err(format!("Not enough free memory for 0x{:x} bytes of RAM and low RAM: {}", request.size, e).into());
+        }
+        Err(_) => {
+            let mut free = c.segments.clone();
+            let low_ram = match low_ram_size {
+                0 => None,
+                size => Some(memalloc::allocate(&mut free, &memalloc::Request { size, align: memalloc::PAGE_SIZE, guard: 0, fixed: None })?),
+            };
+            let pieces = memalloc::allocate_scattered(&mut free, mem_request_size, policy.align, policy.guard, policy.ram_regions)
+                .map_err(|e| format!("Not enough free memory for 0x{:x} bytes of RAM: {}", mem_request_size, e))?;
+            logging::log_message(logging::Level::Debug, format!("RAM of the cell spread over {} regions", pieces.len()).as_str());
+            c.segments = free;
+            c.used_memory = pieces.iter().copied().chain(low_ram).collect();
+            RamLayout {
+                pieces: pieces.iter().map(|(start, end)| (*start, end - start - policy.guard)).collect(),
+                low_ram_start: low_ram.map(|r| r.0).unwrap_or(0),
+                low_ram_size,
+                virt_start: load_address,
             }
         }
-    }
+    };
+    generate_config(&config, c, skip_ivshmem, &ram)?;
 
-    Ok(count)
+    Ok(())
 }
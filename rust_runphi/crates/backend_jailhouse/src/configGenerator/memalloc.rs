@@ -0,0 +1,341 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Allocator of the physical memory of the cells, out of the free segments of state.toml.
+// The segments are [start, end) ranges, stored in the state as "0x..., 0x..." strings.
+// A request is placed best-fit: in the free segment it leaves the least space in, at the end of
+// the segment leaving no fragment behind when the alignment allows it, so that large segments
+// stay whole for large cells. The [memory] section of platform_info.toml is optional:
+//   [memory]
+//   alignment = "0x200000"   alignment of the RAM of the cells, the page size by default
+//   guard = "0x100000"       gap kept free after the RAM of each cell, none by default
+//   mmu = false              the cores of the cells have no MMU, only an MPU, true by default
+//   default = "0x4000000"    RAM of the cells without a memory limit, 64 MiB by default
+//   min = "0x1000000"        memory of a cell, overhead included: smaller requests are raised
+//   max = "0x40000000"       memory of a cell, overhead included: larger requests are rejected
+//   ram_regions = 4          RAM regions a cell can be given when no free segment can host its
+//                            RAM whole, 4 by default, 1 to always keep the RAM contiguous
+// The memory limit of the container is the RAM of the cell, rounded up to the page size. The
+// overhead is the memory the cell takes besides it: the low RAM and the communication region.
+
+use toml::Value;
+
+use crate::configGenerator::templates::parse_number;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+const DEFAULT_RAM_SIZE: u64 = 0x4000000;
+const DEFAULT_RAM_REGIONS: u64 = 4;
+
+// A [start, end) physical range
+pub type Segment = (u64, u64);
+
+// Placement of the RAM of a cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    pub size: u64,
+    // Alignment of the start, a power of two
+    pub align: u64,
+    // Free gap kept after the RAM, reserved with it
+    pub guard: u64,
+    // Physical address the RAM must start at, for binaries that cannot be relocated
+    pub fixed: Option<u64>,
+}
+
+// Placement policy of the [memory] section of the platform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub align: u64,
+    pub guard: u64,
+    // Whether the cores of the cells translate addresses. Without an MMU the binaries run at
+    // physical addresses, and are placed physically at their load address
+    pub mmu: bool,
+    pub default_size: u64,
+    pub min: u64,
+    pub max: Option<u64>,
+    pub ram_regions: usize,
+}
+
+// Parses a "start, end" segment, as in [free_segments] and in the sections of the containers
+pub fn parse_segment(text: &str) -> Result<Segment, String> {
+    let parts: Vec<&str> = text.split(',').map(|p| p.trim()).collect();
+    if parts.len() != 2 {
+        return Err(format!("\"{}\" is not a \"start, end\" range", text));
+    }
+    let start = parse_number(parts[0]).ok_or_else(|| format!("{} is not a number", parts[0]))?;
+    let end = parse_number(parts[1]).ok_or_else(|| format!("{} is not a number", parts[1]))?;
+    if start > end {
+        return Err(format!("start 0x{:x} is above end 0x{:x}", start, end));
+    }
+    Ok((start, end))
+}
+
+pub fn format_segment(segment: &Segment) -> String {
+    format!("0x{:x}, 0x{:x}", segment.0, segment.1)
+}
+
+pub fn platform_memory(platform: &Value) -> Result<Policy, String> {
+    let section = platform.get("memory");
+    let field = |name: &str, default: u64| -> Result<u64, String> {
+        match section.and_then(|s| s.get(name)) {
+            Some(Value::String(value)) => parse_number(value.trim()).ok_or(format!("[memory] {} is not a number", name)),
+            Some(Value::Integer(value)) => u64::try_from(*value).map_err(|_| format!("[memory] {} is negative", name)),
+            Some(_) => Err(format!("[memory] {} must be a number", name)),
+            None => Ok(default),
+        }
+    };
+    let align = field("alignment", PAGE_SIZE)?;
+    if !align.is_power_of_two() || align < PAGE_SIZE {
+        return Err(format!("[memory] alignment 0x{:x} is not a power of two of at least the page size", align));
+    }
+    let guard = field("guard", 0)?;
+    if guard % PAGE_SIZE != 0 {
+        return Err(format!("[memory] guard 0x{:x} is not page aligned", guard));
+    }
+    let default_size = field("default", DEFAULT_RAM_SIZE)?;
+    let min = field("min", 0)?;
+    let max = match section.and_then(|s| s.get("max")) {
+        Some(_) => Some(field("max", 0)?),
+        None => None,
+    };
+    if let Some(max) = max.filter(|max| *max < min) {
+        return Err(format!("[memory] max 0x{:x} is below min 0x{:x}", max, min));
+    }
+    let ram_regions = field("ram_regions", DEFAULT_RAM_REGIONS)?;
+    if ram_regions == 0 {
+        return Err("[memory] ram_regions must be at least 1".to_string());
+    }
+    let mmu = match section.and_then(|s| s.get("mmu")) {
+        Some(Value::Boolean(mmu)) => *mmu,
+        Some(_) => return Err("[memory] mmu must be true or false".to_string()),
+        None => true,
+    };
+    Ok(Policy { align, guard, mmu, default_size, min, max, ram_regions: ram_regions as usize })
+}
+
+fn align_up(value: u64, align: u64) -> Option<u64> {
+    value.checked_add(align - 1).map(|v| v & !(align - 1))
+}
+
+// Size of the RAM of a cell asked `requested` bytes of memory, or none, with `overhead` bytes
+// taken besides the RAM
+pub fn ram_size(policy: &Policy, requested: Option<u64>, overhead: u64) -> Result<u64, String> {
+    let requested = requested.unwrap_or(policy.default_size);
+    let mut size = align_up(requested, PAGE_SIZE).ok_or(format!("0x{:x} bytes of memory are too many", requested))?;
+    if size + overhead < policy.min {
+        size = align_up(policy.min - overhead, PAGE_SIZE).unwrap_or(policy.min);
+    }
+    if let Some(max) = policy.max.filter(|max| size + overhead > *max) {
+        return Err(format!(
+            "the cell needs 0x{:x} bytes of memory (0x{:x} of RAM and 0x{:x} of overhead), above the maximum of the platform 0x{:x}",
+            size + overhead, size, overhead, max
+        ));
+    }
+    Ok(size)
+}
+
+// Reserves the memory of a request in the free segments, returning the reserved segment:
+// the RAM, starting at its beginning, followed by the guard gap
+pub fn allocate(free: &mut Vec<Segment>, request: &Request) -> Result<Segment, String> {
+    let length = request.size.checked_add(request.guard).ok_or("memory request too large")?;
+    if request.size == 0 {
+        return Err("empty memory request".to_string());
+    }
+
+    let start = match request.fixed {
+        Some(address) => {
+            if address % PAGE_SIZE != 0 {
+                return Err(format!("the address 0x{:x} is not page aligned", address));
+            }
+            let end = address.checked_add(length).ok_or("memory request too large")?;
+            if !free.iter().any(|(s, e)| *s <= address && end <= *e) {
+                return Err(format!("0x{:x}-0x{:x} is not free", address, end));
+            }
+            address
+        }
+        None => {
+            // Best fit: the smallest segment that can host the request, then the placement
+            // leaving the fewest fragments, then the lowest address
+            let mut best: Option<(u64, usize, u64)> = None;
+            for (s, e) in free.iter() {
+                let Some(low) = align_up(*s, request.align) else { continue };
+                if low.checked_add(length).is_none_or(|end| end > *e) {
+                    continue;
+                }
+                let high = (*e - length) & !(request.align - 1);
+                for candidate in [low, high] {
+                    let fragments = (candidate > *s) as usize + (candidate + length < *e) as usize;
+                    let key = (*e - *s, fragments, candidate);
+                    if best.is_none_or(|b| key < b) {
+                        best = Some(key);
+                    }
+                }
+            }
+            match best {
+                Some((_, _, start)) => start,
+                None => {
+                    let largest = free.iter().map(|(s, e)| e - s).max().unwrap_or(0);
+                    return Err(format!(
+                        "no free segment can host 0x{:x} bytes aligned to 0x{:x}, the largest free segment is 0x{:x} bytes",
+                        length, request.align, largest
+                    ));
+                }
+            }
+        }
+    };
+
+    // Carve the reserved segment out of the one hosting it
+    let reserved = (start, start + length);
+    let index = free.iter().position(|(s, e)| *s <= reserved.0 && reserved.1 <= *e).ok_or("internal error: segment not found")?;
+    let (s, e) = free.remove(index);
+    if reserved.1 < e {
+        free.insert(index, (reserved.1, e));
+    }
+    if s < reserved.0 {
+        free.insert(index, (s, reserved.0));
+    }
+    Ok(reserved)
+}
+
+// Reserves `size` bytes of RAM in at most `max_pieces` pieces, each aligned and followed by the
+// guard gap, when no free segment can host it whole. The largest free segments are taken first,
+// so that the cell gets as few regions as possible, and the last piece is placed best-fit.
+// Nothing is reserved if the request cannot be satisfied
+pub fn allocate_scattered(free: &mut Vec<Segment>, size: u64, align: u64, guard: u64, max_pieces: usize) -> Result<Vec<Segment>, String> {
+    let mut left_free = free.clone();
+    let mut pieces: Vec<Segment> = Vec::new();
+    let mut left = size;
+    while left > 0 {
+        // The largest piece of RAM a free segment can host
+        let room = left_free.iter()
+            .filter_map(|(s, e)| {
+                let start = align_up(*s, align)?;
+                let room = e.checked_sub(start)?.checked_sub(guard)? & !(PAGE_SIZE - 1);
+                Some(room).filter(|room| *room > 0)
+            })
+            .max();
+        let Some(room) = room else {
+            let total: u64 = free.iter().map(|(s, e)| e - s).sum();
+            return Err(format!("not enough free memory for 0x{:x} bytes, 0x{:x} bytes are free", size, total));
+        };
+        if pieces.len() == max_pieces {
+            return Err(format!("0x{:x} bytes cannot be gathered in {} regions, the free memory is too fragmented", size, max_pieces));
+        }
+        let piece = room.min(left);
+        pieces.push(allocate(&mut left_free, &Request { size: piece, align, guard, fixed: None })?);
+        left -= piece;
+    }
+    *free = left_free;
+    Ok(pieces)
+}
+
+// Gives a segment back to the free ones, merging the contiguous segments. A segment overlapping
+// a free one is rejected and the free segments are left untouched: it was released already, or
+// the state is corrupted
+pub fn release(free: &mut Vec<Segment>, segment: Segment) -> Result<(), String> {
+    if let Some(other) = free.iter().find(|(start, end)| *start < segment.1 && segment.0 < *end) {
+        return Err(format!("{} overlaps the free segment {}", format_segment(&segment), format_segment(other)));
+    }
+    free.push(segment);
+    free.sort_by_key(|&(start, _)| start);
+    let mut merged: Vec<Segment> = Vec::new();
+    for &(start, end) in free.iter().filter(|(start, end)| start < end) {
+        match merged.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => merged.push((start, end)),
+        }
+    }
+    *free = merged;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(size: u64) -> Request {
+        Request { size, align: PAGE_SIZE, guard: 0, fixed: None }
+    }
+
+    #[test]
+    fn allocate_takes_the_smallest_segment_that_fits() {
+        let mut free = vec![(0x0, 0x10000), (0x100000, 0x104000)];
+        assert_eq!(allocate(&mut free, &request(0x4000)), Ok((0x100000, 0x104000)));
+        assert_eq!(free, vec![(0x0, 0x10000)]);
+    }
+
+    #[test]
+    fn allocate_aligns_and_leaves_the_fewest_fragments() {
+        // Aligned at the start two fragments would be left, at the end only one
+        let mut free = vec![(0x1000, 0x10000)];
+        let aligned = Request { align: 0x4000, ..request(0x4000) };
+        assert_eq!(allocate(&mut free, &aligned), Ok((0xc000, 0x10000)));
+        assert_eq!(free, vec![(0x1000, 0xc000)]);
+        // Ties go to the lowest address
+        assert_eq!(allocate(&mut free, &request(0x2000)), Ok((0x1000, 0x3000)));
+        assert_eq!(free, vec![(0x3000, 0xc000)]);
+    }
+
+    #[test]
+    fn allocate_reserves_the_guard() {
+        let mut free = vec![(0x0, 0x10000)];
+        assert_eq!(allocate(&mut free, &Request { guard: 0x1000, ..request(0x2000) }), Ok((0x0, 0x3000)));
+        assert_eq!(free, vec![(0x3000, 0x10000)]);
+    }
+
+    #[test]
+    fn allocate_at_a_fixed_address() {
+        let mut free = vec![(0x0, 0x10000)];
+        let fixed = Request { fixed: Some(0x4000), ..request(0x1000) };
+        assert_eq!(allocate(&mut free, &fixed), Ok((0x4000, 0x5000)));
+        assert_eq!(free, vec![(0x0, 0x4000), (0x5000, 0x10000)]);
+        // Taken already, or across two free segments
+        assert!(allocate(&mut free, &fixed).is_err());
+        assert!(allocate(&mut free, &Request { fixed: Some(0x3000), ..request(0x3000) }).is_err());
+        assert!(allocate(&mut free, &Request { fixed: Some(0x800), ..request(0x1000) }).is_err());
+        assert_eq!(free, vec![(0x0, 0x4000), (0x5000, 0x10000)]);
+    }
+
+    #[test]
+    fn allocate_fails_when_exhausted() {
+        let mut free = vec![(0x0, 0x2000), (0x4000, 0x6000)];
+        assert!(allocate(&mut free, &request(0x3000)).is_err());
+        assert!(allocate(&mut free, &Request { guard: 0x1000, ..request(0x2000) }).is_err());
+        assert!(allocate(&mut free, &request(0)).is_err());
+        assert_eq!(free, vec![(0x0, 0x2000), (0x4000, 0x6000)]);
+    }
+
+    #[test]
+    fn allocate_scattered_takes_the_largest_segments_first() {
+        let mut free = vec![(0x0, 0x3000), (0x10000, 0x14000)];
+        assert_eq!(allocate_scattered(&mut free, 0x6000, PAGE_SIZE, 0, 4), Ok(vec![(0x10000, 0x14000), (0x0, 0x2000)]));
+        assert_eq!(free, vec![(0x2000, 0x3000)]);
+    }
+
+    #[test]
+    fn allocate_scattered_reserves_nothing_on_failure() {
+        let mut free = vec![(0x0, 0x3000), (0x10000, 0x14000)];
+        // Too fragmented, then not enough memory
+        assert!(allocate_scattered(&mut free, 0x6000, PAGE_SIZE, 0, 1).is_err());
+        assert!(allocate_scattered(&mut free, 0x8000, PAGE_SIZE, 0, 4).is_err());
+        assert_eq!(free, vec![(0x0, 0x3000), (0x10000, 0x14000)]);
+    }
+
+    #[test]
+    fn release_merges_the_contiguous_segments() {
+        let mut free = vec![(0x0, 0x10000)];
+        let reserved = allocate(&mut free, &Request { fixed: Some(0x4000), ..request(0x1000) }).unwrap();
+        assert_eq!(release(&mut free, reserved), Ok(()));
+        assert_eq!(free, vec![(0x0, 0x10000)]);
+        assert_eq!(release(&mut free, (0x20000, 0x21000)), Ok(()));
+        assert_eq!(free, vec![(0x0, 0x10000), (0x20000, 0x21000)]);
+    }
+
+    #[test]
+    fn release_rejects_an_overlap() {
+        let mut free = vec![(0x0, 0x1000), (0x2000, 0x3000)];
+        assert!(release(&mut free, (0x800, 0x1800)).is_err());
+        assert!(release(&mut free, (0x2000, 0x3000)).is_err());
+        assert_eq!(free, vec![(0x0, 0x1000), (0x2000, 0x3000)]);
+    }
+}
@@ -1,3 +1,500 @@
 //*********************************************
 // Authors: Marco Barletta (marco.barletta@unina.it)
 //*********************************************
+
+// Networking of the cells. Each networked cell gets an IVSHMEM networking device at a BDF of
+// [free_pci_devices_bdf], linked to the interface of the root cell at the same BDF. Every BDF
+// owns a /30 link out of the subnet of the node: the root cell side takes its first address, the
+// cell the second one, so that the addresses of a cell follow from its BDF. The [network] section
+// of platform_info.toml is optional:
+//   [network]
+//   subnet = "192.168.100.0/24"   subnet of the links, BDF n gets the n-th /30 of it
+//   interface = "enP1p0s{bdf}"    interface of the root cell linked to BDF {bdf}
+//   config_address = "0x7ffff000" where the cells find their network configuration block, the
+//                                 page below the communication region by default
+// The configuration of a cell (MAC, address, gateway) is handed to the guest as a page loaded in
+// the cell at config_address, or as /etc/runphi/network.conf appended to the initramfs of Linux.
+// In a Kubernetes pod the CNI plugin runs before the cell exists: it attaches the pod sandbox to
+// a BDF, recorded in state.toml until the plugin detaches it, and the cell of the pod takes that
+// BDF when created:
+//   [cni_attachments.<sandboxid>]
+//   bdf = 1
+//   pod_ip = "10.244.0.12"
+// The BDF goes back to the free ones once both the attachment and the container are gone.
+
+use std::error::Error;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use toml::map::Map;
+use toml::Value;
+
+use crate::configGenerator;
+use crate::configGenerator::celldesc::{MemRegion, JAILHOUSE_MEM_COMM_REGION, JAILHOUSE_MEM_LOADABLE, JAILHOUSE_MEM_READ};
+use crate::configGenerator::memalloc;
+use crate::configGenerator::templates::parse_number;
+use crate::statefile;
+use f2b;
+
+const WORKPATH: &str = "/usr/share/runPHI";
+const CONFIG_FILE: &str = "platform_info.toml";
+const ATTACHMENTS: &str = "cni_attachments";
+const DEFAULT_SUBNET: (Ipv4Addr, u8) = (Ipv4Addr::new(192, 168, 100, 0), 24);
+// Addresses of each link, a /30
+const LINK_SIZE: u32 = 4;
+const LINK_PREFIX: u8 = 30;
+// Annotations carrying the id of the pod sandbox of a container, for containerd and CRI-O
+const SANDBOX_ANNOTATIONS: [&str; 2] = ["io.kubernetes.cri.sandbox-id", "io.kubernetes.cri-o.SandboxID"];
+// Network configuration block, see NetConfig::block
+const BLOCK_MAGIC: &[u8; 8] = b"RPHINET\0";
+const BLOCK_VERSION: u32 = 1;
+const BLOCK_FLAG_STATIC: u32 = 1;
+const BLOCK_TEXT_OFFSET: usize = 64;
+// Files of the configuration in the run directory of the container, read when the cell is loaded
+pub const BLOCK_FILE: &str = "netconf.bin";
+pub const BLOCK_ADDRESS_FILE: &str = "netconf.address";
+const TEXT_FILE: &str = "network.conf";
+const INITRAMFS_FILE: &str = "initramfs.cpio";
+// Path of the configuration in the initramfs of Linux cells
+const INITRAMFS_DIRS: [&str; 2] = ["etc", "etc/runphi"];
+const INITRAMFS_PATH: &str = "etc/runphi/network.conf";
+
+// The [network] section of the platform
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    pub subnet: (Ipv4Addr, u8),
+    pub interface: String,
+    pub config_address: Option<u64>,
+}
+
+// Addresses of the link of a BDF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Link {
+    // Address of the interface of the root cell, the gateway of the cell
+    pub host: Ipv4Addr,
+    pub guest: Ipv4Addr,
+    pub prefix: u8,
+}
+
+// A pod sandbox attached to a BDF by the CNI plugin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub bdf: u32,
+    // Address given to the pod by the previous plugins, forwarded to the cell
+    pub pod_ip: Option<String>,
+}
+
+// Parses a subnet as "a.b.c.d/prefix"
+pub fn parse_subnet(text: &str) -> Result<(Ipv4Addr, u8), String> {
+    let (address, prefix) = text.trim().split_once('/').ok_or(format!("\"{}\" is not an a.b.c.d/prefix subnet", text))?;
+    let address: Ipv4Addr = address.parse().map_err(|_| format!("{} is not an IPv4 address", address))?;
+    let prefix: u8 = prefix.parse().ok().filter(|p| *p <= LINK_PREFIX).ok_or(format!("/{} is not a prefix up to /{}", prefix, LINK_PREFIX))?;
+    if u32::from(address) & !mask(prefix) != 0 {
+        return Err(format!("{} has host bits set for /{}", address, prefix));
+    }
+    Ok((address, prefix))
+}
+
+fn mask(prefix: u8) -> u32 {
+    if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) }
+}
+
+pub fn platform_network(platform: &Value) -> Result<Policy, String> {
+    let section = platform.get("network");
+    let subnet = match section.and_then(|s| s.get("subnet")) {
+        Some(Value::String(subnet)) => parse_subnet(subnet).map_err(|e| format!("[network] subnet: {}", e))?,
+        Some(_) => return Err("[network] subnet must be a string".to_string()),
+        None => DEFAULT_SUBNET,
+    };
+    let interface = match section.and_then(|s| s.get("interface")) {
+        Some(Value::String(interface)) if interface.contains("{bdf}") => interface.clone(),
+        Some(Value::String(interface)) => return Err(format!("[network] interface \"{}\" has no {{bdf}}", interface)),
+        Some(_) => return Err("[network] interface must be a string".to_string()),
+        // The vPCI bus of the cells is in domain 0 on x86, in domain 1 on the other boards
+        None if configGenerator::platform_arch(platform) == Ok(configGenerator::Arch::X86) => "enp0s{bdf}".to_string(),
+        None => "enP1p0s{bdf}".to_string(),
+    };
+    let config_address = match section.and_then(|s| s.get("config_address")) {
+        Some(Value::String(address)) => Some(parse_number(address.trim()).ok_or("[network] config_address is not a number")?),
+        Some(Value::Integer(address)) => Some(u64::try_from(*address).map_err(|_| "[network] config_address is negative")?),
+        Some(_) => return Err("[network] config_address must be a number".to_string()),
+        None => None,
+    };
+    if let Some(address) = config_address.filter(|a| a % memalloc::PAGE_SIZE != 0) {
+        return Err(format!("[network] config_address 0x{:x} is not page aligned", address));
+    }
+    Ok(Policy { subnet, interface, config_address })
+}
+
+// Reads the [network] section of the platform of the node
+pub fn node_network() -> Result<Policy, Box<dyn Error>> {
+    let platform: Value = fs::read_to_string(Path::new(WORKPATH).join(CONFIG_FILE))?.parse()?;
+    Ok(platform_network(&platform)?)
+}
+
+// Addresses of the link of the BDF
+pub fn link(policy: &Policy, bdf: u32) -> Result<Link, String> {
+    let (subnet, prefix) = policy.subnet;
+    let links = 1u64 << (LINK_PREFIX - prefix);
+    if bdf as u64 >= links {
+        return Err(format!("the subnet {}/{} has no link for BDF {}, it hosts {} links", subnet, prefix, bdf, links));
+    }
+    let base = u32::from(subnet) + bdf * LINK_SIZE;
+    Ok(Link { host: Ipv4Addr::from(base + 1), guest: Ipv4Addr::from(base + 2), prefix: LINK_PREFIX })
+}
+
+// Interface of the root cell linked to the BDF
+pub fn host_interface(policy: &Policy, bdf: u32) -> String {
+    policy.interface.replace("{bdf}", &bdf.to_string())
+}
+
+// Id of the pod sandbox of a container, from the annotations of its OCI config
+pub fn sandbox_id(oci: &serde_json::Value) -> Option<&str> {
+    SANDBOX_ANNOTATIONS.iter().find_map(|key| oci.pointer(&format!("/annotations/{}", key.replace('/', "~1"))).and_then(|v| v.as_str()))
+}
+
+// The attachment of the sandbox in the state, if any
+pub fn attachment(state: &Value, sandbox: &str) -> Option<Attachment> {
+    let entry = state.get(ATTACHMENTS)?.get(sandbox)?;
+    let bdf = entry.get("bdf")?.as_integer().and_then(|b| u32::try_from(b).ok())?;
+    let pod_ip = entry.get("pod_ip").and_then(|p| p.as_str()).map(String::from);
+    Some(Attachment { bdf, pod_ip })
+}
+
+// Whether an attachment holds the BDF
+pub fn attached(state: &Value, bdf: u32) -> bool {
+    state.get(ATTACHMENTS).and_then(|a| a.as_table())
+        .is_some_and(|a| a.values().any(|e| e.get("bdf").and_then(|b| b.as_integer()) == Some(bdf as i64)))
+}
+
+fn load_state() -> Result<Value, Box<dyn Error>> {
+    Ok(statefile::read()?.parse()?)
+}
+
+fn store_state(state: &Value) -> Result<(), Box<dyn Error>> {
+    statefile::write(&toml::to_string(state)?)
+}
+
+// Attaches the sandbox to the lowest free BDF, or returns its attachment if it has one already
+pub fn attach(sandbox: &str, pod_ip: Option<&str>) -> Result<Attachment, Box<dyn Error>> {
+    // The state is locked until written back, runPHI create may be taking a BDF meanwhile
+    let _lock = statefile::lock()?;
+    let mut state = load_state()?;
+    if let Some(attachment) = attachment(&state, sandbox) {
+        return Ok(attachment);
+    }
+    let free = state.get_mut("free_pci_devices_bdf").and_then(|f| f.get_mut("bdf")).and_then(|b| b.as_array_mut())
+        .ok_or("[free_pci_devices_bdf] bdf missing in the state")?;
+    let bdf = free.iter().filter_map(|b| b.as_integer()).min().ok_or("No free BDF left for the pod")?;
+    free.retain(|b| b.as_integer() != Some(bdf));
+
+    let mut entry = Map::new();
+    entry.insert("bdf".to_string(), Value::Integer(bdf));
+    if let Some(pod_ip) = pod_ip {
+        entry.insert("pod_ip".to_string(), Value::String(pod_ip.to_string()));
+    }
+    state.as_table_mut().ok_or("the state is not a table")?
+        .entry(ATTACHMENTS).or_insert_with(|| Value::Table(Map::new()))
+        .as_table_mut().ok_or(format!("[{}] is not a table", ATTACHMENTS))?
+        .insert(sandbox.to_string(), Value::Table(entry));
+    store_state(&state)?;
+    Ok(Attachment { bdf: bdf as u32, pod_ip: pod_ip.map(String::from) })
+}
+
+// The attachment of the sandbox in the state of the node, if any
+pub fn lookup(sandbox: &str) -> Result<Option<Attachment>, Box<dyn Error>> {
+    Ok(attachment(&load_state()?, sandbox))
+}
+
+// Detaches the sandbox, returning its attachment. The BDF is given back unless a container still
+// uses it, in which case the container gives it back when deleted
+pub fn detach(sandbox: &str) -> Result<Option<Attachment>, Box<dyn Error>> {
+    let _lock = statefile::lock()?;
+    let mut state = load_state()?;
+    let Some(attachment) = attachment(&state, sandbox) else { return Ok(None) };
+    if let Some(attachments) = state.get_mut(ATTACHMENTS).and_then(|a| a.as_table_mut()) {
+        attachments.remove(sandbox);
+    }
+    let used = state.as_table().is_some_and(|t| t.values().any(|section| {
+        section.get("pci_bdf").and_then(|b| b.as_str()) == Some(attachment.bdf.to_string().as_str())
+    }));
+    if !used {
+        if let Some(free) = state.get_mut("free_pci_devices_bdf").and_then(|f| f.get_mut("bdf")).and_then(|b| b.as_array_mut()) {
+            free.push(Value::Integer(attachment.bdf as i64));
+            free.sort_by_key(|b| b.as_integer());
+        }
+    }
+    store_state(&state)?;
+    Ok(Some(attachment))
+}
+
+// Network configuration of a cell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetConfig {
+    pub bdf: u32,
+    pub kind: f2b::NetConfKind,
+    pub mac: [u8; 6],
+    pub link: Link,
+    // Address of the pod forwarded to the cell, in a Kubernetes pod
+    pub pod_ip: Option<Ipv4Addr>,
+}
+
+// MAC of the interface of the cell at the BDF, locally administered
+pub fn guest_mac(bdf: u32) -> [u8; 6] {
+    [0x02, b'r', b'p', b'h', (bdf >> 8) as u8, bdf as u8]
+}
+
+impl NetConfig {
+    pub fn mac_string(&self) -> String {
+        self.mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+    }
+
+    // The configuration as shell variables, e.g. to be sourced by an init script
+    pub fn text(&self) -> String {
+        let mut text = format!("MODE={}\nMAC={}\n", self.kind, self.mac_string());
+        if self.kind == f2b::NetConfKind::Static {
+            text.push_str(&format!("ADDRESS={}\nPREFIX={}\nGATEWAY={}\n", self.link.guest, self.link.prefix, self.link.host));
+        }
+        if let Some(pod_ip) = self.pod_ip {
+            text.push_str(&format!("POD_ADDRESS={}\n", pod_ip));
+        }
+        text
+    }
+
+    // The configuration block, a page, little endian:
+    //   0   magic "RPHINET\0"
+    //   8   u32 version, 1
+    //   12  u32 flags, bit 0 set for a static address, clear for DHCP
+    //   16  MAC, 6 bytes
+    //   24  address, 4 bytes, and prefix length, 1 byte
+    //   32  gateway, 4 bytes
+    //   36  pod address, 4 bytes, 0 if none
+    //   40  u32 BDF
+    //   64  the text of the configuration, NUL terminated
+    pub fn block(&self) -> Vec<u8> {
+        let mut block = vec![0u8; memalloc::PAGE_SIZE as usize];
+        let flags = if self.kind == f2b::NetConfKind::Static { BLOCK_FLAG_STATIC } else { 0 };
+        block[0..8].copy_from_slice(BLOCK_MAGIC);
+        block[8..12].copy_from_slice(&BLOCK_VERSION.to_le_bytes());
+        block[12..16].copy_from_slice(&flags.to_le_bytes());
+        block[16..22].copy_from_slice(&self.mac);
+        if self.kind == f2b::NetConfKind::Static {
+            block[24..28].copy_from_slice(&self.link.guest.octets());
+            block[28] = self.link.prefix;
+            block[32..36].copy_from_slice(&self.link.host.octets());
+        }
+        if let Some(pod_ip) = self.pod_ip {
+            block[36..40].copy_from_slice(&pod_ip.octets());
+        }
+        block[40..44].copy_from_slice(&self.bdf.to_le_bytes());
+        let text = self.text();
+        block[BLOCK_TEXT_OFFSET..BLOCK_TEXT_OFFSET + text.len()].copy_from_slice(text.as_bytes());
+        block
+    }
+}
+
+// A cpio archive in the newc format, as the kernel expects for the initramfs, of the directories
+// and of the file
+fn cpio_newc(dirs: &[&str], path: &str, data: &[u8]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let mut entry = |ino: u32, name: &str, mode: u32, data: &[u8]| {
+        let header = format!(
+            "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            ino, mode, 0, 0, if mode & 0o040000 != 0 { 2 } else { 1 }, 0, data.len(), 0, 0, 0, 0, name.len() + 1, 0
+        );
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    };
+    for (i, dir) in dirs.iter().enumerate() {
+        entry(i as u32 + 1, dir, 0o040755, &[]);
+    }
+    entry(dirs.len() as u32 + 1, path, 0o100644, data);
+    entry(0, "TRAILER!!!", 0, &[]);
+    archive
+}
+
+// Produces the network configuration of the cell for the BDF of its networking device, and hands
+// it to the guest: in the initramfs for Linux, as a block loaded in the cell otherwise
+pub fn netconfig(
+    c: &mut configGenerator::Backendconfig,
+    fc: &f2b::FrontendConfig,
+    ic: &mut f2b::ImageConfig,
+    bdf: Option<i8>,
+) -> Result<Option<NetConfig>, Box<dyn Error>> {
+    let Some(bdf) = bdf.filter(|_| c.net != "none").and_then(|b| u32::try_from(b).ok()) else { return Ok(None) };
+    let platform: Value = fs::read_to_string(Path::new(WORKPATH).join(CONFIG_FILE))?.parse()?;
+    let policy = platform_network(&platform)?;
+    let pod_ip = match sandbox_id(&fc.jsonconfig) {
+        Some(sandbox) => lookup(sandbox)?.and_then(|a| a.pod_ip).and_then(|ip| ip.parse().ok()),
+        None => None,
+    };
+    let conf = NetConfig { bdf, kind: ic.netconf, mac: guest_mac(bdf), link: link(&policy, bdf)?, pod_ip };
+    let crundir = Path::new(&fc.crundir);
+    fs::write(crundir.join(TEXT_FILE), conf.text())?;
+
+    if ic.os_var == f2b::OsKind::Linux {
+        // The configuration is appended to the initramfs, as a second cpio archive
+        let x86 = c.arch == configGenerator::Arch::X86;
+        let initramfs = if x86 && ic.initrd.is_some() { &mut ic.initrd } else { &mut ic.cpio };
+        let Some(base) = initramfs.as_ref() else {
+            logging::log_message(logging::Level::Warn, format!("No initramfs to hand the network configuration to id {}", &fc.containerid).as_str());
+            return Ok(Some(conf));
+        };
+        let mut archive = fs::read(base.as_path()).map_err(|e| format!("Cannot read the initramfs {}: {}", base, e))?;
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend(cpio_newc(&INITRAMFS_DIRS, INITRAMFS_PATH, conf.text().as_bytes()));
+        let path = crundir.join(INITRAMFS_FILE);
+        fs::write(&path, archive)?;
+        *initramfs = Some(f2b::ImagePath::host(path));
+        return Ok(Some(conf));
+    }
+    if c.rpu_req {
+        logging::log_message(logging::Level::Debug, format!("No network configuration block for the RPU of id {}", &fc.containerid).as_str());
+        return Ok(Some(conf));
+    }
+
+    // The block is a read-only page of the cell at the well-known address, at the same physical
+    // address on cores without an MMU
+    let address = match policy.config_address {
+        Some(address) => address,
+        None => c.desc.mem_regions.iter()
+            .find(|r| r.flags & JAILHOUSE_MEM_COMM_REGION != 0)
+            .and_then(|r| r.virt_start.checked_sub(memalloc::PAGE_SIZE))
+            .ok_or("No communication region in the cell, set [network] config_address")?,
+    };
+    if let Some(region) = c.desc.mem_regions.iter().find(|r| r.size > 0 && r.virt_start < address + memalloc::PAGE_SIZE && address < r.virt_start.saturating_add(r.size)) {
+        return Err(format!("the network configuration block at 0x{:x} overlaps the region at 0x{:x} of the cell, set [network] config_address", address, region.virt_start).into());
+    }
+    let fixed = (!memalloc::platform_memory(&platform)?.mmu).then_some(address);
+    let request = memalloc::Request { size: memalloc::PAGE_SIZE, align: memalloc::PAGE_SIZE, guard: 0, fixed };
+    let memory = memalloc::allocate(&mut c.segments, &request).map_err(|e| format!("Cannot allocate the network configuration block: {}", e))?;
+    c.used_memory.push(memory);
+    c.desc.mem_regions.push(MemRegion {
+        comment: Some("runPHI network configuration".to_string()),
+        phys_start: memory.0,
+        virt_start: address,
+        size: memalloc::PAGE_SIZE,
+        flags: JAILHOUSE_MEM_READ | JAILHOUSE_MEM_LOADABLE,
+        ..Default::default()
+    });
+    fs::write(crundir.join(BLOCK_FILE), conf.block())?;
+    fs::write(crundir.join(BLOCK_ADDRESS_FILE), format!("0x{:x}", address))?;
+    Ok(Some(conf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(subnet: &str) -> Policy {
+        Policy { subnet: parse_subnet(subnet).unwrap(), interface: "enP1p0s{bdf}".to_string(), config_address: None }
+    }
+
+    fn netconfig(kind: f2b::NetConfKind, pod_ip: Option<Ipv4Addr>) -> NetConfig {
+        NetConfig { bdf: 2, kind, mac: guest_mac(2), link: link(&policy("192.168.100.0/24"), 2).unwrap(), pod_ip }
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn link_is_the_slash_30_of_the_bdf() {
+        let policy = policy("10.1.0.0/24");
+        let ip = |text: &str| text.parse::<Ipv4Addr>().unwrap();
+        assert_eq!(link(&policy, 0).unwrap(), Link { host: ip("10.1.0.1"), guest: ip("10.1.0.2"), prefix: 30 });
+        assert_eq!(link(&policy, 1).unwrap(), Link { host: ip("10.1.0.5"), guest: ip("10.1.0.6"), prefix: 30 });
+        assert_eq!(link(&policy, 63).unwrap(), Link { host: ip("10.1.0.253"), guest: ip("10.1.0.254"), prefix: 30 });
+        // A /24 hosts 64 links, a /30 only the one of BDF 0
+        assert!(link(&policy, 64).is_err());
+        let single = self::policy("10.2.0.4/30");
+        assert_eq!(link(&single, 0).unwrap().guest, ip("10.2.0.6"));
+        assert!(link(&single, 1).is_err());
+        // The links of different BDFs never share addresses
+        let mut addresses: Vec<Ipv4Addr> = (0..64).flat_map(|b| { let l = link(&policy, b).unwrap(); [l.host, l.guest] }).collect();
+        addresses.sort();
+        addresses.dedup();
+        assert_eq!(addresses.len(), 128);
+    }
+
+    #[test]
+    fn static_block_layout() {
+        let conf = netconfig(f2b::NetConfKind::Static, Some(Ipv4Addr::new(10, 244, 0, 12)));
+        let block = conf.block();
+        assert_eq!(block.len(), 0x1000);
+        assert_eq!(&block[0..8], b"RPHINET\0");
+        assert_eq!((u32_at(&block, 8), u32_at(&block, 12)), (1, 1));
+        assert_eq!(&block[16..24], &[0x02, b'r', b'p', b'h', 0, 2, 0, 0]);
+        assert_eq!(&block[24..29], &[192, 168, 100, 10, 30]);
+        assert_eq!(&block[32..36], &[192, 168, 100, 9]);
+        assert_eq!(&block[36..40], &[10, 244, 0, 12]);
+        assert_eq!(u32_at(&block, 40), 2);
+        assert!(block[44..64].iter().all(|b| *b == 0));
+        // The text, NUL terminated
+        let text = conf.text();
+        assert_eq!(text, "MODE=static\nMAC=02:72:70:68:00:02\nADDRESS=192.168.100.10\nPREFIX=30\nGATEWAY=192.168.100.9\nPOD_ADDRESS=10.244.0.12\n");
+        assert_eq!(&block[64..64 + text.len()], text.as_bytes());
+        assert!(block[64 + text.len()..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn dhcp_block_has_only_the_mac() {
+        let block = netconfig(f2b::NetConfKind::Dhcp, None).block();
+        assert_eq!(u32_at(&block, 12), 0);
+        assert_eq!(&block[16..22], &guest_mac(2));
+        assert!(block[24..40].iter().all(|b| *b == 0));
+        assert_eq!(u32_at(&block, 40), 2);
+        assert!(block[64..].starts_with(b"MODE=dhcp\nMAC=02:72:70:68:00:02\n\0"));
+    }
+
+    // Entries of a newc archive: name, mode and data, checking the headers and the padding
+    fn cpio_entries(mut archive: &[u8]) -> Vec<(String, u32, Vec<u8>)> {
+        let mut entries = Vec::new();
+        let total = archive.len();
+        loop {
+            assert_eq!((total - archive.len()) % 4, 0, "entry not aligned");
+            let header = std::str::from_utf8(&archive[..110]).unwrap();
+            assert_eq!(&header[..6], "070701");
+            let field = |i: usize| u32::from_str_radix(&header[6 + 8 * i..14 + 8 * i], 16).unwrap();
+            let (mode, size, name_size) = (field(1), field(6) as usize, field(11) as usize);
+            let name = std::str::from_utf8(&archive[110..110 + name_size - 1]).unwrap().to_string();
+            assert_eq!(archive[110 + name_size - 1], 0);
+            let data_start = (110 + name_size).next_multiple_of(4);
+            assert!(archive[110 + name_size..data_start].iter().all(|b| *b == 0));
+            let data = archive[data_start..data_start + size].to_vec();
+            let next = (data_start + size).next_multiple_of(4);
+            assert!(archive[data_start + size..next].iter().all(|b| *b == 0));
+            archive = &archive[next..];
+            entries.push((name.clone(), mode, data));
+            if name == "TRAILER!!!" {
+                assert!(archive.is_empty());
+                return entries;
+            }
+        }
+    }
+
+    #[test]
+    fn cpio_newc_holds_the_dirs_and_the_file() {
+        // Lengths of name and data hitting every padding
+        for data in [&b""[..], b"A", b"AB", b"ABC", b"ABCD"] {
+            let archive = cpio_newc(&INITRAMFS_DIRS, INITRAMFS_PATH, data);
+            assert_eq!(archive.len() % 4, 0);
+            let entries = cpio_entries(&archive);
+            let names: Vec<&str> = entries.iter().map(|e| e.0.as_str()).collect();
+            assert_eq!(names, ["etc", "etc/runphi", "etc/runphi/network.conf", "TRAILER!!!"]);
+            assert_eq!(entries.iter().map(|e| e.1).collect::<Vec<_>>(), [0o040755, 0o040755, 0o100644, 0]);
+            assert_eq!(entries[2].2, data);
+        }
+        // The first header, field by field: ino 1, mode, uid, gid, 2 links, mtime, size 0, the
+        // devices, name size 4 ("etc" and NUL), check. The name is padded to 116 bytes
+        let archive = cpio_newc(&["etc"], "etc/a", b"x");
+        let fields = ["00000001", "000041ed", "00000000", "00000000", "00000002", "00000000", "00000000", "00000000", "00000000", "00000000", "00000000", "00000004", "00000000"];
+        let header = format!("070701{}etc\0\0\0", fields.concat());
+        assert_eq!(&archive[..116], header.as_bytes());
+        assert_eq!(&archive[116..122], b"070701");
+    }
+}
@@ -12,6 +12,7 @@ use std::error::Error;
 //use std::path::Path;
 
 use crate::configGenerator;
+use crate::configGenerator::celldesc;
 //use f2b;
 
 //const WORKPATH: &str = "/usr/share/runPHI";
@@ -22,7 +23,8 @@ pub fn rpuconf(
     rpusf64: &f64,
 ) -> Result<(), Box<dyn Error>> {
     // cpus will be the effective number of RPUs requested
-    let cpus: u8 = rpusf64.ceil() as u8; // Casting with ceil since fractional CPU allocation isn't supported
+    // At least one, also when the quota is unlimited
+    let cpus: u8 = (rpusf64.ceil() as u8).max(1); // Casting with ceil since fractional CPU allocation isn't supported
 
     // Construct the full path to the free_rpus.txt file
     let mut free_rpus = c.rcpus.clone();
@@ -40,15 +42,9 @@ pub fn rpuconf(
     // Update `c.rcpus` with the remaining RPUs
     c.rcpus = free_rpus;
 
-    // Create the rpus assignment with allocated RPUs in the required format
-    let rpus_hex: Vec<String> = allocated_rpus
-        .iter()
-        .map(|rpu| format!("0x{:x}", rpu + 1)) // Add 1 to each RPU ID for the correct hex representation
-        .collect();
-    let rpus_assignment = format!("\n\t.rcpus = {{\n\t\t{},\n\t}},\n", rpus_hex.join(", "));
-
-    // Append the RPU configuration to the config
-    c.conf.push_str(&rpus_assignment);
+    // Record the allocated RPUs in the rcpu set of the cell
+    let rpu_ids: Vec<usize> = allocated_rpus.iter().map(|rpu| *rpu as usize).collect();
+    c.desc.rcpus = celldesc::cpu_set(&rpu_ids);
 
     Ok(())
 }
\ No newline at end of file
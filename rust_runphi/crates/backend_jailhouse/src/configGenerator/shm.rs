@@ -0,0 +1,145 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Shared-memory channels among cells. A channel is a named region the cells of its members map
+// at its physical address. The first member allocates it out of the free memory, the last one
+// gives it back. The channels are kept in state.toml with their members:
+//   [shm_channels.sensors]
+//   memory = "0x7c000000, 0x7c100000"
+//   rootshared = false
+//   members = ["<containerid>", ...]
+// and each container lists the channels it joined in the `shm` entry of its section.
+
+use std::error::Error;
+use toml::map::Map;
+use toml::Value;
+
+use crate::configGenerator;
+use crate::configGenerator::celldesc::{MemRegion, JAILHOUSE_MEM_READ, JAILHOUSE_MEM_ROOTSHARED, JAILHOUSE_MEM_WRITE};
+use crate::configGenerator::memalloc;
+use crate::statefile;
+use f2b;
+
+const CHANNELS: &str = "shm_channels";
+
+// A channel joined by the cell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Channel {
+    pub name: String,
+    pub memory: memalloc::Segment,
+    pub rootshared: bool,
+    // Whether the cell creates the channel, being its first member
+    pub created: bool,
+}
+
+// The channel as recorded in the state, if it exists
+fn existing(state: &Value, name: &str) -> Result<Option<(memalloc::Segment, bool)>, String> {
+    let Some(channel) = state.get(CHANNELS).and_then(|c| c.get(name)) else { return Ok(None) };
+    let memory = channel.get("memory").and_then(|m| m.as_str()).ok_or(format!("[{}.{}] memory missing", CHANNELS, name))?;
+    let memory = memalloc::parse_segment(memory).map_err(|e| format!("[{}.{}] memory: {}", CHANNELS, name, e))?;
+    let rootshared = channel.get("rootshared").and_then(|r| r.as_bool()).unwrap_or(false);
+    Ok(Some((memory, rootshared)))
+}
+
+// Joins the channels asked by the image, creating the missing ones, and maps them in the cell
+pub fn shmconfig(c: &mut configGenerator::Backendconfig, ic: &f2b::ImageConfig) -> Result<(), Box<dyn Error>> {
+    if ic.shm.is_empty() {
+        return Ok(());
+    }
+    let state: Value = statefile::read()?.parse()?;
+
+    for request in &ic.shm {
+        let size = request.size.map(|size| size.0.next_multiple_of(memalloc::PAGE_SIZE));
+        let channel = match existing(&state, &request.name)? {
+            Some((memory, rootshared)) => {
+                // The members join the region as it was created
+                if let Some(size) = size.filter(|size| *size != memory.1 - memory.0) {
+                    return Err(format!("channel \"{}\" asked with size 0x{:x}, but it has size 0x{:x}", request.name, size, memory.1 - memory.0).into());
+                }
+                if let Some(address) = request.address.filter(|address| address.0 != memory.0) {
+                    return Err(format!("channel \"{}\" asked at {}, but it is at 0x{:x}", request.name, address, memory.0).into());
+                }
+                if request.rootshared.is_some_and(|r| r != rootshared) {
+                    return Err(format!("channel \"{}\" asked with rootshared {}, but it was created with {}", request.name, !rootshared, rootshared).into());
+                }
+                Channel { name: request.name.clone(), memory, rootshared, created: false }
+            }
+            None => {
+                let size = size.ok_or(format!("channel \"{}\" does not exist yet, its size is needed to create it", request.name))?;
+                let alloc = memalloc::Request { size, align: memalloc::PAGE_SIZE, guard: 0, fixed: request.address.map(|address| address.0) };
+                let memory = memalloc::allocate(&mut c.segments, &alloc)
+                    .map_err(|e| format!("Cannot allocate the channel \"{}\": {}", request.name, e))?;
+                Channel { name: request.name.clone(), memory, rootshared: request.rootshared.unwrap_or(false), created: true }
+            }
+        };
+
+        // The channel is mapped at its physical address, which must be free in the cell
+        let (start, end) = channel.memory;
+        if let Some(region) = c.desc.mem_regions.iter().find(|r| r.virt_start < end && start < r.virt_start.saturating_add(r.size)) {
+            return Err(format!(
+                "channel \"{}\" at 0x{:x}-0x{:x} overlaps the region at 0x{:x} of the cell",
+                channel.name, start, end, region.virt_start
+            ).into());
+        }
+        let mut flags = JAILHOUSE_MEM_READ | JAILHOUSE_MEM_WRITE;
+        if channel.rootshared {
+            flags |= JAILHOUSE_MEM_ROOTSHARED;
+        }
+        c.desc.mem_regions.push(MemRegion {
+            comment: Some(format!("shm channel {}", channel.name)),
+            phys_start: start,
+            virt_start: start,
+            size: end - start,
+            flags,
+            ..Default::default()
+        });
+        c.shm.push(channel);
+    }
+
+    Ok(())
+}
+
+// Records the channels joined by the container in the state
+pub fn save(state: &mut Value, containerid: &str, c: &configGenerator::Backendconfig) -> Result<(), String> {
+    if c.shm.is_empty() {
+        return Ok(());
+    }
+    let table = state.as_table_mut().ok_or("the state is not a table")?;
+    let channels = table.entry(CHANNELS).or_insert_with(|| Value::Table(Map::new()))
+        .as_table_mut().ok_or(format!("[{}] is not a table", CHANNELS))?;
+    for channel in &c.shm {
+        if channel.created {
+            let mut entry = Map::new();
+            entry.insert("memory".to_string(), Value::String(memalloc::format_segment(&channel.memory)));
+            entry.insert("rootshared".to_string(), Value::Boolean(channel.rootshared));
+            entry.insert("members".to_string(), Value::Array(Vec::new()));
+            channels.insert(channel.name.clone(), Value::Table(entry));
+        }
+        let members = channels.get_mut(&channel.name).and_then(|c| c.get_mut("members")).and_then(|m| m.as_array_mut())
+            .ok_or(format!("[{}.{}] members missing", CHANNELS, channel.name))?;
+        members.push(Value::String(containerid.to_string()));
+    }
+    Ok(())
+}
+
+// Removes the container from the members of its channels, returning the memory of the channels
+// left without members, which are removed
+pub fn leave(state: &mut Value, containerid: &str, names: &[String]) -> Vec<memalloc::Segment> {
+    let mut freed = Vec::new();
+    let Some(channels) = state.get_mut(CHANNELS).and_then(|c| c.as_table_mut()) else { return freed };
+    for name in names {
+        let Some(channel) = channels.get_mut(name) else { continue };
+        if let Some(members) = channel.get_mut("members").and_then(|m| m.as_array_mut()) {
+            members.retain(|m| m.as_str() != Some(containerid));
+            if !members.is_empty() {
+                continue;
+            }
+        }
+        if let Some(memory) = channel.get("memory").and_then(|m| m.as_str()).and_then(|m| memalloc::parse_segment(m).ok()) {
+            freed.push(memory);
+        }
+        channels.remove(name);
+    }
+    freed
+}
@@ -3,6 +3,13 @@
 //*********************************************
 
 use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use toml::Value;
+
+// Directory of the templates of the platform, one file per template named <NAME>.tmpl
+pub const TEMPLATE_DIR: &str = "/usr/share/runPHI/templates";
+const TEMPLATE_EXT: &str = "tmpl";
 
 /* pub const RAM_TEMPLATE: &'static str = r#"
 /* RAM */ {
@@ -15,7 +22,7 @@ use std::collections::HashMap;
 },
 "#; */
 
-pub const RAM_TEMPLATE: &'static str = r#"
+pub const RAM_TEMPLATE: &str = r#"
 /* RAM */ {
 	.phys_start = {phys_start},
 	.virt_start = {virt_start},
@@ -25,7 +32,7 @@ pub const RAM_TEMPLATE: &'static str = r#"
 },
 "#;
 
-pub const RAM0_TEMPLATE: &'static str = r#"
+pub const RAM0_TEMPLATE: &str = r#"
 /* RAM */ {
 	.phys_start = {phys_start},
 	.virt_start = {virt_start},
@@ -57,7 +64,7 @@ pub const TCMB_TEMPLATE: &'static str = r#"
 },
 "#; */
 
-pub const TCMA_TEMPLATE: &'static str = r#"
+pub const TCMA_TEMPLATE: &str = r#"
 /* TCM 0-A */ {
 	.phys_start = {phys_start},
 	.virt_start = {virt_start},
@@ -67,7 +74,7 @@ pub const TCMA_TEMPLATE: &'static str = r#"
 },
 "#;
 
-pub const TCMB_TEMPLATE: &'static str = r#"
+pub const TCMB_TEMPLATE: &str = r#"
 /* TCM 0-B */ {
 	.phys_start = {phys_start},
 	.virt_start = {virt_start},
@@ -77,7 +84,7 @@ pub const TCMB_TEMPLATE: &'static str = r#"
 },
 "#;
 
-pub const UART_TEMPLATE: &'static str = r#"
+pub const UART_TEMPLATE: &str = r#"
 /* UART */ {
 	.phys_start = {phys_start},
 	.virt_start = {virt_start},
@@ -87,7 +94,7 @@ pub const UART_TEMPLATE: &'static str = r#"
 },
 "#;
 
-pub const COMM_REGION_TEMPLATE: &'static str = r#"
+pub const COMM_REGION_TEMPLATE: &str = r#"
 /* communication region */ {
 	.virt_start = 0x80000000,
 	.size = 0x00001000,
@@ -96,7 +103,7 @@ pub const COMM_REGION_TEMPLATE: &'static str = r#"
 },
 "#;
 
-pub const IVSHMEM_DEMO_TEMPLATE: &'static str = r#"
+pub const IVSHMEM_DEMO_TEMPLATE: &str = r#"
 /* IVSHMEM shared memory region for 00:00.0 (demo) */
 	{
 	.phys_start = 0x7f8f0000,
@@ -132,39 +139,41 @@ pub const IVSHMEM_DEMO_TEMPLATE: &'static str = r#"
 	},
 "#;
 
-pub const IVSHMEM_TEMPLATE: &'static str = r#"JAILHOUSE_SHMEM_NET_REGIONS({address}, 1),"#;
+// IVSHMEM_TEMPLATE, PCI_DEVICE_TEMPLATE and PCI_DEVICE_TEMPLATE_WITH_DEMO are the networking
+// regions and devices of the Jailhouse demo configs. They are no templates of the platform any
+// more: runPHI adds the networking device of a cell with its link of the root cell (see ivshmem.rs)
+pub(crate) const NETWORKING_TEMPLATES: [&str; 4] = ["IVSHMEM_TEMPLATE", "PCI_DEVICE_TEMPLATE", "PCI_DEVICE_TEMPLATE_WITH_DEMO", "X86_PCI_DEVICE_TEMPLATE"];
+
+pub const IVSHMEM_TEMPLATE: &str = r#"JAILHOUSE_SHMEM_NET_REGIONS({address}, 1),"#;
 
-pub const IRQ_CHIP_TEMPLATE: &'static str = r#"
+// GIC of the cells. ivshmem_pin, the INTx of the IVSHMEM networking device, is allocated by
+// runPHI out of [free_irq_pins] of the state, ivshmem_demo_pin is the one of the demo device.
+// uart_pin is left out of the cells not owning the UART
+pub const IRQ_CHIP_TEMPLATE: &str = r#"
 .irqchips = {
 	/* GIC */ {
 		.address = {gic_address},
 		.pin_base = 32,
 		.pin_bitmap = {
-			1 << ({uart_pin} - 32),
-			0,
-			0,
-			(1 << (140 - 128)) | (1 << ({ivshmem_pin} - 128))
+			IRQ_PINS(32, {uart_pin}, {ivshmem_demo_pin}, {ivshmem_pin})
 		},
 	},
 },
 "#;
 
-pub const IRQ_CHIP_BOARD_TEMPLATE: &'static str = r#"
+pub const IRQ_CHIP_BOARD_TEMPLATE: &str = r#"
 .irqchips = {
 	/* GIC */ {
 		.address = {gic_address},
 		.pin_base = 32,
 		.pin_bitmap = {
-			1 << ({uart_pin} - 32),
-			0,
-			0,
-			(1 << ({ivshmem_pin} - 128))
+			IRQ_PINS(32, {uart_pin}, {ivshmem_pin})
 		},
 	},
 },
 "#;
 
-pub const PCI_DEVICE_TEMPLATE_WITH_DEMO: &'static str = r#"
+pub const PCI_DEVICE_TEMPLATE_WITH_DEMO: &str = r#"
 .pci_devices = {
 { /* IVSHMEM 00:00.0 (demo) */
 	.type = JAILHOUSE_PCI_TYPE_IVSHMEM,
@@ -189,7 +198,7 @@ pub const PCI_DEVICE_TEMPLATE_WITH_DEMO: &'static str = r#"
 },
 "#;
 
-pub const PCI_DEVICE_TEMPLATE: &'static str = r#"
+pub const PCI_DEVICE_TEMPLATE: &str = r#"
 .pci_devices = {
 { /* IVSHMEM 00:0{ivshmem_bdf}.0 (networking) */
 	.type = JAILHOUSE_PCI_TYPE_IVSHMEM,
@@ -204,77 +213,135 @@ pub const PCI_DEVICE_TEMPLATE: &'static str = r#"
 },
 "#;
 
-pub const PCI_DEVICE_EMPTY_TEMPLATE: &'static str = r#"
+// The demo device of the QEMU board, whose peer id is the BDF of the cell
+pub const PCI_DEMO_DEVICE_TEMPLATE: &str = r#"
 .pci_devices = {
+{ /* IVSHMEM 00:00.0 (demo) */
+	.type = JAILHOUSE_PCI_TYPE_IVSHMEM,
+	.domain = 1,
+	.bdf = 0 << 3,
+	.bar_mask = JAILHOUSE_IVSHMEM_BAR_MASK_INTX,
+	.shmem_regions_start = 0,
+	.shmem_dev_id = {ivshmem_bdf},
+	.shmem_peers = 3,
+	.shmem_protocol = JAILHOUSE_SHMEM_PROTO_UNDEFINED,
+},
 },
 "#;
 
-pub const QEMU_PREAMBLE_TEMPLATE: &'static str = r#"
-#include "types.h"
-#include "cell-config.h"
-
-struct {
-	struct jailhouse_cell_desc cell;
-	__u64 cpus[1];
-} __attribute__((packed)) config = {
-	.cell = {
-		.signature = JAILHOUSE_CELL_DESC_SIGNATURE,
-		.revision = JAILHOUSE_CONFIG_REVISION,
-		.name = "{containerid}",
-		.flags = JAILHOUSE_CELL_PASSIVE_COMMREG |
-			JAILHOUSE_CELL_VIRTUAL_CONSOLE_PERMITTED,
-
-		.cpu_set_size = sizeof(config.cpus),
-		.num_memory_regions = ARRAY_SIZE(config.mem_regions),
-		.num_irqchips = ARRAY_SIZE(config.irqchips),
-		.num_pci_devices = ARRAY_SIZE(config.pci_devices),
-
-		.vpci_irq_base = 140-32,
-		.cpu_reset_address = 0x70000000,
-
-		.console = {
-			.address = 0x09000000,
-			.type = JAILHOUSE_CON_TYPE_PL011,
-			.flags = JAILHOUSE_CON_ACCESS_MMIO |
-				 JAILHOUSE_CON_REGDIST_4,
-		},
-	},
+pub const PCI_DEVICE_EMPTY_TEMPLATE: &str = r#"
+.pci_devices = {
+},
+"#;
+
+// Preambles hold the board specific part of the cell header. Array sizes, counters, signature and
+// name are filled in by the renderer. Setting `.architecture` selects the Omnivisor header layout.
+// The console is not part of the preamble, it comes from [console] of the platform (communication.rs)
+pub const QEMU_PREAMBLE_TEMPLATE: &str = r#"
+.cell = {
+	.flags = JAILHOUSE_CELL_PASSIVE_COMMREG |
+		JAILHOUSE_CELL_VIRTUAL_CONSOLE_PERMITTED,
+
+	.vpci_irq_base = 140-32,
+	.cpu_reset_address = 0x70000000,
+},
+"#;
+
+pub const ULTRASCALE_PREAMBLE_TEMPLATE: &str = r#"
+.cell = {
+	.architecture = JAILHOUSE_ARM64,
+	.flags = JAILHOUSE_CELL_PASSIVE_COMMREG,
+},
+.stream_ids = {
+	{ .id = 0 }, { .id = 0 },
+},
+"#;
+
+// Header of the cells booted with `jailhouse cell linux`
+pub const LINUX_PREAMBLE_TEMPLATE: &str = r#"
+.cell = {
+	.flags = JAILHOUSE_CELL_PASSIVE_COMMREG |
+		JAILHOUSE_CELL_VIRTUAL_CONSOLE_PERMITTED,
+
+	.vpci_irq_base = 140-32,
+},
+"#;
+
+// x86 cells: IOAPIC, MSI-X interrupts for the IVSHMEM device, the 8250 UART on I/O ports.
+// The RAM follows the layout expected by `jailhouse cell linux` and by the x86 inmates: low RAM
+// at 0, communication region at 1 MiB, high RAM from 2 MiB
+pub const X86_PREAMBLE_TEMPLATE: &str = r#"
+.cell = {
+	.flags = JAILHOUSE_CELL_PASSIVE_COMMREG |
+		JAILHOUSE_CELL_VIRTUAL_CONSOLE_PERMITTED,
+},
+"#;
+
+pub const X86_LINUX_PREAMBLE_TEMPLATE: &str = r#"
+.cell = {
+	.flags = JAILHOUSE_CELL_PASSIVE_COMMREG |
+		JAILHOUSE_CELL_VIRTUAL_CONSOLE_PERMITTED,
+},
+"#;
+
+pub const X86_LOW_RAM_TEMPLATE: &str = r#"
+/* low RAM */ {
+	.phys_start = {phys_start},
+	.virt_start = 0,
+	.size = {size},
+	.flags = JAILHOUSE_MEM_READ | JAILHOUSE_MEM_WRITE |
+		JAILHOUSE_MEM_EXECUTE | JAILHOUSE_MEM_DMA |
+		JAILHOUSE_MEM_LOADABLE,
+},
+"#;
+
+pub const X86_COMM_REGION_TEMPLATE: &str = r#"
+/* communication region */ {
+	.virt_start = 0x00100000,
+	.size = 0x00001000,
+	.flags = JAILHOUSE_MEM_READ | JAILHOUSE_MEM_WRITE |
+		JAILHOUSE_MEM_COMM_REGION,
+},
+"#;
+
+pub const X86_RAM_TEMPLATE: &str = r#"
+/* high RAM */ {
+	.phys_start = {phys_start},
+	.virt_start = {virt_start},
+	.size = {size},
+	.flags = JAILHOUSE_MEM_READ | JAILHOUSE_MEM_WRITE |
+		JAILHOUSE_MEM_EXECUTE | JAILHOUSE_MEM_DMA |
+		JAILHOUSE_MEM_LOADABLE,
+},
 "#;
 
-pub const ULTRASCALE_PREAMBLE_TEMPLATE: &'static str = r#"
-#include "types.h"
-#include "cell-config.h"
-
-struct {
-	struct jailhouse_cell_desc cell;
-	__u64 cpus[1];
-	__u64 rcpus[1];
-	union jailhouse_stream_id stream_ids[2];
-} __attribute__((packed)) config = {
-	.cell = {
-		.signature = JAILHOUSE_CELL_DESC_SIGNATURE,
-		.revision = JAILHOUSE_CONFIG_REVISION,
-		.architecture = JAILHOUSE_ARM64,
-		.name = "{containerid}",
-		.flags = JAILHOUSE_CELL_PASSIVE_COMMREG,
-
-		.cpu_set_size = sizeof(config.cpus),
-		.rcpu_set_size = sizeof(config.rcpus),
-		.num_memory_regions = ARRAY_SIZE(config.mem_regions),
-		.num_irqchips = ARRAY_SIZE(config.irqchips),
-		.num_pci_devices = ARRAY_SIZE(config.pci_devices),
-		.num_stream_ids = ARRAY_SIZE(config.stream_ids),
-
-		.console = {
-			.address = 0xff010000,
-			.type = JAILHOUSE_CON_TYPE_XUARTPS,
-			.flags = JAILHOUSE_CON_ACCESS_MMIO |
-				 JAILHOUSE_CON_REGDIST_4,
+pub const X86_IOAPIC_TEMPLATE: &str = r#"
+.irqchips = {
+	/* IOAPIC */ {
+		.address = {ioapic_address},
+		.id = {ioapic_id},
+		.pin_bitmap = {
+			IRQ_PINS(0, {uart_pin})
 		},
 	},
+},
 "#;
 
-pub const SHM_TEMPLATE: &'static str = r#"
+pub const X86_PIO_TEMPLATE: &str = r#"
+.pio_regions = {
+	/* serial */
+	PIO_RANGE({uart_port}, 8),
+},
+"#;
+
+// RAM templates filled in by runPHI out of the free segments: a low RAM of the size in its table,
+// carved before the main RAM, which gets the requested memory
+pub(crate) const LOW_RAM_TEMPLATES: [&str; 2] = ["RAM0_TEMPLATE", "X86_LOW_RAM_TEMPLATE"];
+pub(crate) const MAIN_RAM_TEMPLATES: [&str; 2] = ["RAM_TEMPLATE", "X86_RAM_TEMPLATE"];
+// Size of the low RAM when its table has none
+pub(crate) const LOW_RAM_DEFAULT_SIZE: u64 = 0x10000;
+
+pub const SHM_TEMPLATE: &str = r#"
 /* SHM */ {
 	.phys_start = 0x46d00000,
 	.virt_start = 0x46d00000,
@@ -284,7 +351,7 @@ pub const SHM_TEMPLATE: &'static str = r#"
 },
 "#;
 
-pub const SYSTEM_COUNTER_TEMPLATE: &'static str = r#"
+pub const SYSTEM_COUNTER_TEMPLATE: &str = r#"
 /* SYSTEM COUNTER */ {
 	.phys_start = 0xff250000,
 	.virt_start = 0xff250000,
@@ -303,19 +370,258 @@ pub fn get_templates_map() -> HashMap<&'static str, &'static str> {
     templates.insert("RAM0_TEMPLATE", RAM0_TEMPLATE);
     templates.insert("UART_TEMPLATE", UART_TEMPLATE);
     templates.insert("COMM_REGION_TEMPLATE", COMM_REGION_TEMPLATE);
-    templates.insert("IVSHMEM_TEMPLATE", IVSHMEM_TEMPLATE);
     templates.insert("IVSHMEM_DEMO_TEMPLATE", IVSHMEM_DEMO_TEMPLATE);
     templates.insert("IRQ_CHIP_TEMPLATE", IRQ_CHIP_TEMPLATE);
 	templates.insert("IRQ_CHIP_BOARD_TEMPLATE", IRQ_CHIP_BOARD_TEMPLATE);
-    templates.insert("PCI_DEVICE_TEMPLATE", PCI_DEVICE_TEMPLATE);
-    templates.insert("PCI_DEVICE_TEMPLATE_WITH_DEMO", PCI_DEVICE_TEMPLATE_WITH_DEMO);
+    templates.insert("PCI_DEMO_DEVICE_TEMPLATE", PCI_DEMO_DEVICE_TEMPLATE);
     templates.insert("PCI_DEVICE_EMPTY_TEMPLATE", PCI_DEVICE_EMPTY_TEMPLATE);
     templates.insert("TCMA_TEMPLATE", TCMA_TEMPLATE);
     templates.insert("TCMB_TEMPLATE", TCMB_TEMPLATE);
 	templates.insert("QEMU_PREAMBLE_TEMPLATE", QEMU_PREAMBLE_TEMPLATE);
 	templates.insert("ULTRASCALE_PREAMBLE_TEMPLATE", ULTRASCALE_PREAMBLE_TEMPLATE);
+	templates.insert("LINUX_PREAMBLE_TEMPLATE", LINUX_PREAMBLE_TEMPLATE);
 	templates.insert("SHM_TEMPLATE", SHM_TEMPLATE);
 	templates.insert("SYSTEM_COUNTER_TEMPLATE", SYSTEM_COUNTER_TEMPLATE);
+	templates.insert("X86_PREAMBLE_TEMPLATE", X86_PREAMBLE_TEMPLATE);
+	templates.insert("X86_LINUX_PREAMBLE_TEMPLATE", X86_LINUX_PREAMBLE_TEMPLATE);
+	templates.insert("X86_LOW_RAM_TEMPLATE", X86_LOW_RAM_TEMPLATE);
+	templates.insert("X86_COMM_REGION_TEMPLATE", X86_COMM_REGION_TEMPLATE);
+	templates.insert("X86_RAM_TEMPLATE", X86_RAM_TEMPLATE);
+	templates.insert("X86_IOAPIC_TEMPLATE", X86_IOAPIC_TEMPLATE);
+	templates.insert("X86_PIO_TEMPLATE", X86_PIO_TEMPLATE);
 
 	templates
 }
+
+// Templates available to a platform. The built-in ones are the defaults, which can be
+// overridden, or extended with new templates, by the files in TEMPLATE_DIR and by the
+// [templates] table of platform_info.toml, the latter taking precedence, e.g.
+//   [templates]
+//   MYBOARD_UART_TEMPLATE = """
+//   /* UART */ { .phys_start = {phys_start}, ... },
+//   """
+pub fn get_platform_templates(config: &Value, template_dir: &Path) -> Result<HashMap<String, String>, String> {
+    let mut templates: HashMap<String, String> = get_templates_map()
+        .into_iter()
+        .map(|(name, template)| (name.to_string(), template.to_string()))
+        .collect();
+
+    // A missing directory just means that the platform does not ship templates
+    if let Ok(entries) = fs::read_dir(template_dir) {
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(TEMPLATE_EXT) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|n| n.to_str()) else { continue };
+            let template = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read template {}: {}", path.display(), e))?;
+            templates.insert(name.to_string(), template);
+        }
+    }
+
+    if let Some(table) = config.get("templates") {
+        let table = table.as_table().ok_or("templates is not a table")?;
+        for (name, template) in table {
+            let template = template
+                .as_str()
+                .ok_or_else(|| format!("templates.{} is not a string", name))?;
+            templates.insert(name.clone(), template.to_string());
+        }
+    }
+
+    Ok(templates)
+}
+
+// Template of the preamble named in [jailhouse_preamble], e.g. QEMU_PREAMBLE for QEMU_PREAMBLE_TEMPLATE
+pub fn get_preamble<'a>(templates: &'a HashMap<String, String>, preamble: &str) -> Result<(String, &'a str), String> {
+    let name = if preamble.ends_with("_TEMPLATE") { preamble.to_string() } else { format!("{}_TEMPLATE", preamble) };
+    match templates.get(&name) {
+        Some(template) => Ok((name, template)),
+        None => Err(format!("Unexpected preamble value: {}", preamble)),
+    }
+}
+
+// Placeholders of a template: `{name}` with a lower case identifier, so that the braces and the
+// upper case constants of the C initializers are never mistaken for one
+fn placeholders(template: &str) -> Vec<(usize, usize, &str)> {
+    let bytes = template.as_bytes();
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'{' {
+            let start = i + 1;
+            let mut end = start;
+            while end < bytes.len() && (bytes[end].is_ascii_lowercase() || bytes[end] == b'_' || (end > start && bytes[end].is_ascii_digit())) {
+                end += 1;
+            }
+            if end > start && end < bytes.len() && bytes[end] == b'}' {
+                found.push((i, end + 1, &template[start..end]));
+                i = end + 1;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    found
+}
+
+// Whether the template has the {name} placeholder
+pub(crate) fn has_placeholder(template: &str, name: &str) -> bool {
+    placeholders(template).iter().any(|(_, _, found)| *found == name)
+}
+
+// Parses a numeric parameter, hex with the 0x prefix or decimal
+pub(crate) fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse::<u64>().ok(),
+    }
+}
+
+// Validates a parameter according to its name: every placeholder is a number, sizes cannot be
+// zero and interrupt pins must be valid GIC interrupt IDs
+fn validate_param(template_name: &str, key: &str, text: &str) -> Result<(), String> {
+    let value = parse_number(text).ok_or_else(|| {
+        format!("[{}] {} = \"{}\" is not a number, expected hex (0x...) or decimal", template_name, key, text)
+    })?;
+    if key.contains("size") && value == 0 {
+        return Err(format!("[{}] {} cannot be 0", template_name, key));
+    }
+    if (key == "pin" || key.ends_with("_pin")) && value >= 1020 {
+        return Err(format!("[{}] {} = {} is not a valid interrupt pin (0-1019)", template_name, key, value));
+    }
+    Ok(())
+}
+
+// Fills in the {placeholders} of a template with the parameters of its table in platform_info.toml
+// and with the values computed by runPHI (e.g. allocated addresses), which take precedence.
+// Computed values not used by the template are ignored, while every parameter of the table must be
+// a placeholder of the template, and every placeholder must get a value.
+pub fn render_template(
+    template_name: &str,
+    template: &str,
+    table: Option<&Value>,
+    computed: &[(&str, String)],
+) -> Result<String, String> {
+    let found = placeholders(template);
+    let used = |key: &str| found.iter().any(|(_, _, name)| *name == key);
+
+    let mut params: HashMap<String, String> = HashMap::new();
+    if let Some(table) = table {
+        let table = table.as_table().ok_or_else(|| format!("{} is not a table", template_name))?;
+        for (key, value) in table {
+            if !used(key) {
+                return Err(format!("[{}] unknown parameter {}, the template has no {{{}}}", template_name, key, key));
+            }
+            let text = match value {
+                Value::String(s) => s.trim().to_string(),
+                Value::Integer(i) => i.to_string(),
+                _ => return Err(format!("[{}] {} must be a string or an integer", template_name, key)),
+            };
+            params.insert(key.clone(), text);
+        }
+    }
+    for (key, value) in computed {
+        if used(key) {
+            params.insert(key.to_string(), value.clone());
+        }
+    }
+
+    // Check everything before rendering, so that a wrong template never reaches the cell
+    let mut keys: Vec<&String> = params.keys().collect();
+    keys.sort();
+    for key in keys {
+        // Computed values left empty drop their argument, e.g. a pin the cell does not get
+        if params[key].is_empty() && computed.iter().any(|(name, _)| name == key) {
+            continue;
+        }
+        validate_param(template_name, key, &params[key])?;
+    }
+    let mut missing: Vec<&str> = found.iter().map(|(_, _, name)| *name).filter(|name| !params.contains_key(*name)).collect();
+    missing.sort();
+    missing.dedup();
+    if !missing.is_empty() {
+        return Err(format!("[{}] no value for placeholder(s) {{{}}}", template_name, missing.join("}, {")));
+    }
+
+    let mut rendered = String::with_capacity(template.len());
+    let mut last = 0;
+    for (start, end, name) in &found {
+        rendered.push_str(&template[last..*start]);
+        rendered.push_str(&params[*name]);
+        last = *end;
+    }
+    rendered.push_str(&template[last..]);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(text: &str) -> Value {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn render_template_fills_in_the_placeholders() {
+        let uart = table("phys_start = \"0x09000000\"\nvirt_start = 0\nsize = \"4096\"");
+        let rendered = render_template("UART_TEMPLATE", UART_TEMPLATE, Some(&uart), &[]).unwrap();
+        assert!(rendered.contains(".phys_start = 0x09000000,\n\t.virt_start = 0,\n\t.size = 4096,"), "{}", rendered);
+
+        // The computed values win over the table, the unused ones are ignored
+        let computed = [("phys_start", "0x70000000".to_string()), ("ivshmem_bdf", "1".to_string())];
+        let rendered = render_template("UART_TEMPLATE", UART_TEMPLATE, Some(&uart), &computed).unwrap();
+        assert!(rendered.contains(".phys_start = 0x70000000,"), "{}", rendered);
+
+        // An empty computed value drops its argument
+        let irqchip = table("gic_address = \"0x08000000\"\nuart_pin = 33");
+        let computed = [("ivshmem_demo_pin", String::new()), ("ivshmem_pin", "141".to_string())];
+        let rendered = render_template("IRQ_CHIP_TEMPLATE", IRQ_CHIP_TEMPLATE, Some(&irqchip), &computed).unwrap();
+        assert!(rendered.contains("IRQ_PINS(32, 33, , 141)"), "{}", rendered);
+
+        // The C initializers are no placeholders
+        assert_eq!(render_template("COMM_REGION_TEMPLATE", COMM_REGION_TEMPLATE, None, &[]).unwrap(), COMM_REGION_TEMPLATE);
+    }
+
+    #[test]
+    fn render_template_rejects_wrong_parameters() {
+        let error = |name: &str, template: &str, params: &str| {
+            render_template(name, template, Some(&table(params)), &[]).unwrap_err()
+        };
+        let uart = |size: &str| format!("phys_start = \"0x09000000\"\nvirt_start = \"0x09000000\"\n{}", size);
+
+        assert_eq!(
+            error("UART_TEMPLATE", UART_TEMPLATE, &uart("size = \"0x1000\"\nsise = \"0x1000\"")),
+            "[UART_TEMPLATE] unknown parameter sise, the template has no {sise}"
+        );
+        assert_eq!(error("UART_TEMPLATE", UART_TEMPLATE, &uart("")), "[UART_TEMPLATE] no value for placeholder(s) {size}");
+        assert_eq!(
+            error("IRQ_CHIP_TEMPLATE", IRQ_CHIP_TEMPLATE, "gic_address = \"0x08000000\""),
+            "[IRQ_CHIP_TEMPLATE] no value for placeholder(s) {ivshmem_demo_pin}, {ivshmem_pin}, {uart_pin}"
+        );
+        for size in ["\"4k\"", "\"0x\"", "\"-1\""] {
+            assert_eq!(
+                error("UART_TEMPLATE", UART_TEMPLATE, &uart(&format!("size = {}", size))),
+                format!("[UART_TEMPLATE] size = {} is not a number, expected hex (0x...) or decimal", size)
+            );
+        }
+        assert_eq!(error("UART_TEMPLATE", UART_TEMPLATE, &uart("size = true")), "[UART_TEMPLATE] size must be a string or an integer");
+        assert_eq!(error("UART_TEMPLATE", UART_TEMPLATE, &uart("size = \"0x0\"")), "[UART_TEMPLATE] size cannot be 0");
+        assert_eq!(error("UART_TEMPLATE", UART_TEMPLATE, &uart("size = 0")), "[UART_TEMPLATE] size cannot be 0");
+
+        let pins = |uart_pin: &str| format!("gic_address = \"0x08000000\"\nivshmem_demo_pin = 140\nivshmem_pin = 141\nuart_pin = {}", uart_pin);
+        assert_eq!(
+            error("IRQ_CHIP_TEMPLATE", IRQ_CHIP_TEMPLATE, &pins("1020")),
+            "[IRQ_CHIP_TEMPLATE] uart_pin = 1020 is not a valid interrupt pin (0-1019)"
+        );
+        assert!(render_template("IRQ_CHIP_TEMPLATE", IRQ_CHIP_TEMPLATE, Some(&table(&pins("1019"))), &[]).is_ok());
+        // The computed values are checked as well
+        let computed = [("ivshmem_pin", "0x3fc".to_string())];
+        assert_eq!(
+            render_template("IRQ_CHIP_TEMPLATE", IRQ_CHIP_TEMPLATE, Some(&table(&pins("33"))), &computed).unwrap_err(),
+            "[IRQ_CHIP_TEMPLATE] ivshmem_pin = 1020 is not a valid interrupt pin (0-1019)"
+        );
+    }
+}
@@ -15,22 +15,21 @@ use std::str;
 use toml::Value;
 use std::time::Instant; //TIME CLOCK MONOTONIC
 
-use f2b;
-use logging;
-
+pub mod bootstrap;
 #[allow(non_snake_case)]
 pub mod configGenerator;
+pub mod platform;
+pub mod statefile;
+
+use configGenerator::{memalloc, network};
 
-const WORKPATH: &str = "/usr/share/runPHI";
+//const WORKPATH: &str = "/usr/share/runPHI";
 //const RUNDIR: &str = "/run/runPHI";
 const JAILHOUSE_PATH: &str = "/root/jailhouse/tools/jailhouse";
-const STATEFILE: &str = "state.toml";
 
 // Reads the state file and returns the rcpus string for the given containerid.
 fn get_rcpu_for_container(containerid: &str) -> Result<String, Box<dyn Error>> {
-    let file_path = Path::new(WORKPATH).join(STATEFILE);
-    let content = fs::read_to_string(&file_path)?;
-    let parsed: Value = content.parse::<Value>()?;
+    let parsed: Value = statefile::read()?.parse::<Value>()?;
     if let Some(container) = parsed.get(containerid) {
         if let Some(rcpu) = container.get("rcpus").and_then(|v| v.as_str()) {
             return Ok(rcpu.to_string());
@@ -40,79 +39,50 @@ fn get_rcpu_for_container(containerid: &str) -> Result<String, Box<dyn Error>> {
 }
 
 fn destroy_update_state(containerid: &str) -> Result<(), Box<dyn Error>> {
-    // Load and parse the current state from state.toml
-    let file_path = Path::new(WORKPATH).join(STATEFILE);
-    let content = fs::read_to_string(&file_path)?;
-    let mut parsed_toml: Value = content.parse::<Value>()?;
+    // Load and parse the current state from state.toml, locked until it is written back
+    let _lock = statefile::lock()?;
+    let mut parsed_toml: Value = statefile::read()?.parse::<Value>()?;
 
     // Extract the data we need from the container section, if it exists
-    let (memory, rcpus, pci_bdf) = if let Some(container) = parsed_toml.get(containerid) {
+    let (mut memory, rcpus, pci_bdf, irq_pins, shm, links) = if let Some(container) = parsed_toml.get(containerid) {
         (
-            container.get("memory").and_then(|m| m.as_str()).map(String::from),
+            // A list of segments, or a single one in the states of older runPHI versions
+            match container.get("memory") {
+                Some(Value::Array(segments)) => segments.iter().filter_map(|s| s.as_str()).map(String::from).collect(),
+                Some(Value::String(segment)) => vec![segment.clone()],
+                _ => Vec::new(),
+            },
             container.get("rcpus").and_then(|r| r.as_str()).map(String::from),
             container.get("pci_bdf").and_then(|p| p.as_str()).map(String::from),
+            container.get("irq_pins").and_then(|p| p.as_str()).map(String::from),
+            container.get("shm").and_then(|s| s.as_array()).map(|names| names.iter().filter_map(|n| n.as_str()).map(String::from).collect::<Vec<_>>()).unwrap_or_default(),
+            container.get("ivshmem").and_then(|s| s.as_array()).map(|names| names.iter().filter_map(|n| n.as_str()).map(String::from).collect::<Vec<_>>()).unwrap_or_default(),
         )
     } else {
         return Err(format!("Container {} not found in state.toml", containerid).into());
     };
 
-    // Free memory: Add container's memory segment back to `free_segments` +
-    // + Merging logic for free memory segments
-    if let Some(free_segments) = parsed_toml.get_mut("free_segments") {
-        let segments = free_segments.get_mut("segments").and_then(|s| s.as_array_mut());
-        
-        if let Some(segments) = segments {
-            // Add the new memory segment to the list
-            if let Some(memory) = memory {
-                segments.push(Value::String(memory));
-            }
-
-            // Parse segments into tuples of (start, end)
-            let mut parsed_segments: Vec<(u64, u64)> = segments
-                .iter()
-                .filter_map(|s| s.as_str())
-                .filter_map(|seg| {
-                    let parts: Vec<&str> = seg.split(", ").collect();
-                    if parts.len() == 2 {
-                        Some((
-                            u64::from_str_radix(parts[0].trim_start_matches("0x"), 16).ok()?,
-                            u64::from_str_radix(parts[1].trim_start_matches("0x"), 16).ok()?,
-                        ))
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-
-            // Sort segments by their start address
-            parsed_segments.sort_by_key(|&(start, _)| start);
-
-            // Merge contiguous segments
-            let mut merged_segments = Vec::new();
-            let mut current_segment = parsed_segments[0];
-
-            for &(start, end) in &parsed_segments[1..] {
-                if current_segment.1 == start {
-                    // If contiguous, extend the current segment
-                    current_segment.1 = end;
-                } else {
-                    // Otherwise, save the current segment and start a new one
-                    merged_segments.push(current_segment);
-                    current_segment = (start, end);
-                }
+    // Leave the shared-memory channels, the ones left without members are freed with the memory
+    memory.extend(configGenerator::shm::leave(&mut parsed_toml, containerid, &shm).iter().map(memalloc::format_segment));
+    // and the IVSHMEM links, the ones left without members give back their device numbers too
+    memory.extend(configGenerator::ivshmem::leave(&mut parsed_toml, containerid, &links).iter().map(memalloc::format_segment));
+
+    // Free memory: Add container's memory segments back to `free_segments`, merging the contiguous ones
+    if let Some(segments) = parsed_toml.get_mut("free_segments").and_then(|f| f.get_mut("segments")).and_then(|s| s.as_array_mut()) {
+        let mut free: Vec<memalloc::Segment> = segments
+            .iter()
+            .filter_map(|s| s.as_str())
+            .filter_map(|seg| memalloc::parse_segment(seg).ok())
+            .collect();
+        for segment in memory.iter().filter_map(|m| memalloc::parse_segment(m).ok()) {
+            // The rest of the container is freed anyway
+            if let Err(e) = memalloc::release(&mut free, segment) {
+                logging::log_message(logging::Level::Error, format!("Memory of id {} not freed: {}", containerid, e).as_str());
             }
-            // Add the last segment
-            merged_segments.push(current_segment);
-
-            // Convert merged segments back to string format and update `segments`
-            *segments = merged_segments
-                .into_iter()
-                .map(|(start, end)| Value::String(format!("0x{:x}, 0x{:x}", start, end)))
-                .collect();
         }
+        *segments = free.iter().map(|s| Value::String(memalloc::format_segment(s))).collect();
     }
 
-
     // Free rcpus: Add container's `rcpus` back to `free_rcpus`
     if let Some(rcpus) = rcpus {
         if rcpus != "none" {
@@ -132,7 +102,8 @@ fn destroy_update_state(containerid: &str) -> Result<(), Box<dyn Error>> {
     // Free pci_bdf: Add container's `pci_bdf` back to `free_pci_devices_bdf`
     if let Some(pci_bdf) = pci_bdf {
         if pci_bdf != "none" {
-            if let Ok(bdf_value) = pci_bdf.parse::<i64>() {
+            // A BDF still attached to the sandbox of the pod is given back by the CNI plugin
+            if let Some(bdf_value) = pci_bdf.parse::<i64>().ok().filter(|b| !network::attached(&parsed_toml, *b as u32)) {
                 if let Some(free_pci_devices_bdf) = parsed_toml.get_mut("free_pci_devices_bdf") {
                     let bdf = free_pci_devices_bdf.get_mut("bdf").and_then(|b| b.as_array_mut());
                     if let Some(bdf) = bdf {
@@ -143,6 +114,23 @@ fn destroy_update_state(containerid: &str) -> Result<(), Box<dyn Error>> {
         }
     }
 
+    // Free irq_pins: Add container's `irq_pins` back to `free_irq_pins`
+    if let Some(irq_pins) = irq_pins {
+        if irq_pins != "none" {
+            if let Some(free_irq_pins) = parsed_toml.get_mut("free_irq_pins") {
+                let pins = free_irq_pins.get_mut("pins").and_then(|p| p.as_array_mut());
+                if let Some(pins) = pins {
+                    for pin in irq_pins.split(',').map(|s| s.trim()) {
+                        if let Ok(pin_value) = pin.parse::<i64>() {
+                            pins.push(Value::Integer(pin_value));
+                        }
+                    }
+                    pins.sort_by_key(|p| p.as_integer());
+                }
+            }
+        }
+    }
+
     // Remove the container section
     parsed_toml.as_table_mut().unwrap().remove(containerid);
 
@@ -154,42 +142,42 @@ fn destroy_update_state(containerid: &str) -> Result<(), Box<dyn Error>> {
     }
 
     // Save the updated state back to state.toml
-    let updated_content = toml::to_string(&parsed_toml)?;
-    fs::write(&file_path, updated_content)?;
+    statefile::write(&toml::to_string(&parsed_toml)?)?;
 
     Ok(())
 }
 
-pub fn startguest(containerid: &str, crundir: &str) -> Result<(), Box<dyn Error>> {
-    logging::log_message(logging::Level::Debug, format!("Start guest for cell with id {}", containerid).as_str());
+pub fn startguest(containerid: &str, cellname: &str, crundir: &str) -> Result<(), Box<dyn Error>> {
+    logging::log_message(logging::Level::Debug, format!("Start guest for cell {} with id {}", cellname, containerid).as_str());
     //let start = Instant::now(); //TAKE THE START TIME OF THE PHASE
     let os_content = std::fs::read_to_string(format!("{}/OS", crundir))?;
     let os = os_content.trim();
     if os == "linux" {
         println!("Linux non-root cell {} has already been running, connect to Guest through ssh root from localhost to port number exposed", containerid);
     } else {
-        let command_str = format!("{} {} {} {}", JAILHOUSE_PATH, "cell", "start", containerid);
+        let command_str = format!("{} {} {} {}", JAILHOUSE_PATH, "cell", "start", cellname);
         logging::log_message(logging::Level::Debug, format!("Starting cell with id {}", containerid).as_str());
         logging::log_message(logging::Level::Trace, format!("Starting cell by calling: {}", &command_str).as_str());
         let _ = Command::new(JAILHOUSE_PATH)
             .arg("cell")
             .arg("start")
-            .arg(containerid)
+            .arg(cellname)
             .output()
             .expect("Failed to execute command");
     }
     //log_elapsed_time(start,"Duration of start cell"); //TAKE THE END TIME OF THE PHASE
-    return Ok(());
+    Ok(())
 }
 
-pub fn stopguest(containerid: &str, crundir: &str) -> Result<(), Box<dyn Error>> {
+pub fn stopguest(containerid: &str, cellname: &str, crundir: &str) -> Result<(), Box<dyn Error>> {
+    logging::log_message(logging::Level::Debug, format!("Stop guest for cell {} with id {}", cellname, containerid).as_str());
     //let start_time = Instant::now(); //TAKE THE START TIME OF THE PHASE
-    let command_str = format!("{} {} {} {}", JAILHOUSE_PATH, "cell", "shutdown", containerid);
+    let command_str = format!("{} {} {} {}", JAILHOUSE_PATH, "cell", "shutdown", cellname);
     logging::log_message(logging::Level::Trace, format!("The command is: {}", &command_str).as_str());
     let _ = Command::new(JAILHOUSE_PATH)
         .arg("cell")
         .arg("shutdown")
-        .arg(containerid)
+        .arg(cellname)
         .output()
         .expect("Failed to execute command");
     // Now kill caronte
@@ -200,23 +188,23 @@ pub fn stopguest(containerid: &str, crundir: &str) -> Result<(), Box<dyn Error>>
     let _ = nix::sys::signal::kill(pid, Signal::SIGTERM);
     //log_elapsed_time(start,"Duration of stop cell"); //TAKE THE END TIME OF THE PHASE
 
-    return Ok(());
+    Ok(())
 }
 
 //TODO: We need to implement a way to deassign the pci_devices (ivshmem) from a cell when we destroy it
 //For now I'll put it here but it should be something that the jailhouse driver offers just as with the cpus
-pub fn destroyguest(containerid: &str, crundir: &str) -> Result<(), Box<dyn Error>> {
+pub fn destroyguest(containerid: &str, cellname: &str, crundir: &str) -> Result<(), Box<dyn Error>> {
 
     //let start_time = Instant::now(); //TAKE THE START TIME OF THE PHASE
     let _ = destroy_update_state(containerid);
 
-    // Execute the command to destroy the jailhouse cell using the name of the cell containerid
-    let command_str = format!("{} {} {} {}", JAILHOUSE_PATH, "cell", "destroy", containerid);
+    // Execute the command to destroy the jailhouse cell using the name of the cell
+    let command_str = format!("{} {} {} {}", JAILHOUSE_PATH, "cell", "destroy", cellname);
     logging::log_message(logging::Level::Trace, format!("The command is: {}", &command_str).as_str());
     let _ = Command::new(JAILHOUSE_PATH)
         .arg("cell")
         .arg("destroy")
-        .arg(containerid)
+        .arg(cellname)
         .output()
         .expect("Failed to execute command");
 
@@ -226,15 +214,15 @@ pub fn destroyguest(containerid: &str, crundir: &str) -> Result<(), Box<dyn Erro
     let pidk: i32 = pidtokill.parse().expect("Failed to parse number");
     let pid = Pid::from_raw(pidk);
     let _ = nix::sys::signal::kill(pid, Signal::SIGTERM);
-    fs::remove_dir_all(&crundir).ok();
+    fs::remove_dir_all(crundir).ok();
 
     //log_elapsed_time(start,"Duration of destroy cell"); //TAKE THE END TIME OF THE PHASE
-    return Ok(());
+    Ok(())
 }
 
 pub fn cleanup(_containerid: &str, crundir: &str) -> Result<(), Box<dyn Error>> {
-    fs::remove_dir_all(&crundir).ok();
-    return Ok(());
+    fs::remove_dir_all(crundir).ok();
+    Ok(())
 }
 
 // Create spawns a process, caronte, that is required to keep the container open. Caronte is set as
@@ -245,7 +233,7 @@ pub fn createguest(fc: &f2b::FrontendConfig, ic: &f2b::ImageConfig) -> Result<()
 
     // We have to differentiate among OSes, because linux has a different jh command
     // while other OSes may have special params, e.g. loading address for zephyr
-    if ic.os_var != "linux" {
+    if ic.os_var != f2b::OsKind::Linux {
         // Handle baremetal or libOS built with application
         // Here we have to wait both commands to return to guarantee ordering, and then we start caronte
         // caronte is needed to keep a pid alive expected by containerd before giving the start
@@ -271,7 +259,7 @@ pub fn createguest(fc: &f2b::FrontendConfig, ic: &f2b::ImageConfig) -> Result<()
         // to omnivisor during the cell load. Notice that here onivisor takes care 
         // of placing the binary in the appropriate memory area.
         if ic.rpu_req {
-            let inmate_name = ic.inmate.rsplitn(2, '/').next().unwrap_or("");
+            let inmate_name = ic.inmate.file_name();
             let sym_destination = format!("/lib/firmware/{}", &inmate_name) ;
             logging::log_message(logging::Level::Trace, format!("The symdest is {}", &sym_destination).as_str());
            
@@ -294,8 +282,8 @@ pub fn createguest(fc: &f2b::FrontendConfig, ic: &f2b::ImageConfig) -> Result<()
             // Create the command but not run it, first log it. Then run it and capture its output.
             let mut cmd_load = Command::new(JAILHOUSE_PATH);
             cmd_load.arg("cell").arg("load")
-                .arg(&fc.containerid)
-                .arg("-r").arg(&inmate_name)   
+                .arg(&fc.cellname)
+                .arg("-r").arg(inmate_name)   
                 .arg(&rcpu);
                 
             let command_str: Vec<String> = std::iter::once(cmd_load.get_program().to_string_lossy().to_string())
@@ -336,13 +324,18 @@ pub fn createguest(fc: &f2b::FrontendConfig, ic: &f2b::ImageConfig) -> Result<()
             logging::log_message(logging::Level::Debug, format!("Loading cell with id {}", &fc.containerid).as_str());
             
             let mut cmd_load = Command::new(JAILHOUSE_PATH); 
-            cmd_load.arg("cell").arg("load").arg(&fc.containerid).arg(&ic.inmate);
+            cmd_load.arg("cell").arg("load").arg(&fc.cellname).arg(ic.inmate.as_path());
 
             // Append the starting vaddress when present in the JSON
             //TODO MANAGE OMNIVISOR CONTAINERS APU
-            if !ic.starting_vaddress.is_empty() {
-                cmd_load.arg("-a").arg(&ic.starting_vaddress);
-            } 
+            if let Some(vaddress) = ic.starting_vaddress {
+                cmd_load.arg("-a").arg(vaddress.to_string());
+            }
+            // The network configuration block goes at its address in the cell
+            let crundir = Path::new(&fc.crundir);
+            if let Ok(address) = fs::read_to_string(crundir.join(network::BLOCK_ADDRESS_FILE)) {
+                cmd_load.arg(crundir.join(network::BLOCK_FILE)).arg("-a").arg(address.trim());
+            }
             
             let command_str: Vec<String> = std::iter::once(cmd_load.get_program().to_string_lossy().to_string())
                .chain(cmd_load.get_args().map(|arg| arg.to_string_lossy().to_string())).collect();
@@ -353,27 +346,42 @@ pub fn createguest(fc: &f2b::FrontendConfig, ic: &f2b::ImageConfig) -> Result<()
         }
 
         //let caronte_command = format!("echo \"caronte is listening\"");
-        let caronte_command = format!("");
+        let caronte_command = String::new();
         logging::log_message(logging::Level::Debug, format!("Starting caronted with id {}", &fc.containerid).as_str());
         let start_output = Command::new("/usr/share/runPHI/caronte")
             .arg(caronte_command)
-            .arg(&fc.containerid)
+            .arg(&fc.cellname)
             .spawn()?;
         let pid = start_output.id();
         std::fs::write(&fc.pidfile, format!("{}", pid)).expect("Unable to write pidfile");
         //log_elapsed_time(start,"Duration of create cell"); //TAKE THE END TIME OF THE PHASE
     
-    } else if ic.os_var == "linux" {
-        // Here we manage separately the (unlikely) linux case due to a dedicated jh command
-        // Commands only for Linux aarch64
-        //TODO: create a gitlab branch for x86
-        let command = format!(
-                    "jailhouse cell linux {} {} -d {} -i {} -c \"console ttyAMA0,115200\"",
-                    fc.containerid, ic.kernel, ic.dtb, ic.cpio
+    } else {
+        // Linux cells are booted by a dedicated jh command, on the generated cell config
+        let x86 = configGenerator::node_arch()? == configGenerator::Arch::X86;
+        let mut command = format!(
+                    "{} cell linux {} {}",
+                    JAILHOUSE_PATH,
+                    shell_quote(&cellfile),
+                    shell_quote(&ic.kernel.as_ref().ok_or("No kernel for linux cell")?.to_string())
         );
+        // On x86 the kernel is a bzImage, booted without a dtb, optionally with an initrd
+        if !x86 {
+            command.push_str(&format!(" -d {}", shell_quote(&ic.dtb.as_ref().ok_or("No dtb for linux cell")?.to_string())));
+        }
+        let initramfs = if x86 { ic.initrd.as_ref().or(ic.cpio.as_ref()) } else { ic.cpio.as_ref() };
+        if let Some(initramfs) = initramfs {
+            command.push_str(&format!(" -i {}", shell_quote(&initramfs.to_string())));
+        }
+        // The command line built by the config generator, from the console, root and network
+        let cmdline = fs::read_to_string(Path::new(&fc.crundir).join(configGenerator::linux::CMDLINE_FILE)).unwrap_or_default();
+        if !cmdline.trim().is_empty() {
+            command.push_str(&format!(" -c {}", shell_quote(cmdline.trim())));
+        }
+        logging::log_message(logging::Level::Trace, format!("Starting linux cell by calling: {}", command).as_str());
         let start_output = Command::new("/usr/share/runPHI/caronte")
                     .arg(command)
-                    .arg(&fc.containerid)
+                    .arg(&fc.cellname)
                     .spawn()?;
         let pid = start_output.id();
         std::fs::write(&fc.pidfile, format!("{}", pid)).expect("Unable to write pidfile");
@@ -381,11 +389,16 @@ pub fn createguest(fc: &f2b::FrontendConfig, ic: &f2b::ImageConfig) -> Result<()
     Ok(())
 }
 
+// Quotes an argument of the command run by caronte through the shell
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
 pub fn storeinfo(fc: &f2b::FrontendConfig, ic: &f2b::ImageConfig) -> Result<(), Box<dyn Error>> {
     std::fs::write(format!("{}/bundle", fc.crundir), &fc.bundle)?;
     std::fs::write(format!("{}/pidfile", fc.crundir), &fc.pidfile)?;
-    std::fs::write(format!("{}/OS", fc.crundir), &ic.os_var)?;
-    return Ok(());
+    std::fs::write(format!("{}/OS", fc.crundir), ic.os_var.as_str())?;
+    Ok(())
 }
 
 // Function to log the elapsed time with a custom message
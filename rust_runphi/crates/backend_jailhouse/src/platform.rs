@@ -0,0 +1,636 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Linter of the platform description of a node: platform_info.toml, with the templates of the
+// cells, and the initial state.toml, with the resources runPHI allocates to the cells.
+// Both are hand-written, and most mistakes would otherwise surface only when a cell is created
+// on the board. Every problem is collected, with the file and the entry it was found in.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use toml::Value;
+
+use crate::configGenerator::celldesc::{CellDesc, IrqChip, MemRegion, JAILHOUSE_CON_ACCESS_MMIO, JAILHOUSE_MEM_COMM_REGION};
+use crate::configGenerator::{self, communication, cpu, ivshmem, linux, memalloc, network};
+use crate::configGenerator::templates::{self, get_platform_templates, get_preamble, render_template, LOW_RAM_TEMPLATES, MAIN_RAM_TEMPLATES, NETWORKING_TEMPLATES};
+
+const PAGE_SIZE: u64 = 0x1000;
+// Shared peripheral interrupts of the GIC, the only ones a cell can be assigned
+const SPI_FIRST: u64 = 32;
+const SPI_LAST: u64 = 1019;
+// Pins covered by the pin_bitmap of an irqchip
+const IRQCHIP_PINS: u64 = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub file: String,
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.file, self.location, self.message)
+    }
+}
+
+// A physical range fixed by the platform, with where it comes from
+struct Range {
+    location: String,
+    start: u64,
+    end: u64,
+}
+
+struct Checker {
+    platform_file: String,
+    state_file: String,
+    problems: Vec<Problem>,
+}
+
+impl Checker {
+    fn platform(&mut self, location: &str, message: String) {
+        self.problems.push(Problem { file: self.platform_file.clone(), location: location.to_string(), message });
+    }
+
+    fn state(&mut self, location: &str, message: String) {
+        self.problems.push(Problem { file: self.state_file.clone(), location: location.to_string(), message });
+    }
+}
+
+fn load(path: &Path) -> Result<Value, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("cannot read the file: {}", e))?;
+    content.parse::<Value>().map_err(|e| format!("invalid TOML: {}", e))
+}
+
+fn overlaps(a: &Range, b: &Range) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+// Checks the platform description, returning every problem found (none if it is consistent)
+pub fn check(platform_path: &Path, state_path: &Path, template_dir: &Path) -> Vec<Problem> {
+    let mut ck = Checker {
+        platform_file: platform_path.display().to_string(),
+        state_file: state_path.display().to_string(),
+        problems: Vec::new(),
+    };
+    let platform = load(platform_path).map_err(|e| ck.platform("-", e)).ok();
+    let state = load(state_path).map_err(|e| ck.state("-", e)).ok();
+
+    let resources = match &state {
+        Some(state) => check_state(&mut ck, state),
+        None => Resources::default(),
+    };
+    if let Some(platform) = &platform {
+        check_platform(&mut ck, platform, template_dir, &resources);
+    }
+    ck.problems
+}
+
+// Resources of the state the platform is checked against
+#[derive(Default)]
+struct Resources {
+    available: Option<Range>,
+    bdfs: Vec<u64>,
+    // Free pins for the IVSHMEM devices, when allocated by runPHI
+    irq_pins: Option<Vec<u64>>,
+    // Device numbers left to the IVSHMEM links
+    link_bdfs: Vec<u64>,
+}
+
+// Checks the memory, the BDFs and the interrupt pins of state.toml, returning them
+fn check_state(ck: &mut Checker, state: &Value) -> Resources {
+    let location = "[available_memory] memory";
+    let available = match state.get("available_memory").and_then(|m| m.get("memory")) {
+        Some(Value::String(memory)) => match memalloc::parse_segment(memory) {
+            Ok((start, end)) => {
+                if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+                    ck.state(location, format!("0x{:x}-0x{:x} is not page aligned", start, end));
+                }
+                if start == end {
+                    ck.state(location, "no memory is available".to_string());
+                }
+                Some(Range { location: location.to_string(), start, end })
+            }
+            Err(e) => {
+                ck.state(location, e);
+                None
+            }
+        },
+        Some(_) => {
+            ck.state(location, "must be a \"start, end\" string".to_string());
+            None
+        }
+        None => {
+            ck.state(location, "missing".to_string());
+            None
+        }
+    };
+
+    let mut segments: Vec<Range> = Vec::new();
+    match state.get("free_segments").and_then(|s| s.get("segments")) {
+        Some(Value::Array(list)) => {
+            for (i, segment) in list.iter().enumerate() {
+                let location = format!("[free_segments] segments[{}]", i);
+                let Some(segment) = segment.as_str() else {
+                    ck.state(&location, "must be a \"start, end\" string".to_string());
+                    continue;
+                };
+                let (start, end) = match memalloc::parse_segment(segment) {
+                    Ok(range) => range,
+                    Err(e) => {
+                        ck.state(&location, e);
+                        continue;
+                    }
+                };
+                if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+                    ck.state(&location, format!("0x{:x}-0x{:x} is not page aligned", start, end));
+                }
+                if let Some(available) = &available {
+                    if start < available.start || end > available.end {
+                        ck.state(&location, format!("0x{:x}-0x{:x} is outside the available memory 0x{:x}-0x{:x}", start, end, available.start, available.end));
+                    }
+                }
+                let range = Range { location, start, end };
+                for other in segments.iter().filter(|other| overlaps(other, &range)) {
+                    ck.state(&range.location, format!("overlaps {}", other.location));
+                }
+                segments.push(range);
+            }
+        }
+        Some(_) => ck.state("[free_segments] segments", "must be an array".to_string()),
+        None => ck.state("[free_segments] segments", "missing".to_string()),
+    }
+
+    let mut bdfs: Vec<u64> = Vec::new();
+    match state.get("free_pci_devices_bdf").and_then(|b| b.get("bdf")) {
+        Some(Value::Array(list)) => {
+            for (i, bdf) in list.iter().enumerate() {
+                let location = format!("[free_pci_devices_bdf] bdf[{}]", i);
+                // The BDF is the PCI device number, the first one (0) is left to the demo device
+                match bdf.as_integer() {
+                    Some(bdf) if (1..32).contains(&bdf) => {
+                        if bdfs.contains(&(bdf as u64)) {
+                            ck.state(&location, format!("{} is repeated", bdf));
+                        } else {
+                            bdfs.push(bdf as u64);
+                        }
+                    }
+                    _ => ck.state(&location, format!("{} is not a PCI device number between 1 and 31", bdf)),
+                }
+            }
+        }
+        Some(_) => ck.state("[free_pci_devices_bdf] bdf", "must be an array".to_string()),
+        None => ck.state("[free_pci_devices_bdf] bdf", "missing".to_string()),
+    }
+
+    // Each BDF is linked to the root cell, whose memory lies outside the one of the cells
+    for (i, bdf) in bdfs.iter().enumerate() {
+        let location = format!("[free_pci_devices_bdf] bdf[{}]", i);
+        match ivshmem::network_link(state, *bdf as u32) {
+            Ok((link, _)) => {
+                let range = Range { location: String::new(), start: link.memory.0, end: link.memory.1 };
+                if let Some(available) = available.as_ref().filter(|available| overlaps(available, &range)) {
+                    ck.state(&location, format!("the memory 0x{:x}-0x{:x} of link \"{}\" overlaps {}", range.start, range.end, link.name, available.location));
+                }
+            }
+            Err(e) => ck.state(&location, e),
+        }
+    }
+
+    let irq_pins = match state.get("free_irq_pins") {
+        Some(section) => match section.get("pins") {
+            Some(Value::Array(list)) => {
+                let mut pins: Vec<u64> = Vec::new();
+                for (i, pin) in list.iter().enumerate() {
+                    let location = format!("[free_irq_pins] pins[{}]", i);
+                    match pin.as_integer().and_then(|pin| u64::try_from(pin).ok()) {
+                        Some(pin) if (SPI_FIRST..=SPI_LAST).contains(&pin) => {
+                            if pins.contains(&pin) {
+                                ck.state(&location, format!("{} is repeated", pin));
+                            } else {
+                                pins.push(pin);
+                            }
+                        }
+                        _ => ck.state(&location, format!("{} is not a shared peripheral interrupt ({}-{})", pin, SPI_FIRST, SPI_LAST)),
+                    }
+                }
+                if pins.len() < bdfs.len() {
+                    ck.state("[free_irq_pins] pins", format!("{} pins for {} free BDFs, networked cells may run out of pins", pins.len(), bdfs.len()));
+                }
+                Some(pins)
+            }
+            _ => {
+                ck.state("[free_irq_pins] pins", "missing, or not an array".to_string());
+                None
+            }
+        },
+        None => None,
+    };
+
+    // The device numbers of the links are optional, and apart from the networking ones
+    let link_bdfs: Vec<u64> = match ivshmem::free_bdfs(state) {
+        Ok(list) => list.into_iter().map(u64::from).collect(),
+        Err(e) => {
+            let location = format!("[{}]", ivshmem::FREE_BDFS);
+            ck.state(&format!("{} bdf", location), e.trim_start_matches(&format!("{} ", location)).to_string());
+            Vec::new()
+        }
+    };
+    for (i, bdf) in link_bdfs.iter().enumerate() {
+        let location = format!("[{}] bdf[{}]", ivshmem::FREE_BDFS, i);
+        if link_bdfs[..i].contains(bdf) {
+            ck.state(&location, format!("{} is repeated", bdf));
+        } else if bdfs.contains(bdf) {
+            ck.state(&location, format!("{} is also in [free_pci_devices_bdf]", bdf));
+        } else if ivshmem::taken_bdfs(state).contains(&(*bdf as u32)) {
+            ck.state(&location, format!("{} is already taken by a link of [ivshmem_links]", bdf));
+        }
+    }
+
+    Resources { available, bdfs, irq_pins, link_bdfs }
+}
+
+// Names of the templates listed in platform_info.toml under [section] key
+fn template_list<'a>(ck: &mut Checker, platform: &'a Value, section: &str, key: &str) -> Vec<(String, &'a str)> {
+    let mut names = Vec::new();
+    match platform.get(section).and_then(|s| s.get(key)) {
+        Some(Value::Array(list)) => {
+            for (i, name) in list.iter().enumerate() {
+                let location = format!("[{}] {}[{}]", section, key, i);
+                match name.as_str() {
+                    Some(name) => names.push((location, name)),
+                    None => ck.platform(&location, "must be the name of a template".to_string()),
+                }
+            }
+        }
+        Some(_) => ck.platform(&format!("[{}] {}", section, key), "must be an array".to_string()),
+        None => ck.platform(&format!("[{}] {}", section, key), "missing".to_string()),
+    }
+    names
+}
+
+// Checks that the interrupt pins in the table of a template are routed by one of the irqchips it
+// renders to, i.e. within the 128 pins from their pin_base. Without irqchips, the pins must be
+// shared peripheral interrupts of the GIC
+fn check_pins(ck: &mut Checker, platform: &Value, template_name: &str, irqchips: &[IrqChip]) {
+    let Some(table) = platform.get(template_name).and_then(|t| t.as_table()) else { return };
+    for (key, value) in table {
+        if key != "pin" && !key.ends_with("_pin") {
+            continue;
+        }
+        let pin = match value {
+            Value::String(s) => templates::parse_number(s.trim()),
+            Value::Integer(i) => u64::try_from(*i).ok(),
+            _ => None,
+        };
+        let location = format!("[{}] {}", template_name, key);
+        match pin {
+            Some(pin) if irqchips.is_empty() => {
+                if !(SPI_FIRST..=SPI_LAST).contains(&pin) {
+                    ck.platform(&location, format!("{} is not a shared peripheral interrupt ({}-{})", value, SPI_FIRST, SPI_LAST));
+                }
+            }
+            Some(pin) => {
+                if !irqchips.iter().any(|chip| (chip.pin_base as u64..chip.pin_base as u64 + IRQCHIP_PINS).contains(&pin)) {
+                    ck.platform(&location, format!("{} is not routed by any irqchip of the template", value));
+                }
+            }
+            None => ck.platform(&location, format!("{} is not an interrupt number", value)),
+        }
+    }
+}
+
+fn check_platform(ck: &mut Checker, platform: &Value, template_dir: &Path, resources: &Resources) {
+    let bdfs = &resources.bdfs;
+    let templates = match get_platform_templates(platform, template_dir) {
+        Ok(templates) => templates,
+        Err(e) => {
+            ck.platform("[templates]", e);
+            return;
+        }
+    };
+
+    // The preambles, the one of Linux cells being optional
+    let mut vpci_irq_base = 0;
+    for key in ["preamble", "linux_preamble"] {
+        let location = format!("[jailhouse_preamble] {}", key);
+        match platform.get("jailhouse_preamble").and_then(|p| p.get(key)).and_then(|p| p.as_str()) {
+            Some(preamble) => match get_preamble(&templates, preamble) {
+                Ok((name, template)) => {
+                    let mut desc = CellDesc::new("check");
+                    match render_template(&name, template, platform.get(&name), &[]) {
+                        Ok(rendered) => {
+                            if let Err(e) = desc.apply_preamble(&rendered) {
+                                ck.platform(&location, format!("{}: {}", name, e));
+                            } else if key == "preamble" {
+                                vpci_irq_base = desc.header.vpci_irq_base as u64;
+                            }
+                        }
+                        Err(e) => ck.platform(&location, e),
+                    }
+                }
+                Err(e) => ck.platform(&location, e),
+            },
+            None if key == "preamble" => ck.platform(&location, "missing".to_string()),
+            None => {}
+        }
+    }
+
+    // The architecture, when it does not follow from the preambles
+    if let Err(e) = configGenerator::platform_arch(platform) {
+        ck.platform("[jailhouse_preamble] arch", e.trim_start_matches("[jailhouse_preamble] arch ").to_string());
+    }
+
+    // The UART given to the cells asking for it
+    let uart = communication::platform_console(platform).unwrap_or_else(|e| {
+        ck.platform("[console]", e.trim_start_matches("[console] ").to_string());
+        None
+    });
+
+    // The CPUs reserved to the root cell and the clusters
+    if let Err(e) = cpu::platform_cpus(platform) {
+        let (location, message) = e.split_once(": ").unwrap_or(("[cpus]", e.as_str()));
+        ck.platform(location, message.to_string());
+    }
+
+    // The alignment and the guard gap of the cells
+    if let Err(e) = memalloc::platform_memory(platform) {
+        ck.platform("[memory]", e.trim_start_matches("[memory] ").to_string());
+    }
+
+    // The subnet of the links of the cells and the interfaces of the root cell
+    match network::platform_network(platform) {
+        Ok(policy) => {
+            for bdf in bdfs {
+                if let Err(e) = network::link(&policy, *bdf as u32) {
+                    ck.platform("[network] subnet", e);
+                }
+            }
+        }
+        Err(e) => ck.platform("[network]", e.trim_start_matches("[network] ").to_string()),
+    }
+
+    // The console of the kernel of linux cells
+    if let Err(e) = linux::platform_console(platform) {
+        ck.platform("[linux]", e.trim_start_matches("[linux] ").to_string());
+    }
+
+    // The memory regions, keeping the ones at a fixed address
+    let mut fixed: Vec<Range> = Vec::new();
+    for (location, name) in template_list(ck, platform, "mem_regions", "regions") {
+        if NETWORKING_TEMPLATES.contains(&name) {
+            ck.platform(&location, format!("{} is no template any more, the networking regions come with the links of the root cell in [ivshmem_links] of the state", name));
+            continue;
+        }
+        let Some(template) = templates.get(name) else {
+            ck.platform(&location, format!("unknown template {}", name));
+            continue;
+        };
+        check_pins(ck, platform, name, &[]);
+        // Values computed by runPHI, as in mem.rs: one instance of the template for each of them
+        let instances: Vec<(String, Vec<(&str, String)>)> = match name {
+            // Carved out of the free segments, only the template itself is checked
+            _ if LOW_RAM_TEMPLATES.contains(&name) || MAIN_RAM_TEMPLATES.contains(&name) => {
+                vec![(String::new(), vec![("phys_start", "0x0".to_string()), ("size", format!("0x{:x}", PAGE_SIZE))])]
+            }
+            _ => vec![(String::new(), Vec::new())],
+        };
+        for (suffix, computed) in instances {
+            let mut desc = CellDesc::new("check");
+            let error = match render_template(name, template, platform.get(name), &computed) {
+                Ok(rendered) => desc.add_mem_regions(&rendered).err().map(|e| format!("{}{}: {}", name, suffix, e)),
+                Err(e) => Some(e),
+            };
+            if let Some(e) = error {
+                ck.platform(&location, e);
+                break;
+            }
+            if LOW_RAM_TEMPLATES.contains(&name) || MAIN_RAM_TEMPLATES.contains(&name) {
+                continue;
+            }
+            let regions: Vec<&MemRegion> = desc.mem_regions.iter().filter(|r| r.size > 0 && r.flags & JAILHOUSE_MEM_COMM_REGION == 0).collect();
+            for (j, region) in regions.iter().enumerate() {
+                fixed.push(Range {
+                    location: format!("{} ({}{}, region {})", location, name, suffix, j),
+                    start: region.phys_start,
+                    end: region.phys_start.saturating_add(region.size),
+                });
+            }
+        }
+    }
+
+    // An MMIO UART must be mapped by a region, to be usable by its owner
+    if let Some(uart) = uart.filter(|u| u.flags as u64 & JAILHOUSE_CON_ACCESS_MMIO != 0) {
+        if !fixed.iter().any(|r| r.start <= uart.address && uart.address < r.end) {
+            ck.platform("[console] address", format!("0x{:x} is not mapped by any region of [mem_regions] regions", uart.address));
+        }
+    }
+
+    // Fixed regions cannot overlap each other, nor the memory given to the cells
+    for (i, range) in fixed.iter().enumerate() {
+        let mut others: Vec<&Range> = fixed[..i].iter().collect();
+        others.extend(resources.available.iter());
+        for other in others.into_iter().filter(|other| overlaps(other, range)) {
+            let message = format!("0x{:x}-0x{:x} overlaps {}", range.start, range.end, other.location);
+            ck.platform(&range.location, message);
+        }
+    }
+
+    // The devices, whose BDFs cannot be taken by the links
+    let mut demo = false;
+    let mut fixed_bdfs: HashMap<u16, String> = HashMap::new();
+    for (location, name) in template_list(ck, platform, "devices", "devs") {
+        if NETWORKING_TEMPLATES.contains(&name) {
+            ck.platform(&location, format!("{} is no template any more, the networking device comes with the links of the root cell in [ivshmem_links] of the state", name));
+            continue;
+        }
+        let Some(template) = templates.get(name) else {
+            ck.platform(&location, format!("unknown template {}", name));
+            continue;
+        };
+        demo |= templates::has_placeholder(template, "ivshmem_demo_pin");
+        let probe = bdfs.first().copied().unwrap_or(1);
+        // Values computed by runPHI, as in device.rs
+        let mut computed = vec![
+            ("ivshmem_bdf", probe.to_string()),
+            ("ivshmem_demo_pin", (vpci_irq_base + SPI_FIRST).to_string()),
+        ];
+        let allocated_pins = resources.irq_pins.as_ref().filter(|_| templates::has_placeholder(template, "ivshmem_pin"));
+        if let Some(pins) = allocated_pins {
+            computed.push(("ivshmem_pin", pins.first().copied().unwrap_or(SPI_FIRST).to_string()));
+        }
+        let mut desc = CellDesc::new("check");
+        let error = match render_template(name, template, platform.get(name), &computed) {
+            Ok(rendered) => desc.add_elements(&rendered).err().map(|e| format!("{}: {}", name, e)),
+            Err(e) => Some(e),
+        };
+        if let Some(e) = error {
+            ck.platform(&location, e);
+            continue;
+        }
+        check_pins(ck, platform, name, &desc.irqchips);
+        for pin in allocated_pins.into_iter().flatten() {
+            if !desc.irqchips.iter().any(|chip| (chip.pin_base as u64..chip.pin_base as u64 + IRQCHIP_PINS).contains(pin)) {
+                ck.state("[free_irq_pins] pins", format!("{} is not routed by any irqchip of {}", pin, name));
+            }
+        }
+        for device in &desc.pci_devices {
+            fixed_bdfs.insert(device.bdf >> 3, format!("{} ({})", location, name));
+        }
+    }
+    // Cells with the demo device take its pin too
+    if let Some(pins) = resources.irq_pins.as_ref().filter(|pins| demo && pins.len() < 2 * bdfs.len()) {
+        ck.state("[free_irq_pins] pins", format!("{} pins for {} free BDFs with the demo device, networked cells may run out of pins", pins.len(), bdfs.len()));
+    }
+    for (i, bdf) in bdfs.iter().enumerate() {
+        if let Some(owner) = fixed_bdfs.get(&(*bdf as u16)) {
+            ck.state(&format!("[free_pci_devices_bdf] bdf[{}]", i), format!("{} is already taken by the device of {}", bdf, owner));
+        }
+    }
+    for (i, bdf) in resources.link_bdfs.iter().enumerate() {
+        if let Some(owner) = fixed_bdfs.get(&(*bdf as u16)) {
+            ck.state(&format!("[{}] bdf[{}]", ivshmem::FREE_BDFS, i), format!("{} is already taken by the device of {}", bdf, owner));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // The QEMU board of target/target_configs, with two networked BDFs
+    const PLATFORM: &str = r#"
+[mem_regions]
+regions = ["UART_TEMPLATE", "RAM0_TEMPLATE", "RAM_TEMPLATE", "COMM_REGION_TEMPLATE"]
+
+[jailhouse_preamble]
+preamble = "QEMU_PREAMBLE"
+
+[UART_TEMPLATE]
+phys_start = "0x09000000"
+virt_start = "0x09000000"
+size = "0x1000"
+
+[RAM0_TEMPLATE]
+virt_start = "0"
+size = "0x10000"
+
+[RAM_TEMPLATE]
+virt_start = "0x70000000"
+
+[devices]
+devs = ["IRQ_CHIP_TEMPLATE", "PCI_DEMO_DEVICE_TEMPLATE"]
+
+[IRQ_CHIP_TEMPLATE]
+gic_address = "0x08000000"
+uart_pin = "33"
+"#;
+
+    const STATE: &str = r#"
+[available_memory]
+memory = "0x70000000, 0x7f800000"
+
+[free_segments]
+segments = ["0x70000000, 0x7f800000"]
+
+[free_pci_devices_bdf]
+bdf = [1, 2]
+
+[free_irq_pins]
+pins = [141, 142, 143, 144]
+
+[ivshmem_links.net1]
+bdf = 1
+memory = "0x7f900000, 0x7f9ff000"
+protocol = "veth"
+peers = 2
+rw_size = "0x0"
+out_size = "0x7f000"
+members = ["root"]
+ids = [0]
+
+[ivshmem_links.net2]
+bdf = 2
+memory = "0x7fa00000, 0x7faff000"
+protocol = "veth"
+peers = 2
+rw_size = "0x0"
+out_size = "0x7f000"
+members = ["root"]
+ids = [0]
+"#;
+
+    // Checks the platform and the state, written to a directory of their own, without
+    // templates of the node
+    fn run(name: &str, platform: &str, state: &str) -> (Vec<Problem>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("runphi-platform-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("platform_info.toml"), platform).unwrap();
+        fs::write(dir.join("state.toml"), state).unwrap();
+        let problems = check(&dir.join("platform_info.toml"), &dir.join("state.toml"), &dir.join("templates"));
+        (problems, dir)
+    }
+
+    // The only problem found is at the location of the file, with a message containing `message`
+    fn assert_problem(name: &str, platform: &str, state: &str, file: &str, location: &str, message: &str) {
+        let (problems, dir) = run(name, platform, state);
+        let _ = fs::remove_dir_all(&dir);
+        let expected = dir.join(file).display().to_string();
+        assert!(
+            problems.len() == 1 && problems[0].file == expected && problems[0].location == location && problems[0].message.contains(message),
+            "expected {}: {}: ...{}..., found {:?}", expected, location, message, problems
+        );
+    }
+
+    #[test]
+    fn consistent_platform_has_no_problems() {
+        let (problems, dir) = run("ok", PLATFORM, STATE);
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(problems, Vec::new());
+    }
+
+    #[test]
+    fn overlaps_are_reported() {
+        // A region of the templates in the memory of the cells
+        let platform = PLATFORM.replace("phys_start = \"0x09000000\"", "phys_start = \"0x7f000000\"");
+        assert_problem("overlap-region", &platform, STATE, "platform_info.toml", "[mem_regions] regions[0] (UART_TEMPLATE, region 0)", "overlaps [available_memory] memory");
+        // Two free segments
+        let state = STATE.replace("segments = [\"0x70000000, 0x7f800000\"]", "segments = [\"0x70000000, 0x74000000\", \"0x73000000, 0x7f800000\"]");
+        assert_problem("overlap-segments", PLATFORM, &state, "state.toml", "[free_segments] segments[1]", "overlaps [free_segments] segments[0]");
+    }
+
+    #[test]
+    fn segment_outside_the_available_memory_is_reported() {
+        let state = STATE.replace("segments = [\"0x70000000, 0x7f800000\"]", "segments = [\"0x70000000, 0x7f900000\"]");
+        assert_problem("outside", PLATFORM, &state, "state.toml", "[free_segments] segments[0]", "outside the available memory 0x70000000-0x7f800000");
+    }
+
+    #[test]
+    fn misaligned_segment_is_reported() {
+        let state = STATE.replace("segments = [\"0x70000000, 0x7f800000\"]", "segments = [\"0x70000800, 0x7f800000\"]");
+        assert_problem("misaligned", PLATFORM, &state, "state.toml", "[free_segments] segments[0]", "0x70000800-0x7f800000 is not page aligned");
+    }
+
+    #[test]
+    fn missing_templates_are_reported() {
+        let platform = PLATFORM.replace("\"COMM_REGION_TEMPLATE\"]", "\"COMM_REGION_TEMPLATE\", \"FLASH_TEMPLATE\"]");
+        assert_problem("missing-template", &platform, STATE, "platform_info.toml", "[mem_regions] regions[4]", "unknown template FLASH_TEMPLATE");
+        let platform = PLATFORM.replace("\"QEMU_PREAMBLE\"", "\"BOARD_PREAMBLE\"");
+        assert_problem("missing-preamble", &platform, STATE, "platform_info.toml", "[jailhouse_preamble] preamble", "BOARD_PREAMBLE");
+    }
+
+    #[test]
+    fn pins_out_of_range_are_reported() {
+        // Out of the 128 pins of the GIC from pin_base 32
+        let platform = PLATFORM.replace("uart_pin = \"33\"", "uart_pin = \"200\"");
+        assert_problem("pin-irqchip", &platform, STATE, "platform_info.toml", "[devices] devs[0]", "pin 200 out of the irqchip");
+        // Not a shared peripheral interrupt, among enough good ones
+        let state = STATE.replace("pins = [141, 142, 143, 144]", "pins = [141, 142, 16, 144, 145]");
+        assert_problem("pin-spi", PLATFORM, &state, "state.toml", "[free_irq_pins] pins[2]", "16 is not a shared peripheral interrupt (32-1019)");
+    }
+}
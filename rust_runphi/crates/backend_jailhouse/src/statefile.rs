@@ -0,0 +1,53 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Access to state.toml, the resources of the node. It is updated by the runPHI instances called
+// concurrently by the container manager and by the CNI plugin, so every read-modify-write of the
+// state is done holding an exclusive lock on it. The lock is taken on a separate file, since the
+// state is replaced by a rename: a crash while writing never leaves a truncated state behind.
+
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use nix::fcntl::{flock, FlockArg};
+
+const WORKPATH: &str = "/usr/share/runPHI";
+const STATEFILE: &str = "state.toml";
+const STATE_LOCK: &str = "state.lock";
+
+// Exclusive lock on the state, released when dropped
+pub struct StateLock {
+    _file: File,
+}
+
+pub fn path() -> PathBuf {
+    Path::new(WORKPATH).join(STATEFILE)
+}
+
+// Waits for the exclusive lock on the state. It must not be taken twice by the same process,
+// the second lock would wait for the first one forever
+pub fn lock() -> Result<StateLock, Box<dyn Error>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(Path::new(WORKPATH).join(STATE_LOCK))?;
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+    Ok(StateLock { _file: file })
+}
+
+pub fn read() -> Result<String, Box<dyn Error>> {
+    Ok(fs::read_to_string(path())?)
+}
+
+// Replaces the state, writing a temporary file renamed into place
+pub fn write(content: &str) -> Result<(), Box<dyn Error>> {
+    let path = path();
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
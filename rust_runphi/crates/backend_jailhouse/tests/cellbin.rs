@@ -0,0 +1,206 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Golden tests of the native .cell serializer. The reference files in tests/golden were
+// produced by compiling the rendered C with gcc and objcopy, the same is repeated here when
+// the toolchain is available.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use backend::configGenerator::cellbin;
+use backend::configGenerator::celldesc::{cpu_set, CellDesc, Layout};
+use backend::configGenerator::communication::platform_console;
+use backend::configGenerator::templates::*;
+
+// Fills in the placeholders as runPHI does, with the parameters of the table of the template
+fn fill(template: &str, params: &[(&str, &str)]) -> String {
+    let table = toml::Value::Table(params.iter().map(|(key, value)| (key.to_string(), toml::Value::from(*value))).collect());
+    render_template("golden", template, Some(&table), &[]).unwrap()
+}
+
+// UART of the board, as in the [console] table of platform_info.toml
+fn console(con_type: &str, address: &str) -> backend::configGenerator::celldesc::Console {
+    console_with_flags(con_type, address, "JAILHOUSE_CON_ACCESS_MMIO | JAILHOUSE_CON_REGDIST_4")
+}
+
+fn console_with_flags(con_type: &str, address: &str, flags: &str) -> backend::configGenerator::celldesc::Console {
+    let platform: toml::Value = format!(
+        "[console]\ntype = \"{}\"\naddress = \"{}\"\nflags = \"{}\"\n",
+        con_type, address, flags
+    ).parse().unwrap();
+    platform_console(&platform).unwrap().unwrap()
+}
+
+// Cell of the QEMU board as generated for a container with networking
+fn qemu_cell() -> CellDesc {
+    let mut desc = CellDesc::new("qemu-golden");
+    desc.apply_preamble(QEMU_PREAMBLE_TEMPLATE).unwrap();
+    desc.header.console = console("JAILHOUSE_CON_TYPE_PL011", "0x09000000");
+    desc.cpus = cpu_set(&[1, 2]);
+    desc.add_mem_regions(IVSHMEM_DEMO_TEMPLATE).unwrap();
+    desc.add_mem_regions(&fill(IVSHMEM_TEMPLATE, &[("address", "0x7f900000")])).unwrap();
+    desc.add_mem_regions(&fill(UART_TEMPLATE, &[("phys_start", "0x09000000"), ("virt_start", "0x09000000"), ("size", "0x1000")])).unwrap();
+    desc.add_mem_regions(&fill(RAM0_TEMPLATE, &[("phys_start", "0x70000000"), ("virt_start", "0"), ("size", "0x10000")])).unwrap();
+    desc.add_mem_regions(&fill(RAM_TEMPLATE, &[("phys_start", "0x70010000"), ("virt_start", "0x70000000"), ("size", "0x4000000")])).unwrap();
+    desc.add_mem_regions(COMM_REGION_TEMPLATE).unwrap();
+    desc.add_elements(&fill(IRQ_CHIP_TEMPLATE, &[("gic_address", "0x08000000"), ("uart_pin", "33"), ("ivshmem_demo_pin", "140"), ("ivshmem_pin", "141")])).unwrap();
+    desc.add_elements(&fill(PCI_DEVICE_TEMPLATE_WITH_DEMO, &[("ivshmem_bdf", "1")])).unwrap();
+    desc
+}
+
+// Same board, container without networking: no ivshmem regions and devices
+fn qemu_nonet_cell() -> CellDesc {
+    let mut desc = CellDesc::new("qemu-nonet");
+    desc.apply_preamble(QEMU_PREAMBLE_TEMPLATE).unwrap();
+    desc.header.console = console("JAILHOUSE_CON_TYPE_PL011", "0x09000000");
+    desc.cpus = cpu_set(&[3]);
+    desc.add_mem_regions(&fill(UART_TEMPLATE, &[("phys_start", "0x09000000"), ("virt_start", "0x09000000"), ("size", "0x1000")])).unwrap();
+    desc.add_mem_regions(&fill(RAM_TEMPLATE, &[("phys_start", "0x74000000"), ("virt_start", "0x74000000"), ("size", "0x1000000")])).unwrap();
+    desc.add_mem_regions(COMM_REGION_TEMPLATE).unwrap();
+    desc.add_elements(&fill(IRQ_CHIP_TEMPLATE, &[("gic_address", "0x08000000"), ("uart_pin", "33"), ("ivshmem_demo_pin", "140"), ("ivshmem_pin", "141")])).unwrap();
+    desc.add_elements(PCI_DEVICE_EMPTY_TEMPLATE).unwrap();
+    desc
+}
+
+// Omnivisor cell of the ZCU104, running on an RPU
+fn ultrascale_cell() -> CellDesc {
+    let mut desc = CellDesc::new("zcu104-golden");
+    desc.apply_preamble(ULTRASCALE_PREAMBLE_TEMPLATE).unwrap();
+    desc.header.console = console("JAILHOUSE_CON_TYPE_XUARTPS", "0xff010000");
+    desc.cpus = cpu_set(&[]);
+    desc.rcpus = cpu_set(&[1]);
+    desc.add_mem_regions(&fill(IVSHMEM_TEMPLATE, &[("address", "0x7e000000")])).unwrap();
+    desc.add_mem_regions(&fill(UART_TEMPLATE, &[("phys_start", "0xff010000"), ("virt_start", "0xff010000"), ("size", "0x1000")])).unwrap();
+    desc.add_mem_regions(&fill(TCMA_TEMPLATE, &[("phys_start", "0xffe00000"), ("virt_start", "0xffe00000"), ("size", "0x00010000")])).unwrap();
+    desc.add_mem_regions(&fill(TCMB_TEMPLATE, &[("phys_start", "0xffe20000"), ("virt_start", "0xffe20000"), ("size", "0x00010000")])).unwrap();
+    desc.add_mem_regions(&fill(RAM_TEMPLATE, &[("phys_start", "0x3ed00000"), ("virt_start", "0"), ("size", "0x8000000")])).unwrap();
+    desc.add_mem_regions(COMM_REGION_TEMPLATE).unwrap();
+    desc.add_elements(&fill(IRQ_CHIP_BOARD_TEMPLATE, &[("gic_address", "0xf9010000"), ("uart_pin", "33"), ("ivshmem_pin", "146")])).unwrap();
+    desc.add_elements(&fill(PCI_DEVICE_TEMPLATE, &[("ivshmem_bdf", "1")])).unwrap();
+    desc
+}
+
+// x86 cell with the UART on I/O ports, the IOAPIC and the networking device on MSI-X, as
+// ivshmemconfig adds it on x86 boards
+fn x86_cell() -> CellDesc {
+    let mut desc = CellDesc::new("x86-golden");
+    desc.apply_preamble(X86_PREAMBLE_TEMPLATE).unwrap();
+    desc.header.console = console_with_flags("JAILHOUSE_CON_TYPE_8250", "0x3f8", "JAILHOUSE_CON_ACCESS_PIO | JAILHOUSE_CON_REGDIST_1");
+    desc.cpus = cpu_set(&[2, 3]);
+    desc.add_mem_regions(&fill(X86_LOW_RAM_TEMPLATE, &[("phys_start", "0x3a600000"), ("size", "0x100000")])).unwrap();
+    desc.add_mem_regions(X86_COMM_REGION_TEMPLATE).unwrap();
+    desc.add_mem_regions(&fill(X86_RAM_TEMPLATE, &[("phys_start", "0x3a700000"), ("virt_start", "0x200000"), ("size", "0x4000000")])).unwrap();
+    desc.add_mem_regions(&fill(IVSHMEM_TEMPLATE, &[("address", "0x3f100000")])).unwrap();
+    desc.add_elements(&fill(X86_IOAPIC_TEMPLATE, &[("ioapic_address", "0xfec00000"), ("ioapic_id", "0xff00"), ("uart_pin", "4")])).unwrap();
+    desc.add_elements(&fill(X86_PIO_TEMPLATE, &[("uart_port", "0x3f8")])).unwrap();
+    desc.add_elements(r#"
+.pci_devices = {
+	{ /* IVSHMEM 00:01.0 (networking) */
+		.type = JAILHOUSE_PCI_TYPE_IVSHMEM,
+		.domain = 0,
+		.bdf = 1 << 3,
+		.bar_mask = JAILHOUSE_IVSHMEM_BAR_MASK_MSIX,
+		.num_msix_vectors = 2,
+		.shmem_regions_start = 3,
+		.shmem_dev_id = 1,
+		.shmem_peers = 2,
+		.shmem_protocol = JAILHOUSE_SHMEM_PROTO_VETH,
+	},
+},
+"#).unwrap();
+    desc
+}
+
+fn golden(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("{}.cell", name));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e))
+}
+
+// Jailhouse headers shipped with runPHI, as installed in /usr/share/runPHI/include
+fn include_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../../../target/runPHI_cell_configs/include")
+}
+
+fn has_toolchain() -> bool {
+    ["gcc", "objcopy"]
+        .iter()
+        .all(|tool| Command::new(tool).arg("--version").output().is_ok_and(|o| o.status.success()))
+}
+
+fn compile_with_gcc(desc: &CellDesc, name: &str) -> Option<Vec<u8>> {
+    if !has_toolchain() || !include_dir().exists() {
+        eprintln!("gcc or the Jailhouse headers are missing, skipping the comparison with gcc");
+        return None;
+    }
+    let workdir = std::env::temp_dir().join(format!("runphi-cellbin-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&workdir).unwrap();
+    let cell = cellbin::compile(&desc.render_c().unwrap(), &workdir, name, &include_dir()).unwrap();
+    let _ = std::fs::remove_dir_all(&workdir);
+    Some(cell)
+}
+
+#[test]
+fn native_matches_golden_qemu() {
+    assert_eq!(cellbin::serialize(&qemu_cell()).unwrap(), golden("qemu"));
+}
+
+#[test]
+fn native_matches_golden_qemu_without_network() {
+    assert_eq!(cellbin::serialize(&qemu_nonet_cell()).unwrap(), golden("qemu-nonet"));
+}
+
+#[test]
+fn native_matches_golden_ultrascale() {
+    let desc = ultrascale_cell();
+    assert_eq!(desc.header.layout, Layout::Omnivisor);
+    assert_eq!(cellbin::serialize(&desc).unwrap(), golden("ultrascale"));
+}
+
+#[test]
+fn native_matches_golden_x86() {
+    let desc = x86_cell();
+    assert_eq!(desc.pio_regions.len(), 1);
+    assert_eq!(desc.irqchips[0].pin_bitmap[0], 1 << 4);
+    assert_eq!(cellbin::serialize(&desc).unwrap(), golden("x86"));
+}
+
+#[test]
+fn native_matches_gcc() {
+    // The headers in the repo are the upstream Jailhouse ones, hence only the Jailhouse layout
+    for (name, desc) in [("qemu", qemu_cell()), ("qemu-nonet", qemu_nonet_cell()), ("x86", x86_cell())] {
+        if let Some(cell) = compile_with_gcc(&desc, name) {
+            assert_eq!(cellbin::serialize(&desc).unwrap(), cell, "{} differs from gcc", name);
+        }
+    }
+}
+
+#[test]
+fn rcpus_rejected_without_omnivisor_layout() {
+    let mut desc = qemu_cell();
+    desc.rcpus = cpu_set(&[0]);
+    assert!(cellbin::serialize(&desc).is_err());
+    assert!(desc.render_c().is_err());
+}
+
+#[test]
+fn deserialize_roundtrips_golden() {
+    for name in ["qemu", "qemu-nonet", "ultrascale", "x86"] {
+        let cell = golden(name);
+        let desc = cellbin::deserialize(&cell).unwrap();
+        assert_eq!(cellbin::serialize(&desc).unwrap(), cell, "{} does not round trip", name);
+    }
+}
+
+#[test]
+fn set_name_matches_a_cell_built_with_that_name() {
+    for (name, desc) in [("qemu", qemu_cell()), ("ultrascale", ultrascale_cell())] {
+        let mut renamed = desc.clone();
+        renamed.header.name = "other-cell".to_string();
+        let mut cell = golden(name);
+        cellbin::set_name(&mut cell, "other-cell").unwrap();
+        assert_eq!(cell, cellbin::serialize(&renamed).unwrap(), "{} renamed differs", name);
+    }
+    assert!(cellbin::set_name(&mut golden("qemu"), &"x".repeat(32)).is_err());
+}
@@ -0,0 +1,135 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// Tests of the device tree reader and writer, and of the patching of the device tree of linux
+// cells, on the device tree of the Linux inmate of Jailhouse on QEMU, tests/golden/inmate-qemu-arm64.dts.
+
+use std::path::Path;
+
+use backend::configGenerator::celldesc::{cpu_set, CellDesc};
+use backend::configGenerator::fdt::{self, Fdt};
+use backend::configGenerator::linux::patch_dtb;
+use backend::configGenerator::templates::*;
+use backend::configGenerator::Backendconfig;
+
+fn fill(template: &str, params: &[(&str, &str)]) -> String {
+    let mut filled = template.to_string();
+    for (key, value) in params {
+        filled = filled.replace(&format!("{{{}}}", key), value);
+    }
+    filled
+}
+
+fn inmate() -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/inmate-qemu-arm64.dtb");
+    std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e))
+}
+
+// Linux cell of the QEMU board on CPUs 1 and 3, with networking at BDF 1 and pins from 144
+fn linux_cell() -> Backendconfig {
+    let mut c = Backendconfig::new();
+    let mut desc = CellDesc::new("linux-fdt");
+    desc.cpus = cpu_set(&[1, 3]);
+    desc.add_mem_regions(&fill(RAM0_TEMPLATE, &[("phys_start", "0x70000000"), ("virt_start", "0"), ("size", "0x10000")])).unwrap();
+    desc.add_mem_regions(&fill(RAM_TEMPLATE, &[("phys_start", "0x70010000"), ("virt_start", "0x70000000"), ("size", "0x4000000")])).unwrap();
+    desc.add_mem_regions(COMM_REGION_TEMPLATE).unwrap();
+    desc.add_elements(&fill(PCI_DEVICE_TEMPLATE, &[("ivshmem_bdf", "1")])).unwrap();
+    desc.header.vpci_irq_base = 112;
+    c.desc = desc;
+    c
+}
+
+fn patched(c: &Backendconfig) -> Fdt {
+    let mut tree = fdt::parse(&inmate()).unwrap();
+    patch_dtb(&mut tree, c, "console=ttyAMA0,115200 ip=dhcp").unwrap();
+    // What is written must read back the same
+    assert_eq!(fdt::parse(&fdt::serialize(&tree)).unwrap(), tree);
+    tree
+}
+
+#[test]
+fn parse_reads_the_inmate_device_tree() {
+    let tree = fdt::parse(&inmate()).unwrap();
+    assert_eq!(tree.root.prop_str("model"), Some("Jailhouse cell on QEMU ARM64"));
+    assert_eq!(tree.root.prop_u32("#address-cells"), Some(2));
+    let cpus = tree.root.child("cpus").unwrap();
+    assert_eq!(cpus.children.iter().map(|n| n.name.as_str()).collect::<Vec<_>>(), ["cpu@2", "cpu@3"]);
+    let gic = tree.root.find_phandle(tree.root.prop_u32("interrupt-parent").unwrap()).unwrap();
+    assert_eq!(gic.name, "interrupt-controller@8000000");
+    assert_eq!(gic.prop("interrupt-controller"), Some(&[][..]));
+    let uart = tree.root.child("serial@9000000").unwrap();
+    assert_eq!(uart.prop("compatible"), Some(&b"arm,pl011\0arm,primecell\0"[..]));
+}
+
+#[test]
+fn serialize_round_trips() {
+    let tree = fdt::parse(&inmate()).unwrap();
+    let data = fdt::serialize(&tree);
+    let reread = fdt::parse(&data).unwrap();
+    assert_eq!(reread, tree);
+    assert_eq!(fdt::serialize(&reread), data);
+
+    let mut reserved = tree.clone();
+    reserved.reserved = vec![(0x70000000, 0x1000)];
+    reserved.boot_cpuid = 3;
+    assert_eq!(fdt::parse(&fdt::serialize(&reserved)).unwrap(), reserved);
+}
+
+#[test]
+fn parse_rejects_broken_trees() {
+    let data = inmate();
+    assert!(fdt::parse(&data[..100]).is_err());
+    assert!(fdt::parse(b"not a device tree, not at all").is_err());
+    let mut wrong_magic = data.clone();
+    wrong_magic[0] = 0;
+    assert!(fdt::parse(&wrong_magic).is_err());
+}
+
+#[test]
+fn patch_sets_memory_cpus_pci_and_bootargs() {
+    let tree = patched(&linux_cell());
+
+    // The RAM of the cell, without the low RAM of the loader
+    let memory: Vec<_> = tree.root.children.iter().filter(|n| n.base_name() == "memory").collect();
+    assert_eq!(memory.len(), 1);
+    assert_eq!(memory[0].name, "memory@70000000");
+    assert_eq!(memory[0].prop_str("device_type"), Some("memory"));
+    assert_eq!(memory[0].prop("reg").map(fdt::to_cells), Some(vec![0, 0x70000000, 0, 0x4000000]));
+
+    // CPU 3 is kept, CPU 1 is copied from the first cpu node
+    let cpus = tree.root.child("cpus").unwrap();
+    assert_eq!(cpus.children.iter().map(|n| n.name.as_str()).collect::<Vec<_>>(), ["cpu@1", "cpu@3"]);
+    assert_eq!(cpus.children[0].prop_u32("reg"), Some(1));
+    assert_eq!(cpus.children[0].prop_str("enable-method"), Some("psci"));
+    assert_eq!(cpus.children[1].prop_u32("reg"), Some(3));
+
+    // INTx 1-4 raise vpci_irq_base + 0..3, as GIC SPIs
+    let map = tree.root.child("pci@7000000").unwrap().prop("interrupt-map").map(fdt::to_cells).unwrap();
+    let gic = tree.root.child("interrupt-controller@8000000").unwrap().prop_u32("phandle").unwrap();
+    let expected: Vec<u32> = (1..=4).flat_map(|pin| [0, 0, 0, pin, gic, 0, 111 + pin, 1]).collect();
+    assert_eq!(map, expected);
+
+    assert_eq!(tree.root.child("chosen").unwrap().prop_str("bootargs"), Some("console=ttyAMA0,115200 ip=dhcp"));
+}
+
+#[test]
+fn patch_drops_the_pci_host_of_cells_without_devices() {
+    let mut c = linux_cell();
+    c.desc.pci_devices.clear();
+    let tree = patched(&c);
+    assert!(tree.root.children.iter().all(|n| n.prop_str("device_type") != Some("pci")));
+    assert!(tree.root.child("serial@9000000").is_some());
+}
+
+#[test]
+fn patch_fails_without_cpus() {
+    let mut tree = fdt::parse(&inmate()).unwrap();
+    tree.root.children.retain(|n| n.name != "cpus");
+    assert!(patch_dtb(&mut tree, &linux_cell(), "").is_err());
+
+    let mut c = linux_cell();
+    c.desc.cpus = cpu_set(&[]);
+    let mut tree = fdt::parse(&inmate()).unwrap();
+    assert!(patch_dtb(&mut tree, &c, "").is_err());
+}
@@ -0,0 +1,313 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+// CNI plugin of the partitioned containers, chained after the plugins giving the pod its address.
+// The pod sandbox runs in the root cell, while the application runs in a cell reached through
+// the IVSHMEM networking device of its BDF. On ADD the sandbox is attached to a BDF in the state of
+// runPHI, which the cell of the pod takes when created, the interface of the root cell linked to
+// that BDF gets its address and the traffic for the pod address is forwarded to the cell:
+//   iptables -t nat -I PREROUTING 1 -d <pod ip> -j DNAT --to-destination <cell ip>
+//   iptables -t nat -I OUTPUT 1 -d <pod ip> -j DNAT --to-destination <cell ip>
+//   iptables -t nat -A POSTROUTING -d <cell ip> -j MASQUERADE
+// The rules are tagged with the sandbox id, added only if missing, and removed on DEL.
+
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+use std::io::{self, Read};
+use std::net::Ipv4Addr;
+use std::process::{exit, Command};
+
+use backend::configGenerator::network;
+
+const SUPPORTED_VERSIONS: [&str; 5] = ["0.3.0", "0.3.1", "0.4.0", "1.0.0", "1.1.0"];
+// Error codes of the CNI specification
+const ERR_INCOMPATIBLE_VERSION: u32 = 1;
+const ERR_UNKNOWN_CONTAINER: u32 = 3;
+const ERR_INVALID_ENV: u32 = 4;
+const ERR_IO: u32 = 5;
+const ERR_DECODE: u32 = 6;
+const ERR_INVALID_CONFIG: u32 = 7;
+// Errors of the plugin: the node cannot host the pod, or its forwarding is broken
+const ERR_NO_BDF: u32 = 100;
+const ERR_CHECK: u32 = 101;
+
+#[derive(Debug)]
+struct CniError {
+    code: u32,
+    msg: String,
+    details: String,
+}
+
+impl CniError {
+    fn new(code: u32, msg: &str, details: impl ToString) -> Self {
+        CniError { code, msg: msg.to_string(), details: details.to_string() }
+    }
+}
+
+// Network configuration on stdin, the fields of the other plugins are ignored
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NetConf {
+    cni_version: String,
+    #[serde(default)]
+    prev_result: Option<serde_json::Value>,
+}
+
+// Forwarding of a pod to its cell
+struct Forward<'a> {
+    sandbox: &'a str,
+    pod: Ipv4Addr,
+    guest: Ipv4Addr,
+}
+
+impl Forward<'_> {
+    // The NAT rules, as chain, position to insert at (appended if none) and rule
+    fn rules(&self) -> Vec<(&'static str, Option<&'static str>, Vec<String>)> {
+        let comment = format!("runphi {}", self.sandbox);
+        let tag = |mut rule: Vec<String>| {
+            rule.extend(["-m", "comment", "--comment", comment.as_str()].map(String::from));
+            rule
+        };
+        let dnat = || tag(["-d", &self.pod.to_string(), "-j", "DNAT", "--to-destination", &self.guest.to_string()].map(String::from).to_vec());
+        vec![
+            ("PREROUTING", Some("1"), dnat()),
+            ("OUTPUT", Some("1"), dnat()),
+            ("POSTROUTING", None, tag(["-d", &self.guest.to_string(), "-j", "MASQUERADE"].map(String::from).to_vec())),
+        ]
+    }
+}
+
+// Runs a command, returning whether it succeeded, and its stderr
+fn run(program: &str, args: &[&str]) -> Result<(bool, String), CniError> {
+    logging::log_message(logging::Level::Trace, format!("CNI: {} {}", program, args.join(" ")).as_str());
+    let output = Command::new(program).args(args).output()
+        .map_err(|e| CniError::new(ERR_IO, &format!("cannot run {}", program), e))?;
+    Ok((output.status.success(), String::from_utf8_lossy(&output.stderr).trim().to_string()))
+}
+
+fn run_checked(program: &str, args: &[&str]) -> Result<(), CniError> {
+    match run(program, args)? {
+        (true, _) => Ok(()),
+        (false, stderr) => Err(CniError::new(ERR_IO, &format!("{} {} failed", program, args.join(" ")), stderr)),
+    }
+}
+
+fn rule_exists(chain: &str, rule: &[String]) -> Result<bool, CniError> {
+    let mut args = vec!["-t", "nat", "-C", chain];
+    args.extend(rule.iter().map(|a| a.as_str()));
+    Ok(run("iptables", &args)?.0)
+}
+
+fn add_rules(forward: &Forward) -> Result<(), CniError> {
+    for (chain, position, rule) in forward.rules() {
+        if rule_exists(chain, &rule)? {
+            continue;
+        }
+        let mut args = match position {
+            Some(position) => vec!["-t", "nat", "-I", chain, position],
+            None => vec!["-t", "nat", "-A", chain],
+        };
+        args.extend(rule.iter().map(|a| a.as_str()));
+        run_checked("iptables", &args)?;
+    }
+    Ok(())
+}
+
+// Removes every copy of the rules, missing ones are not an error
+fn delete_rules(forward: &Forward) -> Result<(), CniError> {
+    for (chain, _, rule) in forward.rules() {
+        while rule_exists(chain, &rule)? {
+            let mut args = vec!["-t", "nat", "-D", chain];
+            args.extend(rule.iter().map(|a| a.as_str()));
+            run_checked("iptables", &args)?;
+        }
+    }
+    Ok(())
+}
+
+// First IPv4 address of the result of the previous plugins, without its prefix
+fn pod_address(prev_result: Option<&serde_json::Value>) -> Option<Ipv4Addr> {
+    prev_result?.get("ips")?.as_array()?.iter()
+        .filter_map(|ip| ip.get("address")?.as_str())
+        .find_map(|address| address.split('/').next()?.parse().ok())
+}
+
+fn env_var(name: &str) -> Result<String, CniError> {
+    env::var(name).ok().filter(|v| !v.is_empty()).ok_or_else(|| CniError::new(ERR_INVALID_ENV, &format!("{} is not set", name), ""))
+}
+
+fn read_conf() -> Result<NetConf, CniError> {
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input).map_err(|e| CniError::new(ERR_IO, "cannot read the network configuration", e))?;
+    logging::log_message(logging::Level::Debug, format!("CNI: configuration {}", input.trim()).as_str());
+    parse_conf(&input)
+}
+
+fn parse_conf(input: &str) -> Result<NetConf, CniError> {
+    let conf: NetConf = serde_json::from_str(input).map_err(|e| CniError::new(ERR_DECODE, "invalid network configuration", e))?;
+    if !SUPPORTED_VERSIONS.contains(&conf.cni_version.as_str()) {
+        return Err(CniError::new(ERR_INCOMPATIBLE_VERSION, &format!("CNI version {} is not supported", conf.cni_version), ""));
+    }
+    Ok(conf)
+}
+
+// The addresses of the link of the BDF and the interface of the root cell linked to it
+fn link_of(bdf: u32) -> Result<(network::Link, String), CniError> {
+    let policy = network::node_network().map_err(|e| CniError::new(ERR_INVALID_CONFIG, "invalid [network] of the platform", e))?;
+    let link = network::link(&policy, bdf).map_err(|e| CniError::new(ERR_INVALID_CONFIG, "invalid [network] of the platform", e))?;
+    Ok((link, network::host_interface(&policy, bdf)))
+}
+
+// Sets up the interface of the root cell linked to the BDF, and forwards the pod to the cell. What
+// was set up is undone on failure
+fn connect(sandbox: &str, pod: Ipv4Addr, bdf: u32) -> Result<(), CniError> {
+    let (link, interface) = link_of(bdf)?;
+    logging::log_message(logging::Level::Info, format!("CNI: sandbox {} at BDF {}, pod {} forwarded to {} through {}", sandbox, bdf, pod, link.guest, interface).as_str());
+
+    // The root cell side of the link, replaced if already there
+    let address = format!("{}/{}", link.host, link.prefix);
+    run_checked("ip", &["addr", "replace", &address, "dev", &interface])?;
+    let forward = Forward { sandbox, pod, guest: link.guest };
+    let connected = run_checked("ip", &["link", "set", &interface, "up"]).and_then(|_| add_rules(&forward));
+    if connected.is_err() {
+        let _ = delete_rules(&forward);
+        let _ = run("ip", &["addr", "del", &address, "dev", &interface]);
+    }
+    connected
+}
+
+fn add(conf: &NetConf, sandbox: &str) -> Result<serde_json::Value, CniError> {
+    let prev_result = conf.prev_result.as_ref()
+        .ok_or_else(|| CniError::new(ERR_INVALID_CONFIG, "no prevResult, the plugin must be chained after a plugin giving the pod an address", ""))?;
+    let pod = pod_address(Some(prev_result))
+        .ok_or_else(|| CniError::new(ERR_INVALID_CONFIG, "the prevResult has no IPv4 address for the pod", ""))?;
+
+    // An ADD repeated for an attached sandbox finds its attachment, kept if the ADD fails
+    let attached = network::lookup(sandbox).map_err(|e| CniError::new(ERR_IO, "cannot read the state of runPHI", e))?.is_some();
+    let attachment = network::attach(sandbox, Some(&pod.to_string())).map_err(|e| CniError::new(ERR_NO_BDF, "cannot attach the pod to a BDF", e))?;
+    if let Err(e) = connect(sandbox, pod, attachment.bdf) {
+        // The BDF is given back, instead of staying taken until a DEL
+        if !attached {
+            if let Err(detach) = network::detach(sandbox) {
+                logging::log_message(logging::Level::Error, format!("CNI: sandbox {} not detached from BDF {}: {}", sandbox, attachment.bdf, detach).as_str());
+            }
+        }
+        return Err(e);
+    }
+
+    // The result of the previous plugins is passed on unchanged
+    let mut result = prev_result.clone();
+    result["cniVersion"] = json!(conf.cni_version);
+    Ok(result)
+}
+
+fn del(conf: &NetConf, sandbox: &str) -> Result<(), CniError> {
+    let attachment = network::lookup(sandbox).map_err(|e| CniError::new(ERR_IO, "cannot read the state of runPHI", e))?;
+    // Nothing to clean up if the sandbox was never attached, or was detached already
+    let Some(attachment) = attachment else { return Ok(()) };
+    let (link, interface) = link_of(attachment.bdf)?;
+    let pod = attachment.pod_ip.as_deref().and_then(|ip| ip.parse().ok()).or(pod_address(conf.prev_result.as_ref()));
+    if let Some(pod) = pod {
+        delete_rules(&Forward { sandbox, pod, guest: link.guest })?;
+    }
+    // The interface may be gone with the cell
+    let _ = run("ip", &["addr", "del", &format!("{}/{}", link.host, link.prefix), "dev", &interface]);
+    network::detach(sandbox).map_err(|e| CniError::new(ERR_IO, "cannot detach the pod from its BDF", e))?;
+    logging::log_message(logging::Level::Info, format!("CNI: sandbox {} detached from BDF {}", sandbox, attachment.bdf).as_str());
+    Ok(())
+}
+
+fn check(conf: &NetConf, sandbox: &str) -> Result<(), CniError> {
+    let attachment = network::lookup(sandbox).map_err(|e| CniError::new(ERR_IO, "cannot read the state of runPHI", e))?
+        .ok_or_else(|| CniError::new(ERR_UNKNOWN_CONTAINER, &format!("sandbox {} is not attached to a BDF", sandbox), ""))?;
+    let (link, _) = link_of(attachment.bdf)?;
+    let pod = pod_address(conf.prev_result.as_ref())
+        .or(attachment.pod_ip.as_deref().and_then(|ip| ip.parse().ok()))
+        .ok_or_else(|| CniError::new(ERR_INVALID_CONFIG, "the prevResult has no IPv4 address for the pod", ""))?;
+    for (chain, _, rule) in (Forward { sandbox, pod, guest: link.guest }).rules() {
+        if !rule_exists(chain, &rule)? {
+            return Err(CniError::new(ERR_CHECK, &format!("the forwarding rule of {} in {} is missing", sandbox, chain), rule.join(" ")));
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    // Logs with runPHI, in /usr/share/runPHI/log.txt
+    logging::init_logger(None);
+    let command = env::var("CNI_COMMAND").unwrap_or_default();
+    let version = json!({ "cniVersion": "1.0.0", "supportedVersions": SUPPORTED_VERSIONS });
+    let result = match command.as_str() {
+        "VERSION" => Ok(Some(version)),
+        "ADD" | "DEL" | "CHECK" => read_conf().and_then(|conf| {
+            let sandbox = env_var("CNI_CONTAINERID")?;
+            match command.as_str() {
+                "ADD" => add(&conf, &sandbox).map(Some),
+                "DEL" => del(&conf, &sandbox).map(|_| None),
+                _ => check(&conf, &sandbox).map(|_| None),
+            }
+        }),
+        _ => Err(CniError::new(ERR_INVALID_ENV, &format!("unknown CNI_COMMAND \"{}\"", command), "")),
+    };
+
+    match result {
+        Ok(Some(output)) => println!("{}", output),
+        Ok(None) => {}
+        Err(e) => {
+            logging::log_message(logging::Level::Error, format!("CNI {}: {} {}", command, e.msg, e.details).as_str());
+            println!("{}", json!({ "cniVersion": "1.0.0", "code": e.code, "msg": e.msg, "details": e.details }));
+            exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forward_rules_are_tagged_with_the_sandbox() {
+        let forward = Forward { sandbox: "abc", pod: Ipv4Addr::new(10, 244, 0, 12), guest: Ipv4Addr::new(192, 168, 100, 6) };
+        let rules: Vec<(&str, Option<&str>, String)> = forward.rules().into_iter()
+            .map(|(chain, position, rule)| (chain, position, rule.join(" ")))
+            .collect();
+        let dnat = "-d 10.244.0.12 -j DNAT --to-destination 192.168.100.6 -m comment --comment runphi abc";
+        assert_eq!(rules, vec![
+            ("PREROUTING", Some("1"), dnat.to_string()),
+            ("OUTPUT", Some("1"), dnat.to_string()),
+            ("POSTROUTING", None, "-d 192.168.100.6 -j MASQUERADE -m comment --comment runphi abc".to_string()),
+        ]);
+        // The comment is a single argument of iptables
+        assert_eq!(forward.rules()[2].2.last().map(String::as_str), Some("runphi abc"));
+    }
+
+    #[test]
+    fn pod_address_is_the_first_ipv4() {
+        let result = json!({ "ips": [
+            { "address": "fd00::12/64" },
+            { "address": "10.244.0.12/24", "gateway": "10.244.0.1" },
+            { "address": "10.244.0.13/24" },
+        ] });
+        assert_eq!(pod_address(Some(&result)), Some(Ipv4Addr::new(10, 244, 0, 12)));
+        assert_eq!(pod_address(Some(&json!({ "ips": [{ "address": "10.244.0.12" }] }))), Some(Ipv4Addr::new(10, 244, 0, 12)));
+        assert_eq!(pod_address(Some(&json!({ "ips": [{ "address": "fd00::12/64" }] }))), None);
+        assert_eq!(pod_address(Some(&json!({ "interfaces": [] }))), None);
+        assert_eq!(pod_address(None), None);
+    }
+
+    #[test]
+    fn parse_conf_checks_the_version() {
+        for version in SUPPORTED_VERSIONS {
+            let conf = parse_conf(&format!(r#"{{"cniVersion": "{}", "name": "pods", "type": "partitioned"}}"#, version)).unwrap();
+            assert_eq!((conf.cni_version.as_str(), conf.prev_result), (version, None));
+        }
+        let conf = parse_conf(r#"{"cniVersion": "1.0.0", "prevResult": {"ips": []}}"#).unwrap();
+        assert_eq!(conf.prev_result, Some(json!({ "ips": [] })));
+
+        assert_eq!(parse_conf(r#"{"cniVersion": "0.2.0"}"#).unwrap_err().code, ERR_INCOMPATIBLE_VERSION);
+        assert_eq!(parse_conf(r#"{"name": "pods"}"#).unwrap_err().code, ERR_DECODE);
+        assert_eq!(parse_conf("not json").unwrap_err().code, ERR_DECODE);
+    }
+}
@@ -0,0 +1,948 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//          Francesco Boccola (francesco.boccola@unina.it)
+//*********************************************
+
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::FrontendConfig;
+
+// Version of the config.json schema understood by this runPHI. Images not declaring a version
+// are assumed to follow the first one
+pub const IMAGECONFIG_VERSION: u32 = 1;
+//TODO: parametrize boot boot.bin and config.json
+const IMAGECONFIG_PATH: &str = "boot/config.json";
+const DEFAULT_INMATE: &str = "boot/boot.bin";
+const WORKPATH: &str = "/usr/share/runPHI";
+const NODE_DEFAULTS_FILE: &str = "node_defaults.toml";
+
+// Kind of software running in the cell. It drives how the cell is created and loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsKind {
+    // The image contains the linux kernel, started with the dedicated hypervisor command
+    Linux,
+    // A binary integrating the application with the Zephyr runtime
+    Zephyr,
+    // A bare metal application, or any other libOS built with the application
+    Baremetal,
+}
+
+impl OsKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OsKind::Linux => "linux",
+            OsKind::Zephyr => "zephyr",
+            OsKind::Baremetal => "baremetal",
+        }
+    }
+}
+
+impl fmt::Display for OsKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// Console of the cell. The UART of the board can be owned by one cell at a time, the others can
+// use the virtual console of the hypervisor (`jailhouse console`) or go without one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsoleKind {
+    // The UART described in [console] of platform_info.toml
+    Uart,
+    // The console of the hypervisor
+    Virtual,
+    // No console at all
+    None,
+}
+
+impl ConsoleKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConsoleKind::Uart => "uart",
+            ConsoleKind::Virtual => "virtual",
+            ConsoleKind::None => "none",
+        }
+    }
+}
+
+impl fmt::Display for ConsoleKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// Addressing of the networking interface of the cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetConfKind {
+    // The address, gateway and MAC computed by runPHI for the BDF of the cell
+    Static,
+    // The guest asks its address to a DHCP server of the root cell, only the MAC is given
+    Dhcp,
+}
+
+impl NetConfKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NetConfKind::Static => "static",
+            NetConfKind::Dhcp => "dhcp",
+        }
+    }
+}
+
+impl fmt::Display for NetConfKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// A physical or virtual address. It can be given as a hex string ("0x70000000"), a decimal
+// string, or a plain json number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address(pub u64);
+
+impl Address {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => s.parse::<u64>(),
+        };
+        parsed
+            .map(Address)
+            .map_err(|e| format!("\"{}\" is not a valid address: {}", s, e))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:x}", self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::String(s) => Address::parse(&s).map_err(de::Error::custom),
+            serde_json::Value::Number(n) => n
+                .as_u64()
+                .map(Address)
+                .ok_or_else(|| de::Error::custom(format!("{} is not a valid address", n))),
+            other => Err(de::Error::custom(format!("expected an address, found {}", other))),
+        }
+    }
+}
+
+// Images used to leave unset values as empty strings, keep treating them as missing
+fn empty_as_none<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Address>, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(s) if s.trim().is_empty() => Ok(None),
+        other => Address::deserialize(other).map(Some).map_err(de::Error::custom),
+    }
+}
+
+// Shared-memory channel joined by the cell, by name. The first member creates it: the size is
+// mandatory then, and the region is allocated from the free memory unless an address is given.
+// The other members join the existing region, size, address and rootshared can be omitted and
+// must match the channel otherwise
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShmChannel {
+    pub name: String,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub size: Option<Address>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub address: Option<Address>,
+    // Whether the root cell keeps the region mapped too, false when the channel is created
+    #[serde(default)]
+    pub rootshared: Option<bool>,
+}
+
+impl ShmChannel {
+    fn validate(channels: &[ShmChannel]) -> Result<(), String> {
+        for (i, channel) in channels.iter().enumerate() {
+            if !valid_name(&channel.name) {
+                return Err(format!("\"{}\" is not a channel name, use letters, digits, '-', '_' and '.'", channel.name));
+            }
+            if channels[..i].iter().any(|other| other.name == channel.name) {
+                return Err(format!("channel \"{}\" joined twice", channel.name));
+            }
+            if channel.size.is_some_and(|size| size.0 == 0) {
+                return Err(format!("channel \"{}\" has size 0", channel.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Protocol spoken over an IVSHMEM device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShmemProtocol {
+    // Virtual ethernet, as the networking device linked to the root cell
+    Veth,
+    // Virtio over IVSHMEM, the member with id 0 is the backend, the others the frontends
+    VirtioConsole,
+    VirtioBlock,
+    // Any custom protocol of the application
+    Undefined,
+}
+
+impl ShmemProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShmemProtocol::Veth => "veth",
+            ShmemProtocol::VirtioConsole => "virtio-console",
+            ShmemProtocol::VirtioBlock => "virtio-block",
+            ShmemProtocol::Undefined => "undefined",
+        }
+    }
+}
+
+impl fmt::Display for ShmemProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// IVSHMEM device linking the cell to the other members of a named link. As for the shared-memory
+// channels, the first member creates the link, choosing its protocol, peers and sizes, and the
+// other members join it, with the fields given matching the link. `id` is the peer id of the
+// cell, the lowest free one if missing
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IvshmemDevice {
+    pub name: String,
+    #[serde(default)]
+    pub protocol: Option<ShmemProtocol>,
+    #[serde(default)]
+    pub peers: Option<u8>,
+    // Size of the read/write section, shared by all the peers
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub rw_size: Option<Address>,
+    // Size of the output section of each peer, written by the peer only
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub out_size: Option<Address>,
+    #[serde(default)]
+    pub id: Option<u8>,
+}
+
+// Most peers of an IVSHMEM device in Jailhouse
+pub const IVSHMEM_MAX_PEERS: u8 = 16;
+
+impl IvshmemDevice {
+    fn validate(devices: &[IvshmemDevice]) -> Result<(), String> {
+        for (i, device) in devices.iter().enumerate() {
+            if !valid_name(&device.name) {
+                return Err(format!("\"{}\" is not a link name, use letters, digits, '-', '_' and '.'", device.name));
+            }
+            if devices[..i].iter().any(|other| other.name == device.name) {
+                return Err(format!("link \"{}\" joined twice", device.name));
+            }
+            if let Some(peers) = device.peers.filter(|peers| !(2..=IVSHMEM_MAX_PEERS).contains(peers)) {
+                return Err(format!("link \"{}\" has {} peers, between 2 and {} are supported", device.name, peers, IVSHMEM_MAX_PEERS));
+            }
+            if let (Some(id), Some(peers)) = (device.id, device.peers) {
+                if id >= peers {
+                    return Err(format!("link \"{}\": id {} is not below the {} peers", device.name, id, peers));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Parses the named entries of an annotation, separated by ';', each a name followed by its
+// options, e.g. "sensors,size=0x100000,rootshared;logs". The flags are boolean options, true if
+// given without value, the numbers are options with an integer value
+fn parse_named_list(value: &str, flags: &[&str], numbers: &[&str]) -> Result<serde_json::Value, String> {
+    let mut entries = Vec::new();
+    for entry in value.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let mut parts = entry.split(',').map(|p| p.trim());
+        let mut object = serde_json::Map::new();
+        object.insert("name".to_string(), serde_json::Value::from(parts.next().unwrap_or_default()));
+        for part in parts {
+            let (key, value) = match part.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None if flags.contains(&part) => (part, "true"),
+                None => return Err(format!("\"{}\" is not an option of \"{}\"", part, entry)),
+            };
+            let value = if flags.contains(&key) {
+                serde_json::Value::Bool(parse_bool(value)?)
+            } else if numbers.contains(&key) {
+                serde_json::Value::from(value.parse::<u64>().map_err(|_| format!("\"{}\" is not a number", value))?)
+            } else {
+                serde_json::Value::from(value)
+            };
+            object.insert(key.to_string(), value);
+        }
+        entries.push(serde_json::Value::Object(object));
+    }
+    Ok(serde_json::Value::Array(entries))
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// Path of a file used to boot the cell. Paths coming from the image are resolved against the
+// container rootfs and cannot escape it, while paths provided by runPHI itself (e.g. the default
+// kernel) point to the host filesystem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImagePath(PathBuf);
+
+impl ImagePath {
+    // Resolve a path given by the image against the rootfs. Absolute paths are relative to the
+    // rootfs as well, and any ".." is rejected, so that the image cannot reach host files. The
+    // symlinks of the image are followed, and must not lead out of the rootfs either
+    pub fn resolve(rootfs: &str, path: &str) -> Result<Self, String> {
+        let mut resolved = PathBuf::from(rootfs);
+        for component in Path::new(path.trim()).components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(part) => resolved.push(part),
+                Component::ParentDir => {
+                    return Err(format!("\"{}\" must not contain \"..\"", path));
+                }
+                Component::Prefix(_) => return Err(format!("\"{}\" is not a valid path", path)),
+            }
+        }
+        if resolved == Path::new(rootfs) {
+            return Err(format!("\"{}\" does not name a file", path));
+        }
+        let root = fs::canonicalize(rootfs).map_err(|e| format!("Cannot resolve the rootfs {}: {}", rootfs, e))?;
+        if !canonicalize_existing(&resolved)?.starts_with(&root) {
+            return Err(format!("\"{}\" leads out of the rootfs through a symlink", path));
+        }
+        Ok(ImagePath(resolved))
+    }
+
+    // A file of the host, provided by runPHI and not by the image
+    pub fn host<P: Into<PathBuf>>(path: P) -> Self {
+        ImagePath(path.into())
+    }
+
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+
+    // Name of the file, without the directories
+    pub fn file_name(&self) -> &str {
+        self.0.file_name().and_then(|n| n.to_str()).unwrap_or("")
+    }
+}
+
+// Canonical form of the path, following the symlinks of the part that exists. The missing part,
+// which holds no symlink, is appended as is
+fn canonicalize_existing(path: &Path) -> Result<PathBuf, String> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        match fs::canonicalize(existing) {
+            Ok(canonical) => return Ok(canonical.join(missing.into_iter().rev().collect::<PathBuf>())),
+            // A dangling symlink exists as well, and cannot be checked
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && fs::symlink_metadata(existing).is_err() => {
+                missing.extend(existing.file_name());
+                existing = existing.parent().ok_or(format!("Cannot resolve {}", path.display()))?;
+            }
+            Err(e) => return Err(format!("Cannot resolve {}: {}", existing.display(), e)),
+        }
+    }
+}
+
+impl fmt::Display for ImagePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+impl AsRef<Path> for ImagePath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+// config.json as written in the image, before validation. Every field is optional here, checks
+// on mandatory fields and on the consistency among fields are done when building ImageConfig.
+// Unknown fields are rejected, so that a misspelled one is not silently ignored
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawImageConfig {
+    #[serde(default)]
+    version: Option<u32>,
+    #[serde(default)]
+    os_var: Option<OsKind>,
+    // The initramfs of a linux cell, "ramdisk" being its older name
+    #[serde(default, alias = "ramdisk")]
+    cpio: Option<String>,
+    #[serde(default)]
+    kernel: Option<String>,
+    #[serde(default)]
+    inmate: Option<String>,
+    #[serde(default)]
+    dtb: Option<String>,
+    #[serde(default)]
+    initrd: Option<String>,
+    #[serde(default)]
+    netconf: Option<NetConfKind>,
+    #[serde(default)]
+    root: Option<String>,
+    #[serde(default)]
+    cmdline: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    starting_vaddress: Option<Address>,
+    #[serde(default)]
+    net: Option<String>,
+    #[serde(default)]
+    rpu_req: Option<bool>,
+    #[serde(default)]
+    console: Option<ConsoleKind>,
+    #[serde(default)]
+    cpu_cluster: Option<u32>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    phys_address: Option<Address>,
+    #[serde(default)]
+    shm: Option<Vec<ShmChannel>>,
+    #[serde(default)]
+    ivshmem: Option<Vec<IvshmemDevice>>,
+}
+
+// Annotations of the OCI config overriding the image config, with the field they set
+const ANNOTATIONS: [(&str, &str); 17] = [
+    ("runphi.io/os", "os_var"),
+    ("runphi.io/inmate", "inmate"),
+    ("runphi.io/rpu", "rpu_req"),
+    ("runphi.io/load-address", "starting_vaddress"),
+    ("runphi.io/kernel", "kernel"),
+    ("runphi.io/dtb", "dtb"),
+    ("runphi.io/cpio", "cpio"),
+    ("runphi.io/initrd", "initrd"),
+    ("runphi.io/net", "net"),
+    ("runphi.io/netconf", "netconf"),
+    ("runphi.io/root", "root"),
+    ("runphi.io/cmdline", "cmdline"),
+    ("runphi.io/console", "console"),
+    ("runphi.io/cpu-cluster", "cpu_cluster"),
+    ("runphi.io/phys-address", "phys_address"),
+    ("runphi.io/shm", "shm"),
+    ("runphi.io/ivshmem", "ivshmem"),
+];
+// Env variables of the container process overriding the image config, with the field they set.
+// LINUX is handled apart, since it selects the os instead of carrying a value
+const ENV_VARS: [(&str, &str); 3] = [("INMATE", "inmate"), ("KERNEL", "kernel"), ("RAMDISK", "cpio")];
+const ENV_LINUX: &str = "LINUX";
+
+impl RawImageConfig {
+    // The image config file is optional, but if present it must be valid
+    fn from_file(mountpoint: &str) -> Result<Self, Box<dyn Error>> {
+        let file_path = Path::new(mountpoint).join(IMAGECONFIG_PATH);
+        let json_str = match fs::read_to_string(&file_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("Cannot read image config {}: {}", file_path.display(), e).into()),
+        };
+        serde_json::from_str(&json_str)
+            .map_err(|e| format!("Invalid image config {}: {}", file_path.display(), e).into())
+    }
+
+    // The image config overridden by the env variables, then by the annotations
+    fn from_levels(mountpoint: &str, oci: &serde_json::Value) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::from_file(mountpoint)?
+            .overlay(Self::from_env(oci)?)
+            .overlay(Self::from_annotations(oci)?))
+    }
+
+    fn from_annotations(oci: &serde_json::Value) -> Result<Self, Box<dyn Error>> {
+        let mut raw = Self::default();
+        let annotations = match oci.get("annotations").and_then(|a| a.as_object()) {
+            Some(annotations) => annotations,
+            None => return Ok(raw),
+        };
+        for (key, field) in ANNOTATIONS {
+            if let Some(value) = annotations.get(key).and_then(|v| v.as_str()) {
+                raw = raw.overlay(
+                    Self::from_string_field(field, value)
+                        .map_err(|e| format!("Invalid annotation {}: {}", key, e))?,
+                );
+            }
+        }
+        Ok(raw)
+    }
+
+    fn from_env(oci: &serde_json::Value) -> Result<Self, Box<dyn Error>> {
+        let mut raw = Self::default();
+        let env = match oci.pointer("/process/env").and_then(|e| e.as_array()) {
+            Some(env) => env,
+            None => return Ok(raw),
+        };
+        for var in env.iter().filter_map(|v| v.as_str()) {
+            let (name, value) = match var.split_once('=') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            if name == ENV_LINUX {
+                if parse_bool(value).map_err(|e| format!("Invalid env variable {}: {}", name, e))? {
+                    raw.os_var = Some(OsKind::Linux);
+                }
+                continue;
+            }
+            if let Some((_, field)) = ENV_VARS.iter().find(|(env_name, _)| *env_name == name) {
+                raw = raw.overlay(
+                    Self::from_string_field(field, value)
+                        .map_err(|e| format!("Invalid env variable {}: {}", name, e))?,
+                );
+            }
+        }
+        Ok(raw)
+    }
+
+    // Annotations and env variables are plain strings, convert them to the type of the field
+    // they set, and parse them as if they were in config.json
+    fn from_string_field(field: &str, value: &str) -> Result<Self, String> {
+        let json_value = match field {
+            "rpu_req" => serde_json::Value::Bool(parse_bool(value)?),
+            "shm" => parse_named_list(value, &["rootshared"], &[])?,
+            "ivshmem" => parse_named_list(value, &[], &["peers", "id"])?,
+            "cpu_cluster" => serde_json::Value::from(
+                value.trim().parse::<u32>().map_err(|_| format!("\"{}\" is not a cluster index", value))?,
+            ),
+            _ => serde_json::Value::String(value.to_string()),
+        };
+        let mut object = serde_json::Map::new();
+        object.insert(field.to_string(), json_value);
+        serde_json::from_value(serde_json::Value::Object(object)).map_err(|e| e.to_string())
+    }
+
+    // Fields set in `other` take precedence over the ones in `self`
+    fn overlay(self, other: Self) -> Self {
+        Self {
+            version: other.version.or(self.version),
+            os_var: other.os_var.or(self.os_var),
+            cpio: other.cpio.or(self.cpio),
+            kernel: other.kernel.or(self.kernel),
+            inmate: other.inmate.or(self.inmate),
+            dtb: other.dtb.or(self.dtb),
+            initrd: other.initrd.or(self.initrd),
+            netconf: other.netconf.or(self.netconf),
+            root: other.root.or(self.root),
+            cmdline: other.cmdline.or(self.cmdline),
+            starting_vaddress: other.starting_vaddress.or(self.starting_vaddress),
+            net: other.net.or(self.net),
+            rpu_req: other.rpu_req.or(self.rpu_req),
+            console: other.console.or(self.console),
+            cpu_cluster: other.cpu_cluster.or(self.cpu_cluster),
+            phys_address: other.phys_address.or(self.phys_address),
+            shm: other.shm.or(self.shm),
+            ivshmem: other.ivshmem.or(self.ivshmem),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" | "" => Ok(false),
+        other => Err(format!("\"{}\" is not a boolean", other)),
+    }
+}
+
+// Node-wide defaults for the image config, in WORKPATH/node_defaults.toml. They are merged under
+// the config of every image, so that a generic image plus the node profile is enough to start a
+// cell. Unlike image paths, the paths here refer to the host filesystem
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeDefaults {
+    #[serde(default)]
+    pub os_var: Option<OsKind>,
+    #[serde(default)]
+    pub net: Option<String>,
+    #[serde(default)]
+    pub console: Option<ConsoleKind>,
+    // Files and kernel arguments used to boot linux cells whose image does not bring its own
+    #[serde(default)]
+    pub linux: LinuxDefaults,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinuxDefaults {
+    #[serde(default)]
+    pub kernel: Option<PathBuf>,
+    #[serde(default)]
+    pub dtb: Option<PathBuf>,
+    #[serde(default)]
+    pub initrd: Option<PathBuf>,
+    #[serde(default)]
+    pub cpio: Option<PathBuf>,
+    #[serde(default)]
+    pub root: Option<String>,
+    #[serde(default)]
+    pub cmdline: Option<String>,
+}
+
+impl NodeDefaults {
+    // The defaults file is optional, but if present it must be valid
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        let file_path = Path::new(WORKPATH).join(NODE_DEFAULTS_FILE);
+        match fs::read_to_string(&file_path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|e| format!("Invalid node defaults {}: {}", file_path.display(), e).into()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Cannot read node defaults {}: {}", file_path.display(), e).into()),
+        }
+    }
+}
+
+// This structure holds the information that describe the image to be started as partitioned cell
+// These are additional to standard information required by containers. For example, if dealing with a
+// binary, the starting virtual address is required to perform a mapping, or the devices used or the
+// binary to load in the cell
+#[derive(Debug, Clone)]
+pub struct ImageConfig {
+    // Version of the schema the image was written for
+    pub version: u32,
+    // if OSvar is "linux", the OS file contains the image of the linux kernel
+    // if the OSvar contains anything else, like "zephyr", then a file integrating the runtime is loaded
+    // When avialable, this variable could identify also a bare metal runtime like a WASM OS
+    pub os_var: OsKind,
+    // Check if the container comes with its own Kernel and/or Ramdisk
+    // runPHI requires that both the kernel and the ramdisk are exposed by client through container's env variables KERNEL=/path/to/kernel_image, RAMDISK=/path/to/cpio
+    // moreover, in case of Jailhouse, the user should provide the path, in the container fs, of the inmate to run
+    pub cpio: Option<ImagePath>,
+    // When available, a custom kernel and ramkdisk shipped in the container can be specified. In this case the
+    // application decides to bring its own kernel (platformm/board-dependent)
+    pub kernel: Option<ImagePath>,
+    // The inmate variable represents the file to be loaded containing the bare metal code or the
+    // app with the libOS
+    pub inmate: ImagePath,
+    // The dtb is only for linux arm64, borderline case
+    pub dtb: Option<ImagePath>,
+    // Same here, the initrd is an alternative to the cpio on x86
+    pub initrd: Option<ImagePath>,
+    // How the guest configures its networking interface, static by default
+    pub netconf: NetConfKind,
+    // Root device of a linux cell (root= of the kernel command line), none to stay in the initramfs
+    pub root: Option<String>,
+    // Arguments appended to the kernel command line of a linux cell
+    pub cmdline: Option<String>,
+    // The starting_vaddress variable specifies the virtual address that the binary in inmate is
+    // expecting to start. This is the virtual start of the RAM of the cell when MMU is available,
+    // and decides the physical placement of the RAM when MMU not available (RPUs, MPU-only cores)
+    pub starting_vaddress: Option<Address>,
+    pub net: String,
+    pub rpu_req: bool,
+    // Console asked for the cell. When missing, the cell gets the UART of the board if there is
+    // one and it is free, the virtual console otherwise
+    pub console: Option<ConsoleKind>,
+    // Index of the CPU cluster of the platform ([cpus] clusters) the cell should run on
+    pub cpu_cluster: Option<u32>,
+    // Physical address the RAM of the cell must start at, for binaries that cannot be relocated.
+    // When missing, the memory manager places the RAM where it fits best
+    pub phys_address: Option<Address>,
+    // Shared-memory channels the cell joins
+    pub shm: Vec<ShmChannel>,
+    // IVSHMEM devices linking the cell to other cells, besides the networking one
+    pub ivshmem: Vec<IvshmemDevice>,
+}
+
+impl ImageConfig {
+    // Build the configuration of the cell out of, from the highest to the lowest precedence:
+    // 1. annotations of the OCI config (runphi.io/*)
+    // 2. selected env variables of the container process (INMATE, KERNEL, RAMDISK, LINUX)
+    // 3. the configuration shipped in the image, in <rootfs>/boot/config.json
+    // 4. the node defaults, in WORKPATH/node_defaults.toml
+    // A field missing in a level is taken from the following one. The image config can be missing
+    // altogether, as long as the mandatory fields are provided by the other levels
+    pub fn get(fc: &FrontendConfig) -> Result<Self, Box<dyn Error>> {
+        let defaults = NodeDefaults::load()?;
+        let raw = RawImageConfig::from_levels(&fc.mountpoint, &fc.jsonconfig)?;
+        Self::from_raw(raw, &defaults, &fc.mountpoint)
+            .map_err(|e| format!("Invalid image config for {}: {}", fc.containerid, e).into())
+    }
+
+    fn from_raw(raw: RawImageConfig, defaults: &NodeDefaults, mountpoint: &str) -> Result<Self, String> {
+        let version = raw.version.unwrap_or(IMAGECONFIG_VERSION);
+        if version == 0 || version > IMAGECONFIG_VERSION {
+            return Err(field_error("version", format!("unsupported version {}, this runPHI supports up to {}", version, IMAGECONFIG_VERSION)));
+        }
+        let os_var = raw
+            .os_var
+            .or(defaults.os_var)
+            .ok_or_else(|| field_error("os_var", "missing field, and no default in the node defaults".to_string()))?;
+
+        let resolve = |field: &str, value: Option<String>| -> Result<Option<ImagePath>, String> {
+            match value {
+                Some(path) if !path.trim().is_empty() => ImagePath::resolve(mountpoint, &path)
+                    .map(Some)
+                    .map_err(|e| field_error(field, e)),
+                _ => Ok(None),
+            }
+        };
+        let inmate = match resolve("inmate", raw.inmate)? {
+            Some(inmate) => inmate,
+            None => ImagePath::resolve(mountpoint, DEFAULT_INMATE).map_err(|e| field_error("inmate", e))?,
+        };
+
+        let mut config = ImageConfig {
+            version,
+            os_var,
+            cpio: resolve("cpio", raw.cpio)?,
+            kernel: resolve("kernel", raw.kernel)?,
+            inmate,
+            dtb: resolve("dtb", raw.dtb)?,
+            initrd: resolve("initrd", raw.initrd)?,
+            netconf: raw.netconf.unwrap_or(NetConfKind::Static),
+            root: raw.root.filter(|root| !root.trim().is_empty()),
+            cmdline: raw.cmdline.filter(|cmdline| !cmdline.trim().is_empty()),
+            starting_vaddress: raw.starting_vaddress,
+            net: raw.net.or_else(|| defaults.net.clone()).unwrap_or_default(),
+            rpu_req: raw.rpu_req.unwrap_or(false),
+            console: raw.console.or(defaults.console),
+            cpu_cluster: raw.cpu_cluster,
+            phys_address: raw.phys_address,
+            shm: raw.shm.unwrap_or_default(),
+            ivshmem: raw.ivshmem.unwrap_or_default(),
+        };
+        config.validate()?;
+
+        // Linux cells not bringing their own boot files get the ones of the node
+        if config.os_var == OsKind::Linux {
+            let linux = &defaults.linux;
+            let host_default = |value: Option<ImagePath>, default: &Option<PathBuf>| {
+                value.or_else(|| default.clone().map(ImagePath::host))
+            };
+            config.kernel = host_default(config.kernel, &linux.kernel);
+            config.dtb = host_default(config.dtb, &linux.dtb);
+            config.initrd = host_default(config.initrd, &linux.initrd);
+            config.cpio = host_default(config.cpio, &linux.cpio);
+            config.root = config.root.or_else(|| linux.root.clone());
+            config.cmdline = config.cmdline.or_else(|| linux.cmdline.clone());
+        }
+        Ok(config)
+    }
+
+    // Cross-checks among fields
+    fn validate(&self) -> Result<(), String> {
+        if self.rpu_req {
+            // RPUs run a firmware loaded by the hypervisor, there is no way to boot linux there
+            if self.os_var == OsKind::Linux {
+                return Err(field_error("rpu_req", "RPUs cannot run a linux cell".to_string()));
+            }
+            if self.cpu_cluster.is_some() {
+                return Err(field_error("cpu_cluster", "not meaningful with rpu_req, RPUs are not in the CPU clusters".to_string()));
+            }
+        }
+        ShmChannel::validate(&self.shm).map_err(|e| field_error("shm", e))?;
+        IvshmemDevice::validate(&self.ivshmem).map_err(|e| field_error("ivshmem", e))?;
+        if self.os_var != OsKind::Linux {
+            for (field, value) in [("kernel", &self.kernel), ("dtb", &self.dtb), ("cpio", &self.cpio), ("initrd", &self.initrd)] {
+                if value.is_some() {
+                    return Err(field_error(field, format!("only meaningful with os_var \"linux\", found \"{}\"", self.os_var)));
+                }
+            }
+            for (field, value) in [("root", &self.root), ("cmdline", &self.cmdline)] {
+                if value.is_some() {
+                    return Err(field_error(field, format!("only meaningful with os_var \"linux\", found \"{}\"", self.os_var)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn field_error(field: &str, reason: String) -> String {
+    format!("field `{}`: {}", field, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    // An empty rootfs with a boot directory, under the temporary directory
+    fn rootfs(name: &str) -> PathBuf {
+        let rootfs = std::env::temp_dir().join(format!("runphi-imageconfig-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&rootfs);
+        fs::create_dir_all(rootfs.join("boot")).unwrap();
+        rootfs
+    }
+
+    #[test]
+    fn resolve_stays_in_the_rootfs() {
+        let rootfs = rootfs("resolve");
+        let root = rootfs.to_str().unwrap();
+        fs::write(rootfs.join("boot/inmate.bin"), b"inmate").unwrap();
+
+        let resolved = ImagePath::resolve(root, "boot/inmate.bin").unwrap();
+        assert_eq!(resolved.as_path(), rootfs.join("boot/inmate.bin"));
+        // Absolute paths are relative to the rootfs, and the files may be missing yet
+        let resolved = ImagePath::resolve(root, "/boot/./zephyr.bin").unwrap();
+        assert_eq!(resolved.as_path(), rootfs.join("boot/zephyr.bin"));
+
+        assert!(ImagePath::resolve(root, "boot/../../etc/shadow").is_err());
+        assert!(ImagePath::resolve(root, "..").is_err());
+        assert!(ImagePath::resolve(root, "").is_err());
+        assert!(ImagePath::resolve(root, "/").is_err());
+        assert!(ImagePath::resolve(root, "./").is_err());
+        fs::remove_dir_all(&rootfs).unwrap();
+    }
+
+    #[test]
+    fn resolve_rejects_symlinks_out_of_the_rootfs() {
+        let rootfs = rootfs("symlink");
+        let root = rootfs.to_str().unwrap();
+        fs::write(rootfs.join("boot/real.bin"), b"inmate").unwrap();
+        symlink("real.bin", rootfs.join("boot/inmate.bin")).unwrap();
+        symlink("/boot/real.bin", rootfs.join("boot/absolute.bin")).unwrap();
+        symlink("/etc/passwd", rootfs.join("boot/passwd")).unwrap();
+        symlink("/etc", rootfs.join("etc")).unwrap();
+        symlink("/nonexistent-runphi", rootfs.join("boot/dangling")).unwrap();
+
+        // Symlinks within the rootfs are followed
+        assert_eq!(ImagePath::resolve(root, "boot/inmate.bin").unwrap().as_path(), rootfs.join("boot/inmate.bin"));
+        // The target of an absolute symlink is on the host
+        assert!(ImagePath::resolve(root, "boot/absolute.bin").is_err());
+        assert!(ImagePath::resolve(root, "boot/passwd").is_err());
+        // A symlinked directory, even to a missing file
+        assert!(ImagePath::resolve(root, "etc/passwd").is_err());
+        assert!(ImagePath::resolve(root, "etc/missing/kernel").is_err());
+        assert!(ImagePath::resolve(root, "boot/dangling").is_err());
+        fs::remove_dir_all(&rootfs).unwrap();
+    }
+
+    #[test]
+    fn image_config_rejects_unknown_fields() {
+        let raw: Result<RawImageConfig, _> = serde_json::from_str(r#"{"os_var": "linux", "kernal": "/boot/Image"}"#);
+        assert!(raw.unwrap_err().to_string().contains("unknown field `kernal`"));
+        let raw: RawImageConfig = serde_json::from_str(r#"{"os_var": "linux", "kernel": "/boot/Image"}"#).unwrap();
+        assert_eq!(raw.kernel.as_deref(), Some("/boot/Image"));
+    }
+
+    // The config of an image shipping the config.json given, started with the OCI config given
+    fn image_config(name: &str, config_json: &str, oci: serde_json::Value, defaults: &NodeDefaults) -> Result<ImageConfig, String> {
+        let rootfs = rootfs(name);
+        fs::write(rootfs.join(IMAGECONFIG_PATH), config_json).unwrap();
+        let root = rootfs.to_str().unwrap();
+        let config = RawImageConfig::from_levels(root, &oci)
+            .map_err(|e| e.to_string())
+            .and_then(|raw| ImageConfig::from_raw(raw, defaults, root));
+        fs::remove_dir_all(&rootfs).unwrap();
+        config.map(|config| ImageConfig {
+            inmate: ImagePath::host(config.inmate.as_path().strip_prefix(&rootfs).unwrap()),
+            ..config
+        })
+    }
+
+    #[test]
+    fn annotations_override_env_override_image() {
+        let image = r#"{"os_var": "zephyr", "inmate": "/boot/image.bin", "console": "uart", "starting_vaddress": "0x1000"}"#;
+        let oci = |env: &[&str], annotations: &[(&str, &str)]| serde_json::json!({
+            "process": { "env": env },
+            "annotations": annotations.iter().map(|(k, v)| (k.to_string(), serde_json::Value::from(*v))).collect::<serde_json::Map<_, _>>(),
+        });
+        let defaults = NodeDefaults { os_var: Some(OsKind::Linux), net: Some("none".to_string()), console: Some(ConsoleKind::Virtual), ..Default::default() };
+
+        // The image config wins over the node defaults, which fill in the missing fields
+        let config = image_config("levels-image", image, oci(&[], &[]), &defaults).unwrap();
+        assert_eq!((config.os_var, config.inmate.as_path(), config.console), (OsKind::Zephyr, Path::new("boot/image.bin"), Some(ConsoleKind::Uart)));
+        assert_eq!((config.net.as_str(), config.starting_vaddress), ("none", Some(Address(0x1000))));
+
+        // The env variables win over the image config
+        let config = image_config("levels-env", image, oci(&["INMATE=/boot/env.bin", "PATH=/bin"], &[]), &defaults).unwrap();
+        assert_eq!(config.inmate.as_path(), Path::new("boot/env.bin"));
+
+        // The annotations win over the env variables
+        let annotations = [("runphi.io/inmate", "/boot/annotation.bin"), ("runphi.io/console", "none"), ("runphi.io/net", "default")];
+        let config = image_config("levels-annotations", image, oci(&["INMATE=/boot/env.bin"], &annotations), &defaults).unwrap();
+        assert_eq!((config.inmate.as_path(), config.console, config.net.as_str()), (Path::new("boot/annotation.bin"), Some(ConsoleKind::None), "default"));
+        // Fields the higher levels leave out are kept
+        assert_eq!((config.os_var, config.starting_vaddress), (OsKind::Zephyr, Some(Address(0x1000))));
+
+        // LINUX selects linux over the image, an annotation selects the os over both
+        let config = image_config("levels-linux", image, oci(&["LINUX=1"], &[("runphi.io/console", "virtual")]), &defaults).unwrap();
+        assert_eq!(config.os_var, OsKind::Linux);
+        let config = image_config("levels-os", image, oci(&["LINUX=1"], &[("runphi.io/os", "baremetal")]), &defaults).unwrap();
+        assert_eq!(config.os_var, OsKind::Baremetal);
+
+        // A wrong annotation is reported as such
+        let error = image_config("levels-invalid", image, oci(&[], &[("runphi.io/rpu", "maybe")]), &defaults).unwrap_err();
+        assert!(error.contains("runphi.io/rpu"), "{}", error);
+    }
+
+    #[test]
+    fn ramdisk_is_the_cpio_of_linux_cells() {
+        let defaults = NodeDefaults::default();
+        let image = r#"{"os_var": "linux", "kernel": "/boot/Image", "ramdisk": "/boot/rootfs.cpio"}"#;
+        let config = image_config("ramdisk-image", image, serde_json::json!({}), &defaults).unwrap();
+        assert!(config.cpio.unwrap().as_path().ends_with("boot/rootfs.cpio"));
+        // RAMDISK overrides the cpio of the image
+        let image = r#"{"os_var": "linux", "kernel": "/boot/Image", "cpio": "/boot/rootfs.cpio"}"#;
+        let oci = serde_json::json!({ "process": { "env": ["RAMDISK=/boot/env.cpio"] } });
+        let config = image_config("ramdisk-env", image, oci, &defaults).unwrap();
+        assert!(config.cpio.unwrap().as_path().ends_with("boot/env.cpio"));
+
+        // Both names at once are ambiguous
+        let image = r#"{"os_var": "linux", "cpio": "/boot/a.cpio", "ramdisk": "/boot/b.cpio"}"#;
+        assert!(image_config("ramdisk-both", image, serde_json::json!({}), &defaults).is_err());
+    }
+
+    #[test]
+    fn linux_files_are_rejected_for_other_os() {
+        let defaults = NodeDefaults::default();
+        for (name, field) in [("kernel", "kernel"), ("dtb", "dtb"), ("cpio", "cpio"), ("ramdisk", "cpio"), ("initrd", "initrd")] {
+            let image = format!(r#"{{"os_var": "zephyr", "{}": "/boot/file"}}"#, name);
+            let error = image_config(&format!("linux-only-{}", name), &image, serde_json::json!({}), &defaults).unwrap_err();
+            assert!(error.contains(&format!("field `{}`", field)), "{}", error);
+        }
+        let error = image_config("linux-only-env", r#"{"os_var": "baremetal"}"#, serde_json::json!({ "process": { "env": ["RAMDISK=/boot/rootfs.cpio"] } }), &defaults).unwrap_err();
+        assert!(error.contains("only meaningful with os_var \"linux\""), "{}", error);
+    }
+
+    #[test]
+    fn node_defaults_fill_in_the_image() {
+        let defaults: NodeDefaults = toml::from_str(r#"
+            os_var = "linux"
+            net = "none"
+
+            [linux]
+            kernel = "/root/linux/Image"
+            dtb = "/root/linux/inmate.dtb"
+            cpio = "/root/linux/rootfs.cpio.gz"
+            root = "/dev/vda"
+            cmdline = "loglevel=4"
+        "#).unwrap();
+        assert!(toml::from_str::<NodeDefaults>("[linux]\nkernal = \"/root/linux/Image\"").is_err());
+
+        // A linux image without boot files gets the ones of the host
+        let config = image_config("defaults-linux", "{}", serde_json::json!({}), &defaults).unwrap();
+        assert_eq!((config.os_var, config.net.as_str()), (OsKind::Linux, "none"));
+        assert_eq!(config.kernel, Some(ImagePath::host("/root/linux/Image")));
+        assert_eq!(config.dtb, Some(ImagePath::host("/root/linux/inmate.dtb")));
+        assert_eq!(config.cpio, Some(ImagePath::host("/root/linux/rootfs.cpio.gz")));
+        assert_eq!((config.root.as_deref(), config.cmdline.as_deref()), (Some("/dev/vda"), Some("loglevel=4")));
+
+        // The files and arguments of the image win, each on its own
+        let image = r#"{"kernel": "/boot/Image", "cmdline": "quiet"}"#;
+        let config = image_config("defaults-image", image, serde_json::json!({}), &defaults).unwrap();
+        assert!(config.kernel.unwrap().as_path().ends_with("boot/Image"));
+        assert_eq!(config.dtb, Some(ImagePath::host("/root/linux/inmate.dtb")));
+        assert_eq!((config.root.as_deref(), config.cmdline.as_deref()), (Some("/dev/vda"), Some("quiet")));
+
+        // The other cells get none of them
+        let config = image_config("defaults-zephyr", r#"{"os_var": "zephyr", "net": "default"}"#, serde_json::json!({}), &defaults).unwrap();
+        assert_eq!((config.kernel, config.dtb, config.cpio, config.root, config.cmdline), (None, None, None, None, None));
+        assert_eq!(config.net, "default");
+
+        // Without a default, os_var is mandatory
+        let error = image_config("defaults-none", "{}", serde_json::json!({}), &NodeDefaults::default()).unwrap_err();
+        assert!(error.contains("field `os_var`"), "{}", error);
+    }
+}
@@ -0,0 +1,196 @@
+//*********************************************
+// Authors: Marco Barletta (marco.barletta@unina.it)
+//*********************************************
+
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use nix::fcntl::{flock, FlockArg};
+use toml::{map::Map, Value};
+
+// This file implements the mapping between OCI container IDs and the cell names used by the
+// hypervisor. Container IDs coming from Kubernetes are 64-char hashes, while hypervisors like
+// Jailhouse fail with a partition of a longer name, so each container gets a short name that is
+// unique on the node. The mapping is persisted in the run dir, such that every command of the
+// container lifecycle resolves the same name.
+
+const CELLNAMES_FILE: &str = "cellnames.toml";
+const CELLNAMES_LOCK: &str = "cellnames.lock";
+// Jailhouse reserves 32 bytes for the name, including the terminator. 24 chars were historically
+// used by runPHI, keep the same length
+const CELLNAME_LEN: usize = 24;
+// Chars of the container ID kept in clear in the cell name, to ease debugging with jailhouse tools
+const CELLNAME_PREFIX_LEN: usize = 15;
+
+// Returns the cell name for the container, creating and storing a new one if missing
+pub fn assign(rundir: &str, containerid: &str) -> Result<String, Box<dyn Error>> {
+    with_table(rundir, |table| {
+        if let Some(name) = table.get(containerid).and_then(|n| n.as_str()) {
+            return Ok((name.to_string(), false));
+        }
+        // Look for a name not yet in use, salting the hash on collision
+        let mut salt: u64 = 0;
+        let name = loop {
+            let candidate = candidate_name(containerid, salt);
+            if !table.values().any(|v| v.as_str() == Some(candidate.as_str())) {
+                break candidate;
+            }
+            salt += 1;
+        };
+        logging::log_message(logging::Level::Debug, format!("Assigned cell name {} to id {}", &name, containerid).as_str());
+        table.insert(containerid.to_string(), Value::String(name.clone()));
+        Ok((name, true))
+    })
+}
+
+// Returns the cell name previously assigned to the container
+pub fn lookup(rundir: &str, containerid: &str) -> Result<String, Box<dyn Error>> {
+    with_table(rundir, |table| {
+        table
+            .get(containerid)
+            .and_then(|n| n.as_str())
+            .map(|n| (n.to_string(), false))
+            .ok_or_else(|| format!("No cell name assigned to id {}", containerid).into())
+    })
+}
+
+// Removes the container from the mapping, so that its name can be reused
+pub fn release(rundir: &str, containerid: &str) -> Result<(), Box<dyn Error>> {
+    with_table(rundir, |table| Ok(((), table.remove(containerid).is_some())))
+}
+
+// Short name derived from the container ID. IDs that are already short enough (e.g., given by hand
+// with ctr) are kept as they are, otherwise the name is made of the first chars of the ID followed
+// by a hash of the whole ID. The salt changes the hash when the name is already taken
+fn candidate_name(containerid: &str, salt: u64) -> String {
+    if salt == 0
+        && containerid.len() <= CELLNAME_LEN
+        && containerid.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return containerid.to_string();
+    }
+    let prefix: String = containerid
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .take(CELLNAME_PREFIX_LEN)
+        .collect();
+    let hash = fnv1a(containerid.as_bytes(), salt);
+    let hash_len = CELLNAME_LEN - prefix.len() - 1;
+    let hash_str = format!("{:016x}", hash);
+    format!("{}-{}", prefix, &hash_str[..hash_len.min(hash_str.len())])
+}
+
+// FNV-1a is stable across builds and platforms, unlike the std hasher, which matters since names
+// may be recomputed by a different runPHI version
+fn fnv1a(bytes: &[u8], salt: u64) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in salt.to_le_bytes().iter().chain(bytes.iter()) {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Runs `f` on the mapping table while holding an exclusive lock on it, since multiple runPHI
+// instances may be called concurrently by the container manager. The table is written back
+// only if `f` reports a modification
+fn with_table<T, F>(rundir: &str, f: F) -> Result<T, Box<dyn Error>>
+where
+    F: FnOnce(&mut Map<String, Value>) -> Result<(T, bool), Box<dyn Error>>,
+{
+    fs::create_dir_all(rundir)?;
+    let lockfile = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(Path::new(rundir).join(CELLNAMES_LOCK))?;
+    flock(lockfile.as_raw_fd(), FlockArg::LockExclusive)?;
+
+    let file_path = Path::new(rundir).join(CELLNAMES_FILE);
+    let mut table = match fs::read_to_string(&file_path) {
+        Ok(content) => content.parse::<Value>()?.as_table().cloned().unwrap_or_default(),
+        Err(_) => Map::new(),
+    };
+    let (result, modified) = f(&mut table)?;
+    if modified {
+        fs::write(&file_path, toml::to_string(&Value::Table(table))?)?;
+    }
+    // The lock is released when lockfile is closed
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty run dir, under the temporary directory
+    fn rundir(name: &str) -> String {
+        let rundir = std::env::temp_dir().join(format!("runphi-cellname-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&rundir);
+        rundir.to_string_lossy().into_owned()
+    }
+
+    const K8S_ID: &str = "3f1c9a7e5b2d4c6f8a0e1b3d5f7a9c2e4b6d8f0a1c3e5a7b9d1f3a5c7e9b0d2f";
+
+    #[test]
+    fn short_ids_are_kept() {
+        assert_eq!(candidate_name("zephyr-demo", 0), "zephyr-demo");
+        let id = "a".repeat(CELLNAME_LEN);
+        assert_eq!(candidate_name(&id, 0), id);
+        // Chars the hypervisor tools may not like are hashed away
+        assert_ne!(candidate_name("demo.1", 0), "demo.1");
+    }
+
+    #[test]
+    fn long_ids_are_hashed() {
+        for id in [K8S_ID, &"a".repeat(CELLNAME_LEN + 1), "ctr/with.dots/and/slashes/and/more"] {
+            let name = candidate_name(id, 0);
+            assert_eq!(name.len(), CELLNAME_LEN, "{}", name);
+            assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'), "{}", name);
+            // Stable across calls, different for another salt
+            assert_eq!(name, candidate_name(id, 0));
+            assert_ne!(name, candidate_name(id, 1));
+        }
+        assert!(candidate_name(K8S_ID, 0).starts_with(&K8S_ID[..CELLNAME_PREFIX_LEN]));
+        assert_ne!(candidate_name(K8S_ID, 0), candidate_name(&K8S_ID.replace('f', "e"), 0));
+    }
+
+    #[test]
+    fn assign_salts_on_collision() {
+        let rundir = rundir("collision");
+        // Another container holds the name of the ID already
+        let taken = candidate_name(K8S_ID, 0);
+        fs::create_dir_all(&rundir).unwrap();
+        fs::write(Path::new(&rundir).join(CELLNAMES_FILE), format!("other = \"{}\"\n", taken)).unwrap();
+
+        let name = assign(&rundir, K8S_ID).unwrap();
+        assert_eq!(name, candidate_name(K8S_ID, 1));
+        assert_eq!(name.len(), CELLNAME_LEN);
+        // The name is kept by the following commands
+        assert_eq!(assign(&rundir, K8S_ID).unwrap(), name);
+        assert_eq!(lookup(&rundir, K8S_ID).unwrap(), name);
+        assert_eq!(lookup(&rundir, "other").unwrap(), taken);
+        fs::remove_dir_all(&rundir).unwrap();
+    }
+
+    #[test]
+    fn release_makes_the_name_reusable() {
+        let rundir = rundir("release");
+        let first = assign(&rundir, "demo").unwrap();
+        assert_eq!(first, "demo");
+        release(&rundir, "demo").unwrap();
+        assert!(lookup(&rundir, "demo").is_err());
+        // Released twice, or never assigned
+        release(&rundir, "demo").unwrap();
+
+        // A salted name goes back to the plain one once the colliding container is released
+        fs::write(Path::new(&rundir).join(CELLNAMES_FILE), format!("other = \"{}\"\n", candidate_name(K8S_ID, 0))).unwrap();
+        assert_eq!(assign(&rundir, K8S_ID).unwrap(), candidate_name(K8S_ID, 1));
+        release(&rundir, "other").unwrap();
+        release(&rundir, K8S_ID).unwrap();
+        assert_eq!(assign(&rundir, K8S_ID).unwrap(), candidate_name(K8S_ID, 0));
+        fs::remove_dir_all(&rundir).unwrap();
+    }
+}
@@ -2,69 +2,83 @@
 // Authors: Marco Barletta (marco.barletta@unina.it)
 //*********************************************
 
-use serde_json;
+use std::env;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::process::CommandExt;
 use std::path::Path;
-use std::process::{exit, Command};
+use std::process::{Command, ExitStatus};
 
 // This file implements all the logic related to the forwarding to runc
 
+// Default location of the vanilla runc, where switch_to_runphi.sh backs it up
+const RUNC_DEFAULT_PATH: &str = "/usr/local/sbin/runc_vanilla";
+// Environment variable that overrides the runc binary to forward to
+const RUNC_PATH_ENV: &str = "RUNPHI_RUNC_PATH";
+
+// Outcome of the forwarding filter for delete, the caller decides what to do with it
+#[derive(Debug)]
+pub enum Decision {
+    // The container belongs to runPHI, go on with the partitioned container management
+    Manage,
+    // runc has already handled the command, runPHI must terminate with the same status
+    Forwarded(ExitStatus),
+}
+
+// Path of the runc binary commands are forwarded to
+pub fn runc_path() -> String {
+    env::var(RUNC_PATH_ENV).unwrap_or_else(|_| RUNC_DEFAULT_PATH.to_string())
+}
+
+// Build the runc command line out of the very same arguments runPHI was invoked with.
+// Arguments are taken as OsString, so that nothing is lost or reencoded on the way
+fn runc_command() -> Command {
+    let mut runccmd = Command::new(runc_path());
+    runccmd.args(env::args_os().skip(1));
+    runccmd
+}
+
 // forward to runc if the filter detects the need
-// THE FUNCTION MAY EXIT THE PROGRAM
 // This function always has the config in the json structure, however this is non empty only
 // for create case.
-//TODO: improve code quality here, do not manage program exit inside function
-pub fn runc_forward_ifnecessary(config: &serde_json::Value, containerid: &str) {
-    if need_forward_to_runc(&config, &containerid) {
+// When forwarding, the runPHI process image is replaced by runc, so that pid, stdio, exit
+// code and signals are the ones of runc. Hence, the function returns only when the
+// container is managed by runPHI (Ok), or if runc could not be executed (the error of execve).
+pub fn runc_forward_ifnecessary(config: &serde_json::Value, containerid: &str) -> io::Result<()> {
+    if need_forward_to_runc(config, containerid) {
         logging::log_message(logging::Level::Info,  format!("Forwarding to runc id {}", &containerid).as_str());
-        call_runc()
+        return Err(call_runc());
     }
+    Ok(())
 }
 
-pub fn call_runc() {
-    let mut runccmd = Command::new("/usr/local/sbin/runc_vanilla");
-    for arg in std::env::args().skip(1) {
-        runccmd.arg(arg);
-    }
-    match runccmd.status() {
-        Ok(status) => {
-            if status.success() {
-                exit(0);
-            } else {
-                exit(1);
-            }
-        }
-        Err(_) => {
-            exit(1);
-        }
-    }
+// Replace the current process with runc. Returns only on failure, with the error of execve
+pub fn call_runc() -> io::Error {
+    let mut runccmd = runc_command();
+    logging::log_message(logging::Level::Trace, format!("Executing {:?}", &runccmd).as_str());
+    let err = runccmd.exec();
+    logging::log_message(logging::Level::Error, format!("Failed to execute runc: {}", err).as_str());
+    err
 }
 
-pub fn runc_forward_ifnecessary_delete(config: &serde_json::Value, containerid: &str) {
-    if need_forward_to_runc(&config, &containerid) {
+// Delete cannot replace the process image, since the forwarding table has to be updated once
+// runc succeeded. runc is then run as a child, and its exit status is handed back to the caller
+pub fn runc_forward_ifnecessary_delete(config: &serde_json::Value, containerid: &str) -> io::Result<Decision> {
+    if need_forward_to_runc(config, containerid) {
         logging::log_message(logging::Level::Info,  format!("Forwarding to runc id {}", &containerid).as_str());
-        let mut runccmd = Command::new("/usr/local/sbin/runc_vanilla");
-        for arg in std::env::args().skip(1) {
-            runccmd.arg(arg);
-        }
-        match runccmd.status() {
-            Ok(status) => {
-                if status.success() {
-                    delete_entry_table(&containerid);
-                    exit(0);
-                } else {
-                    logging::log_message(logging::Level::Error, "Runc returned an error");
-                    exit(1);
-                }
-            }
-            Err(_) => {
-                logging::log_message(logging::Level::Error, "Runc returned an error");
-                exit(1);
-            }
+        let status = runc_command().status().map_err(|e| {
+            logging::log_message(logging::Level::Error, format!("Failed to execute runc: {}", e).as_str());
+            e
+        })?;
+        if status.success() {
+            delete_entry_table(containerid);
+        } else {
+            logging::log_message(logging::Level::Error, format!("Runc returned an error: {}", status).as_str());
         }
+        return Ok(Decision::Forwarded(status));
     }
+    Ok(Decision::Manage)
 }
 
 // Recognize if to forward to runc
@@ -96,13 +110,13 @@ pub fn need_forward_to_runc(config: &serde_json::Value, containerid: &str) -> bo
     // If it fails to open file, return false (there was no filter to store any ID in the table)
     if let Ok(lines) = read_lines("/usr/share/runPHI/redirect.txt") {
         // Consumes the iterator, returns an (Optional) String
-        for line in lines.flatten() {
+        for line in lines.map_while(Result::ok) {
             if line.contains(containerid) {
                 return true;
             }
         }
     }
-    return false;
+    false
 }
 
 // This function, if needed updates the forwarding table removing the id of removed container
@@ -6,7 +6,7 @@ use chrono;
 use serde_json;
 use std::fs;
 use std::io;
-use std::io::Error;
+use std::error::Error;
 use std::path::Path;
 
 use backend;
@@ -15,14 +15,14 @@ use liboci_cli;
 
 const WORKPATH: &str = "/usr/share/runPHI";
 
-pub fn start(containerid: &str, crundir: &str) {
+pub fn start(containerid: &str, cellname: &str, crundir: &str) {
     //TODO: check and handle return
-    let _ = backend::startguest(containerid, crundir);
+    let _ = backend::startguest(containerid, cellname, crundir);
 }
 
 #[allow(dead_code)]
-pub fn pause(containerid: &str, crundir: &str) {
-    let _ = backend::stopguest(containerid, crundir);
+pub fn pause(containerid: &str, cellname: &str, crundir: &str) {
+    let _ = backend::stopguest(containerid, cellname, crundir);
     //TODO: check status
 }
 
@@ -35,26 +35,26 @@ pub fn resume(containerid: &str, crundir: &str) {
 }
 
 #[allow(dead_code)]
-pub fn stop(containerid: &str, crundir: &str) {
-    let _ = backend::stopguest(containerid, crundir);
+pub fn stop(containerid: &str, cellname: &str, crundir: &str) {
+    let _ = backend::stopguest(containerid, cellname, crundir);
     //TODO: check status
 }
 
 // Flow: stop guest, destory guest, look for processes (caronte and shim) containing the container id and kill em
-pub fn kill(containerid: &str, crundir: &str) {
-    let _ = backend::stopguest(containerid, crundir);
+pub fn kill(containerid: &str, cellname: &str, crundir: &str) {
+    let _ = backend::stopguest(containerid, cellname, crundir);
     //TODO: check status
 
-    let _ = backend::destroyguest(containerid, crundir);
+    let _ = backend::destroyguest(containerid, cellname, crundir);
     //TODO: check status
 }
 
 // Basically copy of destroy atm plus remotion
-pub fn delete(containerid: &str, crundir: &str) {
-    let _ = backend::stopguest(containerid, crundir);
+pub fn delete(containerid: &str, cellname: &str, crundir: &str) {
+    let _ = backend::stopguest(containerid, cellname, crundir);
     //TODO: check status
 
-    let _ = backend::destroyguest(containerid, crundir);
+    let _ = backend::destroyguest(containerid, cellname, crundir);
     //TODO: check status
 
     let _ = backend::cleanup(containerid, crundir);
@@ -63,10 +63,11 @@ pub fn delete(containerid: &str, crundir: &str) {
 // Flow: call config generator to create config file, then call mount (?), create-guest giving the config file, and finally start guest
 pub fn create(
     containerid: &str,
+    cellname: &str,
     args: liboci_cli::Create,
     crundir: &str,
     parsedconfig: serde_json::Value,
-) -> Result<(), Error> {
+) -> Result<(), Box<dyn Error>> {
     let mut f2b: f2b::FrontendConfig = f2b::FrontendConfig::new();
     //TODO: replace the following with something unaware of the backend
     f2b.crundir = crundir.to_string();
@@ -75,6 +76,7 @@ pub fn create(
         None => ".".to_string(),
     };
     f2b.containerid = containerid.to_string();
+    f2b.cellname = cellname.to_string();
     f2b.bundle = args.bundle.to_string_lossy().into_owned();
     f2b.pidfile = args.pid_file.unwrap().to_string_lossy().into_owned();
     f2b.jsonconfig = parsedconfig;
@@ -84,7 +86,7 @@ pub fn create(
     if !Path::new(&format!("{}/bundle", &crundir)).exists() {
         let rootfs_in = f2b.jsonconfig["root"]["path"]
             .as_str()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Cannot determine rootfs"))?;
+            .ok_or_else(|| io::Error::other("Cannot determine rootfs"))?;
         f2b.mountpoint = if rootfs_in.starts_with('/') {
             rootfs_in.to_string()
         } else {
@@ -99,8 +101,8 @@ pub fn create(
 
     // Execute config_generator script to generate configuration file
     logging::log_message(logging::Level::Info,  format!("Creating config for ID {}", &containerid).as_str());
-    let ic_p = backend::configGenerator::config_generate(&f2b);
-    let ic: f2b::ImageConfig = *ic_p.unwrap();
+    // A broken image config makes create fail here, before any resource is taken
+    let ic: f2b::ImageConfig = *backend::configGenerator::config_generate(&f2b)?;
 
     // Execute mount utility to adjust rootfs
     //TODO: call mount
@@ -109,22 +111,26 @@ pub fn create(
     //     .arg(format!("{}/backend/mount {} \"{}\" mount", WORKPATH, containerid, crundir))
     //     .status();
 
-    logging::log_message(logging::Level::Info, format!("Creating guest for ID {}", &containerid).as_str());
-    let _ = backend::createguest(&f2b, &ic);
-    //TODO handle return value
-
     // Save info on files required by start guest as well as other commands
     // Here the point is that startguest maybe called alone, and it would read info from file
     // It is easier to not distinguish behavior and always read from file
-    let _ = backend::storeinfo(&f2b, &ic);
+    // Saved before the guest is created, so that a failed create can be undone as a delete
+    logging::log_message(logging::Level::Info, format!("Creating guest for ID {}", &containerid).as_str());
+    if let Err(e) = backend::storeinfo(&f2b, &ic).and_then(|_| backend::createguest(&f2b, &ic)) {
+        // Give back the resources recorded in the state by the config generator, and destroy
+        // the cell if it was created
+        logging::log_message(logging::Level::Error, format!("Failed to create guest for ID {}: {}", &containerid, e).as_str());
+        let _ = backend::destroyguest(containerid, cellname, crundir);
+        return Err(e);
+    }
 
     //backend::storeadditionalinfo(&mut backendconfig); Enable for debug
 
-    return Ok(());
+    Ok(())
 }
 
 //TODO: Test this, how to invoke from ctr???
-pub fn state(container_id: &str, crundir: &str) -> Result<(), Error> {
+pub fn state(container_id: &str, crundir: &str) -> Result<(), io::Error> {
     // Read bundle and pidfile
     //TODO: move this to backend
     let bundle = fs::read_to_string(format!("{}/bundle", crundir))?;
@@ -155,5 +161,49 @@ pub fn state(container_id: &str, crundir: &str) -> Result<(), Error> {
     }}"#,
         container_id, pid, bundle, mountpoint, date
     );
-    return Ok(());
+    Ok(())
+}
+
+// Checks the platform description of the node, printing every problem found.
+// Returns whether the description is consistent
+pub fn platform_check(platform_info: &Path, state: &Path, template_dir: &Path) -> bool {
+    let problems = backend::platform::check(platform_info, state, template_dir);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("{} and {} are consistent", platform_info.display(), state.display());
+    } else {
+        println!("{} problem(s) found", problems.len());
+    }
+    problems.is_empty()
+}
+
+// Writes the initial state.toml and a skeleton platform_info.toml of the node, generated from
+// its root cell config, then lists what is left to fill in
+pub fn platform_init(root_config: &Path, linux_mem: &str, output_dir: &Path, force: bool) -> Result<(), Box<dyn Error>> {
+    let linux_mem = backend::bootstrap::parse_mem_size(linux_mem)?;
+    let config = fs::read(root_config)?;
+    let generated = backend::bootstrap::generate(&config, linux_mem, &root_config.display().to_string())?;
+
+    let state = output_dir.join("state.toml");
+    let platform_info = output_dir.join("platform_info.toml");
+    for path in [&state, &platform_info] {
+        if path.exists() && !force {
+            return Err(format!("{} already exists, use --force to overwrite it", path.display()).into());
+        }
+    }
+    fs::create_dir_all(output_dir)?;
+    fs::write(&state, generated.state)?;
+    fs::write(&platform_info, generated.platform_info)?;
+    println!("Written {} and {}", state.display(), platform_info.display());
+
+    let problems = backend::platform::check(&platform_info, &state, Path::new("/usr/share/runPHI/templates"));
+    if !problems.is_empty() {
+        println!("Left to fill in:");
+        for problem in &problems {
+            println!("{}", problem);
+        }
+    }
+    Ok(())
 }
@@ -6,9 +6,13 @@
 
 //use clap::{CommandFactory, Parser};
 use clap::Parser;
-use serde_json;
 use std::error::Error;
 use std::fs;
+use std::path::PathBuf;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{exit, ExitStatus};
+
+use nix::sys::signal::{self, Signal};
 
 //LIBRARIES FOR log_timestamp_with_memory_mmap function
 //use std::fs::OpenOptions;
@@ -18,10 +22,8 @@ use std::fs;
 //use nix::libc::{mmap, munmap, MAP_SHARED, PROT_READ};
 
 //use std::ptr;
-//use std::process::exit;
 
 use liboci_cli::{GlobalOpts, StandardCmd};
-use logging;
 
 // High-level commandline option definition
 // This takes global options as well as individual commands as specified in [OCI runtime-spec](https://github.com/opencontainers/runtime-spec/blob/master/runtime.md)
@@ -45,11 +47,52 @@ enum SubCommand {
     Standard(Box<liboci_cli::StandardCmd>),
     #[clap(flatten)]
     Common(Box<liboci_cli::CommonCmd>),
+    // runPHI specific commands, not part of the OCI spec
+    #[clap(subcommand)]
+    Platform(PlatformCmd),
+}
+
+/// Tools for the platform description of the node
+#[derive(Parser, Debug)]
+enum PlatformCmd {
+    /// Cross-validate platform_info.toml and the initial state.toml, reporting every problem
+    Check(PlatformCheck),
+    /// Generate the initial state.toml and a skeleton platform_info.toml from the root cell config
+    Init(PlatformInit),
+}
+
+#[derive(Parser, Debug)]
+struct PlatformCheck {
+    /// Platform description, with the templates of the cells
+    #[clap(long, default_value = "/usr/share/runPHI/platform_info.toml")]
+    platform_info: PathBuf,
+    /// Initial state of the node, with the resources available to the cells
+    #[clap(long, default_value = "/usr/share/runPHI/state.toml")]
+    state: PathBuf,
+    /// Directory of the template files of the platform
+    #[clap(long, default_value = "/usr/share/runPHI/templates")]
+    templates: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct PlatformInit {
+    /// Root cell config passed to jailhouse enable (compiled system config or root .cell)
+    root_config: PathBuf,
+    /// Memory used by Linux in the root cell, as its mem= parameter (e.g. 768M)
+    #[clap(long)]
+    linux_mem: String,
+    /// Directory where state.toml and platform_info.toml are written
+    #[clap(long, default_value = ".")]
+    output_dir: PathBuf,
+    /// Overwrite existing files
+    #[clap(long)]
+    force: bool,
 }
 
 mod frontend {
     pub mod commands;
 }
+mod cellname;
 mod forwarding;
 
 
@@ -70,26 +113,29 @@ fn main() -> Result<(), Box<dyn Error>> {
     let opts = Opts::parse();
     //let _app = Opts::command();
 
-    logging::init_logger(Some(std::path::PathBuf::from(std::path::Path::new("/usr/share/runPHI/log.txt"))));//opts.global.log);
+    // The platform commands report to the terminal, and may run out of the board
+    if !matches!(opts.subcmd, SubCommand::Platform(_)) {
+        logging::init_logger(Some(std::path::PathBuf::from(std::path::Path::new("/usr/share/runPHI/log.txt"))));//opts.global.log);
+    }
 
-    let _ = match opts.subcmd {
+    match opts.subcmd {
         SubCommand::Standard(cmd) => match *cmd {
             // We here distinguish the behaviour by command defined as OCI spec
-            // Common to all commands, take the first 24 chars to get the containerID
-            // RunPHI restricts the ID to 24 chars because hypervisors like Jailhouse may fail with
-            // a partition of a longer name
+            // Common to all commands, the full containerID is used for runPHI bookkeeping (run dir,
+            // state), while hypervisors like Jailhouse may fail with a partition of a longer name,
+            // hence the backend gets a short cell name mapped to the ID (see cellname.rs)
             // After collecting the ID, we have to check if we need to forward to runc because container
             // does not belong to RunPHI management cycle.
             //TODO: fix common part handling
             StandardCmd::Create(create) => {
-                containerid = create.container_id.chars().take(24).collect::<String>();
+                containerid = create.container_id.clone();
                 logging::log_message(logging::Level::Info,  format!("Creating with id {}", &containerid).as_str());
                 logging::log_message(logging::Level::Debug,  "Parse json");
                 let config_json = fs::read_to_string(format!(
                     "{}/config.json",
                     &create.bundle.to_string_lossy().into_owned()))?;
                 config = serde_json::from_str(&config_json)?;
-                forwarding::runc_forward_ifnecessary(&config, &containerid);
+                forwarding::runc_forward_ifnecessary(&config, &containerid)?;
 
                 // If we are here, there was no forwarding to runc, hence we start runphi management
                 let crundir = format!("{}/{}", RUNDIR, containerid);
@@ -97,41 +143,74 @@ fn main() -> Result<(), Box<dyn Error>> {
                 fs::remove_dir_all(&crundir).ok();
                 //Create container directory to store runphi-related information
                 fs::create_dir_all(&crundir)?;
+                let cellname = cellname::assign(RUNDIR, &containerid)?;
 
-                let _ = frontend::commands::create(&containerid, create, &crundir, config);
+                if let Err(e) = frontend::commands::create(&containerid, &cellname, create, &crundir, config) {
+                    logging::log_message(logging::Level::Error, format!("Create failed for id {}: {}", &containerid, e).as_str());
+                    // The resources of the container are not taken or were given back, do not
+                    // leave its traces around
+                    fs::remove_dir_all(&crundir).ok();
+                    cellname::release(RUNDIR, &containerid)?;
+                    return Err(e);
+                }
             }
             StandardCmd::Start(start) => {
-                containerid = start.container_id.chars().take(24).collect::<String>();
+                containerid = start.container_id.clone();
                 logging::log_message(logging::Level::Info,  format!("Starting with id {}", &containerid).as_str());
-                forwarding::runc_forward_ifnecessary(&config, &containerid);
+                forwarding::runc_forward_ifnecessary(&config, &containerid)?;
                 let crundir = format!("{}/{}", RUNDIR, containerid);
-                let _ = frontend::commands::start(&containerid, &crundir);
+                let cellname = cellname::lookup(RUNDIR, &containerid)?;
+                frontend::commands::start(&containerid, &cellname, &crundir);
             }
             StandardCmd::Kill(kill) => {
-                containerid = kill.container_id.chars().take(24).collect::<String>();
+                containerid = kill.container_id.clone();
                 logging::log_message(logging::Level::Info,  format!("Killing with id {}", &containerid).as_str());
-                forwarding::runc_forward_ifnecessary(&config, &containerid);
+                forwarding::runc_forward_ifnecessary(&config, &containerid)?;
                 let crundir = format!("{}/{}", RUNDIR, containerid);
-                let _ = frontend::commands::kill(&containerid, &crundir);
+                let cellname = cellname::lookup(RUNDIR, &containerid)?;
+                frontend::commands::kill(&containerid, &cellname, &crundir);
             }
 
             StandardCmd::Delete(delete) => {
-                containerid = delete.container_id.chars().take(24).collect::<String>();
+                containerid = delete.container_id.clone();
                 logging::log_message(logging::Level::Info,  format!("Deleting with id {}", &containerid).as_str());
-                forwarding::runc_forward_ifnecessary_delete(&config, &containerid);
+                if let forwarding::Decision::Forwarded(status) =
+                    forwarding::runc_forward_ifnecessary_delete(&config, &containerid)?
+                {
+                    exit_as(status);
+                }
                 let crundir = format!("{}/{}", RUNDIR, containerid);
-                let _ = frontend::commands::delete(&containerid, &crundir);
+                match cellname::lookup(RUNDIR, &containerid) {
+                    Ok(cellname) => {
+                        frontend::commands::delete(&containerid, &cellname, &crundir);
+                        cellname::release(RUNDIR, &containerid)?;
+                    }
+                    // No cell was ever created for this container, only the run dir may be left
+                    Err(e) => {
+                        logging::log_message(logging::Level::Warn, format!("{}, cleaning up only", e).as_str());
+                        fs::remove_dir_all(&crundir).ok();
+                    }
+                }
             }
 
             StandardCmd::State(state) => {
-                containerid = state.container_id.chars().take(24).collect::<String>();
+                containerid = state.container_id.clone();
                 logging::log_message(logging::Level::Info,  format!("State with id {}", &containerid).as_str());
-                forwarding::runc_forward_ifnecessary(&config, &containerid);
+                forwarding::runc_forward_ifnecessary(&config, &containerid)?;
                 let crundir = format!("{}/{}", RUNDIR, containerid);
                 let _ = frontend::commands::state(&containerid, &crundir);
             }
         },
-        SubCommand::Common(_) => {} /* Unimplemented yet
+        SubCommand::Platform(PlatformCmd::Check(check)) => {
+            if !frontend::commands::platform_check(&check.platform_info, &check.state, &check.templates) {
+                exit(1);
+            }
+        }
+        SubCommand::Platform(PlatformCmd::Init(init)) => {
+            frontend::commands::platform_init(&init.root_config, &init.linux_mem, &init.output_dir, init.force)?;
+        }
+        // runPHI does not implement these commands, runc takes care of them for the containers it manages
+        SubCommand::Common(_) => return Err(Box::new(forwarding::call_runc())), /* Unimplemented yet
                                     match *cmd {
                                         CommonCmd::Checkpoint(checkpoint) => {
                                             commands::checkpoint::checkpoint(checkpoint, root_path)
@@ -169,7 +248,24 @@ fn main() -> Result<(), Box<dyn Error>> {
     };
 
     //log_timestamp_with_memory_mmap("end main", log_file, mem_address, mem_size).unwrap();
-    return Ok(());
+    Ok(())
+}
+
+// Terminate runPHI mirroring the exit status of a forwarded runc: same exit code, or same
+// signal if runc was killed by one, so that the caller cannot tell the difference
+fn exit_as(status: ExitStatus) -> ! {
+    if let Some(signum) = status.signal() {
+        if let Ok(sig) = Signal::try_from(signum) {
+            // Restore the default disposition before raising, otherwise the signal may be ignored
+            unsafe {
+                let _ = signal::signal(sig, signal::SigHandler::SigDfl);
+            }
+            let _ = signal::raise(sig);
+        }
+        // Same convention used by shells for children killed by a signal
+        exit(128 + signum);
+    }
+    exit(status.code().unwrap_or(1));
 }
 
 /* #[allow(dead_code)]